@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+use tornado_std::thread;
+
+const STEPS: usize = 50;
+
+// 用牛顿法逼近x的平方根，每一步之后让出处理核，其它线程在此期间使用自己的浮点寄存器
+fn sqrt(x: f64, yield_each_step: bool) -> f64 {
+    let mut guess = x;
+    for _ in 0..STEPS {
+        guess = 0.5 * (guess + x / guess);
+        if yield_each_step {
+            thread::yield_now();
+        }
+    }
+    guess
+}
+
+#[no_mangle]
+fn main() -> i32 {
+    let inputs = [2.0, 3.0, 10.0, 12345.0];
+    let mut handles = [None, None, None, None];
+    for (slot, &x) in handles.iter_mut().zip(inputs.iter()) {
+        *slot = Some(thread::spawn(move || sqrt(x, true)));
+    }
+    // 主线程也在使用浮点寄存器，切换线程时它们必须被保存和恢复
+    let mut sum = 0.0;
+    for &x in inputs.iter() {
+        sum += x;
+        thread::yield_now();
+    }
+    for (handle, &x) in handles.iter_mut().filter_map(Option::take).zip(inputs.iter()) {
+        let root = handle.join().expect("join thread");
+        let expected = sqrt(x, false);
+        println!("sqrt({}) = {}", x, root);
+        assert_eq!(root.to_bits(), expected.to_bits());
+    }
+    println!("sum of inputs = {}", sum);
+    assert_eq!(sum, 12360.0);
+    0
+}
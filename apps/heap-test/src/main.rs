@@ -0,0 +1,35 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use tornado_std::heap;
+use tornado_std::io::ErrorKind;
+
+#[no_mangle]
+fn main() -> i32 {
+    let start = heap::sbrk(0).expect("read program break");
+    let boxed = Box::new(0x2333usize);
+    let mut words = BTreeMap::new();
+    for word in "the quick brown fox jumps over the lazy dog the end".split(' ') {
+        *words.entry(String::from(word)).or_insert(0) += 1;
+    }
+    println!("Box: {:#x}, words: {:?}", boxed, words);
+    // 超过一页的分配，堆会多次增长
+    let squares: Vec<usize> = (0..100_000).map(|i| i * i).collect();
+    assert_eq!(squares[99_999], 99_999 * 99_999);
+    let end = heap::sbrk(0).expect("read program break");
+    println!("Heap grew from {:#x} to {:#x} ({} KiB)", start, end, (end - start) / 1024);
+    drop(squares);
+    // 内核限制了每个进程使用的内存，超过限制的请求会失败
+    match heap::sbrk(1 << 30) {
+        Err(e) if e.kind() == ErrorKind::OutOfMemory => println!("Growing the heap by 1 GiB failed as expected: {}", e),
+        other => {
+            println!("Growing the heap by 1 GiB should fail, got {:?}", other);
+            return 1;
+        },
+    }
+    0
+}
@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+use core::fmt::Write;
+use tornado_std::env;
+use tornado_std::io;
+use tornado_std::ipc::{self, Channel, SharedMemory, MAX_MESSAGE_SIZE};
+use tornado_std::process;
+
+const GREETING: &[u8] = b"hello from the parent, through shared memory";
+
+// 不带参数运行时作为父进程，创建通道和子进程；子进程的第一个参数是通道端点的句柄
+#[no_mangle]
+fn main() -> i32 {
+    let ans = match env::args().nth(1) {
+        None => parent(),
+        Some(handle) => match handle.parse() {
+            Ok(handle) => child(unsafe { Channel::from_raw_handle(handle) }),
+            Err(_) => Err(io::Error::from_raw_os_error(22)), // EINVAL
+        },
+    };
+    match ans {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("ipc-test: {}", e);
+            1
+        },
+    }
+}
+
+fn parent() -> io::Result<()> {
+    let (ours, theirs) = ipc::channel()?;
+    let mut arg = Buffer::default();
+    let _ = write!(arg, "{}", theirs.as_raw_handle());
+    let mut child = process::spawn("/bin/ipc-test", &[arg.as_str()])?;
+    drop(theirs); // 子进程有自己的句柄
+    let shm = SharedMemory::new(8192)?;
+    let mut mapping = shm.map()?;
+    mapping[..GREETING.len()].copy_from_slice(GREETING);
+    ours.send_with(b"request", shm)?;
+    let mut buf = [0u8; MAX_MESSAGE_SIZE];
+    let reply = ours.recv(&mut buf)?;
+    // 子进程写入的回复直接出现在父进程的映射中
+    let len = mapping.iter().position(|&b| b == 0).unwrap_or(mapping.len());
+    println!("parent: got {:?}, shared memory now says {:?}",
+        core::str::from_utf8(&buf[..reply.len]), core::str::from_utf8(&mapping[..len]));
+    let code = child.wait()?;
+    println!("parent: child returned {}", code);
+    Ok(())
+}
+
+fn child(channel: Channel) -> io::Result<()> {
+    let mut buf = [0u8; MAX_MESSAGE_SIZE];
+    let message = channel.recv(&mut buf)?;
+    let shm = message.shared_memory.ok_or(io::Error::from_raw_os_error(22))?;
+    let mut mapping = shm.map()?;
+    println!("child: got {:?} with {} bytes of shared memory: {:?}",
+        core::str::from_utf8(&buf[..message.len]), shm.len(), core::str::from_utf8(&mapping[..GREETING.len()]));
+    let reply = b"hello from the child";
+    mapping[..reply.len()].copy_from_slice(reply);
+    mapping[reply.len()] = 0;
+    channel.send(b"done")
+}
+
+// 格式化句柄编号用的缓冲区
+#[derive(Default)]
+struct Buffer {
+    data: [u8; 20],
+    len: usize,
+}
+
+impl Buffer {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.data[..self.len]).unwrap()
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.data.len() {
+            return Err(core::fmt::Error);
+        }
+        self.data[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
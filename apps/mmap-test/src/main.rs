@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+use tornado_std::fs::{self, File, OpenOptions};
+use tornado_std::io::{self, ErrorKind, Read, Write};
+use tornado_std::mman::MmapOptions;
+
+const PATH: &str = "/mmap-test.txt";
+
+#[no_mangle]
+fn main() -> i32 {
+    match run() {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("mmap-test: {}", e);
+            1
+        },
+    }
+}
+
+fn run() -> io::Result<()> {
+    File::create(PATH)?.write_all(b"hello, world")?;
+    let file = OpenOptions::new().read(true).write(true).open(PATH)?;
+    // 共享映射的修改在最后一个映射取消时写回文件
+    let mut shared = unsafe { MmapOptions::new().map_mut(&file)? };
+    shared[..5].copy_from_slice(b"HELLO");
+    // 私有映射的修改不会写回
+    let mut private = MmapOptions::new().map_copy(&file)?;
+    private[7..].copy_from_slice(b"there");
+    println!("shared: {:?}, private: {:?}", core::str::from_utf8(&shared), core::str::from_utf8(&private));
+    let mut anon = MmapOptions::new().len(3 * 4096).map_anon()?;
+    assert!(anon.iter().all(|&b| b == 0));
+    anon[3 * 4096 - 1] = 1;
+    cat("/proc/self/maps")?;
+    drop((shared, private, anon));
+    let mut content = [0u8; 16];
+    let n = File::open(PATH)?.read(&mut content)?;
+    println!("file after unmap: {:?}", core::str::from_utf8(&content[..n]));
+    assert_eq!(&content[..n], b"HELLO, world");
+    // 只读打开的文件，共享映射不能改为可写
+    let map = unsafe { MmapOptions::new().map(&File::open(PATH)?)? };
+    match map.make_mut() {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => println!("make_mut on read-only file failed as expected: {}", e),
+        Err(e) => return Err(e),
+        Ok(_) => panic!("make_mut on read-only file should fail"),
+    }
+    fs::remove_file(PATH)
+}
+
+fn cat(path: &str) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = [0u8; 512];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(()),
+            n => io::stdout().write_all(&buf[..n])?,
+        }
+    }
+}
@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+use tornado_std::fs::File;
+use tornado_std::io::{self, Read, Write};
+
+// 像cat一样，依次输出这些文件的内容
+const FILES: &[&str] = &["/proc/self/status", "/proc/self/maps", "/proc/meminfo", "/proc/sbi", "/proc/slabinfo"];
+
+#[no_mangle]
+fn main() -> i32 {
+    let mut code = 0;
+    for path in FILES {
+        println!("==> {} <==", path);
+        if let Err(e) = cat(path) {
+            println!("proc-info: {}: {}", path, e);
+            code = 1;
+        }
+    }
+    code
+}
+
+fn cat(path: &str) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = [0u8; 512];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(()),
+            n => io::stdout().write_all(&buf[..n])?,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! 内建命令
+//!
+//! 内建命令在shell进程中运行。cd改变的是shell自己的当前目录，所以必须是内建命令；
+//! 其它几个命令放在这里，是因为系统中还没有对应的程序
+
+use crate::path::PathBuf;
+use tornado_std::fs::{self, File};
+use tornado_std::io::{self, Read, Write};
+
+// 命令运行完以后，shell是继续读取下一行，还是带着返回值退出
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue(i32),
+    Exit(i32),
+}
+
+impl Flow {
+    pub fn code(&self) -> i32 {
+        match *self {
+            Flow::Continue(code) | Flow::Exit(code) => code,
+        }
+    }
+}
+
+type Builtin = fn(args: &[&str], cwd: &mut PathBuf) -> Flow;
+
+// 命令的名字、用法和实现
+const BUILTINS: &[(&str, &str, Builtin)] = &[
+    ("cd", "cd [dir]          change the current directory", cd),
+    ("pwd", "pwd               print the current directory", pwd),
+    ("ls", "ls [path...]      list directory contents", ls),
+    ("cat", "cat [file...]     print files, or standard input", cat),
+    ("echo", "echo [arg...]     print the arguments", echo),
+    ("exit", "exit [code]       leave the shell", exit),
+    ("help", "help              show this message", help),
+];
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.iter().any(|&(builtin, _, _)| builtin == name)
+}
+
+// 运行内建命令，args的第一项是命令的名字。不是内建命令时返回None
+pub fn run(args: &[&str], cwd: &mut PathBuf) -> Option<Flow> {
+    let &(_, _, f) = BUILTINS.iter().find(|&&(name, _, _)| Some(&name) == args.first())?;
+    Some(f(args, cwd))
+}
+
+fn cd(args: &[&str], cwd: &mut PathBuf) -> Flow {
+    let target = args.get(1).copied().unwrap_or("/");
+    let path = match cwd.join(target) {
+        Ok(path) => path,
+        Err(e) => return fail("cd", target, e),
+    };
+    match fs::metadata(path.as_str()) {
+        Ok(meta) if meta.is_dir() => {
+            *cwd = path;
+            Flow::Continue(0)
+        },
+        Ok(_) => fail("cd", target, "not a directory"),
+        Err(e) => fail("cd", target, e),
+    }
+}
+
+fn pwd(_args: &[&str], cwd: &mut PathBuf) -> Flow {
+    println!("{}", cwd);
+    Flow::Continue(0)
+}
+
+fn ls(args: &[&str], cwd: &mut PathBuf) -> Flow {
+    let targets = if args.len() > 1 { &args[1..] } else { &["."][..] };
+    let mut code = 0;
+    for (i, target) in targets.iter().enumerate() {
+        if targets.len() > 1 {
+            println!("{}{}:", if i == 0 { "" } else { "\n" }, target);
+        }
+        let result = cwd.join(target).map_err(Error::Path).and_then(|path| list(path.as_str(), target));
+        if let Err(e) = result {
+            code = fail("ls", target, e).code();
+        }
+    }
+    Flow::Continue(code)
+}
+
+// 目录列出其中的项目，目录的名字后面加上`/`；其它文件只列出它自己
+fn list(path: &str, target: &str) -> Result<(), Error> {
+    if !fs::metadata(path)?.is_dir() {
+        println!("{}", target);
+        return Ok(());
+    }
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let suffix = if entry.file_type().is_dir() { "/" } else { "" };
+        println!("{}{}", entry.file_name(), suffix);
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str], cwd: &mut PathBuf) -> Flow {
+    if args.len() == 1 {
+        return match copy_to_stdout(&mut io::stdin()) {
+            Ok(()) => Flow::Continue(0),
+            Err(e) => fail("cat", "-", e),
+        };
+    }
+    let mut code = 0;
+    for target in &args[1..] {
+        let result = cwd.join(target).map_err(Error::Path).and_then(|path| {
+            let mut file = File::open(path.as_str())?;
+            copy_to_stdout(&mut file).map_err(Error::Io)
+        });
+        if let Err(e) = result {
+            code = fail("cat", target, e).code();
+        }
+    }
+    Flow::Continue(code)
+}
+
+fn copy_to_stdout(input: &mut impl Read) -> io::Result<()> {
+    let mut buf = [0u8; 512];
+    loop {
+        match input.read(&mut buf)? {
+            0 => return Ok(()),
+            n => io::stdout().write_all(&buf[..n])?,
+        }
+    }
+}
+
+fn echo(args: &[&str], _cwd: &mut PathBuf) -> Flow {
+    for (i, arg) in args.iter().enumerate().skip(1) {
+        print!("{}{}", if i == 1 { "" } else { " " }, arg);
+    }
+    println!("");
+    Flow::Continue(0)
+}
+
+fn exit(args: &[&str], _cwd: &mut PathBuf) -> Flow {
+    match args.get(1).map(|code| code.parse::<i32>()) {
+        None => Flow::Exit(0),
+        Some(Ok(code)) => Flow::Exit(code),
+        Some(Err(_)) => fail("exit", args[1], "numeric argument required"),
+    }
+}
+
+fn help(_args: &[&str], _cwd: &mut PathBuf) -> Flow {
+    println!("Built-in commands:");
+    for (_, usage, _) in BUILTINS {
+        println!("  {}", usage);
+    }
+    println!("Other commands run the program with that name in /bin, or at the given path.");
+    println!("Use `|` to connect commands, `<`, `>` and `>>` to redirect input and output.");
+    Flow::Continue(0)
+}
+
+// 内建命令失败时输出错误信息，返回值为1
+fn fail(cmd: &str, target: &str, e: impl core::fmt::Display) -> Flow {
+    eprintln!("{}: {}: {}", cmd, target, e);
+    Flow::Continue(1)
+}
+
+enum Error {
+    Io(io::Error),
+    Path(crate::path::PathTooLong),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Path(e) => write!(f, "{}", e),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+mod builtin;
+mod parse;
+mod path;
+
+use builtin::Flow;
+use parse::{Pipeline, Stage, MAX_ARGS, MAX_LINE, MAX_STAGES};
+use path::PathBuf;
+use tornado_std::env;
+use tornado_std::fs::{File, OpenOptions};
+use tornado_std::io::{self, ErrorKind, Read, Write, STDIN, STDOUT};
+use tornado_std::process::{self, Child};
+use tornado_std::signal::{self, SigHandler};
+
+// 运行命令时标准输入输出会被重定向，shell把原来的标准输入输出保存在这两个描述符中
+const SAVED_STDIN: usize = 10;
+const SAVED_STDOUT: usize = 11;
+
+// 在管道中运行内建命令时，shell启动自己的另一个实例：`shell -b <当前目录> <命令> [参数...]`
+const BUILTIN_FLAG: &str = "-b";
+
+#[no_mangle]
+fn main() -> i32 {
+    let mut args = env::args();
+    let self_path = args.next().unwrap_or("/bin/shell");
+    if args.next() == Some(BUILTIN_FLAG) {
+        return run_builtin_child(args);
+    }
+    if let Err(e) = io::dup2(STDIN, SAVED_STDIN).and_then(|_| io::dup2(STDOUT, SAVED_STDOUT)) {
+        eprintln!("shell: cannot save standard input and output: {}", e);
+        return 1;
+    }
+    // Ctrl-C向所有进程发送SIGINT，只结束正在运行的命令，shell自己继续运行
+    if let Err(e) = signal::signal(signal::SIGINT, SigHandler::Ignore) {
+        eprintln!("shell: cannot ignore SIGINT: {}", e);
+    }
+    let mut shell = Shell { self_path, cwd: PathBuf::root(), status: 0 };
+    shell.run()
+}
+
+fn run_builtin_child(mut args: env::Args) -> i32 {
+    let mut cwd = PathBuf::root();
+    if let Err(e) = cwd.push(args.next().unwrap_or("/")) {
+        eprintln!("shell: {}", e);
+        return 1;
+    }
+    let mut argv = [""; MAX_ARGS];
+    let mut argc = 0;
+    for arg in args.take(MAX_ARGS) {
+        argv[argc] = arg;
+        argc += 1;
+    }
+    match builtin::run(&argv[..argc], &mut cwd) {
+        Some(flow) => flow.code(),
+        None => {
+            eprintln!("shell: {}: not a built-in command", argv[0]);
+            127
+        },
+    }
+}
+
+struct Shell {
+    // 这个程序的路径，用来在管道中运行内建命令
+    self_path: &'static str,
+    cwd: PathBuf,
+    // 上一条命令的返回值
+    status: i32,
+}
+
+impl Shell {
+    fn run(&mut self) -> i32 {
+        println!("Tornado shell. Type `help` for the list of built-in commands.");
+        loop {
+            print!("tornado:{}$ ", self.cwd);
+            let mut buf = [0u8; MAX_LINE];
+            let len = match read_line(&mut buf) {
+                Ok(Some(len)) => len,
+                Ok(None) => return self.status, // 输入结束
+                Err(e) => {
+                    eprintln!("shell: read standard input: {}", e);
+                    return 1;
+                },
+            };
+            let line = match core::str::from_utf8(&buf[..len]) {
+                Ok(line) => line,
+                Err(_) => {
+                    eprintln!("shell: input is not valid UTF-8");
+                    continue;
+                },
+            };
+            let mut words = [0u8; MAX_LINE];
+            let pipeline = match parse::parse(line, &mut words) {
+                Ok(pipeline) if pipeline.stages().is_empty() => continue,
+                Ok(pipeline) => pipeline,
+                Err(e) => {
+                    eprintln!("shell: {}", e);
+                    self.status = 2;
+                    continue;
+                },
+            };
+            match self.execute(&pipeline) {
+                Flow::Continue(code) => {
+                    if let Some(sig) = signal::exit_signal(code) {
+                        eprintln!("[killed by signal {}]", sig);
+                    } else if code != 0 {
+                        eprintln!("[exit code {}]", code);
+                    }
+                    self.status = code;
+                },
+                Flow::Exit(code) => return code,
+            }
+        }
+    }
+
+    fn execute(&mut self, pipeline: &Pipeline) -> Flow {
+        let stages = pipeline.stages();
+        // 单独的内建命令在shell中运行，这样cd和exit才能起作用
+        if stages.len() == 1 && builtin::is_builtin(stages[0].args()[0]) {
+            let stage = &stages[0];
+            let redirect = self.open_redirections(stage, None, None).and_then(|(input, output)| {
+                Redirect::new(input.as_ref(), output.as_ref())
+            });
+            return match redirect {
+                Ok(_redirect) => builtin::run(stage.args(), &mut self.cwd).unwrap(),
+                Err(e) => {
+                    eprintln!("shell: {}", e);
+                    Flow::Continue(1)
+                },
+            };
+        }
+        // 从左到右依次启动管道中的每个命令，shell不保留管道的写端，读取的命令才能读到文件末尾
+        let mut children: [Option<Child>; MAX_STAGES] = Default::default();
+        let mut pipe_reader = None;
+        let mut code = 0;
+        for (i, stage) in stages.iter().enumerate() {
+            let last = i + 1 == stages.len();
+            match self.spawn_stage(stage, pipe_reader.take(), last) {
+                Ok((child, next)) => {
+                    children[i] = Some(child);
+                    pipe_reader = next;
+                },
+                Err(e) => {
+                    let name = stage.args()[0];
+                    code = match e.kind() {
+                        ErrorKind::NotFound => {
+                            eprintln!("shell: {}: command not found", name);
+                            127
+                        },
+                        _ => {
+                            eprintln!("shell: {}: {}", name, e);
+                            126
+                        },
+                    };
+                    break;
+                },
+            }
+        }
+        drop(pipe_reader);
+        // 管道的返回值是最后一个命令的返回值
+        for child in children.iter_mut().flatten() {
+            match child.wait() {
+                Ok(status) => code = status,
+                Err(e) => eprintln!("shell: wait for process {}: {}", child.id(), e),
+            }
+        }
+        Flow::Continue(code)
+    }
+
+    // 启动管道中的一个命令。不是最后一个命令时，创建到下一个命令的管道，返回它的读端。
+    // 子进程会继承这个读端，所以读取的命令提前结束时，写入的命令不会收到BrokenPipe
+    fn spawn_stage(&self, stage: &Stage, pipe_reader: Option<File>, last: bool) -> io::Result<(Child, Option<File>)> {
+        let (pipe_writer, next) = if last {
+            (None, None)
+        } else {
+            let (reader, writer) = io::pipe()?;
+            (Some(writer), Some(reader))
+        };
+        // 重定向的文件优先于管道；输出重定向到文件时，下一个命令从管道中读不到任何数据
+        let (input, output) = self.open_redirections(stage, pipe_reader, pipe_writer)?;
+        let _redirect = Redirect::new(input.as_ref(), output.as_ref())?;
+        let args = stage.args();
+        let child = if builtin::is_builtin(args[0]) {
+            let mut argv = [""; MAX_ARGS + 2];
+            argv[0] = BUILTIN_FLAG;
+            argv[1] = self.cwd.as_str();
+            argv[2..2 + args.len()].copy_from_slice(args);
+            process::spawn(self.self_path, &argv[..2 + args.len()])?
+        } else {
+            let program = self.find_program(args[0])?;
+            process::spawn(program.as_str(), &args[1..])?
+        };
+        Ok((child, next))
+    }
+
+    fn open_redirections(&self, stage: &Stage, input: Option<File>, output: Option<File>) -> io::Result<(Option<File>, Option<File>)> {
+        let input = match stage.stdin {
+            Some(path) => Some(File::open(self.resolve(path)?.as_str())?),
+            None => input,
+        };
+        let output = match stage.stdout {
+            Some((path, append)) => {
+                let mut options = OpenOptions::new();
+                options.write(true).create(true).append(append).truncate(!append);
+                Some(options.open(self.resolve(path)?.as_str())?)
+            },
+            None => output,
+        };
+        Ok((input, output))
+    }
+
+    // 带有`/`的名字是程序的路径，其它名字在/bin中查找
+    fn find_program(&self, name: &str) -> io::Result<PathBuf> {
+        if name.contains('/') {
+            self.resolve(name)
+        } else {
+            PathBuf::root().join("bin").and_then(|bin| bin.join(name)).map_err(|_| path_too_long())
+        }
+    }
+
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        self.cwd.join(path).map_err(|_| path_too_long())
+    }
+}
+
+fn path_too_long() -> io::Error {
+    io::Error::from_raw_os_error(36) // ENAMETOOLONG
+}
+
+// 把标准输入输出指向给定的文件，离开作用域时恢复成shell原来的标准输入输出
+struct Redirect;
+
+impl Redirect {
+    fn new(input: Option<&File>, output: Option<&File>) -> io::Result<Redirect> {
+        let redirect = Redirect;
+        if let Some(file) = input {
+            io::dup2(file.as_raw_fd(), STDIN)?;
+        }
+        if let Some(file) = output {
+            io::dup2(file.as_raw_fd(), STDOUT)?;
+        }
+        Ok(redirect)
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        let _ = io::dup2(SAVED_STDIN, STDIN);
+        let _ = io::dup2(SAVED_STDOUT, STDOUT);
+    }
+}
+
+// 控制台不会回显输入，shell自己回显，并处理退格、Ctrl-C和Ctrl-D。
+// 返回这一行的长度，不包括换行符；输入结束时返回None
+fn read_line(buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut len = 0;
+    let mut byte = [0u8];
+    loop {
+        if io::stdin().read(&mut byte)? == 0 {
+            return Ok(if len == 0 { None } else { Some(len) });
+        }
+        match byte[0] {
+            b'\r' | b'\n' => {
+                println!("");
+                return Ok(Some(len));
+            },
+            0x7f | 0x08 if len > 0 => {
+                // 删除最后一个完整的UTF-8字符。三个字节以上的字符通常是汉字，在终端中占两格
+                let start = buf[..len].iter().rposition(|&b| b & 0xc0 != 0x80).unwrap_or(0);
+                let width = if len - start >= 3 { 2 } else { 1 };
+                len = start;
+                for _ in 0..width {
+                    print!("\x08 \x08");
+                }
+            },
+            0x03 => {
+                println!("^C");
+                return Ok(Some(0));
+            },
+            0x04 if len == 0 => {
+                println!("");
+                return Ok(None);
+            },
+            b'\t' if len < buf.len() => {
+                buf[len] = b' ';
+                len += 1;
+                print!(" ");
+            },
+            c if c >= 0x20 && len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                io::stdout().write_all(&byte)?;
+            },
+            _ => {}, // 其它控制字符，或者这一行已经满了
+        }
+    }
+}
@@ -0,0 +1,209 @@
+//! 命令行的解析
+//!
+//! 命令行由管道符`|`分隔成几个命令，每个命令可以用`<`、`>`和`>>`重定向标准输入输出。
+//! 单引号中的内容原样保留；双引号中可以用反斜杠转义`"`和`\`；引号以外的反斜杠转义下一个字符。
+//! tornado-std没有内存分配器，解析结果保存在固定大小的数组中，参数引用调用者提供的缓冲区
+
+use core::fmt;
+
+// 一行输入的最大长度
+pub const MAX_LINE: usize = 256;
+// 一个命令最多的参数个数，包括程序的名字
+pub const MAX_ARGS: usize = 16;
+// 一个管道中最多的命令个数
+pub const MAX_STAGES: usize = 8;
+// 一行中最多的单词和符号个数
+const MAX_TOKENS: usize = 64;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ParseError {
+    LineTooLong,
+    UnclosedQuote,
+    // 重定向符号后面没有文件名
+    MissingRedirectTarget,
+    // 管道符的某一边没有命令
+    EmptyCommand,
+    TooManyArgs,
+    TooManyCommands,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ParseError::LineTooLong => "line too long",
+            ParseError::UnclosedQuote => "unclosed quote",
+            ParseError::MissingRedirectTarget => "missing file name after redirection",
+            ParseError::EmptyCommand => "empty command in pipeline",
+            ParseError::TooManyArgs => "too many arguments",
+            ParseError::TooManyCommands => "too many commands in pipeline",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Stage<'a> {
+    argv: [&'a str; MAX_ARGS],
+    argc: usize,
+    // 标准输入重定向的文件
+    pub stdin: Option<&'a str>,
+    // 标准输出重定向的文件，以及是否追加到文件末尾
+    pub stdout: Option<(&'a str, bool)>,
+}
+
+impl<'a> Stage<'a> {
+    const EMPTY: Stage<'static> = Stage { argv: [""; MAX_ARGS], argc: 0, stdin: None, stdout: None };
+
+    // 程序的名字和参数
+    pub fn args(&self) -> &[&'a str] {
+        &self.argv[..self.argc]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.argc == 0 && self.stdin.is_none() && self.stdout.is_none()
+    }
+}
+
+pub struct Pipeline<'a> {
+    stages: [Stage<'a>; MAX_STAGES],
+    len: usize,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn stages(&self) -> &[Stage<'a>] {
+        &self.stages[..self.len]
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Token {
+    // 去掉引号和转义以后的单词，在缓冲区中的起止位置
+    Word(usize, usize),
+    Pipe,
+    Input,
+    Output,
+    Append,
+}
+
+// 解析一行输入。去掉引号和转义以后的单词写到words中，所以words不会比line更长。空行得到空的管道
+pub fn parse<'a>(line: &str, words: &'a mut [u8; MAX_LINE]) -> Result<Pipeline<'a>, ParseError> {
+    if line.len() > MAX_LINE {
+        return Err(ParseError::LineTooLong);
+    }
+    let mut tokens = [Token::Pipe; MAX_TOKENS];
+    let token_count = tokenize(line.as_bytes(), words, &mut tokens)?;
+    let words: &'a [u8] = words;
+    // 单词只在ASCII字符处分开，每个单词仍然是合法的UTF-8
+    let word = |start: usize, end: usize| core::str::from_utf8(&words[start..end]).unwrap_or("");
+    let mut pipeline = Pipeline { stages: [Stage::EMPTY; MAX_STAGES], len: 0 };
+    let mut stage = Stage::EMPTY;
+    let mut iter = tokens[..token_count].iter();
+    while let Some(&token) = iter.next() {
+        match token {
+            Token::Word(start, end) => {
+                if stage.argc == MAX_ARGS {
+                    return Err(ParseError::TooManyArgs);
+                }
+                stage.argv[stage.argc] = word(start, end);
+                stage.argc += 1;
+            },
+            Token::Input | Token::Output | Token::Append => {
+                let target = match iter.next() {
+                    Some(&Token::Word(start, end)) => word(start, end),
+                    _ => return Err(ParseError::MissingRedirectTarget),
+                };
+                match token {
+                    Token::Input => stage.stdin = Some(target),
+                    _ => stage.stdout = Some((target, token == Token::Append)),
+                }
+            },
+            Token::Pipe => {
+                push_stage(&mut pipeline, stage)?;
+                stage = Stage::EMPTY;
+            },
+        }
+    }
+    if !(pipeline.len == 0 && stage.is_empty()) {
+        push_stage(&mut pipeline, stage)?;
+    }
+    Ok(pipeline)
+}
+
+fn push_stage<'a>(pipeline: &mut Pipeline<'a>, stage: Stage<'a>) -> Result<(), ParseError> {
+    if stage.argc == 0 {
+        return Err(ParseError::EmptyCommand);
+    }
+    if pipeline.len == MAX_STAGES {
+        return Err(ParseError::TooManyCommands);
+    }
+    pipeline.stages[pipeline.len] = stage;
+    pipeline.len += 1;
+    Ok(())
+}
+
+// 把输入拆分成单词和符号，返回符号的个数
+fn tokenize(line: &[u8], words: &mut [u8], tokens: &mut [Token]) -> Result<usize, ParseError> {
+    let mut count = 0;
+    let mut push = |token: Token| {
+        if count == tokens.len() {
+            return Err(ParseError::TooManyArgs);
+        }
+        tokens[count] = token;
+        count += 1;
+        Ok(())
+    };
+    let (mut i, mut out) = (0, 0);
+    while i < line.len() {
+        match line[i] {
+            b' ' | b'\t' => i += 1,
+            b'|' => { push(Token::Pipe)?; i += 1 },
+            b'<' => { push(Token::Input)?; i += 1 },
+            b'>' if line.get(i + 1) == Some(&b'>') => { push(Token::Append)?; i += 2 },
+            b'>' => { push(Token::Output)?; i += 1 },
+            _ => {
+                let start = out;
+                while i < line.len() && !matches!(line[i], b' ' | b'\t' | b'|' | b'<' | b'>') {
+                    match line[i] {
+                        b'\'' => {
+                            let len = line[i + 1..].iter().position(|&b| b == b'\'').ok_or(ParseError::UnclosedQuote)?;
+                            words[out..out + len].copy_from_slice(&line[i + 1..i + 1 + len]);
+                            out += len;
+                            i += len + 2;
+                        },
+                        b'"' => {
+                            i += 1;
+                            loop {
+                                match line.get(i) {
+                                    None => return Err(ParseError::UnclosedQuote),
+                                    Some(b'"') => break,
+                                    Some(b'\\') if matches!(line.get(i + 1), Some(b'"') | Some(b'\\')) => {
+                                        words[out] = line[i + 1];
+                                        i += 2;
+                                    },
+                                    Some(&b) => {
+                                        words[out] = b;
+                                        i += 1;
+                                    },
+                                }
+                                out += 1;
+                            }
+                            i += 1;
+                        },
+                        b'\\' if i + 1 < line.len() => {
+                            words[out] = line[i + 1];
+                            out += 1;
+                            i += 2;
+                        },
+                        b => {
+                            words[out] = b;
+                            out += 1;
+                            i += 1;
+                        },
+                    }
+                }
+                push(Token::Word(start, out))?;
+            },
+        }
+    }
+    Ok(count)
+}
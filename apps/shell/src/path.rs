@@ -0,0 +1,79 @@
+//! 路径的拼接和规范化
+//!
+//! 内核只接受绝对路径，shell自己记录当前目录，把相对路径拼接到它后面再交给内核
+
+use core::fmt;
+
+const MAX_PATH: usize = 256;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PathTooLong;
+
+// 规范化的绝对路径：以`/`开头，不含`.`、`..`和连续的`/`，除了根目录以外不以`/`结尾
+#[derive(Clone)]
+pub struct PathBuf {
+    buf: [u8; MAX_PATH],
+    len: usize,
+}
+
+impl PathBuf {
+    pub fn root() -> Self {
+        let mut buf = [0; MAX_PATH];
+        buf[0] = b'/';
+        PathBuf { buf, len: 1 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // 只会从&str中复制完整的路径分量，总是合法的UTF-8
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("/")
+    }
+
+    // 拼接路径。path是绝对路径时替换整个路径，`..`回到上一级目录，根目录的上一级还是根目录
+    pub fn push(&mut self, path: &str) -> Result<(), PathTooLong> {
+        if path.starts_with('/') {
+            self.len = 1;
+        }
+        for part in path.split('/') {
+            match part {
+                "" | "." => {},
+                ".." => self.pop(),
+                _ => {
+                    let sep = if self.len == 1 { 0 } else { 1 };
+                    if self.len + sep + part.len() > MAX_PATH {
+                        return Err(PathTooLong);
+                    }
+                    if sep == 1 {
+                        self.buf[self.len] = b'/';
+                    }
+                    self.buf[self.len + sep..self.len + sep + part.len()].copy_from_slice(part.as_bytes());
+                    self.len += sep + part.len();
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // 在这个路径的基础上拼接path，返回新的路径
+    pub fn join(&self, path: &str) -> Result<PathBuf, PathTooLong> {
+        let mut ans = self.clone();
+        ans.push(path)?;
+        Ok(ans)
+    }
+
+    fn pop(&mut self) {
+        let parent = self.buf[..self.len].iter().rposition(|&b| b == b'/').unwrap_or(0);
+        self.len = parent.max(1);
+    }
+}
+
+impl fmt::Display for PathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for PathTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("path too long")
+    }
+}
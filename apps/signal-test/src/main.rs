@@ -0,0 +1,90 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use tornado_std::env;
+use tornado_std::io;
+use tornado_std::process;
+use tornado_std::signal::{self, SigHandler, SigSet};
+use tornado_std::thread;
+
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_usr1(_sig: usize, _addr: usize) {
+    RECEIVED.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn on_segv(sig: usize, addr: usize) {
+    println!("child: caught signal {} at address {:#x}", sig, addr);
+    process::exit(0);
+}
+
+// 不带参数运行时作为父进程；子进程的第一个参数说明它要做什么
+#[no_mangle]
+fn main() -> i32 {
+    let ans = match env::args().nth(1) {
+        None => parent(),
+        Some("spin") => loop {
+            thread::yield_now();
+        },
+        Some("segv") => fault(),
+        Some("catch") => catch(),
+        Some(_) => Err(io::Error::from_raw_os_error(22)), // EINVAL
+    };
+    match ans {
+        Ok(()) => 0,
+        Err(e) => {
+            println!("signal-test: {}", e);
+            1
+        },
+    }
+}
+
+fn parent() -> io::Result<()> {
+    // 主线程的线程号和进程号相同
+    let pid = thread::current().id();
+    signal::signal(signal::SIGUSR1, SigHandler::Handler(on_usr1))?;
+    // 处理函数在系统调用返回之前运行
+    signal::kill(pid, signal::SIGUSR1)?;
+    println!("parent: handler ran {} time(s) after kill", RECEIVED.load(Ordering::SeqCst));
+    // 屏蔽的信号保持等待，解除屏蔽时才处理
+    signal::block(SigSet::empty().with(signal::SIGUSR1))?;
+    signal::kill(pid, signal::SIGUSR1)?;
+    println!("parent: while blocked, handler ran {} time(s)", RECEIVED.load(Ordering::SeqCst));
+    signal::unblock(SigSet::empty().with(signal::SIGUSR1))?;
+    println!("parent: after unblocking, handler ran {} time(s)", RECEIVED.load(Ordering::SeqCst));
+    // SIGTERM的默认处理方式结束子进程，返回值是128加上信号的编号
+    let mut child = process::spawn("/bin/signal-test", &["spin"])?;
+    signal::kill(child.id(), signal::SIGTERM)?;
+    report("spin", child.wait()?);
+    let mut child = process::spawn("/bin/signal-test", &["spin"])?;
+    child.kill()?;
+    report("spin", child.wait()?);
+    // 访问异常转换为SIGSEGV
+    let mut child = process::spawn("/bin/signal-test", &["segv"])?;
+    report("segv", child.wait()?);
+    let mut child = process::spawn("/bin/signal-test", &["catch"])?;
+    report("catch", child.wait()?);
+    Ok(())
+}
+
+fn report(name: &str, code: i32) {
+    match signal::exit_signal(code) {
+        Some(sig) => println!("parent: child `{}` killed by signal {} (exit code {})", name, sig, code),
+        None => println!("parent: child `{}` returned {}", name, code),
+    }
+}
+
+// 处理函数不返回，返回时会重新执行出错的指令
+fn catch() -> io::Result<()> {
+    signal::signal(signal::SIGSEGV, SigHandler::Handler(on_segv))?;
+    fault()
+}
+
+fn fault() -> ! {
+    let ptr = 0x10 as *mut usize;
+    unsafe { ptr.write_volatile(1) };
+    unreachable!("write to an unmapped address succeeded")
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#[macro_use]
+extern crate tornado_std;
+
+use core::time::Duration;
+use tornado_std::sync::{Barrier, Condvar, Mutex, Once, RwLock};
+use tornado_std::thread;
+
+const THREADS: usize = 4;
+const ROUNDS: usize = 100;
+
+static COUNTER: Mutex<usize> = Mutex::new(0);
+// 生产者放入的数据，None表示队列为空
+static SLOT: Mutex<Option<usize>> = Mutex::new(None);
+static SLOT_CHANGED: Condvar = Condvar::new();
+static CONFIG: RwLock<usize> = RwLock::new(0);
+static INIT: Once = Once::new();
+static INIT_COUNT: Mutex<usize> = Mutex::new(0);
+static BARRIER: Barrier = Barrier::new(THREADS);
+
+#[no_mangle]
+fn main() -> i32 {
+    // 多个线程同时增加计数器，中途让出处理核，让其它线程在锁上等待
+    let mut handles = [None, None, None, None];
+    for slot in handles.iter_mut() {
+        *slot = Some(thread::spawn(|| {
+            INIT.call_once(|| *INIT_COUNT.lock() += 1);
+            for _ in 0..ROUNDS {
+                let mut counter = COUNTER.lock();
+                let value = *counter;
+                thread::yield_now();
+                *counter = value + 1;
+            }
+            let _ = *CONFIG.read();
+            BARRIER.wait().is_leader()
+        }));
+    }
+    let mut leaders = 0;
+    for handle in handles.iter_mut().filter_map(Option::take) {
+        if handle.join().expect("join thread") {
+            leaders += 1;
+        }
+    }
+    println!("counter = {}, once ran {} time(s), {} leader(s)", *COUNTER.lock(), *INIT_COUNT.lock(), leaders);
+    assert_eq!(*COUNTER.lock(), THREADS * ROUNDS);
+    assert_eq!(*INIT_COUNT.lock(), 1);
+    assert_eq!(leaders, 1);
+    // 生产者和消费者通过条件变量交替运行
+    let consumer = thread::spawn(|| {
+        let mut sum = 0;
+        for _ in 0..10 {
+            let mut slot = SLOT_CHANGED.wait_while(SLOT.lock(), |slot| slot.is_none());
+            sum += slot.take().unwrap();
+            SLOT_CHANGED.notify_all();
+        }
+        sum
+    });
+    for i in 1..=10 {
+        let mut slot = SLOT_CHANGED.wait_while(SLOT.lock(), |slot| slot.is_some());
+        *slot = Some(i);
+        SLOT_CHANGED.notify_all();
+    }
+    let sum = consumer.join().expect("join consumer");
+    println!("consumer received sum = {}", sum);
+    assert_eq!(sum, 55);
+    // 写者独占读写锁
+    *CONFIG.write() = 42;
+    assert_eq!(*CONFIG.read(), 42);
+    assert!(CONFIG.try_write().is_some());
+    let reader = CONFIG.read();
+    assert!(CONFIG.try_write().is_none());
+    drop(reader);
+    // 没有通知时等待超时
+    let (_guard, result) = SLOT_CHANGED.wait_timeout(SLOT.lock(), Duration::from_millis(50));
+    println!("wait_timeout timed out: {}", result.timed_out());
+    assert!(result.timed_out());
+    0
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+#![feature(thread_local)]
+#[macro_use]
+extern crate tornado_std;
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use tornado_std::thread;
+
+// 所有线程共享的计数器
+static TOTAL: AtomicUsize = AtomicUsize::new(0);
+
+// 每个线程有自己的副本
+#[thread_local]
+static LOCAL: Cell<usize> = Cell::new(100);
+
+#[no_mangle]
+fn main() -> i32 {
+    let main_id = thread::current().id();
+    let mut handles = [None, None, None, None];
+    for (i, slot) in handles.iter_mut().enumerate() {
+        // 第i个线程把计数器增加i + 1次
+        *slot = Some(thread::spawn(move || {
+            for _ in 0..=i {
+                LOCAL.set(LOCAL.get() + 1);
+                TOTAL.fetch_add(1, Ordering::SeqCst);
+                thread::yield_now();
+            }
+            (thread::current().id(), LOCAL.get())
+        }));
+    }
+    for handle in handles.iter_mut().filter_map(Option::take) {
+        let tid = handle.thread().id();
+        let (id, local) = handle.join().expect("join thread");
+        assert_eq!(id, tid);
+        println!("thread {} finished, local = {}", id, local);
+    }
+    // 其它线程的修改不影响主线程的副本
+    println!("main thread {}, local = {}, total = {}", main_id, LOCAL.get(), TOTAL.load(Ordering::SeqCst));
+    assert_eq!(LOCAL.get(), 100);
+    assert_eq!(TOTAL.load(Ordering::SeqCst), 10);
+    0
+}
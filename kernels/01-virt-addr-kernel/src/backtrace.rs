@@ -0,0 +1,201 @@
+//! 调用栈回溯
+//!
+//! 内核和用户程序都用`-C force-frame-pointers=yes`编译，每个函数的栈帧中保存返回地址和上一层的帧指针：
+//! s0指向调用这个函数之前的栈顶，返回地址在s0 - 1个字的位置，上一层的帧指针在s0 - 2个字的位置。
+//! 沿着帧指针向上就能得到整个调用链。
+//!
+//! xtask从内核的ELF文件中提取函数的符号，放在initramfs之后，内核用它把返回地址转换为函数名。
+//! 用户栈通过页表检查以后读取，损坏的用户栈不会让内核出错
+
+use crate::{executor::ResumeContext, mm, scheduler::UserFrameAllocator, syscall::copy_from_user, KernelPageMode};
+use core::{convert::TryInto, mem::size_of, ops::Range};
+
+// 最多回溯的层数，防止帧指针形成环
+const MAX_DEPTH: usize = 32;
+
+const WORD: usize = size_of::<usize>();
+
+// 每个符号的地址、名字偏移和名字长度
+const ENTRY_SIZE: usize = 16;
+
+// xtask生成的符号表，格式见xtask_symbols_kernel
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    names: &'a [u8],
+    count: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < 8 || &data[..4] != b"KSYM" {
+            return None;
+        }
+        let count = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let names_start = count.checked_mul(ENTRY_SIZE)?.checked_add(8)?;
+        if names_start > data.len() {
+            return None;
+        }
+        Some(SymbolTable { entries: &data[8..names_start], names: &data[names_start..], count })
+    }
+
+    fn addr(&self, index: usize) -> u64 {
+        let entry = &self.entries[index * ENTRY_SIZE..];
+        u64::from_le_bytes(entry[..8].try_into().unwrap())
+    }
+
+    fn name(&self, index: usize) -> &'a str {
+        let entry = &self.entries[index * ENTRY_SIZE..];
+        let offset = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as usize;
+        self.names.get(offset..offset + len)
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .unwrap_or("<invalid symbol>")
+    }
+
+    // 包含addr的函数名和addr在函数中的偏移。符号按地址排序，取不大于addr的最后一个
+    pub fn lookup(&self, addr: usize) -> Option<(&'a str, usize)> {
+        let addr = addr as u64;
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.addr(mid) <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let index = low.checked_sub(1)?;
+        Some((self.name(index), (addr - self.addr(index)) as usize))
+    }
+}
+
+static SYMBOLS: spin::Once<Option<SymbolTable<'static>>> = spin::Once::new();
+
+// 检查xtask放在物理地址base处的符号表，最长max_len字节。没有符号表时回溯只输出地址
+pub fn init(base: usize, max_len: usize) {
+    let va = mm::PhysAddr(base).to_kernel_virt().0;
+    let data = unsafe { core::slice::from_raw_parts(va as *const u8, max_len) };
+    let table = SYMBOLS.call_once(|| SymbolTable::parse(data));
+    match table {
+        Some(table) => info!("Loaded {} kernel symbols at {:#x}", table.count, base),
+        None => warn!("No kernel symbols found at {:#x}, backtraces will not be symbolized", base),
+    }
+}
+
+// 从帧指针fp开始向上回溯，read读取一个字，读不到时停止。对每一层的返回地址调用f
+fn walk(mut fp: usize, mut read: impl FnMut(usize) -> Option<usize>, mut f: impl FnMut(usize, usize)) {
+    for depth in 0..MAX_DEPTH {
+        if fp % WORD != 0 || fp < 2 * WORD {
+            break;
+        }
+        let (ra, prev) = match (read(fp - WORD), read(fp - 2 * WORD)) {
+            (Some(ra), Some(prev)) => (ra, prev),
+            _ => break,
+        };
+        if ra == 0 {
+            break;
+        }
+        f(depth, ra);
+        // 栈向低地址增长，上一层的帧指针一定更大
+        if prev <= fp {
+            break;
+        }
+        fp = prev;
+    }
+}
+
+fn print_frame(depth: usize, addr: usize) {
+    let symbol = SYMBOLS.get().and_then(|table| table.as_ref()).and_then(|table| table.lookup(addr));
+    match symbol {
+        Some((name, offset)) => println!("  #{:<2} {:#x} {} + {:#x}", depth, addr, name, offset),
+        None => println!("  #{:<2} {:#x}", depth, addr),
+    }
+}
+
+// 输出内核当前的调用栈。stack是当前使用的内核栈，帧指针不在其中时停止
+#[inline(never)]
+pub fn print_kernel(stack: Range<usize>) {
+    let fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    println!("Kernel backtrace:");
+    walk_kernel(fp, stack, 0);
+}
+
+// 输出内核中被异常打断的调用栈。pc是出错的位置，fp是出错时的帧指针，stack是出错时使用的内核栈
+pub fn print_kernel_trap(pc: usize, fp: usize, stack: Range<usize>) {
+    println!("Kernel backtrace at trap:");
+    print_frame(0, pc);
+    walk_kernel(fp, stack, 1);
+}
+
+// 只读取stack中的内容，跳过不在内核代码中的返回地址。输出的层数从first_depth开始
+fn walk_kernel(fp: usize, stack: Range<usize>, first_depth: usize) {
+    let read = |addr: usize| {
+        if stack.start <= addr && addr + WORD <= stack.end {
+            Some(unsafe { *(addr as *const usize) })
+        } else {
+            None
+        }
+    };
+    let text = text_range();
+    walk(fp, read, |depth, ra| {
+        if text.contains(&ra) {
+            print_frame(first_depth + depth, ra);
+        }
+    });
+}
+
+// 输出出错的用户线程的调用栈，通过页表读取用户栈。用户程序没有符号表，只输出地址
+pub fn print_user(pid: usize, space: &mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, ctx: &ResumeContext) {
+    warn!("Process {} backtrace:", pid);
+    warn!("  #0  {:#x}", ctx.sepc);
+    let read = |addr: usize| {
+        let mut buf = [0u8; WORD];
+        copy_from_user(space, addr, &mut buf).ok()?;
+        Some(usize::from_ne_bytes(buf))
+    };
+    walk(ctx.s0, read, |depth, ra| warn!("  #{:<2} {:#x}", depth + 1, ra));
+}
+
+fn text_range() -> Range<usize> {
+    extern "C" { fn stext(); fn etext(); }
+    stext as usize..etext as usize
+}
+
+pub(crate) fn test_backtrace() {
+    // 两个符号的符号表
+    let mut data = [0u8; 8 + 2 * ENTRY_SIZE + 9];
+    data[..4].copy_from_slice(b"KSYM");
+    data[4..8].copy_from_slice(&2u32.to_le_bytes());
+    for (i, (addr, offset, len)) in [(0x1000u64, 0u32, 4u32), (0x1100, 4, 5)].iter().enumerate() {
+        let entry = &mut data[8 + i * ENTRY_SIZE..8 + (i + 1) * ENTRY_SIZE];
+        entry[..8].copy_from_slice(&addr.to_le_bytes());
+        entry[8..12].copy_from_slice(&offset.to_le_bytes());
+        entry[12..16].copy_from_slice(&len.to_le_bytes());
+    }
+    data[8 + 2 * ENTRY_SIZE..].copy_from_slice(b"mainpanic");
+    let table = SymbolTable::parse(&data).expect("parse symbol table");
+    assert_eq!(table.lookup(0xfff), None);
+    assert_eq!(table.lookup(0x1000), Some(("main", 0)));
+    assert_eq!(table.lookup(0x10ff), Some(("main", 0xff)));
+    assert_eq!(table.lookup(0x1234), Some(("panic", 0x134)));
+    assert!(SymbolTable::parse(b"KSYM\xff\xff\xff\xff").is_none());
+    // 三层栈帧：每层的帧指针之下是返回地址和上一层的帧指针，最外层之上的返回地址为0
+    let base = 0x8000;
+    let stack = [base + WORD * 4, 0x3000, base + WORD * 6, 0x2000, base + WORD * 8, 0x1000, 0, 0];
+    let read = |addr: usize| stack.get(addr.checked_sub(base)? / WORD).copied();
+    let mut frames = [0; MAX_DEPTH];
+    let mut depth = 0;
+    walk(base + WORD * 2, read, |i, ra| {
+        frames[i] = ra;
+        depth = i + 1;
+    });
+    assert_eq!(&frames[..depth], &[0x3000, 0x2000, 0x1000]);
+    // 帧指针没有变大时停止
+    let stack = [base, 0x3000];
+    let read = |addr: usize| stack.get(addr.checked_sub(base)? / WORD).copied();
+    depth = 0;
+    walk(base + WORD * 2, read, |i, _| depth = i + 1);
+    assert_eq!(depth, 1);
+    println!("[kernel-backtrace-test] Symbol lookup and frame walk test passed");
+}
@@ -0,0 +1,119 @@
+//! 内核命令行
+//!
+//! 固件把内核命令行放在设备树/chosen节点的bootargs属性中，QEMU用`-append`设置它。
+//! 命令行由空格分隔的`名字=值`组成，解析为BootConfig，没有给出的项使用默认值：
+//! - `init=/bin/shell`：第一个用户程序，优先于initramfs中的/etc/init
+//! - `loglevel=`、`logbuf=`、`logcolor=`：日志的级别和颜色，见console模块
+//! - `stack_pages=5`：用户程序主线程的栈有多少页
+//! - `program_pages=64`：用户程序从0x1000开始最多占用多少页，用户堆紧接在它之后
+//! - `timeslice_ms=10`：线程运行多久以后被时钟中断抢占，为0时只在线程让出或者阻塞时切换
+//!
+//! 内核堆和启动栈在解析设备树之前就要使用，它们的大小仍然是编译时的常量
+
+use alloc::{string::{String, ToString}, vec::Vec};
+use core::{ops::RangeInclusive, str::FromStr};
+use log::LevelFilter;
+
+const DEFAULT_STACK_PAGES: usize = 5;
+const DEFAULT_PROGRAM_PAGES: usize = 64;
+const DEFAULT_TIMESLICE_MS: u64 = 10;
+
+// 用户栈和程序区域的页数上限，保证用户堆和栈不会进入mmap的区域
+const MAX_STACK_PAGES: usize = 1024;
+const MAX_PROGRAM_PAGES: usize = 1024;
+const MAX_TIMESLICE_MS: u64 = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct BootConfig {
+    pub init: Option<String>,
+    pub loglevel: Option<String>,
+    pub logbuf: Option<LevelFilter>,
+    pub logcolor: bool,
+    pub stack_pages: usize,
+    pub program_pages: usize,
+    pub timeslice_ms: u64,
+    // 无法识别或者值不正确的参数。解析命令行时日志还没有初始化，之后再报告
+    pub ignored: Vec<String>,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        BootConfig {
+            init: None,
+            loglevel: None,
+            logbuf: None,
+            logcolor: true,
+            stack_pages: DEFAULT_STACK_PAGES,
+            program_pages: DEFAULT_PROGRAM_PAGES,
+            timeslice_ms: DEFAULT_TIMESLICE_MS,
+            ignored: Vec::new(),
+        }
+    }
+}
+
+impl BootConfig {
+    pub fn parse(bootargs: &str) -> Self {
+        let mut config = BootConfig::default();
+        for arg in bootargs.split_whitespace() {
+            let mut parts = arg.splitn(2, '=');
+            let (name, value) = (parts.next().unwrap(), parts.next().unwrap_or(""));
+            let ok = match name {
+                "init" => set(&mut config.init, Some(value.to_string()).filter(|v| v.starts_with('/')).map(Some)),
+                "loglevel" => set(&mut config.loglevel, Some(value.to_string()).filter(|v| !v.is_empty()).map(Some)),
+                "logbuf" => set(&mut config.logbuf, LevelFilter::from_str(value).ok().map(Some)),
+                "logcolor" => set(&mut config.logcolor, match value {
+                    "on" | "1" => Some(true),
+                    "off" | "0" => Some(false),
+                    _ => None,
+                }),
+                "stack_pages" => set(&mut config.stack_pages, number(value, 1..=MAX_STACK_PAGES)),
+                "program_pages" => set(&mut config.program_pages, number(value, 1..=MAX_PROGRAM_PAGES)),
+                "timeslice_ms" => set(&mut config.timeslice_ms, number(value, 0..=MAX_TIMESLICE_MS)),
+                _ => false,
+            };
+            if !ok {
+                config.ignored.push(arg.to_string());
+            }
+        }
+        config
+    }
+}
+
+// 值正确时设置字段，否则保留默认值
+fn set<T>(field: &mut T, value: Option<T>) -> bool {
+    match value {
+        Some(value) => {
+            *field = value;
+            true
+        },
+        None => false,
+    }
+}
+
+fn number<T: FromStr + PartialOrd>(value: &str, range: RangeInclusive<T>) -> Option<T> {
+    value.parse().ok().filter(|n| range.contains(n))
+}
+
+pub(crate) fn test_config() {
+    let config = BootConfig::parse("");
+    assert_eq!(config.init, None);
+    assert_eq!(config.stack_pages, DEFAULT_STACK_PAGES);
+    assert_eq!(config.program_pages, DEFAULT_PROGRAM_PAGES);
+    assert_eq!(config.timeslice_ms, DEFAULT_TIMESLICE_MS);
+    assert!(config.logcolor && config.ignored.is_empty());
+    let config = BootConfig::parse("init=/bin/echo  loglevel=debug,mm=trace logbuf=trace logcolor=off stack_pages=16 timeslice_ms=0");
+    assert_eq!(config.init.as_deref(), Some("/bin/echo"));
+    assert_eq!(config.loglevel.as_deref(), Some("debug,mm=trace"));
+    assert_eq!(config.logbuf, Some(LevelFilter::Trace));
+    assert!(!config.logcolor);
+    assert_eq!(config.stack_pages, 16);
+    assert_eq!(config.timeslice_ms, 0);
+    assert!(config.ignored.is_empty());
+    // 值不正确的参数保留默认值
+    let config = BootConfig::parse("init=shell stack_pages=0 program_pages=many quiet console=ttyS0");
+    assert_eq!(config.init, None);
+    assert_eq!(config.stack_pages, DEFAULT_STACK_PAGES);
+    assert_eq!(config.program_pages, DEFAULT_PROGRAM_PAGES);
+    assert_eq!(config.ignored, ["init=shell", "stack_pages=0", "program_pages=many", "quiet", "console=ttyS0"]);
+    println!("[kernel-config-test] Command line parsing test passed");
+}
@@ -1,13 +1,113 @@
+//! 控制台和内核日志
+//!
+//! 内核日志是log库的后端。每条日志带有处理核编号、time寄存器的值和级别，可以按级别着色。
+//! 输出到控制台的日志有默认的级别，也可以按模块设置不同的级别；另外最近的日志保存在内存中的环形缓冲区里，
+//! 缓冲区有自己的级别，内核panic时把它输出，可以看到出错之前没有显示在控制台上的调试信息。
+//!
+//! 级别从内核命令行中设置，见config模块：
+//! - `loglevel=info,mm=trace,fs::fat32=off`：默认级别和各个模块的级别，模块路径不包括内核的包名
+//! - `logbuf=debug`：环形缓冲区的级别
+//! - `logcolor=off`：不使用颜色
+
+use crate::config::BootConfig;
 use crate::sbi::console_putchar;
+use crate::drivers::uart::Ns16550a;
+use alloc::{string::{String, ToString}, vec::Vec};
 use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::{Mutex, RwLock};
+
+// 内核的串口。初始化之前为None，此时使用SBI控制台输出，用于启动早期
+static UART: Mutex<Option<Ns16550a>> = Mutex::new(None);
+
+// 找到串口以后，所有的输出都改由串口驱动直接完成，不再经过SBI
+//
+// unsafe说明：调用者必须保证base是ns16550a串口寄存器的地址，并且已经映射到当前的地址空间
+pub unsafe fn init_uart(base: usize) {
+    let mut uart = Ns16550a::new(base);
+    uart.set_rx_filter(filter_input);
+    *UART.lock() = Some(uart);
+}
+
+// 控制台上的Ctrl-C不作为输入，由内核转换为SIGINT
+const CTRL_C: u8 = 0x03;
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn filter_input(byte: u8) -> bool {
+    if byte == CTRL_C {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        return false;
+    }
+    true
+}
+
+// 上次调用以后是否按下过Ctrl-C
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+// 输出字节。字节会原样送到串口，因此UTF-8编码的字符串可以正确显示
+pub fn write_bytes(bytes: &[u8]) {
+    let mut uart = UART.lock();
+    match uart.as_mut() {
+        Some(uart) => uart.write_bytes(bytes),
+        None => for &byte in bytes {
+            console_putchar(byte as usize);
+        },
+    }
+}
+
+// 读取一个字节，没有输入时返回None
+pub fn read_byte() -> Option<u8> {
+    let mut uart = UART.lock();
+    match uart.as_mut() {
+        Some(uart) => uart.read_byte(),
+        None => match crate::sbi::console_getchar() {
+            usize::MAX => None, // SBI返回-1，说明没有输入
+            c => Some(c as u8).filter(|&byte| filter_input(byte)),
+        },
+    }
+}
+
+// 串口中断注册以后，等待输入时可以休眠，直到中断到来
+static INPUT_INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+// 等待新的输入。没有串口中断时只能忙等，由read_byte轮询
+pub fn wait_for_input() {
+    if INPUT_INTERRUPT.load(Ordering::Relaxed) {
+        crate::task::wait_for_interrupt()
+    } else {
+        core::hint::spin_loop()
+    }
+}
+
+// 等待缓冲区中的输出全部发送，在关机之前使用
+pub fn flush() {
+    if let Some(uart) = UART.lock().as_mut() {
+        uart.flush()
+    }
+}
+
+// 注册串口的中断处理函数
+pub fn enable_uart_interrupt(irq: u32) {
+    crate::interrupt::register_handler(irq, handle_uart_interrupt);
+    INPUT_INTERRUPT.store(true, Ordering::Relaxed);
+}
+
+// 串口的中断处理函数
+fn handle_uart_interrupt() {
+    if let Some(uart) = UART.lock().as_mut() {
+        uart.handle_interrupt()
+    }
+}
 
 struct Stdout;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_putchar(c as usize);
-        }
+        write_bytes(s.as_bytes());
         Ok(())
     }
 }
@@ -29,3 +129,204 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
 }
+
+// 没有设置时输出到控制台的级别
+const DEFAULT_CONSOLE_LEVEL: LevelFilter = LevelFilter::Info;
+// 没有设置时保存到环形缓冲区的级别
+const DEFAULT_BUFFER_LEVEL: LevelFilter = LevelFilter::Debug;
+// 环形缓冲区的大小，超过以后覆盖最早的日志
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+struct LogConfig {
+    console: LevelFilter,
+    // 模块路径和它的级别，越长的路径越具体，优先使用
+    modules: Vec<(String, LevelFilter)>,
+    buffer: LevelFilter,
+    color: bool,
+}
+
+impl LogConfig {
+    // 记录来自的模块在控制台上的级别
+    fn console_level(&self, target: &str) -> LevelFilter {
+        // 日志的target是模块路径，去掉开头的包名
+        let path = target.find("::").map(|i| &target[i + 2..]).unwrap_or("");
+        self.modules.iter()
+            .filter(|(module, _)| path == module || (path.starts_with(module.as_str()) && path[module.len()..].starts_with("::")))
+            .max_by_key(|(module, _)| module.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(self.console)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|&(_, level)| level)
+            .chain([self.console, self.buffer].iter().copied())
+            .max()
+            .unwrap_or(LevelFilter::Off)
+    }
+
+    // loglevel的值：逗号分隔，单独的级别是默认级别，`模块=级别`设置模块的级别。返回无法识别的项
+    fn parse_levels<'a>(&mut self, spec: &'a str) -> Vec<&'a str> {
+        let mut invalid = Vec::new();
+        for item in spec.split(',').filter(|item| !item.is_empty()) {
+            let mut parts = item.splitn(2, '=');
+            let (first, second) = (parts.next().unwrap(), parts.next());
+            match (second, LevelFilter::from_str(second.unwrap_or(first))) {
+                (None, Ok(level)) => self.console = level,
+                (Some(_), Ok(level)) => self.modules.push((first.to_string(), level)),
+                (_, Err(_)) => invalid.push(item),
+            }
+        }
+        invalid
+    }
+}
+
+static LOG_CONFIG: RwLock<LogConfig> = RwLock::new(LogConfig {
+    console: DEFAULT_CONSOLE_LEVEL,
+    modules: Vec::new(),
+    buffer: DEFAULT_BUFFER_LEVEL,
+    color: true,
+});
+
+// 最近的日志，不带颜色
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    // 下一个写入的位置
+    head: usize,
+    // 是否已经写满过一次，此时head之后是最早的数据
+    wrapped: bool,
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            self.data[self.head] = byte;
+            self.head += 1;
+            if self.head == LOG_BUFFER_SIZE {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LogBuffer {
+    // 按时间顺序的两段数据。写满过时，最早的一行可能只剩下后半部分，跳过它
+    fn contents(&self) -> (&[u8], &[u8]) {
+        if !self.wrapped {
+            return (&self.data[..self.head], &[]);
+        }
+        let older = &self.data[self.head..];
+        match older.iter().position(|&b| b == b'\n') {
+            Some(pos) => (&older[pos + 1..], &self.data[..self.head]),
+            None => (&[], &self.data[..self.head]),
+        }
+    }
+}
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer { data: [0; LOG_BUFFER_SIZE], head: 0, wrapped: false });
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+fn level_color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[36m",
+        Level::Trace => "\x1b[90m",
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let config = LOG_CONFIG.read();
+        metadata.level() <= config.buffer || metadata.level() <= config.console_level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        let config = LOG_CONFIG.read();
+        let level = record.level();
+        let (ticks, hart_id) = (crate::timer::now(), crate::hart_id());
+        if level <= config.console_level(record.target()) {
+            let (color, reset) = if config.color { (level_color(level), "\x1b[0m") } else { ("", "") };
+            print(format_args!("{}[{:>12} {}] {:<5} {}{}\n", color, ticks, hart_id, level, record.args(), reset));
+        }
+        if level <= config.buffer {
+            // panic时可能已经持有这个锁，不能等待
+            if let Some(mut buffer) = LOG_BUFFER.try_lock() {
+                let _ = writeln!(buffer, "[{:>12} {}] {:<5} {}: {}", ticks, hart_id, level, record.target(), record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// 按内核命令行设置日志的级别，注册日志后端
+pub fn init_log(boot_config: &BootConfig) {
+    let mut config = LOG_CONFIG.write();
+    let invalid = match &boot_config.loglevel {
+        Some(spec) => config.parse_levels(spec),
+        None => Vec::new(),
+    };
+    if let Some(level) = boot_config.logbuf {
+        config.buffer = level;
+    }
+    config.color = boot_config.logcolor;
+    log::set_max_level(config.max_level());
+    drop(config);
+    if log::set_logger(&LOGGER).is_err() {
+        warn!("Kernel logger is already initialized");
+    }
+    // 日志后端注册以后才能输出
+    for item in invalid {
+        warn!("Ignored invalid log level `{}`", item);
+    }
+}
+
+// 输出环形缓冲区中的日志，在panic时使用。缓冲区正在被写入时放弃
+pub fn dump_log() {
+    let buffer = match LOG_BUFFER.try_lock() {
+        Some(buffer) => buffer,
+        None => {
+            println!("Kernel log buffer is busy");
+            return;
+        },
+    };
+    let (older, newer) = buffer.contents();
+    if older.is_empty() && newer.is_empty() {
+        return;
+    }
+    println!("Recent kernel log:");
+    write_bytes(older);
+    write_bytes(newer);
+}
+
+pub(crate) fn test_log() {
+    let mut config = LogConfig { console: LevelFilter::Info, modules: Vec::new(), buffer: LevelFilter::Off, color: false };
+    assert!(config.parse_levels("warn,mm=trace,fs::fat32=off").is_empty());
+    assert_eq!(config.console, LevelFilter::Warn);
+    assert_eq!(config.modules.len(), 2);
+    assert_eq!(config.console_level("kernel::mm"), LevelFilter::Trace);
+    assert_eq!(config.console_level("kernel::mmio"), LevelFilter::Warn);
+    assert_eq!(config.console_level("kernel::fs::fat32::dir"), LevelFilter::Off);
+    assert_eq!(config.console_level("kernel::fs"), LevelFilter::Warn);
+    assert_eq!(config.max_level(), LevelFilter::Trace);
+    assert_eq!(config.parse_levels("loud,fs=quiet,error"), ["loud", "fs=quiet"]);
+    assert_eq!(config.console, LevelFilter::Error);
+    let mut buffer = LogBuffer { data: [0; LOG_BUFFER_SIZE], head: 0, wrapped: false };
+    let _ = writeln!(buffer, "first");
+    assert_eq!(buffer.contents(), (&b"first\n"[..], &b""[..]));
+    // 写满以后丢掉最早的不完整的行
+    for i in 0..LOG_BUFFER_SIZE / 8 {
+        let _ = writeln!(buffer, "line {:02}", i % 100);
+    }
+    let (older, newer) = buffer.contents();
+    assert!(older.starts_with(b"line ") || older.is_empty() && newer.starts_with(b"line "));
+    assert!(newer.ends_with(b"\n"));
+    assert!(older.len() + newer.len() < LOG_BUFFER_SIZE);
+    println!("[kernel-log-test] Log filter and ring buffer test passed");
+}
@@ -0,0 +1,54 @@
+//! 设备驱动模块
+
+pub mod plic;
+pub mod uart;
+pub mod virtio;
+pub mod virtio_blk;
+
+use crate::dtb::MmioDevice;
+use spin::Once;
+use virtio::MmioTransport;
+use virtio_blk::{BlkError, VirtioBlk};
+
+// 块设备的同步接口，文件系统通过它按扇区读写
+pub trait BlockDevice: Send + Sync {
+    // 扇区的总数
+    fn sector_count(&self) -> u64;
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlkError>;
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), BlkError>;
+}
+
+static BLOCK_DEVICE: Once<VirtioBlk> = Once::new();
+
+// 系统中的块设备。目前只使用找到的第一个virtio块设备
+pub fn block_device() -> Option<&'static VirtioBlk> {
+    BLOCK_DEVICE.get()
+}
+
+// 探测设备树中的virtio-mmio设备，初始化找到的驱动程序，并注册它们的中断处理函数。
+// 调用之前，设备的寄存器必须已经映射到线性映射中，中断控制器必须已经初始化
+pub fn probe_virtio(devices: &[MmioDevice]) {
+    for device in devices {
+        let base = crate::mm::PhysAddr(device.base).to_kernel_virt().0;
+        let transport = match unsafe { MmioTransport::probe(base) } {
+            Some(transport) => transport,
+            None => continue, // 这个位置没有挂载设备
+        };
+        match transport.device_id() {
+            virtio::DEVICE_ID_BLOCK if BLOCK_DEVICE.get().is_none() => {
+                let blk = match VirtioBlk::new(transport) {
+                    Ok(blk) => BLOCK_DEVICE.call_once(|| blk),
+                    Err(e) => {
+                        error!("Failed to initialize virtio-blk at {:#x}: {:?}", device.base, e);
+                        continue
+                    }
+                };
+                if let Some(irq) = device.irq {
+                    crate::interrupt::register_handler(irq, move || blk.handle_interrupt());
+                }
+                info!("Found virtio-blk at {:#x}, {} sectors", device.base, blk.capacity());
+            },
+            id => debug!("Ignored virtio device id {} at {:#x}", id, device.base),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! 平台级中断控制器（PLIC）驱动
+//!
+//! 每个中断源有一个优先级；每个上下文（一般是某个处理核的某个特权级）有一组使能位和一个阈值。
+//! 优先级大于阈值、并且在上下文中使能的中断，才会通知到这个上下文。
+
+const PRIORITY_BASE: usize = 0x0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x200000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4; // 读是认领，写是完成
+
+#[derive(Debug)]
+pub struct Plic {
+    base: usize,
+}
+
+impl Plic {
+    // unsafe说明：调用者必须保证base是PLIC寄存器的地址，并且已经映射到当前的地址空间
+    pub const unsafe fn new(base: usize) -> Self {
+        Plic { base }
+    }
+
+    // 设置中断源的优先级。优先级为0的中断源永远不会产生中断
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        self.write(PRIORITY_BASE + irq as usize * 4, priority)
+    }
+
+    pub fn enable(&self, context: usize, irq: u32) {
+        let offset = ENABLE_BASE + context * ENABLE_STRIDE + (irq as usize / 32) * 4;
+        let value = self.read(offset);
+        self.write(offset, value | (1 << (irq % 32)))
+    }
+
+    pub fn disable(&self, context: usize, irq: u32) {
+        let offset = ENABLE_BASE + context * ENABLE_STRIDE + (irq as usize / 32) * 4;
+        let value = self.read(offset);
+        self.write(offset, value & !(1 << (irq % 32)))
+    }
+
+    pub fn set_threshold(&self, context: usize, threshold: u32) {
+        self.write(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_THRESHOLD, threshold)
+    }
+
+    // 认领一个待处理的中断。没有中断时返回None
+    pub fn claim(&self, context: usize) -> Option<u32> {
+        match self.read(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM) {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    // 通知中断控制器，认领的中断已经处理完成
+    pub fn complete(&self, context: usize, irq: u32) {
+        self.write(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM, irq)
+    }
+
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+}
+
+// QEMU virt平台上，每个处理核有两个上下文，依次是机器态和监管态
+pub fn supervisor_context(hart_id: usize) -> usize {
+    hart_id * 2 + 1
+}
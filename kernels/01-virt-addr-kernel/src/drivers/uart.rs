@@ -0,0 +1,175 @@
+//! ns16550a串口驱动
+//!
+//! QEMU virt平台的串口兼容ns16550a，寄存器的间隔为1字节。
+//! 发送和接收都经过环形缓冲区：写入时先放到发送缓冲区，发送寄存器空闲时再写到硬件；
+//! 接收中断到来时，把硬件中的数据读到接收缓冲区，等待内核取走。
+
+const RBR: usize = 0; // 接收缓冲寄存器，只读
+const THR: usize = 0; // 发送保持寄存器，只写
+const IER: usize = 1; // 中断使能寄存器
+const FCR: usize = 2; // 先进先出队列控制寄存器，只写
+const IIR: usize = 2; // 中断标识寄存器，只读
+const LCR: usize = 3; // 线路控制寄存器
+const MCR: usize = 4; // 调制解调器控制寄存器
+const LSR: usize = 5; // 线路状态寄存器
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+
+// 发送队列的深度。发送保持寄存器空闲时，一次最多可以写入这么多字节
+const TX_FIFO_DEPTH: usize = 16;
+
+const BUFFER_SIZE: usize = 1024;
+
+pub struct Ns16550a {
+    base: usize,
+    rx: RingBuffer<BUFFER_SIZE>,
+    tx: RingBuffer<BUFFER_SIZE>,
+    // 收到的每个字节先交给它，返回false的字节不放入接收缓冲区
+    rx_filter: Option<fn(u8) -> bool>,
+}
+
+impl Ns16550a {
+    // unsafe说明：调用者必须保证base是串口寄存器的地址，并且已经映射到当前的地址空间
+    pub unsafe fn new(base: usize) -> Self {
+        let mut ans = Ns16550a { base, rx: RingBuffer::new(), tx: RingBuffer::new(), rx_filter: None };
+        ans.init();
+        ans
+    }
+
+    fn init(&mut self) {
+        self.write_reg(IER, 0); // 先关闭所有中断
+        self.write_reg(LCR, 0x80); // 设置分频数之前，打开除数锁存
+        self.write_reg(0, 0x03); // 分频数低位，38400波特率；QEMU不关心这个值
+        self.write_reg(1, 0x00); // 分频数高位
+        self.write_reg(LCR, 0x03); // 8位数据，无校验，1位停止位；关闭除数锁存
+        self.write_reg(FCR, 0x07); // 打开并清空收发队列
+        self.write_reg(MCR, 0x0b); // DTR、RTS和OUT2，OUT2用于打开中断输出
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+
+    // 设置接收过滤函数，用来在中断到来时就处理Ctrl-C这样的控制字符
+    pub fn set_rx_filter(&mut self, filter: fn(u8) -> bool) {
+        self.rx_filter = Some(filter);
+    }
+
+    // 把数据放入发送缓冲区。缓冲区满的时候，等待硬件发送，不会丢失数据
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            while self.tx.is_full() {
+                self.pump_tx();
+            }
+            self.tx.push(byte);
+        }
+        self.pump_tx();
+        if !self.tx.is_empty() {
+            // 还有数据没有发送完，发送寄存器空闲的时候产生中断，继续发送
+            self.write_reg(IER, IER_RX_AVAILABLE | IER_TX_EMPTY);
+        }
+    }
+
+    // 等待发送缓冲区的所有数据都写到硬件
+    pub fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            self.pump_tx();
+        }
+    }
+
+    // 读一个字节。先从接收缓冲区读取，没有数据时再查询硬件
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if let Some(byte) = self.rx.pop() {
+            return Some(byte);
+        }
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR);
+            if self.accept(byte) {
+                return Some(byte);
+            }
+        }
+        None
+    }
+
+    fn accept(&self, byte: u8) -> bool {
+        self.rx_filter.map_or(true, |filter| filter(byte))
+    }
+
+    // 串口中断处理函数。应当在中断控制器分发串口的中断时调用
+    pub fn handle_interrupt(&mut self) {
+        while self.read_reg(IIR) & IIR_NO_INTERRUPT == 0 {
+            self.pump_rx();
+            self.pump_tx();
+            if self.tx.is_empty() {
+                self.write_reg(IER, IER_RX_AVAILABLE);
+            }
+        }
+    }
+
+    fn pump_rx(&mut self) {
+        while self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            let byte = self.read_reg(RBR);
+            // 接收缓冲区满的时候丢弃最新的数据
+            if self.accept(byte) && !self.rx.is_full() {
+                self.rx.push(byte);
+            }
+        }
+    }
+
+    fn pump_tx(&mut self) {
+        if self.read_reg(LSR) & LSR_TX_EMPTY == 0 {
+            return;
+        }
+        // 发送保持寄存器空闲，说明硬件队列已经全部发送，可以连续写入一整个队列的数据
+        for _ in 0..TX_FIFO_DEPTH {
+            match self.tx.pop() {
+                Some(byte) => self.write_reg(THR, byte),
+                None => break,
+            }
+        }
+    }
+
+    #[inline]
+    fn read_reg(&self, offset: usize) -> u8 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u8) }
+    }
+
+    #[inline]
+    fn write_reg(&mut self, offset: usize, value: u8) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u8, value) }
+    }
+}
+
+// 固定容量的字节环形缓冲区
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize, // 下一个读取的位置
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        RingBuffer { buf: [0; N], head: 0, len: 0 }
+    }
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+    fn push(&mut self, byte: u8) {
+        debug_assert!(!self.is_full());
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+    }
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(byte)
+    }
+}
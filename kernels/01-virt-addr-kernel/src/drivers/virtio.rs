@@ -0,0 +1,359 @@
+//! virtio-mmio传输层和分离式虚拟队列
+//!
+//! 同时支持旧版（版本1）和新版（版本2）的virtio-mmio设备。QEMU默认提供旧版设备。
+//! 交给设备的是物理地址。虚拟队列和请求缓冲区在内核堆或者内核镜像中，都在线性映射里，
+//! 减去线性映射的偏移就得到物理地址，见phys_addr函数。
+
+use crate::mm;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::sync::atomic::{fence, Ordering};
+
+pub const VIRTIO_MAGIC: u32 = 0x74726976; // "virt"
+pub const DEVICE_ID_BLOCK: u32 = 2;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028; // 仅旧版
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c; // 仅旧版
+const QUEUE_PFN: usize = 0x040; // 仅旧版
+const QUEUE_READY: usize = 0x044; // 仅新版
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080; // 以下仅新版
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const CONFIG: usize = 0x100;
+
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VirtioError {
+    // 设备不接受驱动选择的特性
+    FeatureNegotiation,
+    // 虚拟队列不存在，或者设备支持的队列长度太小
+    QueueUnavailable,
+}
+
+// virtio-mmio传输层，负责设备的寄存器访问
+#[derive(Debug)]
+pub struct MmioTransport {
+    base: usize,
+    version: u32,
+}
+
+impl MmioTransport {
+    // 探测一个virtio-mmio设备。如果这个位置没有设备，返回None
+    //
+    // unsafe说明：调用者必须保证base是virtio-mmio寄存器的地址，并且已经映射到当前的地址空间
+    pub unsafe fn probe(base: usize) -> Option<Self> {
+        let transport = MmioTransport { base, version: 0 };
+        if transport.read(MAGIC_VALUE) != VIRTIO_MAGIC {
+            return None;
+        }
+        let version = transport.read(VERSION);
+        if version != 1 && version != 2 {
+            return None;
+        }
+        if transport.read(DEVICE_ID) == 0 { // 占位用的空设备
+            return None;
+        }
+        Some(MmioTransport { base, version })
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn device_id(&self) -> u32 {
+        self.read(DEVICE_ID)
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    // 复位设备并协商特性，返回协商得到的特性。之后应当设置虚拟队列，再调用finish_init
+    pub fn begin_init(&self, driver_features: u64) -> Result<u64, VirtioError> {
+        self.write(STATUS, 0); // 复位
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_DRIVER);
+        let device_features = self.device_features();
+        let mut negotiated = device_features & driver_features;
+        if !self.is_legacy() {
+            if device_features & VIRTIO_F_VERSION_1 == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeatureNegotiation);
+            }
+            negotiated |= VIRTIO_F_VERSION_1;
+        }
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, negotiated as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (negotiated >> 32) as u32);
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+        } else {
+            self.set_status(STATUS_FEATURES_OK);
+            if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeatureNegotiation);
+            }
+        }
+        Ok(negotiated)
+    }
+
+    pub fn finish_init(&self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    // 把虚拟队列交给设备
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) -> Result<(), VirtioError> {
+        self.write(QUEUE_SEL, index);
+        let max = self.read(QUEUE_NUM_MAX) as usize;
+        if max == 0 || max < QUEUE_SIZE {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        self.write(QUEUE_NUM, QUEUE_SIZE as u32);
+        if self.is_legacy() {
+            // 旧版设备要求描述符表、可用环和已用环放在连续的内存中，已用环按页对齐
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (queue.desc_addr() / PAGE_SIZE) as u32);
+        } else {
+            let (desc, driver, device) = (queue.desc_addr() as u64, queue.avail_addr() as u64, queue.used_addr() as u64);
+            self.write(QUEUE_DESC_LOW, desc as u32);
+            self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, driver as u32);
+            self.write(QUEUE_DRIVER_HIGH, (driver >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, device as u32);
+            self.write(QUEUE_DEVICE_HIGH, (device >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        }
+        Ok(())
+    }
+
+    pub fn notify(&self, index: u32) {
+        fence(Ordering::SeqCst);
+        self.write(QUEUE_NOTIFY, index);
+    }
+
+    // 读取并应答中断状态
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        self.write(INTERRUPT_ACK, status);
+        status
+    }
+
+    pub fn config_read_u32(&self, offset: usize) -> u32 {
+        self.read(CONFIG + offset)
+    }
+
+    pub fn config_read_u64(&self, offset: usize) -> u64 {
+        // 配置空间的64位字段，按两个32位字段读取
+        let low = self.read(CONFIG + offset) as u64;
+        let high = self.read(CONFIG + offset + 4) as u64;
+        (high << 32) | low
+    }
+
+    fn device_features(&self) -> u64 {
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    fn set_status(&self, bits: u32) {
+        self.write(STATUS, self.read(STATUS) | bits);
+    }
+
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, value) }
+    }
+}
+
+// 虚拟队列的长度
+pub const QUEUE_SIZE: usize = 16;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+#[repr(C, align(4096))]
+struct QueuePage<T>(T);
+
+// 描述符表后面紧跟着可用环
+#[repr(C)]
+struct DriverArea {
+    desc: [Descriptor; QUEUE_SIZE],
+    avail: AvailRing,
+}
+
+// 描述符表和可用环在第一页，已用环在第二页，满足旧版设备的布局要求
+#[repr(C)]
+struct QueueMemory {
+    driver: QueuePage<DriverArea>,
+    device: QueuePage<UsedRing>,
+}
+
+// 分离式虚拟队列
+pub struct VirtQueue {
+    mem: *mut QueueMemory,
+    free_head: u16,
+    num_free: usize,
+    last_used_idx: u16,
+}
+
+// 队列内存只通过VirtQueue访问，可以在线程之间转移
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    pub fn new() -> Self {
+        let mem = unsafe { alloc_zeroed(Layout::new::<QueueMemory>()) } as *mut QueueMemory;
+        assert!(!mem.is_null(), "allocate virtqueue memory");
+        let desc = unsafe { &mut (*mem).driver.0.desc };
+        for i in 0..QUEUE_SIZE - 1 {
+            desc[i].next = (i + 1) as u16; // 空闲描述符串成链表
+        }
+        VirtQueue { mem, free_head: 0, num_free: QUEUE_SIZE, last_used_idx: 0 }
+    }
+
+    // 描述符表、可用环和已用环的物理地址
+    pub fn desc_addr(&self) -> usize {
+        phys_addr(unsafe { &(*self.mem).driver.0.desc as *const _ as usize })
+    }
+
+    pub fn avail_addr(&self) -> usize {
+        phys_addr(unsafe { &(*self.mem).driver.0.avail as *const _ as usize })
+    }
+
+    pub fn used_addr(&self) -> usize {
+        phys_addr(unsafe { &(*self.mem).device.0 as *const _ as usize })
+    }
+
+    // 把一个请求放入可用环。inputs是设备只读的缓冲区，outputs是设备写入的缓冲区，都是(虚拟地址, 长度)。
+    // 描述符不够时返回None；成功时返回请求头部描述符的编号，设备完成后通过pop_used返回这个编号
+    pub fn add(&mut self, inputs: &[(usize, usize)], outputs: &[(usize, usize)]) -> Option<u16> {
+        let count = inputs.len() + outputs.len();
+        if count == 0 || count > self.num_free {
+            return None;
+        }
+        let area = unsafe { &mut (*self.mem).driver.0 };
+        let (desc, avail) = (&mut area.desc, &mut area.avail);
+        let head = self.free_head;
+        let buffers = inputs.iter().map(|b| (b, 0)).chain(outputs.iter().map(|b| (b, DESC_F_WRITE)));
+        for (i, (&(addr, len), flags)) in buffers.enumerate() {
+            let idx = self.free_head;
+            let d = &mut desc[idx as usize];
+            d.addr = phys_addr(addr) as u64;
+            d.len = len as u32;
+            d.flags = flags | if i + 1 < count { DESC_F_NEXT } else { 0 };
+            self.free_head = d.next;
+        }
+        self.num_free -= count;
+        let slot = avail.idx as usize % QUEUE_SIZE;
+        avail.ring[slot] = head;
+        fence(Ordering::SeqCst); // 设备看到新的idx之前，描述符必须已经写好
+        unsafe { core::ptr::write_volatile(&mut avail.idx, avail.idx.wrapping_add(1)) };
+        Some(head)
+    }
+
+    // 从已用环取出一个完成的请求，释放它的描述符，返回(头部描述符编号, 设备写入的长度)
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe { &(*self.mem).device.0 };
+        let used_idx = unsafe { core::ptr::read_volatile(&used.idx) };
+        if self.last_used_idx == used_idx {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let elem = used.ring[self.last_used_idx as usize % QUEUE_SIZE];
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        let head = elem.id as u16;
+        self.free_chain(head);
+        Some((head, elem.len))
+    }
+
+    fn free_chain(&mut self, head: u16) {
+        let desc = unsafe { &mut (*self.mem).driver.0.desc };
+        let mut idx = head;
+        loop {
+            self.num_free += 1;
+            let d = &mut desc[idx as usize];
+            if d.flags & DESC_F_NEXT == 0 {
+                d.next = self.free_head;
+                break;
+            }
+            idx = d.next;
+        }
+        self.free_head = head;
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.mem as *mut u8, Layout::new::<QueueMemory>()) }
+    }
+}
+
+// 线性映射中的内核虚拟地址对应的物理地址。线程的内核栈不在线性映射中，不能用作设备读写的缓冲区
+fn phys_addr(va: usize) -> usize {
+    debug_assert!(mm::kernel_stack_range(va).is_none(), "buffer {:#x} on a kernel stack is passed to device", va);
+    mm::PhysAddr::from_kernel_virt(mm::VirtAddr(va)).0
+}
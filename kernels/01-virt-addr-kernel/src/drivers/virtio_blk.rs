@@ -0,0 +1,233 @@
+//! virtio块设备驱动
+//!
+//! 读写以512字节的扇区为单位。每个读写请求是一个Future：第一次轮询时提交到虚拟队列，
+//! 设备完成后产生中断，中断处理函数标记请求完成并唤醒等待它的任务。
+
+use super::virtio::{MmioTransport, VirtQueue, VirtioError};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const REQ_TYPE_IN: u32 = 0;
+const REQ_TYPE_OUT: u32 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_IOERR: u8 = 1;
+
+const CONFIG_CAPACITY: usize = 0; // 以扇区为单位的容量
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BlkError {
+    // 设备报告读写失败
+    IoError,
+    // 设备不支持这个请求
+    Unsupported,
+    // 扇区号超出了设备的容量
+    OutOfRange,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// 正在进行的请求。请求头和状态字节要交给设备读写，在请求完成之前不能移动或释放
+struct Request {
+    header: RequestHeader,
+    status: UnsafeCell<u8>,
+    done: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+// 设备写入状态字节之后才会设置done，读取状态之前一定先检查done
+unsafe impl Sync for Request {}
+
+struct BlkInner {
+    queue: VirtQueue,
+    pending: BTreeMap<u16, Arc<Request>>,
+    // 等待空闲描述符的任务
+    waiting: Vec<Waker>,
+}
+
+pub struct VirtioBlk {
+    transport: MmioTransport,
+    capacity: u64,
+    inner: Mutex<BlkInner>,
+}
+
+impl VirtioBlk {
+    pub fn new(transport: MmioTransport) -> Result<Self, VirtioError> {
+        transport.begin_init(0)?; // 不需要额外的特性
+        let queue = VirtQueue::new();
+        transport.setup_queue(0, &queue)?;
+        transport.finish_init();
+        let capacity = transport.config_read_u64(CONFIG_CAPACITY);
+        Ok(VirtioBlk {
+            transport,
+            capacity,
+            inner: Mutex::new(BlkInner { queue, pending: BTreeMap::new(), waiting: Vec::new() }),
+        })
+    }
+
+    // 设备的容量，单位是扇区
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn read_block<'a>(&'a self, sector: u64, buf: &'a mut [u8]) -> BlockRequest<'a> {
+        assert_eq!(buf.len(), SECTOR_SIZE, "buffer should be exactly one sector");
+        BlockRequest::new(self, REQ_TYPE_IN, sector, buf.as_mut_ptr() as usize)
+    }
+
+    pub fn write_block<'a>(&'a self, sector: u64, buf: &'a [u8]) -> BlockRequest<'a> {
+        assert_eq!(buf.len(), SECTOR_SIZE, "buffer should be exactly one sector");
+        BlockRequest::new(self, REQ_TYPE_OUT, sector, buf.as_ptr() as usize)
+    }
+
+    // 中断处理函数，注册到中断控制器
+    pub fn handle_interrupt(&self) {
+        self.transport.ack_interrupt();
+        self.process_used();
+    }
+
+    // 处理已用环中所有完成的请求
+    fn process_used(&self) {
+        let mut wakers = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            while let Some((head, _len)) = inner.queue.pop_used() {
+                if let Some(request) = inner.pending.remove(&head) {
+                    request.done.store(true, Ordering::Release);
+                    if let Some(waker) = request.waker.lock().take() {
+                        wakers.push(waker);
+                    }
+                }
+            }
+            wakers.extend(inner.waiting.drain(..));
+        }
+        // 释放锁以后再唤醒，被唤醒的任务可能马上提交新的请求
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    // 尝试提交请求；描述符不够时返回None
+    fn submit(&self, req_type: u32, sector: u64, buf: usize, cx: &mut Context) -> Option<Arc<Request>> {
+        let request = Arc::new(Request {
+            header: RequestHeader { req_type, reserved: 0, sector },
+            status: UnsafeCell::new(0xff),
+            done: AtomicBool::new(false),
+            waker: Mutex::new(Some(cx.waker().clone())),
+        });
+        let header = (&request.header as *const RequestHeader as usize, core::mem::size_of::<RequestHeader>());
+        let data = (buf, SECTOR_SIZE);
+        let status = (request.status.get() as usize, 1);
+        let mut inner = self.inner.lock();
+        let head = if req_type == REQ_TYPE_IN {
+            inner.queue.add(&[header], &[data, status])
+        } else {
+            inner.queue.add(&[header, data], &[status])
+        };
+        match head {
+            Some(head) => {
+                inner.pending.insert(head, request.clone());
+                drop(inner);
+                self.transport.notify(0);
+                Some(request)
+            },
+            None => {
+                inner.waiting.push(cx.waker().clone());
+                None
+            }
+        }
+    }
+}
+
+// 在当前处理核上等待读写完成
+impl super::BlockDevice for VirtioBlk {
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlkError> {
+        crate::task::block_on(self.read_block(sector, buf))
+    }
+    fn write_sector(&self, sector: u64, buf: &[u8]) -> Result<(), BlkError> {
+        crate::task::block_on(self.write_block(sector, buf))
+    }
+}
+
+// 一次扇区读写。完成前被丢弃时，会等待设备完成，保证设备不会写入已经释放的缓冲区
+pub struct BlockRequest<'a> {
+    blk: &'a VirtioBlk,
+    req_type: u32,
+    sector: u64,
+    buf: usize,
+    request: Option<Arc<Request>>,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> BlockRequest<'a> {
+    fn new(blk: &'a VirtioBlk, req_type: u32, sector: u64, buf: usize) -> Self {
+        BlockRequest { blk, req_type, sector, buf, request: None, _buf: PhantomData }
+    }
+}
+
+impl Future for BlockRequest<'_> {
+    type Output = Result<(), BlkError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.sector >= self.blk.capacity {
+            return Poll::Ready(Err(BlkError::OutOfRange));
+        }
+        let request = match &self.request {
+            Some(request) => request.clone(),
+            None => match self.blk.submit(self.req_type, self.sector, self.buf, cx) {
+                Some(request) => {
+                    self.request = Some(request.clone());
+                    request
+                },
+                None => return Poll::Pending, // 等待空闲的描述符
+            },
+        };
+        if !request.done.load(Ordering::Acquire) {
+            *request.waker.lock() = Some(cx.waker().clone());
+            // 设置唤醒器的同时请求可能已经完成，再检查一次
+            if !request.done.load(Ordering::Acquire) {
+                return Poll::Pending;
+            }
+        }
+        let status = unsafe { *request.status.get() };
+        Poll::Ready(match status {
+            STATUS_OK => Ok(()),
+            STATUS_IOERR => Err(BlkError::IoError),
+            _ => Err(BlkError::Unsupported),
+        })
+    }
+}
+
+impl Drop for BlockRequest<'_> {
+    fn drop(&mut self) {
+        if let Some(request) = &self.request {
+            while !request.done.load(Ordering::Acquire) {
+                self.blk.process_used();
+            }
+        }
+    }
+}
+
+// 启动时的冒烟测试：读取第一个扇区
+pub(crate) fn test_virtio_blk(blk: &VirtioBlk) {
+    let mut buf = [0u8; SECTOR_SIZE];
+    crate::task::block_on(blk.read_block(0, &mut buf)).expect("read first sector");
+    println!("[kernel-virtio-blk-test] Read sector 0 of {} sectors, test passed", blk.capacity());
+}
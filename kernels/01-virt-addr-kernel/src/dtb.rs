@@ -0,0 +1,252 @@
+//! 设备树模块
+//!
+//! 启动时，固件把扁平设备树（FDT）的物理地址放在a1寄存器里传给内核。
+//! 设备树所在的内存只在启动页表的线性映射中，内核地址空间不映射它，所以应当在切换到内核地址空间之前解析，
+//! 把内核需要的设备信息保存到DeviceInfo里。
+
+use alloc::{string::{String, ToString}, vec::Vec};
+use core::convert::TryInto;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DtbError {
+    // 魔数不正确，不是设备树
+    BadMagic,
+    // 结构块中出现了无法识别的记号
+    BadToken(u32),
+    // 长度或偏移量超出了设备树的范围
+    Truncated,
+}
+
+// 设备树的一个节点
+#[derive(Debug)]
+pub struct Node<'a> {
+    pub name: &'a str,
+    parent: Option<usize>,
+    props: Vec<(&'a str, &'a [u8])>,
+}
+
+impl<'a> Node<'a> {
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.props.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+    }
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        let value = self.property(name)?;
+        let value = value.split(|&b| b == 0).next()?;
+        core::str::from_utf8(value).ok()
+    }
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        let value = self.property(name)?;
+        Some(u32::from_be_bytes(value.get(0..4)?.try_into().ok()?))
+    }
+    // 读取一个由一个或两个单元组成的数值
+    pub fn property_cells(&self, name: &str) -> Option<usize> {
+        let value = self.property(name)?;
+        read_cells(value, value.len() / 4)
+    }
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        match self.property("compatible") {
+            Some(list) => list.split(|&b| b == 0).any(|s| s == compatible.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+// 解析后的设备树。节点按深度优先的顺序保存，引用原设备树中的字符串和属性
+#[derive(Debug)]
+pub struct DeviceTree<'a> {
+    nodes: Vec<Node<'a>>,
+}
+
+impl<'a> DeviceTree<'a> {
+    // 设备树通过线性映射访问
+    //
+    // unsafe说明：调用者必须保证dtb_pa处确实是一棵设备树，并且在'a生命周期内可以访问
+    pub unsafe fn from_raw(dtb_pa: usize) -> Result<Self, DtbError> {
+        let dtb_va = crate::mm::PhysAddr(dtb_pa).to_kernel_virt().0;
+        let header = core::slice::from_raw_parts(dtb_va as *const u8, 8);
+        if be32(header, 0)? != FDT_MAGIC {
+            return Err(DtbError::BadMagic);
+        }
+        let total_size = be32(header, 4)? as usize;
+        Self::parse(core::slice::from_raw_parts(dtb_va as *const u8, total_size))
+    }
+
+    pub fn parse(blob: &'a [u8]) -> Result<Self, DtbError> {
+        if be32(blob, 0)? != FDT_MAGIC {
+            return Err(DtbError::BadMagic);
+        }
+        let off_struct = be32(blob, 8)? as usize;
+        let off_strings = be32(blob, 12)? as usize;
+        let mut nodes = Vec::new();
+        let mut stack: Vec<usize> = Vec::new();
+        let mut offset = off_struct;
+        loop {
+            let token = be32(blob, offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(blob, offset)?;
+                    offset = align4(offset + name.len() + 1);
+                    nodes.push(Node { name, parent: stack.last().copied(), props: Vec::new() });
+                    stack.push(nodes.len() - 1);
+                },
+                FDT_END_NODE => { stack.pop(); },
+                FDT_PROP => {
+                    let len = be32(blob, offset)? as usize;
+                    let name_off = be32(blob, offset + 4)? as usize;
+                    offset += 8;
+                    let value = blob.get(offset..offset + len).ok_or(DtbError::Truncated)?;
+                    offset = align4(offset + len);
+                    let name = cstr(blob, off_strings + name_off)?;
+                    let &cur = stack.last().ok_or(DtbError::BadToken(token))?;
+                    nodes[cur].props.push((name, value));
+                },
+                FDT_NOP => {},
+                FDT_END => break,
+                _ => return Err(DtbError::BadToken(token)),
+            }
+        }
+        Ok(DeviceTree { nodes })
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node<'a>> {
+        self.nodes.iter()
+    }
+
+    pub fn find_compatible<'b>(&'b self, compatible: &'b str) -> impl Iterator<Item = &'b Node<'a>> + 'b {
+        self.nodes.iter().filter(move |node| node.is_compatible(compatible))
+    }
+
+    // 按路径查找节点，比如"/chosen"。节点名可以省略"@"后面的单元地址
+    pub fn find_by_path(&self, path: &str) -> Option<&Node<'a>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut cur = 0; // 第0个节点是根节点
+        for part in path.split('/').filter(|s| !s.is_empty()) {
+            cur = self.nodes.iter().position(|node| {
+                node.parent == Some(cur)
+                    && (node.name == part || node.name.split('@').next() == Some(part))
+            })?;
+        }
+        Some(&self.nodes[cur])
+    }
+
+    // 读取节点的reg属性，返回(起始地址, 长度)的列表。单元数由父节点的#address-cells和#size-cells决定
+    pub fn reg(&self, node: &Node<'a>) -> Vec<(usize, usize)> {
+        let parent = node.parent.map(|idx| &self.nodes[idx]);
+        let address_cells = parent.and_then(|p| p.property_u32("#address-cells")).unwrap_or(2) as usize;
+        let size_cells = parent.and_then(|p| p.property_u32("#size-cells")).unwrap_or(1) as usize;
+        let mut ans = Vec::new();
+        if let Some(value) = node.property("reg") {
+            let entry_len = (address_cells + size_cells) * 4;
+            if entry_len == 0 {
+                return ans;
+            }
+            for entry in value.chunks_exact(entry_len) {
+                let addr = read_cells(&entry[..address_cells * 4], address_cells);
+                let size = read_cells(&entry[address_cells * 4..], size_cells);
+                if let (Some(addr), Some(size)) = (addr, size) {
+                    ans.push((addr, size));
+                }
+            }
+        }
+        ans
+    }
+
+    // 读取节点的中断号。QEMU virt平台上，中断控制器的#interrupt-cells为1，每个单元就是一个中断号
+    pub fn interrupts(&self, node: &Node<'a>) -> Vec<u32> {
+        match node.property("interrupts") {
+            Some(value) => value.chunks_exact(4)
+                .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+// 设备树中解析得到、内核需要用到的设备信息。解析完成后就不再需要访问设备树
+#[derive(Debug)]
+pub struct DeviceInfo {
+    pub uart: Option<MmioDevice>,
+    pub plic: Option<MmioDevice>,
+    // 所有virtio-mmio插槽，其中大部分可能没有挂载设备，需要探测
+    pub virtio: Vec<MmioDevice>,
+    // 固件通过/chosen节点传来的initramfs，(起始地址, 结束地址)
+    pub initrd: Option<(usize, usize)>,
+    // time寄存器每秒增加的次数，来自/cpus节点
+    pub timebase_frequency: Option<usize>,
+    // 内核命令行，来自/chosen节点
+    pub bootargs: Option<String>,
+}
+
+// 一个通过内存映射读写寄存器的设备
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct MmioDevice {
+    pub base: usize,
+    pub size: usize,
+    pub irq: Option<u32>,
+}
+
+impl DeviceInfo {
+    // unsafe说明：调用者必须保证dtb_pa处是有效的设备树，并且它在当前地址空间的线性映射中
+    pub unsafe fn parse(dtb_pa: usize) -> Result<DeviceInfo, DtbError> {
+        let tree = DeviceTree::from_raw(dtb_pa)?;
+        let uart = tree.find_compatible("ns16550a").next()
+            .and_then(|node| mmio_device(&tree, node));
+        let plic = tree.find_compatible("riscv,plic0").next()
+            .and_then(|node| mmio_device(&tree, node));
+        let virtio = tree.find_compatible("virtio,mmio")
+            .filter_map(|node| mmio_device(&tree, node))
+            .collect();
+        let initrd = tree.find_by_path("/chosen").and_then(|chosen| {
+            let start = chosen.property_cells("linux,initrd-start")?;
+            let end = chosen.property_cells("linux,initrd-end")?;
+            Some((start, end)).filter(|(start, end)| start < end)
+        });
+        let timebase_frequency = tree.find_by_path("/cpus")
+            .and_then(|cpus| cpus.property_cells("timebase-frequency"));
+        let bootargs = tree.find_by_path("/chosen")
+            .and_then(|chosen| chosen.property_str("bootargs"))
+            .map(|bootargs| bootargs.to_string());
+        Ok(DeviceInfo { uart, plic, virtio, initrd, timebase_frequency, bootargs })
+    }
+}
+
+fn mmio_device(tree: &DeviceTree, node: &Node) -> Option<MmioDevice> {
+    let &(base, size) = tree.reg(node).first()?;
+    let irq = tree.interrupts(node).first().copied();
+    Some(MmioDevice { base, size, irq })
+}
+
+fn be32(blob: &[u8], offset: usize) -> Result<u32, DtbError> {
+    let bytes = blob.get(offset..offset + 4).ok_or(DtbError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn cstr(blob: &[u8], offset: usize) -> Result<&str, DtbError> {
+    let rest = blob.get(offset..).ok_or(DtbError::Truncated)?;
+    let len = rest.iter().position(|&b| b == 0).ok_or(DtbError::Truncated)?;
+    core::str::from_utf8(&rest[..len]).map_err(|_| DtbError::Truncated)
+}
+
+fn read_cells(value: &[u8], cells: usize) -> Option<usize> {
+    let mut ans: usize = 0;
+    for i in 0..cells {
+        let cell = u32::from_be_bytes(value.get(i * 4..i * 4 + 4)?.try_into().ok()?);
+        ans = (((ans as u64) << 32) | cell as u64) as usize;
+    }
+    Some(ans)
+}
+
+#[inline]
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
@@ -1,59 +1,54 @@
 use riscv::register::{
-    sstatus::{self, Sstatus, SPP},
-    scause::{self, Trap, Exception},
+    sstatus::{self, Sstatus, FS, SPP},
+    scause::{self, Trap, Exception, Interrupt},
     stvec::{self, TrapMode}, stval,
     satp::Satp,
 };
 use core::{
     pin::Pin,
     ops::{Generator, GeneratorState},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 use crate::mm;
 
+// 跳板页上用户异常入口的虚拟地址。进入用户程序之前写到stvec，回到内核后再换成内核的异常入口
+static USER_TRAP_ENTRY: AtomicUsize = AtomicUsize::new(0);
+
 pub fn init(trampoline_va_start: mm::VirtAddr) {
+    // 跳板代码在内核镜像中的地址和它在跳板页上的地址，相差一个固定的偏移
     extern "C" { fn strampoline(); }
-    let trampoline_pa_start = strampoline as usize;
-    let trap_entry_fn_pa = trampoline_trap_entry as usize;
-    let trap_entry_fn_va = trap_entry_fn_pa - trampoline_pa_start + trampoline_va_start.0;
+    let trampoline_start = strampoline as usize;
+    let trap_entry_fn = trampoline_trap_entry as usize;
+    let trap_entry_fn_va = trap_entry_fn - trampoline_start + trampoline_va_start.0;
     let mut addr = trap_entry_fn_va;
     if addr & 0x2 != 0 {
         addr += 0x2; // 必须对齐到4个字节
     }
-    unsafe { stvec::write(addr, TrapMode::Direct) };
+    USER_TRAP_ENTRY.store(addr, Ordering::Relaxed);
 }
 
 #[repr(C)]
 pub struct Runtime { 
     user_satp: Satp,
     trampoline_resume: fn(*mut ResumeContext, Satp),
-    current_user_stack: mm::VirtAddr,
     context_addr: mm::VirtAddr,
 }
 
 impl Runtime {
-    pub fn new_user(new_sepc: usize, user_stack_addr: mm::VirtAddr, new_satp: Satp, trampoline_va_start: mm::VirtAddr, context_addr: mm::VirtAddr) -> Self {
-        let mut ans: Runtime = Runtime {
+    // 创建时还没有用户程序，由调度器用load_context换上第一个进程
+    pub fn new(trampoline_va_start: mm::VirtAddr, context_addr: mm::VirtAddr) -> Self {
+        Runtime {
             user_satp: unsafe { core::mem::MaybeUninit::zeroed().assume_init() },
-            current_user_stack: user_stack_addr,
             trampoline_resume: {
                 extern "C" { fn strampoline(); }
-                let trampoline_pa_start = strampoline as usize;
-                let resume_fn_pa = trampoline_resume as usize;
-                let resume_fn_va = resume_fn_pa - trampoline_pa_start + trampoline_va_start.0;
-                // println!("pa start = {:x?}, pa = {:x?}, va = {:x?}",trampoline_pa_start, resume_fn_pa, resume_fn_va);
+                let trampoline_start = strampoline as usize;
+                let resume_fn = trampoline_resume as usize;
+                let resume_fn_va = resume_fn - trampoline_start + trampoline_va_start.0;
+                trace!("resume fn kernel start = {:x?}, kernel = {:x?}, va = {:x?}", trampoline_start, resume_fn, resume_fn_va);
                 unsafe { core::mem::transmute(resume_fn_va) }
             },
             context_addr,
-        };
-        unsafe { ans.prepare_next_app(new_sepc, new_satp) };
-        ans
-    }
-
-    unsafe fn reset(&mut self) {
-        self.context_mut().sp = self.current_user_stack.0;
-        sstatus::set_spp(SPP::User);
-        self.context_mut().sstatus = sstatus::read();
-        self.context_mut().kernel_stack = 0x233333666666; // 将会被resume函数覆盖
+        }
     }
 
     // 在处理异常的时候，使用context_mut得到运行时当前用户的上下文，可以改变上下文的内容
@@ -61,10 +56,15 @@ impl Runtime {
         &mut *(self.context_addr.0 as *mut ResumeContext)
     }
 
-    pub unsafe fn prepare_next_app(&mut self, new_sepc: usize, new_satp: Satp) {
-        self.reset();
-        self.context_mut().sepc = new_sepc;
-        self.user_satp = new_satp;
+    // 切换用户程序之前，把当前程序的上下文从跳板数据页复制出来
+    pub unsafe fn save_context(&mut self, context: &mut ResumeContext) {
+        *context = self.context_mut().clone();
+    }
+
+    // 换上另一个用户程序的上下文和地址空间，下次resume时运行它
+    pub unsafe fn load_context(&mut self, context: &ResumeContext, satp: Satp) {
+        *self.context_mut() = context.clone();
+        self.user_satp = satp;
     }
 }
 
@@ -72,15 +72,27 @@ impl Generator for Runtime {
     type Yield = KernelTrap;
     type Return = ();
     fn resume(mut self: Pin<&mut Self>, _arg: ()) -> GeneratorState<Self::Yield, Self::Return> {
+        // 内核中不打开中断，所以切换stvec到进入用户程序之间，不会发生异常
+        unsafe { stvec::write(USER_TRAP_ENTRY.load(Ordering::Relaxed), TrapMode::Direct) };
         (self.trampoline_resume)(
             unsafe { self.context_mut() } as *mut _,
             self.user_satp
         );
+        unsafe { crate::trap::set_kernel_trap_entry() };
         let stval = stval::read();
         let trap = match scause::read().cause() {
             Trap::Exception(Exception::UserEnvCall) => KernelTrap::Syscall(),
+            Trap::Interrupt(Interrupt::SupervisorExternal) => KernelTrap::External(),
+            Trap::Interrupt(Interrupt::SupervisorTimer) => KernelTrap::Timer(),
             Trap::Exception(Exception::LoadFault) => KernelTrap::LoadAccessFault(stval),
             Trap::Exception(Exception::StoreFault) => KernelTrap::StoreAccessFault(stval),
+            Trap::Exception(Exception::InstructionFault) |
+            Trap::Exception(Exception::InstructionPageFault) |
+            Trap::Exception(Exception::LoadPageFault) |
+            Trap::Exception(Exception::StorePageFault) => KernelTrap::PageFault(stval),
+            Trap::Exception(Exception::InstructionMisaligned) |
+            Trap::Exception(Exception::LoadMisaligned) |
+            Trap::Exception(Exception::StoreMisaligned) => KernelTrap::Misaligned(stval),
             Trap::Exception(Exception::IllegalInstruction) => KernelTrap::IllegalInstruction(stval),
             e => panic!("unhandled exception: {:?}! stval: {:#x?}, ctx: {:#x?}", e, stval, unsafe { self.context_mut() })
         };
@@ -92,13 +104,19 @@ impl Generator for Runtime {
 #[repr(C)]
 pub enum KernelTrap {
     Syscall(),
+    External(),
+    Timer(),
     LoadAccessFault(usize),
     StoreAccessFault(usize),
+    // 访问没有映射或者权限不够的页，参数是出错的地址
+    PageFault(usize),
+    Misaligned(usize),
     IllegalInstruction(usize),
 }
 
 // 应当放到跳板数据页上，用户和内核
-#[derive(Debug)]
+// 所有字段都是寄存器宽度，跳板代码按寄存器编号访问，在RV64和RV32上布局相同
+#[derive(Debug, Clone)]
 #[repr(C)]
 pub struct ResumeContext {
     pub ra: usize, // 0
@@ -138,6 +156,20 @@ pub struct ResumeContext {
     pub kernel_satp: Satp, // 34
 }
 
+impl ResumeContext {
+    // 还没有运行过的用户程序的上下文，从new_sepc开始运行。内核栈和内核的地址空间配置由resume函数填写
+    pub fn new_user(new_sepc: usize, user_stack_addr: mm::VirtAddr) -> Self {
+        let mut ans: ResumeContext = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+        ans.sp = user_stack_addr.0;
+        // 新线程还没有使用过浮点单元，见fpu模块
+        unsafe { sstatus::set_spp(SPP::User); sstatus::set_fs(FS::Off) };
+        ans.sstatus = sstatus::read();
+        ans.sepc = new_sepc;
+        ans.kernel_stack = usize::MAX; // 将会被resume函数覆盖，这个值在RV32上也能表示
+        ans
+    }
+}
+
 /*
 跳板页设计：
 1. 共享的代码跳板页 - 整个系统里有一个
@@ -146,11 +178,38 @@ trampoline段：保存共享的代码
 分配的其它页，每个核一个：保存数据跳板页
 */
 
+// 按照目标平台的寄存器宽度，生成保存、恢复寄存器的汇编指令。
+// 这样同一份跳板代码可以同时用在RV64和RV32上，偏移量按寄存器的数量计算
+#[cfg(target_pointer_width = "64")]
+macro_rules! xs {
+    ($reg: literal, $idx: literal, $base: literal) => { concat!("sd     ", $reg, ", ", $idx, "*8(", $base, ")") };
+}
+#[cfg(target_pointer_width = "64")]
+macro_rules! xl {
+    ($reg: literal, $idx: literal, $base: literal) => { concat!("ld     ", $reg, ", ", $idx, "*8(", $base, ")") };
+}
+#[cfg(target_pointer_width = "64")]
+macro_rules! xsp {
+    ($sign: literal, $n: literal) => { concat!("addi   sp, sp, ", $sign, $n, "*8") };
+}
+#[cfg(target_pointer_width = "32")]
+macro_rules! xs {
+    ($reg: literal, $idx: literal, $base: literal) => { concat!("sw     ", $reg, ", ", $idx, "*4(", $base, ")") };
+}
+#[cfg(target_pointer_width = "32")]
+macro_rules! xl {
+    ($reg: literal, $idx: literal, $base: literal) => { concat!("lw     ", $reg, ", ", $idx, "*4(", $base, ")") };
+}
+#[cfg(target_pointer_width = "32")]
+macro_rules! xsp {
+    ($sign: literal, $n: literal) => { concat!("addi   sp, sp, ", $sign, $n, "*4") };
+}
+
 // 函数作用：
 // 1. 先保存寄存器
 // 2. 再切换地址空间
 // a0 = 生成器上下文
-// sp = 内核栈
+// sp = 内核栈，也就是当前线程的内核栈，被调用者保存的寄存器压在这里
 // sscratch = 用户的a0值
 // 注意：_ctx必须也映射到跳板页里面
 #[naked]
@@ -158,66 +217,66 @@ trampoline段：保存共享的代码
 unsafe extern "C" fn trampoline_resume(_ctx: *mut ResumeContext, _user_satp: usize) {
     asm!(
         // a0 = 生成器上下文, a1 = 用户的地址空间配置, sp = 内核栈
-        "addi   sp, sp, -15*8",
-        "sd     ra, 0*8(sp)
-        sd      gp, 1*8(sp)
-        sd      tp, 2*8(sp)
-        sd      s0, 3*8(sp)
-        sd      s1, 4*8(sp)
-        sd      s2, 5*8(sp)
-        sd      s3, 6*8(sp)
-        sd      s4, 7*8(sp)
-        sd      s5, 8*8(sp)
-        sd      s6, 9*8(sp)
-        sd      s7, 10*8(sp)
-        sd      s8, 11*8(sp)
-        sd      s9, 12*8(sp)
-        sd      s10, 13*8(sp)
-        sd      s11, 14*8(sp)", // 保存子函数寄存器，到内核栈
+        xsp!("-", 15), // 在内核栈上留出15个寄存器的空间
+        xs!("ra", 0, "sp"),
+        xs!("gp", 1, "sp"),
+        xs!("tp", 2, "sp"),
+        xs!("s0", 3, "sp"),
+        xs!("s1", 4, "sp"),
+        xs!("s2", 5, "sp"),
+        xs!("s3", 6, "sp"),
+        xs!("s4", 7, "sp"),
+        xs!("s5", 8, "sp"),
+        xs!("s6", 9, "sp"),
+        xs!("s7", 10, "sp"),
+        xs!("s8", 11, "sp"),
+        xs!("s9", 12, "sp"),
+        xs!("s10", 13, "sp"),
+        xs!("s11", 14, "sp"), // 保存子函数寄存器，到内核栈
         "csrrw  a1, satp, a1", // 写用户的地址空间配置到satp，读内核的satp到a1
         "sfence.vma", // 立即切换地址空间
         // a0 = 生成器上下文, a1 = 内核的地址空间配置, sp = 内核栈
-        "sd     sp, 33*8(a0)", // 保存内核栈位置
+        xs!("sp", 33, "a0"), // 保存内核栈位置
         "mv     sp, a0", 
         // a1 = 内核的地址空间配置, sp = 生成器上下文
-        "sd     a1, 34*8(sp)", // 保存内核的地址空间配置
-        "ld     t0, 31*8(sp)
-        ld      t1, 32*8(sp)
-        csrw    sstatus, t0
-        csrw    sepc, t1
-        ld      ra, 0*8(sp)
-        ld      gp, 2*8(sp)
-        ld      tp, 3*8(sp)
-        ld      t0, 4*8(sp)
-        ld      t1, 5*8(sp)
-        ld      t2, 6*8(sp)
-        ld      s0, 7*8(sp)
-        ld      s1, 8*8(sp)
-        ld      a0, 9*8(sp)
-        ld      a1, 10*8(sp)
-        ld      a2, 11*8(sp)
-        ld      a3, 12*8(sp)
-        ld      a4, 13*8(sp)
-        ld      a5, 14*8(sp)
-        ld      a6, 15*8(sp)
-        ld      a7, 16*8(sp)
-        ld      s2, 17*8(sp)
-        ld      s3, 18*8(sp)
-        ld      s4, 19*8(sp)
-        ld      s5, 20*8(sp)
-        ld      s6, 21*8(sp)
-        ld      s7, 22*8(sp)
-        ld      s8, 23*8(sp)
-        ld      s9, 24*8(sp)
-        ld     s10, 25*8(sp)
-        ld     s11, 26*8(sp)
-        ld      t3, 27*8(sp)
-        ld      t4, 28*8(sp)
-        ld      t5, 29*8(sp)
-        ld      t6, 30*8(sp)", // 加载生成器上下文寄存器，除了a0
+        xs!("a1", 34, "sp"), // 保存内核的地址空间配置
+        xl!("t0", 31, "sp"),
+        xl!("t1", 32, "sp"),
+        "csrw   sstatus, t0
+        csrw    sepc, t1",
+        xl!("ra", 0, "sp"),
+        xl!("gp", 2, "sp"),
+        xl!("tp", 3, "sp"),
+        xl!("t0", 4, "sp"),
+        xl!("t1", 5, "sp"),
+        xl!("t2", 6, "sp"),
+        xl!("s0", 7, "sp"),
+        xl!("s1", 8, "sp"),
+        xl!("a0", 9, "sp"),
+        xl!("a1", 10, "sp"),
+        xl!("a2", 11, "sp"),
+        xl!("a3", 12, "sp"),
+        xl!("a4", 13, "sp"),
+        xl!("a5", 14, "sp"),
+        xl!("a6", 15, "sp"),
+        xl!("a7", 16, "sp"),
+        xl!("s2", 17, "sp"),
+        xl!("s3", 18, "sp"),
+        xl!("s4", 19, "sp"),
+        xl!("s5", 20, "sp"),
+        xl!("s6", 21, "sp"),
+        xl!("s7", 22, "sp"),
+        xl!("s8", 23, "sp"),
+        xl!("s9", 24, "sp"),
+        xl!("s10", 25, "sp"),
+        xl!("s11", 26, "sp"),
+        xl!("t3", 27, "sp"),
+        xl!("t4", 28, "sp"),
+        xl!("t5", 29, "sp"),
+        xl!("t6", 30, "sp"), // 加载生成器上下文寄存器，除了a0
         // sp = 生成器上下文
         "csrw   sscratch, sp",
-        "ld     sp, 1*8(sp)", // 加载用户栈
+        xl!("sp", 1, "sp"), // 加载用户栈
         // sp = 用户栈, sscratch = 生成器上下文
         "sret", // set priv, j sepc
         options(noreturn)
@@ -232,65 +291,65 @@ unsafe extern "C" fn trampoline_trap_entry() {
         // sp = 用户栈, sscratch = 生成器上下文
         "csrrw  sp, sscratch, sp", 
         // sp = 生成器上下文, sscratch = 用户栈
-        "sd     ra, 0*8(sp)
-        sd      gp, 2*8(sp)
-        sd      tp, 3*8(sp)
-        sd      t0, 4*8(sp)
-        sd      t1, 5*8(sp)
-        sd      t2, 6*8(sp)
-        sd      s0, 7*8(sp)
-        sd      s1, 8*8(sp)
-        sd      a0, 9*8(sp)
-        sd      a1, 10*8(sp)
-        sd      a2, 11*8(sp)
-        sd      a3, 12*8(sp)
-        sd      a4, 13*8(sp)
-        sd      a5, 14*8(sp)
-        sd      a6, 15*8(sp)
-        sd      a7, 16*8(sp)
-        sd      s2, 17*8(sp)
-        sd      s3, 18*8(sp)
-        sd      s4, 19*8(sp)
-        sd      s5, 20*8(sp)
-        sd      s6, 21*8(sp)
-        sd      s7, 22*8(sp)
-        sd      s8, 23*8(sp)
-        sd      s9, 24*8(sp)
-        sd     s10, 25*8(sp)
-        sd     s11, 26*8(sp)
-        sd      t3, 27*8(sp)
-        sd      t4, 28*8(sp)
-        sd      t5, 29*8(sp)
-        sd      t6, 30*8(sp)",
-        "csrr   t0, sstatus
-        sd      t0, 31*8(sp)",
-        "csrr   t1, sepc
-        sd      t1, 32*8(sp)",
+        xs!("ra", 0, "sp"),
+        xs!("gp", 2, "sp"),
+        xs!("tp", 3, "sp"),
+        xs!("t0", 4, "sp"),
+        xs!("t1", 5, "sp"),
+        xs!("t2", 6, "sp"),
+        xs!("s0", 7, "sp"),
+        xs!("s1", 8, "sp"),
+        xs!("a0", 9, "sp"),
+        xs!("a1", 10, "sp"),
+        xs!("a2", 11, "sp"),
+        xs!("a3", 12, "sp"),
+        xs!("a4", 13, "sp"),
+        xs!("a5", 14, "sp"),
+        xs!("a6", 15, "sp"),
+        xs!("a7", 16, "sp"),
+        xs!("s2", 17, "sp"),
+        xs!("s3", 18, "sp"),
+        xs!("s4", 19, "sp"),
+        xs!("s5", 20, "sp"),
+        xs!("s6", 21, "sp"),
+        xs!("s7", 22, "sp"),
+        xs!("s8", 23, "sp"),
+        xs!("s9", 24, "sp"),
+        xs!("s10", 25, "sp"),
+        xs!("s11", 26, "sp"),
+        xs!("t3", 27, "sp"),
+        xs!("t4", 28, "sp"),
+        xs!("t5", 29, "sp"),
+        xs!("t6", 30, "sp"),
+        "csrr   t0, sstatus",
+        xs!("t0", 31, "sp"),
+        "csrr   t1, sepc",
+        xs!("t1", 32, "sp"),
         // sp = 生成器上下文, sscratch = 用户栈
         "csrrw  t2, sscratch, sp", 
         // sp = 生成器上下文, sscratch = 生成器上下文, t2 = 用户栈
-        "sd     t2, 1*8(sp)", // 保存用户栈
-        "ld     t3, 34*8(sp)", // t3 = 内核的地址空间配置
+        xs!("t2", 1, "sp"), // 保存用户栈
+        xl!("t3", 34, "sp"), // t3 = 内核的地址空间配置
         "csrw   satp, t3", // 写内核的地址空间配置；用户的地址空间配置将丢弃
         "sfence.vma", // 立即切换地址空间
-        "ld     sp, 33*8(sp)", 
+        xl!("sp", 33, "sp"), 
         // sp = 内核栈
-        "ld     ra, 0*8(sp)
-        ld      gp, 1*8(sp)
-        ld      tp, 2*8(sp)
-        ld      s0, 3*8(sp)
-        ld      s1, 4*8(sp)
-        ld      s2, 5*8(sp)
-        ld      s3, 6*8(sp)
-        ld      s4, 7*8(sp)
-        ld      s5, 8*8(sp)
-        ld      s6, 9*8(sp)
-        ld      s7, 10*8(sp)
-        ld      s8, 11*8(sp)
-        ld      s9, 12*8(sp)
-        ld      s10, 13*8(sp)
-        ld      s11, 14*8(sp)
-        addi    sp, sp, 15*8", // sp = 内核栈
+        xl!("ra", 0, "sp"),
+        xl!("gp", 1, "sp"),
+        xl!("tp", 2, "sp"),
+        xl!("s0", 3, "sp"),
+        xl!("s1", 4, "sp"),
+        xl!("s2", 5, "sp"),
+        xl!("s3", 6, "sp"),
+        xl!("s4", 7, "sp"),
+        xl!("s5", 8, "sp"),
+        xl!("s6", 9, "sp"),
+        xl!("s7", 10, "sp"),
+        xl!("s8", 11, "sp"),
+        xl!("s9", 12, "sp"),
+        xl!("s10", 13, "sp"),
+        xl!("s11", 14, "sp"),
+        xsp!("", 15), // sp = 内核栈
         "jr     ra", // ret指令
         options(noreturn)
     )
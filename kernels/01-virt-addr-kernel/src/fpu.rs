@@ -0,0 +1,130 @@
+//! 浮点寄存器的保存和恢复
+//!
+//! 内核本身不使用浮点指令，用户线程的浮点寄存器可以一直留在处理核上，只在切换线程时按需保存。
+//! sstatus.FS记录浮点单元的状态：Off表示线程还没有用过浮点单元，使用浮点指令会产生非法指令异常；
+//! Initial和Clean表示寄存器和保存的副本相同；Dirty表示线程修改过寄存器。
+//!
+//! 线程第一次使用浮点指令时，内核为它分配保存浮点寄存器的空间，清零寄存器，打开浮点单元后重新执行这条指令。
+//! 切换出去时只有Dirty的线程需要保存寄存器；切换回来时，如果处理核上留着的就是它的寄存器，也不需要恢复。
+//!
+//! 信号处理函数和被打断的代码共用浮点寄存器，处理函数使用浮点指令时需要自己保存它们
+
+use crate::{executor::ResumeContext, mm};
+use core::sync::atomic::{AtomicBool, Ordering};
+use riscv::register::sstatus::{self, Sstatus, FS};
+
+// 处理核是否有浮点单元
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+// 一个线程的浮点寄存器。双精度寄存器在RV32上也是64位
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct FpContext {
+    pub f: [u64; 32],
+    pub fcsr: usize,
+}
+
+// 线程的浮点上下文从这个缓存中分配
+static FP_CONTEXTS: mm::SlabCache<FpContext> = mm::SlabCache::new("fp_context");
+
+// 分配清零的浮点上下文
+pub fn new_context() -> mm::SlabBox<FpContext> {
+    FP_CONTEXTS.alloc(FpContext::default()).expect("allocate floating-point context")
+}
+
+// sstatus.FS是WARL字段，没有浮点单元的处理核上它总是Off
+pub fn init() {
+    unsafe { sstatus::set_fs(FS::Initial) };
+    let available = sstatus::read().fs() != FS::Off;
+    unsafe { sstatus::set_fs(FS::Off) };
+    AVAILABLE.store(available, Ordering::Relaxed);
+    info!("Floating-point unit {}", if available { "available" } else { "not available" });
+}
+
+pub fn is_available() -> bool {
+    AVAILABLE.load(Ordering::Relaxed)
+}
+
+// 用户上下文中的浮点单元状态，返回用户态时写回sstatus
+pub fn state(ctx: &ResumeContext) -> FS {
+    ctx.sstatus.fs()
+}
+
+pub fn set_state(ctx: &mut ResumeContext, fs: FS) {
+    // riscv库的Sstatus只有一个usize字段，没有修改字段的方法；跳板代码也把它当作一个寄存器访问
+    let bits = unsafe { &mut *(&mut ctx.sstatus as *mut Sstatus as *mut usize) };
+    *bits = (*bits & !(0b11 << 13)) | (fs as usize) << 13;
+}
+
+// 把处理核上的浮点寄存器保存到fp中
+pub unsafe fn save(fp: &mut FpContext) {
+    sstatus::set_fs(FS::Clean); // 内核执行浮点指令时浮点单元也必须打开
+    save_registers(fp);
+    sstatus::set_fs(FS::Off);
+}
+
+// 把fp中的浮点寄存器恢复到处理核上
+pub unsafe fn restore(fp: &FpContext) {
+    sstatus::set_fs(FS::Clean);
+    restore_registers(fp);
+    sstatus::set_fs(FS::Off);
+}
+
+#[target_feature(enable = "f,d")]
+unsafe fn save_registers(fp: &mut FpContext) {
+    asm!(
+        "fsd    f0, 0*8({0})", "fsd    f1, 1*8({0})", "fsd    f2, 2*8({0})", "fsd    f3, 3*8({0})",
+        "fsd    f4, 4*8({0})", "fsd    f5, 5*8({0})", "fsd    f6, 6*8({0})", "fsd    f7, 7*8({0})",
+        "fsd    f8, 8*8({0})", "fsd    f9, 9*8({0})", "fsd    f10, 10*8({0})", "fsd    f11, 11*8({0})",
+        "fsd    f12, 12*8({0})", "fsd    f13, 13*8({0})", "fsd    f14, 14*8({0})", "fsd    f15, 15*8({0})",
+        "fsd    f16, 16*8({0})", "fsd    f17, 17*8({0})", "fsd    f18, 18*8({0})", "fsd    f19, 19*8({0})",
+        "fsd    f20, 20*8({0})", "fsd    f21, 21*8({0})", "fsd    f22, 22*8({0})", "fsd    f23, 23*8({0})",
+        "fsd    f24, 24*8({0})", "fsd    f25, 25*8({0})", "fsd    f26, 26*8({0})", "fsd    f27, 27*8({0})",
+        "fsd    f28, 28*8({0})", "fsd    f29, 29*8({0})", "fsd    f30, 30*8({0})", "fsd    f31, 31*8({0})",
+        "frcsr  {1}",
+        in(reg) fp.f.as_mut_ptr(),
+        out(reg) fp.fcsr,
+    );
+}
+
+#[target_feature(enable = "f,d")]
+unsafe fn restore_registers(fp: &FpContext) {
+    asm!(
+        "fld    f0, 0*8({0})", "fld    f1, 1*8({0})", "fld    f2, 2*8({0})", "fld    f3, 3*8({0})",
+        "fld    f4, 4*8({0})", "fld    f5, 5*8({0})", "fld    f6, 6*8({0})", "fld    f7, 7*8({0})",
+        "fld    f8, 8*8({0})", "fld    f9, 9*8({0})", "fld    f10, 10*8({0})", "fld    f11, 11*8({0})",
+        "fld    f12, 12*8({0})", "fld    f13, 13*8({0})", "fld    f14, 14*8({0})", "fld    f15, 15*8({0})",
+        "fld    f16, 16*8({0})", "fld    f17, 17*8({0})", "fld    f18, 18*8({0})", "fld    f19, 19*8({0})",
+        "fld    f20, 20*8({0})", "fld    f21, 21*8({0})", "fld    f22, 22*8({0})", "fld    f23, 23*8({0})",
+        "fld    f24, 24*8({0})", "fld    f25, 25*8({0})", "fld    f26, 26*8({0})", "fld    f27, 27*8({0})",
+        "fld    f28, 28*8({0})", "fld    f29, 29*8({0})", "fld    f30, 30*8({0})", "fld    f31, 31*8({0})",
+        "fscsr  {1}",
+        in(reg) fp.f.as_ptr(),
+        in(reg) fp.fcsr,
+    );
+}
+
+pub(crate) fn test_fpu() {
+    let mut ctx = ResumeContext::new_user(0, crate::mm::VirtAddr(0));
+    assert_eq!(state(&ctx), FS::Off);
+    set_state(&mut ctx, FS::Dirty);
+    assert_eq!(state(&ctx), FS::Dirty);
+    set_state(&mut ctx, FS::Clean);
+    assert_eq!(state(&ctx), FS::Clean);
+    assert_eq!(ctx.sstatus.spp(), sstatus::SPP::User); // 其它字段保持不变
+    if is_available() {
+        let mut saved = FpContext::default();
+        saved.f[1] = 0x4009_21fb_5444_2d18; // 3.141592653589793
+        saved.f[31] = u64::MAX;
+        saved.fcsr = 0b001_00001; // 向零舍入，不精确标志
+        let mut read_back = FpContext::default();
+        unsafe {
+            restore(&saved);
+            save(&mut read_back);
+        }
+        assert_eq!(read_back.f[1], saved.f[1]);
+        assert_eq!(read_back.f[31], saved.f[31]);
+        assert_eq!(read_back.fcsr, saved.fcsr);
+    }
+    println!("[kernel-fpu-test] Floating-point context test passed");
+}
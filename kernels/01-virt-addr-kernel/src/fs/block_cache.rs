@@ -0,0 +1,100 @@
+//! 块设备的扇区缓存
+//!
+//! 采用写回策略：写入只修改缓存，扇区被换出或者调用sync时才写回设备。
+//! 缓存满时换出最久没有使用的扇区。
+
+use super::{FsError, Result};
+use crate::drivers::{virtio_blk::SECTOR_SIZE, BlockDevice};
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+
+struct CacheEntry {
+    sector: u64,
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_use: u64,
+}
+
+struct CacheInner {
+    entries: Vec<CacheEntry>,
+    clock: u64,
+}
+
+pub struct BlockCache {
+    device: &'static dyn BlockDevice,
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl BlockCache {
+    pub fn new(device: &'static dyn BlockDevice, capacity: usize) -> Self {
+        BlockCache {
+            device,
+            capacity,
+            inner: Mutex::new(CacheInner { entries: Vec::with_capacity(capacity), clock: 0 }),
+        }
+    }
+
+    // 读取扇区sector中从offset开始的内容，填满buf
+    pub fn read(&self, sector: u64, offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.with_sector(sector, |data| buf.copy_from_slice(&data[offset..offset + buf.len()]))
+    }
+
+    // 把data写到扇区sector中offset开始的位置
+    pub fn write(&self, sector: u64, offset: usize, data: &[u8]) -> Result<()> {
+        self.with_sector_mut(sector, |buf| buf[offset..offset + data.len()].copy_from_slice(data))
+    }
+
+    pub fn with_sector<R>(&self, sector: u64, f: impl FnOnce(&[u8; SECTOR_SIZE]) -> R) -> Result<R> {
+        let mut inner = self.inner.lock();
+        let idx = self.get_entry(&mut inner, sector)?;
+        Ok(f(&inner.entries[idx].data))
+    }
+
+    pub fn with_sector_mut<R>(&self, sector: u64, f: impl FnOnce(&mut [u8; SECTOR_SIZE]) -> R) -> Result<R> {
+        let mut inner = self.inner.lock();
+        let idx = self.get_entry(&mut inner, sector)?;
+        let entry = &mut inner.entries[idx];
+        entry.dirty = true;
+        Ok(f(&mut entry.data))
+    }
+
+    // 把所有修改过的扇区写回设备
+    pub fn sync(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        for entry in inner.entries.iter_mut().filter(|e| e.dirty) {
+            self.device.write_sector(entry.sector, &entry.data[..]).map_err(|_| FsError::IoError)?;
+            entry.dirty = false;
+        }
+        Ok(())
+    }
+
+    // 找到扇区对应的缓存项，不在缓存中时从设备读取
+    fn get_entry(&self, inner: &mut CacheInner, sector: u64) -> Result<usize> {
+        inner.clock += 1;
+        let clock = inner.clock;
+        if let Some(idx) = inner.entries.iter().position(|e| e.sector == sector) {
+            inner.entries[idx].last_use = clock;
+            return Ok(idx);
+        }
+        if sector >= self.device.sector_count() {
+            return Err(FsError::IoError);
+        }
+        let mut data = Box::new([0u8; SECTOR_SIZE]);
+        self.device.read_sector(sector, &mut data[..]).map_err(|_| FsError::IoError)?;
+        let entry = CacheEntry { sector, data, dirty: false, last_use: clock };
+        if inner.entries.len() < self.capacity {
+            inner.entries.push(entry);
+            return Ok(inner.entries.len() - 1);
+        }
+        let (idx, victim) = inner.entries.iter_mut()
+            .enumerate()
+            .min_by_key(|(_, e)| e.last_use)
+            .expect("cache capacity should not be zero");
+        if victim.dirty {
+            self.device.write_sector(victim.sector, &victim.data[..]).map_err(|_| FsError::IoError)?;
+        }
+        *victim = entry;
+        Ok(idx)
+    }
+}
@@ -0,0 +1,99 @@
+//! 设备文件系统，挂载在/dev
+//!
+//! 目前只有两个设备：console是控制台，null丢弃所有写入的数据
+
+use super::{DirEntry, FileSystem, FsError, Inode, InodeType, Metadata, Result};
+use alloc::{string::ToString, sync::Arc, vec::Vec};
+
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        let devices: Vec<(&'static str, Arc<dyn Inode>)> = alloc::vec![
+            ("console", Arc::new(ConsoleDevice)),
+            ("null", Arc::new(NullDevice)),
+        ];
+        DevFs { root: Arc::new(DevDir { devices }) }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct DevDir {
+    devices: Vec<(&'static str, Arc<dyn Inode>)>,
+}
+
+impl Inode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: 1, kind: InodeType::Directory, size: 0 }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.devices.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, inode)| inode.clone())
+            .ok_or(FsError::NotFound)
+    }
+    fn create(&self, _name: &str, _kind: InodeType) -> Result<Arc<dyn Inode>> {
+        Err(FsError::Unsupported)
+    }
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+    fn entries(&self) -> Result<Vec<DirEntry>> {
+        Ok(self.devices.iter()
+            .map(|(name, inode)| DirEntry { name: name.to_string(), kind: inode.metadata().kind })
+            .collect())
+    }
+}
+
+// 控制台设备。读取时返回已经收到的所有输入；还没有输入时返回WouldBlock，由调度器让进程等待
+struct ConsoleDevice;
+
+impl Inode for ConsoleDevice {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: 2, kind: InodeType::CharDevice, size: 0 }
+    }
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            match crate::console::read_byte() {
+                Some(byte) => {
+                    buf[n] = byte;
+                    n += 1;
+                },
+                None => break,
+            }
+        }
+        if n == 0 && !buf.is_empty() {
+            return Err(FsError::WouldBlock);
+        }
+        Ok(n)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        crate::console::write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+struct NullDevice;
+
+impl Inode for NullDevice {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: 3, kind: InodeType::CharDevice, size: 0 }
+    }
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
@@ -0,0 +1,929 @@
+//! FAT32文件系统
+//!
+//! 支持长文件名、创建和删除目录，写入时按需分配簇链。
+//! 所有读写都经过扇区缓存，调用sync以后修改才写回磁盘。
+//! 还没有实时时钟，新建的目录项使用固定的日期。
+
+use super::{block_cache::BlockCache, DirEntry, FileSystem, FsError, Inode, InodeType, Metadata, Result, MAX_NAME_LEN};
+use crate::{drivers::{virtio_blk::SECTOR_SIZE, BlockDevice}, mm};
+use alloc::{collections::BTreeMap, format, string::String, sync::{Arc, Weak}, vec, vec::Vec};
+use spin::Mutex;
+
+// 扇区缓存的大小
+const CACHE_SECTORS: usize = 64;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ATTR_LONG_NAME_MASK: u8 = 0x3f;
+
+const DIRENT_SIZE: usize = 32;
+const LFN_CHARS: usize = 13;
+const LFN_LAST: u8 = 0x40;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+const ENTRY_KANJI_E5: u8 = 0x05; // 短文件名第一个字节真的是0xe5时，保存为0x05
+
+// 短文件名的主文件名和扩展名是小写（Windows NT的约定）
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+const FAT_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0fff_fff7;
+const FAT_EOC: u32 = 0x0fff_ffff;
+
+// 2021年9月1日
+const FIXED_DATE: u16 = ((2021 - 1980) << 9) | (9 << 5) | 1;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+
+// 由引导扇区中的BIOS参数块得到的布局信息
+#[derive(Debug)]
+struct Geometry {
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    num_fats: u64,
+    data_start: u64,
+    // 数据区的簇数，簇号从2开始
+    cluster_count: u32,
+    root_cluster: u32,
+    fsinfo_sector: u64,
+}
+
+impl Geometry {
+    fn parse(bs: &[u8; SECTOR_SIZE]) -> Result<Geometry> {
+        if bs[510] != 0x55 || bs[511] != 0xaa {
+            return Err(FsError::InvalidInput);
+        }
+        let bytes_per_sector = le16(bs, 11) as usize;
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved_sectors = le16(bs, 14) as u64;
+        let num_fats = bs[16] as u64;
+        let root_entries = le16(bs, 17);
+        let total_sectors_16 = le16(bs, 19) as u64;
+        let fat_size_16 = le16(bs, 22);
+        let total_sectors_32 = le32(bs, 32) as u64;
+        let fat_sectors = le32(bs, 36) as u64;
+        let root_cluster = le32(bs, 44);
+        let fsinfo_sector = le16(bs, 48) as u64;
+        // FAT32的根目录项数和16位的FAT大小都是0
+        if bytes_per_sector != SECTOR_SIZE || root_entries != 0 || fat_size_16 != 0
+            || !sectors_per_cluster.is_power_of_two() || num_fats == 0 || fat_sectors == 0 {
+            return Err(FsError::InvalidInput);
+        }
+        let total_sectors = if total_sectors_16 != 0 { total_sectors_16 } else { total_sectors_32 };
+        let data_start = reserved_sectors + num_fats * fat_sectors;
+        if total_sectors <= data_start {
+            return Err(FsError::InvalidInput);
+        }
+        let cluster_count = ((total_sectors - data_start) / sectors_per_cluster) as u32;
+        // FAT表能记录的簇数也是上限
+        let cluster_count = cluster_count.min((fat_sectors * SECTOR_SIZE as u64 / 4 - 2) as u32);
+        if root_cluster < 2 || root_cluster >= cluster_count + 2 {
+            return Err(FsError::InvalidInput);
+        }
+        Ok(Geometry {
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            num_fats,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fsinfo_sector,
+        })
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+}
+
+struct FatShared {
+    cache: BlockCache,
+    geo: Geometry,
+    // 下一次从这个簇号开始查找空闲簇
+    next_free: Mutex<u32>,
+    // 文件系统的所有操作串行执行
+    lock: Mutex<()>,
+    // 正在使用的节点，按节点编号（目录项的位置）索引。同一个目录项只有一个节点，
+    // 否则通过大小写不同的名字打开同一个文件时，各自的簇链和大小会互相覆盖
+    inodes: Mutex<BTreeMap<usize, Weak<FatInode>>>,
+}
+
+impl FatShared {
+    fn fat_entry(&self, cluster: u32) -> Result<u32> {
+        let pos = cluster as u64 * 4;
+        let sector = self.geo.fat_start + pos / SECTOR_SIZE as u64;
+        let offset = (pos % SECTOR_SIZE as u64) as usize;
+        self.cache.with_sector(sector, |data| le32(data, offset) & FAT_MASK)
+    }
+
+    // 修改所有FAT表副本中的表项，保留高4位
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<()> {
+        let pos = cluster as u64 * 4;
+        for i in 0..self.geo.num_fats {
+            let sector = self.geo.fat_start + i * self.geo.fat_sectors + pos / SECTOR_SIZE as u64;
+            let offset = (pos % SECTOR_SIZE as u64) as usize;
+            self.cache.with_sector_mut(sector, |data| {
+                let old = le32(data, offset);
+                let new = (old & !FAT_MASK) | (value & FAT_MASK);
+                data[offset..offset + 4].copy_from_slice(&new.to_le_bytes());
+            })?;
+        }
+        Ok(())
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.geo.cluster_count + 2
+    }
+
+    // 从first开始的簇链。first为0表示没有分配簇，返回空的簇链
+    fn chain(&self, first: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cur = first;
+        while cur != FAT_FREE {
+            if !self.is_valid_cluster(cur) || chain.len() > self.geo.cluster_count as usize {
+                return Err(FsError::IoError); // 簇链损坏，或者出现了环
+            }
+            chain.push(cur);
+            cur = match self.fat_entry(cur)? {
+                next if next >= FAT_BAD => break,
+                next => next,
+            };
+        }
+        Ok(chain)
+    }
+
+    // 分配一个清零的簇，接在prev的后面
+    fn alloc_cluster(&self, prev: Option<u32>) -> Result<u32> {
+        let mut next_free = self.next_free.lock();
+        let total = self.geo.cluster_count;
+        let start = if self.is_valid_cluster(*next_free) { *next_free } else { 2 };
+        let mut found = None;
+        for i in 0..total {
+            let cluster = 2 + (start - 2 + i) % total;
+            if self.fat_entry(cluster)? == FAT_FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+        self.set_fat_entry(cluster, FAT_EOC)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        *next_free = cluster + 1;
+        drop(next_free);
+        let first_sector = self.geo.cluster_sector(cluster);
+        for sector in first_sector..first_sector + self.geo.sectors_per_cluster {
+            self.cache.with_sector_mut(sector, |data| data.fill(0))?;
+        }
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, first: u32) -> Result<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, FAT_FREE)?;
+        }
+        Ok(())
+    }
+
+    // 按扇区切分簇链中[offset, offset + len)的部分，依次调用f(扇区号, 扇区内偏移, 在区间中的位置, 长度)
+    fn for_each_piece(
+        &self, chain: &[u32], offset: usize, len: usize,
+        mut f: impl FnMut(u64, usize, usize, usize) -> Result<()>
+    ) -> Result<()> {
+        let cluster_bytes = self.geo.cluster_bytes();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let cluster = *chain.get(pos / cluster_bytes).ok_or(FsError::IoError)?;
+            let in_cluster = pos % cluster_bytes;
+            let sector = self.geo.cluster_sector(cluster) + (in_cluster / SECTOR_SIZE) as u64;
+            let in_sector = in_cluster % SECTOR_SIZE;
+            let n = (len - done).min(SECTOR_SIZE - in_sector);
+            f(sector, in_sector, done, n)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn read_chain(&self, chain: &[u32], offset: usize, buf: &mut [u8]) -> Result<()> {
+        self.for_each_piece(chain, offset, buf.len(), |sector, in_sector, done, n| {
+            self.cache.read(sector, in_sector, &mut buf[done..done + n])
+        })
+    }
+
+    fn write_chain(&self, chain: &[u32], offset: usize, data: &[u8]) -> Result<()> {
+        self.for_each_piece(chain, offset, data.len(), |sector, in_sector, done, n| {
+            self.cache.write(sector, in_sector, &data[done..done + n])
+        })
+    }
+
+    fn zero_chain(&self, chain: &[u32], offset: usize, len: usize) -> Result<()> {
+        self.for_each_piece(chain, offset, len, |sector, in_sector, _, n| {
+            self.cache.with_sector_mut(sector, |data| data[in_sector..in_sector + n].fill(0))
+        })
+    }
+
+    // 目录项在磁盘上的位置，作为节点编号
+    fn dirent_ino(&self, dir_chain: &[u32], offset: usize) -> usize {
+        let cluster_bytes = self.geo.cluster_bytes();
+        let sector = self.geo.cluster_sector(dir_chain[offset / cluster_bytes]) + ((offset % cluster_bytes) / SECTOR_SIZE) as u64;
+        sector as usize * (SECTOR_SIZE / DIRENT_SIZE) + (offset % SECTOR_SIZE) / DIRENT_SIZE
+    }
+
+    // 读取目录中所有有效的目录项，包括"."和".."
+    fn read_dir(&self, dir_chain: &[u32]) -> Result<Vec<RawDirEntry>> {
+        let mut raw = vec![0u8; dir_chain.len() * self.geo.cluster_bytes()];
+        self.read_chain(dir_chain, 0, &mut raw)?;
+        let mut ans = Vec::new();
+        let mut lfn = LfnCollector::new();
+        for (idx, e) in raw.chunks_exact(DIRENT_SIZE).enumerate() {
+            let offset = idx * DIRENT_SIZE;
+            match e[0] {
+                ENTRY_END => break,
+                ENTRY_DELETED => { lfn.reset(); continue },
+                _ => {},
+            }
+            let attr = e[11];
+            if attr & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+                lfn.push(offset, e);
+                continue;
+            }
+            if attr & ATTR_VOLUME_ID != 0 {
+                lfn.reset();
+                continue;
+            }
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&e[0..11]);
+            if short_name[0] == ENTRY_KANJI_E5 {
+                short_name[0] = ENTRY_DELETED;
+            }
+            let (name, lfn_offset) = match lfn.finish(&short_name) {
+                Some((name, lfn_offset)) => (name, lfn_offset),
+                None => (short_display_name(&short_name, e[12]), offset),
+            };
+            ans.push(RawDirEntry {
+                name,
+                short_name,
+                attr,
+                first_cluster: ((le16(e, 20) as u32) << 16) | le16(e, 26) as u32,
+                size: le32(e, 28),
+                offset,
+                lfn_offset,
+            });
+        }
+        Ok(ans)
+    }
+
+    // 在目录中找到连续的空闲位置写入目录项，不够时扩展目录。返回最后一个目录项的偏移
+    fn add_dir_entries(&self, dir_first_cluster: u32, entries: &[[u8; DIRENT_SIZE]]) -> Result<usize> {
+        loop {
+            let chain = self.chain(dir_first_cluster)?;
+            let mut raw = vec![0u8; chain.len() * self.geo.cluster_bytes()];
+            self.read_chain(&chain, 0, &mut raw)?;
+            let mut run_start = 0;
+            let mut run_len = 0;
+            let mut found = None;
+            for (idx, e) in raw.chunks_exact(DIRENT_SIZE).enumerate() {
+                if e[0] == ENTRY_END {
+                    // 结束标记之后都是空闲的
+                    let free_after = raw.len() / DIRENT_SIZE - idx;
+                    if run_len == 0 {
+                        run_start = idx;
+                    }
+                    if run_len + free_after >= entries.len() {
+                        found = Some(run_start);
+                    }
+                    break;
+                }
+                if e[0] == ENTRY_DELETED {
+                    if run_len == 0 {
+                        run_start = idx;
+                    }
+                    run_len += 1;
+                    if run_len >= entries.len() {
+                        found = Some(run_start);
+                        break;
+                    }
+                } else {
+                    run_len = 0;
+                }
+            }
+            if let Some(start) = found {
+                for (i, entry) in entries.iter().enumerate() {
+                    self.write_chain(&chain, (start + i) * DIRENT_SIZE, entry)?;
+                }
+                return Ok((start + entries.len() - 1) * DIRENT_SIZE);
+            }
+            self.alloc_cluster(chain.last().copied())?;
+        }
+    }
+
+    // 修改目录项中的首簇号和文件大小
+    fn update_dirent(&self, location: (u32, usize), first_cluster: u32, size: u32) -> Result<()> {
+        let (dir_first_cluster, offset) = location;
+        let chain = self.chain(dir_first_cluster)?;
+        self.write_chain(&chain, offset + 20, &((first_cluster >> 16) as u16).to_le_bytes())?;
+        self.write_chain(&chain, offset + 26, &(first_cluster as u16).to_le_bytes())?;
+        self.write_chain(&chain, offset + 28, &size.to_le_bytes())
+    }
+}
+
+// 目录中的一个有效目录项
+struct RawDirEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    size: u32,
+    // 短目录项的偏移
+    offset: usize,
+    // 第一个长文件名目录项的偏移；没有长文件名时等于offset
+    lfn_offset: usize,
+}
+
+impl RawDirEntry {
+    fn is_dot(&self) -> bool {
+        &self.short_name == b".          " || &self.short_name == b"..         "
+    }
+    fn kind(&self) -> InodeType {
+        if self.attr & ATTR_DIRECTORY != 0 { InodeType::Directory } else { InodeType::File }
+    }
+}
+
+// 收集短目录项前面的长文件名目录项。长文件名目录项按序号从大到小排列，最后一个序号为1
+struct LfnCollector {
+    units: Vec<u16>,
+    expected: u8,
+    checksum: u8,
+    start: usize,
+    valid: bool,
+}
+
+impl LfnCollector {
+    fn new() -> Self {
+        LfnCollector { units: Vec::new(), expected: 0, checksum: 0, start: 0, valid: false }
+    }
+
+    fn reset(&mut self) {
+        self.valid = false;
+    }
+
+    fn push(&mut self, offset: usize, e: &[u8]) {
+        let ord = e[0] & 0x1f;
+        if e[0] & LFN_LAST != 0 {
+            self.units.clear();
+            self.units.resize(ord as usize * LFN_CHARS, 0);
+            self.expected = ord;
+            self.checksum = e[13];
+            self.start = offset;
+            self.valid = ord != 0;
+        } else if !self.valid || ord + 1 != self.expected || e[13] != self.checksum {
+            self.valid = false;
+            return;
+        }
+        if !self.valid {
+            return;
+        }
+        self.expected = ord;
+        let base = (ord as usize - 1) * LFN_CHARS;
+        for (i, &pos) in LFN_POSITIONS.iter().enumerate() {
+            self.units[base + i] = le16(e, pos);
+        }
+    }
+
+    // 遇到短目录项时调用。长文件名完整并且校验和正确时，返回长文件名和它的起始偏移
+    fn finish(&mut self, short_name: &[u8; 11]) -> Option<(String, usize)> {
+        let valid = self.valid && self.expected == 1 && self.checksum == lfn_checksum(short_name);
+        self.valid = false;
+        if !valid {
+            return None;
+        }
+        let len = self.units.iter().position(|&c| c == 0).unwrap_or(self.units.len());
+        let name = core::char::decode_utf16(self.units[..len].iter().copied())
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some((name, self.start))
+    }
+}
+
+// 长文件名目录项中，13个UTF-16字符各自的位置
+const LFN_POSITIONS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+// 没有长文件名时显示的名字，比如"README.TXT"
+fn short_display_name(short_name: &[u8; 11], ntres: u8) -> String {
+    let trim = |s: &[u8]| {
+        let len = s.iter().rposition(|&b| b != b' ').map(|p| p + 1).unwrap_or(0);
+        String::from_utf8_lossy(&s[..len]).into_owned()
+    };
+    let mut base = trim(&short_name[0..8]);
+    let mut ext = trim(&short_name[8..11]);
+    if ntres & NTRES_LOWER_BASE != 0 {
+        base.make_ascii_lowercase();
+    }
+    if ntres & NTRES_LOWER_EXT != 0 {
+        ext.make_ascii_lowercase();
+    }
+    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c)
+}
+
+// 名字本身就是合法的大写8.3短文件名时，可以不使用长文件名
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.find('.') {
+        Some(idx) => (&name[..idx], &name[idx + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3
+        || !base.bytes().chain(ext.bytes()).all(is_short_name_char) {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+// 为长文件名生成不重复的短文件名，比如"LONGFI~1.TXT"
+fn generate_short_name(name: &str, existing: &[[u8; 11]]) -> Result<[u8; 11]> {
+    let convert = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| match c.to_ascii_uppercase() {
+                c if c.is_ascii() && is_short_name_char(c as u8) => c as u8,
+                _ => b'_',
+            })
+            .collect()
+    };
+    let (base, ext) = match name.rfind('.') {
+        Some(idx) if idx > 0 => (convert(&name[..idx]), convert(&name[idx + 1..])),
+        _ => (convert(name), Vec::new()),
+    };
+    let ext = &ext[..ext.len().min(3)];
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        let mut short = [b' '; 11];
+        short[..keep].copy_from_slice(&base[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short[8..8 + ext.len()].copy_from_slice(ext);
+        if !existing.contains(&short) {
+            return Ok(short);
+        }
+    }
+    Err(FsError::NoSpace)
+}
+
+// 长文件名目录项，按在目录中的顺序排列
+fn lfn_entries(name: &str, checksum: u8) -> Vec<[u8; DIRENT_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LFN_CHARS - 1) / LFN_CHARS;
+    if units.len() % LFN_CHARS != 0 {
+        units.push(0); // 结束符，后面用0xffff填充
+        units.resize(count * LFN_CHARS, 0xffff);
+    }
+    (0..count).rev().map(|i| {
+        let mut e = [0u8; DIRENT_SIZE];
+        e[0] = (i + 1) as u8 | if i + 1 == count { LFN_LAST } else { 0 };
+        e[11] = ATTR_LONG_NAME;
+        e[13] = checksum;
+        for (j, &pos) in LFN_POSITIONS.iter().enumerate() {
+            e[pos..pos + 2].copy_from_slice(&units[i * LFN_CHARS + j].to_le_bytes());
+        }
+        e
+    }).collect()
+}
+
+fn short_entry(short_name: &[u8; 11], attr: u8, first_cluster: u32, size: u32) -> [u8; DIRENT_SIZE] {
+    let mut e = [0u8; DIRENT_SIZE];
+    e[0..11].copy_from_slice(short_name);
+    if e[0] == ENTRY_DELETED {
+        e[0] = ENTRY_KANJI_E5;
+    }
+    e[11] = attr;
+    e[16..18].copy_from_slice(&FIXED_DATE.to_le_bytes()); // 创建日期
+    e[18..20].copy_from_slice(&FIXED_DATE.to_le_bytes()); // 访问日期
+    e[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    e[24..26].copy_from_slice(&FIXED_DATE.to_le_bytes()); // 修改日期
+    e[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    e[28..32].copy_from_slice(&size.to_le_bytes());
+    e
+}
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.encode_utf16().count() > MAX_NAME_LEN
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c)) {
+        return Err(FsError::InvalidInput);
+    }
+    Ok(())
+}
+
+struct NodeState {
+    first_cluster: u32,
+    size: usize,
+    // 目录项已经删除，但节点还在被打开的文件使用。最后一个引用释放时才释放簇链
+    unlinked: bool,
+}
+
+// 节点的状态从这个缓存中分配，FatInode本身只记录不变的部分
+static NODE_STATES: mm::SlabCache<Mutex<NodeState>> = mm::SlabCache::new("fat_inode");
+
+impl NodeState {
+    fn alloc(first_cluster: u32, size: usize) -> Result<mm::SlabBox<Mutex<NodeState>>> {
+        NODE_STATES.alloc(Mutex::new(NodeState { first_cluster, size, unlinked: false })).map_err(|_| FsError::NoSpace)
+    }
+}
+
+struct FatInode {
+    fs: Arc<FatShared>,
+    kind: InodeType,
+    ino: usize,
+    // 目录项的位置：所在目录的首簇号，以及短目录项在目录中的偏移。根目录没有目录项
+    location: Option<(u32, usize)>,
+    state: mm::SlabBox<Mutex<NodeState>>,
+}
+
+impl FatInode {
+    fn from_raw(fs: &Arc<FatShared>, dir_first_cluster: u32, dir_chain: &[u32], raw: &RawDirEntry) -> Result<Arc<FatInode>> {
+        let ino = fs.dirent_ino(dir_chain, raw.offset);
+        FatInode::for_dirent(fs, raw.kind(), ino, (dir_first_cluster, raw.offset), raw.first_cluster, raw.size as usize)
+    }
+
+    // 目录项对应的节点。已经有节点在使用时返回它，否则创建新的节点
+    fn for_dirent(
+        fs: &Arc<FatShared>, kind: InodeType, ino: usize, location: (u32, usize), first_cluster: u32, size: usize
+    ) -> Result<Arc<FatInode>> {
+        let mut inodes = fs.inodes.lock();
+        if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(inode);
+        }
+        let inode = Arc::new(FatInode {
+            fs: fs.clone(),
+            kind,
+            ino,
+            location: Some(location),
+            state: NodeState::alloc(first_cluster, size)?,
+        });
+        inodes.insert(ino, Arc::downgrade(&inode));
+        Ok(inode)
+    }
+
+    fn check_dir(&self) -> Result<()> {
+        if self.kind == InodeType::Directory { Ok(()) } else { Err(FsError::NotADirectory) }
+    }
+
+    fn update_dirent(&self, state: &NodeState) -> Result<()> {
+        match self.location {
+            // 删除以后，目录项的位置可能已经给了别的文件
+            Some(_) if state.unlinked => Ok(()),
+            Some(location) => {
+                let size = if self.kind == InodeType::Directory { 0 } else { state.size as u32 };
+                self.fs.update_dirent(location, state.first_cluster, size)
+            },
+            None => Ok(()),
+        }
+    }
+
+    // 保证簇链能容纳len字节，返回簇链
+    fn reserve(&self, state: &mut NodeState, len: usize) -> Result<Vec<u32>> {
+        let mut chain = self.fs.chain(state.first_cluster)?;
+        let cluster_bytes = self.fs.geo.cluster_bytes();
+        while chain.len() * cluster_bytes < len {
+            let cluster = self.fs.alloc_cluster(chain.last().copied())?;
+            if chain.is_empty() {
+                state.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    fn write_locked(&self, state: &mut NodeState, offset: usize, data: &[u8]) -> Result<usize> {
+        let end = offset.checked_add(data.len()).ok_or(FsError::InvalidInput)?;
+        if end > u32::MAX as usize {
+            return Err(FsError::NoSpace); // FAT32的文件不能超过4GiB
+        }
+        let chain = self.reserve(state, end)?;
+        if offset > state.size {
+            // 在文件末尾之后写入，中间的部分填0
+            self.fs.zero_chain(&chain, state.size, offset - state.size)?;
+        }
+        self.fs.write_chain(&chain, offset, data)?;
+        state.size = state.size.max(end);
+        self.update_dirent(state)?;
+        Ok(data.len())
+    }
+
+    fn dir_entries(&self) -> Result<(u32, Vec<u32>, Vec<RawDirEntry>)> {
+        let first_cluster = self.state.lock().first_cluster;
+        let chain = self.fs.chain(first_cluster)?;
+        let entries = self.fs.read_dir(&chain)?;
+        Ok((first_cluster, chain, entries))
+    }
+}
+
+impl Inode for FatInode {
+    fn metadata(&self) -> Metadata {
+        let size = if self.kind == InodeType::File { self.state.lock().size } else { 0 };
+        Metadata { ino: self.ino, kind: self.kind, size }
+    }
+
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if self.kind == InodeType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let _guard = self.fs.lock.lock();
+        let state = self.state.lock();
+        if offset >= state.size {
+            return Ok(0);
+        }
+        let n = buf.len().min(state.size - offset);
+        let chain = self.fs.chain(state.first_cluster)?;
+        self.fs.read_chain(&chain, offset, &mut buf[..n])?;
+        Ok(n)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.kind == InodeType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let _guard = self.fs.lock.lock();
+        let mut state = self.state.lock();
+        self.write_locked(&mut state, offset, buf)
+    }
+
+    fn truncate(&self, size: usize) -> Result<()> {
+        if self.kind == InodeType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let _guard = self.fs.lock.lock();
+        let mut state = self.state.lock();
+        if size > state.size {
+            let chain = self.reserve(&mut state, size)?;
+            self.fs.zero_chain(&chain, state.size, size - state.size)?;
+        } else {
+            let cluster_bytes = self.fs.geo.cluster_bytes();
+            let keep = (size + cluster_bytes - 1) / cluster_bytes;
+            let chain = self.fs.chain(state.first_cluster)?;
+            if keep == 0 {
+                self.fs.free_chain(state.first_cluster)?;
+                state.first_cluster = 0;
+            } else if keep < chain.len() {
+                self.fs.set_fat_entry(chain[keep - 1], FAT_EOC)?;
+                self.fs.free_chain(chain[keep])?;
+            }
+        }
+        state.size = size;
+        self.update_dirent(&state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.check_dir()?;
+        let _guard = self.fs.lock.lock();
+        let (first_cluster, chain, entries) = self.dir_entries()?;
+        let raw = entries.iter()
+            .filter(|e| !e.is_dot())
+            .find(|e| e.name.eq_ignore_ascii_case(name)) // FAT的文件名不区分大小写
+            .ok_or(FsError::NotFound)?;
+        Ok(FatInode::from_raw(&self.fs, first_cluster, &chain, raw)?)
+    }
+
+    fn create(&self, name: &str, kind: InodeType) -> Result<Arc<dyn Inode>> {
+        self.check_dir()?;
+        check_name(name)?;
+        let _guard = self.fs.lock.lock();
+        if self.state.lock().unlinked {
+            return Err(FsError::NotFound); // 目录已经删除
+        }
+        let (first_cluster, _, entries) = self.dir_entries()?;
+        if entries.iter().any(|e| !e.is_dot() && e.name.eq_ignore_ascii_case(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (attr, child_cluster) = match kind {
+            InodeType::File => (ATTR_ARCHIVE, 0),
+            InodeType::Directory => (ATTR_DIRECTORY, self.fs.alloc_cluster(None)?),
+            _ => return Err(FsError::Unsupported),
+        };
+        if kind == InodeType::Directory {
+            // 子目录的".."指向父目录，父目录是根目录时为0
+            let parent_cluster = if self.location.is_none() { 0 } else { first_cluster };
+            let dot = short_entry(b".          ", ATTR_DIRECTORY, child_cluster, 0);
+            let dotdot = short_entry(b"..         ", ATTR_DIRECTORY, parent_cluster, 0);
+            let chain = [child_cluster];
+            self.fs.write_chain(&chain, 0, &dot)?;
+            self.fs.write_chain(&chain, DIRENT_SIZE, &dotdot)?;
+        }
+        let mut dirents = Vec::new();
+        let short_name = match exact_short_name(name) {
+            Some(short_name) => short_name,
+            None => {
+                let existing: Vec<[u8; 11]> = entries.iter().map(|e| e.short_name).collect();
+                let short_name = generate_short_name(name, &existing)?;
+                dirents.extend(lfn_entries(name, lfn_checksum(&short_name)));
+                short_name
+            },
+        };
+        dirents.push(short_entry(&short_name, attr, child_cluster, 0));
+        let offset = match self.fs.add_dir_entries(first_cluster, &dirents) {
+            Ok(offset) => offset,
+            Err(e) => {
+                if child_cluster != 0 {
+                    self.fs.free_chain(child_cluster)?;
+                }
+                return Err(e);
+            },
+        };
+        let chain = self.fs.chain(first_cluster)?; // 目录可能扩展了
+        let ino = self.fs.dirent_ino(&chain, offset);
+        Ok(FatInode::for_dirent(&self.fs, kind, ino, (first_cluster, offset), child_cluster, 0)?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.check_dir()?;
+        // 在文件系统的锁释放以后才丢弃，节点的drop也要获取这个锁
+        let in_use;
+        let _guard = self.fs.lock.lock();
+        let (_, chain, entries) = self.dir_entries()?;
+        let raw = entries.iter()
+            .filter(|e| !e.is_dot())
+            .find(|e| e.name.eq_ignore_ascii_case(name))
+            .ok_or(FsError::NotFound)?;
+        if raw.kind() == InodeType::Directory {
+            let child_chain = self.fs.chain(raw.first_cluster)?;
+            if self.fs.read_dir(&child_chain)?.iter().any(|e| !e.is_dot()) {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        // 之后在这个位置新建的文件使用新的节点
+        in_use = self.fs.inodes.lock().remove(&self.fs.dirent_ino(&chain, raw.offset)).and_then(|w| w.upgrade());
+        match &in_use {
+            Some(inode) => inode.state.lock().unlinked = true,
+            None => self.fs.free_chain(raw.first_cluster)?,
+        }
+        for offset in (raw.lfn_offset..=raw.offset).step_by(DIRENT_SIZE) {
+            self.fs.write_chain(&chain, offset, &[ENTRY_DELETED])?;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Result<Vec<DirEntry>> {
+        self.check_dir()?;
+        let _guard = self.fs.lock.lock();
+        let (_, _, entries) = self.dir_entries()?;
+        Ok(entries.into_iter()
+            .filter(|e| !e.is_dot())
+            .map(|e| DirEntry { kind: e.kind(), name: e.name })
+            .collect())
+    }
+
+    fn cache_name(&self, name: &str) -> String {
+        name.to_ascii_lowercase()
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let (unlinked, first_cluster) = {
+            let state = self.state.lock();
+            (state.unlinked, state.first_cluster)
+        };
+        if unlinked {
+            let _guard = self.fs.lock.lock();
+            if let Err(e) = self.fs.free_chain(first_cluster) {
+                error!("Failed to free clusters of a removed file: {:?}", e);
+            }
+        }
+        // 删除以后同一个位置可能已经有了新的节点
+        let mut inodes = self.fs.inodes.lock();
+        if inodes.get(&self.ino).map_or(false, |w| w.strong_count() == 0) {
+            inodes.remove(&self.ino);
+        }
+    }
+}
+
+pub struct Fat32 {
+    shared: Arc<FatShared>,
+    root: Arc<FatInode>,
+}
+
+impl Fat32 {
+    // 读取块设备上的FAT32文件系统
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Arc<Fat32>> {
+        let cache = BlockCache::new(device, CACHE_SECTORS);
+        let geo = cache.with_sector(0, |bs| Geometry::parse(bs))??;
+        // 文件系统的空闲簇信息在修改以后就不准确了，标记为未知，让其它系统重新统计
+        if geo.fsinfo_sector != 0 && geo.fsinfo_sector < geo.fat_start {
+            cache.with_sector_mut(geo.fsinfo_sector, |data| {
+                if le32(data, 0) == FSINFO_LEAD_SIG && le32(data, 484) == FSINFO_STRUCT_SIG {
+                    data[488..496].fill(0xff);
+                }
+            })?;
+        }
+        let root_cluster = geo.root_cluster;
+        let shared = Arc::new(FatShared {
+            cache, geo, next_free: Mutex::new(2), lock: Mutex::new(()), inodes: Mutex::new(BTreeMap::new())
+        });
+        let root = Arc::new(FatInode {
+            fs: shared.clone(),
+            kind: InodeType::Directory,
+            ino: 1,
+            location: None,
+            state: NodeState::alloc(root_cluster, 0)?,
+        });
+        Ok(Arc::new(Fat32 { shared, root }))
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &'static str {
+        "fat32"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+    fn sync(&self) -> Result<()> {
+        let _guard = self.shared.lock.lock();
+        self.shared.cache.sync()
+    }
+}
+
+fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+// 在挂载的FAT32文件系统上创建、读写和删除文件与目录，结束时不留下任何内容
+pub(crate) fn test_fat32(mount_point: &str) {
+    use super::{OpenFlags, SeekFrom};
+    let dir = format!("{}/kernel-fat32-test", mount_point.trim_end_matches('/'));
+    let path = format!("{}/A file with a long name.txt", dir);
+    super::mkdir(&dir).expect("create test directory");
+    let file = super::open(&path, OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE)
+        .expect("create test file");
+    let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect(); // 跨越多个扇区
+    assert_eq!(file.write(&data), Ok(data.len()));
+    assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+    let mut buf = vec![0u8; 4096];
+    assert_eq!(file.read(&mut buf), Ok(data.len()));
+    assert_eq!(&buf[..data.len()], &data[..]);
+    let names: Vec<String> = super::lookup(&dir).unwrap().inode().entries().unwrap()
+        .into_iter().map(|e| e.name).collect();
+    assert_eq!(names, ["A file with a long name.txt"]);
+    assert_eq!(super::unlink(&dir), Err(FsError::DirectoryNotEmpty));
+    file.inode().truncate(100).expect("truncate test file");
+    assert_eq!(file.stat().size, 100);
+    drop(file);
+    super::unlink(&path).expect("remove test file");
+    // 大小写不同的名字是同一个文件
+    let old_path = format!("{}/Old.txt", dir);
+    let old = super::open(&old_path, OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE).expect("create old file");
+    assert_eq!(old.write(&data), Ok(data.len()));
+    let upper = super::open(&format!("{}/OLD.TXT", dir), OpenFlags::READ).expect("open with another case");
+    assert_eq!(upper.stat().ino, old.stat().ino);
+    assert_eq!(upper.stat().size, data.len() as u64);
+    drop(upper);
+    // 删除打开着的文件以后，新建的文件可能用到它的目录项，但不能用到它的簇
+    super::unlink(&old_path).expect("remove open file");
+    let new = super::open(&old_path, OpenFlags::READ | OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE)
+        .expect("create file with the removed name");
+    assert_eq!(new.write(b"new file"), Ok(8));
+    assert_eq!(old.write(&data), Ok(data.len()));
+    assert_eq!(old.seek(SeekFrom::Start(0)), Ok(0));
+    let mut buf = vec![0u8; 2 * data.len()];
+    assert_eq!(old.read(&mut buf), Ok(2 * data.len()));
+    assert!(buf.chunks(data.len()).all(|chunk| chunk == &data[..]), "removed file changed");
+    assert_eq!(new.seek(SeekFrom::Start(0)), Ok(0));
+    assert_eq!(new.read(&mut buf), Ok(8));
+    assert_eq!(&buf[..8], b"new file");
+    assert_eq!(new.stat().size, 8);
+    drop(old);
+    drop(new);
+    super::unlink(&old_path).expect("remove new file");
+    super::unlink(&dir).expect("remove test directory");
+    assert_eq!(super::lookup(&dir).err(), Some(FsError::NotFound));
+    println!("[kernel-fat32-test] FAT32 file system test passed");
+}
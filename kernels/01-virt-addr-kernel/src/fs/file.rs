@@ -0,0 +1,229 @@
+//! 打开的文件和文件描述符表
+
+use super::{Dentry, FsError, Inode, InodeType, Result};
+use crate::mm::SlabArc;
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+bitflags::bitflags! {
+    // 打开文件的方式，和tornado-std中的定义保持一致
+    pub struct OpenFlags: u32 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const APPEND = 1 << 2;
+        const CREATE = 1 << 3;
+        const TRUNCATE = 1 << 4;
+        const EXCLUSIVE = 1 << 5;
+        const DIRECTORY = 1 << 6;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+// fstat返回给用户的文件信息，和tornado-std中的定义保持一致
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct FileStat {
+    pub ino: u64,
+    pub size: u64,
+    pub kind: u32,
+    pub _reserved: u32,
+}
+
+// 一个打开的文件。dup2复制的文件描述符共享同一个File，也就共享读写位置
+pub struct File {
+    dentry: SlabArc<Dentry>,
+    flags: OpenFlags,
+    offset: Mutex<usize>,
+}
+
+impl File {
+    pub fn open(dentry: SlabArc<Dentry>, flags: OpenFlags) -> Result<Arc<File>> {
+        let kind = dentry.inode().metadata().kind;
+        if kind == InodeType::Directory {
+            if flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::TRUNCATE) {
+                return Err(FsError::IsADirectory);
+            }
+        } else if flags.contains(OpenFlags::DIRECTORY) {
+            return Err(FsError::NotADirectory);
+        }
+        if flags.contains(OpenFlags::TRUNCATE) && kind == InodeType::File {
+            dentry.inode().truncate(0)?;
+        }
+        Ok(Arc::new(File { dentry, flags, offset: Mutex::new(0) }))
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        self.dentry.inode()
+    }
+
+    pub fn dentry(&self) -> &SlabArc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadFileDescriptor);
+        }
+        if self.inode().metadata().kind == InodeType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let mut offset = self.offset.lock();
+        let n = self.inode().read_at(*offset, buf)?;
+        *offset += n;
+        Ok(n)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        if !self.flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.inode().metadata().size;
+        }
+        let n = self.inode().write_at(*offset, buf)?;
+        *offset += n;
+        Ok(n)
+    }
+
+    // 移动读写位置，返回新的位置
+    pub fn seek(&self, pos: SeekFrom) -> Result<usize> {
+        let metadata = self.inode().metadata();
+        if metadata.kind == InodeType::CharDevice || metadata.kind == InodeType::Fifo {
+            return Err(FsError::NotSeekable);
+        }
+        let mut offset = self.offset.lock();
+        let new_offset = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => checked_add_signed(*offset, n),
+            SeekFrom::End(n) => checked_add_signed(metadata.size, n),
+        };
+        *offset = new_offset.ok_or(FsError::InvalidInput)?;
+        Ok(*offset)
+    }
+
+    pub fn stat(&self) -> FileStat {
+        let metadata = self.inode().metadata();
+        FileStat {
+            ino: metadata.ino as u64,
+            size: metadata.size as u64,
+            kind: metadata.kind as u32,
+            _reserved: 0,
+        }
+    }
+
+    // 读取目录项，按getdents的格式写到buf中，返回写入的字节数；读完所有目录项以后返回0。
+    // 每条记录的格式是：记录长度（u16，小端序），类型（u8），名字长度（u8），名字；记录按4字节对齐
+    pub fn getdents(&self, buf: &mut [u8]) -> Result<usize> {
+        if self.inode().metadata().kind != InodeType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let entries = self.inode().entries()?;
+        let mut index = self.offset.lock(); // 目录的读写位置是下一个目录项的序号
+        let mut written = 0;
+        for entry in entries.iter().skip(*index) {
+            let name = entry.name.as_bytes();
+            let rec_len = (4 + name.len() + 3) & !3;
+            if written + rec_len > buf.len() {
+                if written == 0 {
+                    return Err(FsError::InvalidInput); // 缓冲区连一条记录都放不下
+                }
+                break;
+            }
+            let rec = &mut buf[written..written + rec_len];
+            rec[0..2].copy_from_slice(&(rec_len as u16).to_le_bytes());
+            rec[2] = entry.kind as u8;
+            rec[3] = name.len() as u8;
+            rec[4..4 + name.len()].copy_from_slice(name);
+            for b in &mut rec[4 + name.len()..] {
+                *b = 0;
+            }
+            written += rec_len;
+            *index += 1;
+        }
+        Ok(written)
+    }
+}
+
+fn checked_add_signed(base: usize, delta: isize) -> Option<usize> {
+    if delta >= 0 {
+        base.checked_add(delta as usize)
+    } else {
+        base.checked_sub(delta.wrapping_neg() as usize)
+    }
+}
+
+// 每个进程最多同时打开的文件数
+const MAX_FDS: usize = 64;
+
+// 文件描述符表
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<Arc<File>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    // 标准输入、标准输出和标准错误都指向控制台设备
+    pub fn with_stdio() -> Result<Self> {
+        let console = super::open("/dev/console", OpenFlags::READ | OpenFlags::WRITE)?;
+        let mut table = FdTable::new();
+        for _ in 0..3 {
+            table.insert(console.clone())?;
+        }
+        Ok(table)
+    }
+
+    // 使用编号最小的空闲描述符
+    pub fn insert(&mut self, file: Arc<File>) -> Result<usize> {
+        if let Some(fd) = self.files.iter().position(|f| f.is_none()) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() >= MAX_FDS {
+            return Err(FsError::NoSpace);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<File>> {
+        self.files.get(fd).cloned().flatten().ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<()> {
+        match self.files.get_mut(fd) {
+            Some(slot) if slot.is_some() => {
+                *slot = None;
+                Ok(())
+            },
+            _ => Err(FsError::BadFileDescriptor),
+        }
+    }
+
+    // 让new_fd指向old_fd打开的文件；如果new_fd已经打开，先关闭它
+    pub fn dup2(&mut self, old_fd: usize, new_fd: usize) -> Result<usize> {
+        let file = self.get(old_fd)?;
+        if new_fd >= MAX_FDS {
+            return Err(FsError::BadFileDescriptor);
+        }
+        if new_fd >= self.files.len() {
+            self.files.resize(new_fd + 1, None);
+        }
+        self.files[new_fd] = Some(file);
+        Ok(new_fd)
+    }
+}
@@ -0,0 +1,108 @@
+//! 解包initramfs
+//!
+//! initramfs是cpio newc格式的归档，由xtask打包，启动时解包到根文件系统中。
+//! 每个成员是110字节的头部、文件名和文件内容，文件名和内容各自按4字节对齐；
+//! 名为"TRAILER!!!"的成员表示归档结束。只支持普通文件和目录，其它成员会被跳过
+
+use super::{FsError, InodeType, OpenFlags, Result};
+use alloc::format;
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+
+// 内存中这个位置是不是initramfs
+pub fn is_initramfs(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// 把归档中的文件和目录解包到根目录下。返回归档的总长度和解包的文件数
+pub fn unpack_initramfs(data: &[u8]) -> Result<(usize, usize)> {
+    let mut offset = 0;
+    let mut file_count = 0;
+    loop {
+        let header = data.get(offset..offset + HEADER_LEN).ok_or(FsError::InvalidInput)?;
+        if !header.starts_with(MAGIC) {
+            return Err(FsError::InvalidInput);
+        }
+        let mode = header_field(header, 1)?;
+        let file_size = header_field(header, 6)? as usize;
+        let name_size = header_field(header, 11)? as usize;
+        let name_start = offset + HEADER_LEN;
+        // 文件名的长度包括结尾的0
+        let name = data.get(name_start..name_start + name_size.saturating_sub(1)).ok_or(FsError::InvalidInput)?;
+        let name = core::str::from_utf8(name).map_err(|_| FsError::InvalidInput)?;
+        let data_start = align4(name_start + name_size);
+        let content = data.get(data_start..data_start + file_size).ok_or(FsError::InvalidInput)?;
+        offset = align4(data_start + file_size);
+        if name == TRAILER {
+            return Ok((offset, file_count));
+        }
+        let path = format!("/{}", name.trim_start_matches("./").trim_start_matches('/'));
+        match mode & MODE_TYPE_MASK {
+            _ if path == "/" || path == "/." => {}, // 归档中的根目录
+            MODE_DIRECTORY => match super::mkdir(&path) {
+                Ok(()) | Err(FsError::AlreadyExists) => {},
+                Err(e) => return Err(e),
+            },
+            MODE_REGULAR => {
+                let file = super::open(&path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+                file.write(content)?;
+                file_count += 1;
+            },
+            _ => warn!("Skipped initramfs member {} with mode {:o}", name, mode),
+        }
+    }
+}
+
+// 头部中魔数之后的第idx个字段，每个字段是8个十六进制字符
+fn header_field(header: &[u8], idx: usize) -> Result<u32> {
+    let start = MAGIC.len() + idx * 8;
+    let text = core::str::from_utf8(&header[start..start + 8]).map_err(|_| FsError::InvalidInput)?;
+    u32::from_str_radix(text, 16).map_err(|_| FsError::InvalidInput)
+}
+
+#[inline]
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+// 在内存中构造一个小的归档，解包后检查文件内容，最后删除解包出的文件
+pub(crate) fn test_initramfs() {
+    use alloc::vec::Vec;
+    fn push_member(archive: &mut Vec<u8>, name: &str, mode: u32, content: &[u8]) {
+        let fields = [0, mode, 0, 0, 1, 0, content.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        archive.extend_from_slice(MAGIC);
+        for field in fields.iter() {
+            archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(align4(archive.len()), 0);
+        archive.extend_from_slice(content);
+        archive.resize(align4(archive.len()), 0);
+    }
+    let mut archive = Vec::new();
+    push_member(&mut archive, ".", MODE_DIRECTORY | 0o755, b"");
+    push_member(&mut archive, "kernel-initramfs-test", MODE_DIRECTORY | 0o755, b"");
+    push_member(&mut archive, "kernel-initramfs-test/hello.txt", MODE_REGULAR | 0o644, b"Hello, initramfs!");
+    push_member(&mut archive, TRAILER, 0, b"");
+    let len = archive.len();
+    archive.extend_from_slice(b"garbage after the archive");
+    assert!(is_initramfs(&archive));
+    assert_eq!(unpack_initramfs(&archive), Ok((len, 1)));
+    let file = super::open("/kernel-initramfs-test/hello.txt", OpenFlags::READ).expect("open unpacked file");
+    assert_eq!(file.stat().kind, InodeType::File as u32);
+    let mut buf = [0u8; 32];
+    assert_eq!(file.read(&mut buf), Ok(17));
+    assert_eq!(&buf[..17], b"Hello, initramfs!");
+    drop(file);
+    super::unlink("/kernel-initramfs-test/hello.txt").expect("remove unpacked file");
+    super::unlink("/kernel-initramfs-test").expect("remove unpacked directory");
+    assert_eq!(unpack_initramfs(&archive[..HEADER_LEN - 1]).err(), Some(FsError::InvalidInput));
+    println!("[kernel-initramfs-test] Initramfs unpack test passed");
+}
@@ -0,0 +1,355 @@
+//! 虚拟文件系统
+//!
+//! 每个具体的文件系统实现FileSystem和Inode特征，挂载到挂载表中的某个路径上。
+//! 查找路径时，先找到前缀最长的挂载点，再从它的根目录开始逐级查找目录项。
+//! 查找到的目录项（Dentry）会缓存起来；具体的文件系统保证同一个文件只有一个Inode对象。
+
+mod block_cache;
+mod devfs;
+mod fat32;
+mod file;
+mod initramfs;
+mod pipe;
+mod procfs;
+mod tmpfs;
+
+pub use devfs::DevFs;
+pub use fat32::Fat32;
+pub(crate) use fat32::test_fat32;
+pub use initramfs::{is_initramfs, unpack_initramfs};
+pub(crate) use initramfs::test_initramfs;
+pub use pipe::pipe;
+pub(crate) use pipe::test_pipe;
+pub use procfs::ProcFs;
+pub(crate) use procfs::test_procfs;
+pub use tmpfs::TmpFs;
+pub use file::{File, FdTable, FileStat, OpenFlags, SeekFrom};
+
+use crate::mm::{self, SlabArc};
+use alloc::{collections::BTreeMap, string::{String, ToString}, sync::Arc, vec::Vec};
+use spin::{Mutex, RwLock};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FsError {
+    // 文件或目录不存在
+    NotFound,
+    // 要创建的文件已经存在
+    AlreadyExists,
+    // 路径中间的部分不是目录
+    NotADirectory,
+    // 不能对目录进行这个操作
+    IsADirectory,
+    // 要删除的目录不是空的
+    DirectoryNotEmpty,
+    // 参数不合法，比如相对路径或者过长的文件名
+    InvalidInput,
+    // 文件描述符无效，或者打开的方式不允许这个操作
+    BadFileDescriptor,
+    // 设备不支持定位
+    NotSeekable,
+    // 存储空间不足
+    NoSpace,
+    // 操作暂时无法完成，比如读取空的管道，需要等待以后重试
+    WouldBlock,
+    // 管道的读端已经全部关闭
+    BrokenPipe,
+    // 底层设备读写失败
+    IoError,
+    // 文件系统不支持这个操作
+    Unsupported,
+}
+
+impl FsError {
+    // 系统调用返回给用户的错误号，和Linux的错误号保持一致
+    pub fn errno(self) -> usize {
+        match self {
+            FsError::NotFound => 2,           // ENOENT
+            FsError::IoError => 5,            // EIO
+            FsError::BadFileDescriptor => 9,  // EBADF
+            FsError::WouldBlock => 11,        // EAGAIN
+            FsError::AlreadyExists => 17,     // EEXIST
+            FsError::NotADirectory => 20,     // ENOTDIR
+            FsError::IsADirectory => 21,      // EISDIR
+            FsError::InvalidInput => 22,      // EINVAL
+            FsError::NoSpace => 28,           // ENOSPC
+            FsError::NotSeekable => 29,       // ESPIPE
+            FsError::BrokenPipe => 32,        // EPIPE
+            FsError::Unsupported => 38,       // ENOSYS
+            FsError::DirectoryNotEmpty => 39, // ENOTEMPTY
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, FsError>;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum InodeType {
+    File = 1,
+    Directory = 2,
+    CharDevice = 3,
+    Fifo = 4,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    // 在所属文件系统中唯一的编号
+    pub ino: usize,
+    pub kind: InodeType,
+    // 文件的字节数；目录和设备为0，管道为缓冲区中的字节数
+    pub size: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: InodeType,
+}
+
+// 文件系统中的一个文件、目录或者设备。
+// 默认实现返回这个类型的节点不支持的错误，具体的文件系统只需要实现自己支持的操作
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+    // 从offset处读取，返回读取的字节数；读到文件末尾返回0
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::Unsupported)
+    }
+    // 从offset处写入，必要时扩展文件，返回写入的字节数
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::Unsupported)
+    }
+    // 把文件截断或扩展到size字节
+    fn truncate(&self, _size: usize) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+    // 在目录中查找名字为name的节点
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+    // 在目录中创建文件或子目录
+    fn create(&self, _name: &str, _kind: InodeType) -> Result<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+    // 删除目录中的文件或空的子目录
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::NotADirectory)
+    }
+    // 列出目录中的所有项目，不包括"."和".."
+    fn entries(&self) -> Result<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+    // 目录项缓存中子项的名字。文件名不区分大小写的文件系统应当统一大小写，让同一个文件只缓存一次
+    fn cache_name(&self, name: &str) -> String {
+        name.to_string()
+    }
+    // 查找到的子节点能否缓存在目录项中。内容随时变化的目录（比如/proc）返回false
+    fn cache_children(&self) -> bool {
+        true
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    // 文件系统类型的名字，比如"devfs"
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Arc<dyn Inode>;
+    // 把缓存中修改过的数据写回设备
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// 目录项从这个缓存中分配
+static DENTRIES: mm::SlabCache<mm::ArcInner<Dentry>> = mm::SlabCache::new("dentry");
+
+// 目录项。把名字和节点联系起来，并缓存已经查找过的子目录项
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    children: Mutex<BTreeMap<String, SlabArc<Dentry>>>,
+}
+
+impl Dentry {
+    pub fn new(name: &str, inode: Arc<dyn Inode>) -> Result<SlabArc<Dentry>> {
+        DENTRIES.alloc_arc(Dentry { name: name.to_string(), inode, children: Mutex::new(BTreeMap::new()) })
+            .map_err(|_| FsError::NoSpace)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<SlabArc<Dentry>> {
+        let key = self.inode.cache_name(name);
+        let mut children = self.children.lock();
+        if let Some(child) = children.get(&key) {
+            return Ok(child.clone());
+        }
+        let child = Dentry::new(name, self.inode.lookup(name)?)?;
+        if self.inode.cache_children() {
+            children.insert(key, child.clone());
+        }
+        Ok(child)
+    }
+
+    pub fn create(&self, name: &str, kind: InodeType) -> Result<SlabArc<Dentry>> {
+        let key = self.inode.cache_name(name);
+        let mut children = self.children.lock();
+        if children.contains_key(&key) {
+            return Err(FsError::AlreadyExists);
+        }
+        let child = Dentry::new(name, self.inode.create(name, kind)?)?;
+        children.insert(key, child.clone());
+        Ok(child)
+    }
+
+    pub fn unlink(&self, name: &str) -> Result<()> {
+        let key = self.inode.cache_name(name);
+        let mut children = self.children.lock();
+        self.inode.unlink(name)?;
+        children.remove(&key);
+        Ok(())
+    }
+}
+
+impl core::fmt::Debug for Dentry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Dentry").field("name", &self.name).field("metadata", &self.inode.metadata()).finish()
+    }
+}
+
+struct Mount {
+    // 挂载点路径拆分后的各个部分，根目录为空
+    path: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+    root: SlabArc<Dentry>,
+}
+
+static MOUNTS: RwLock<Vec<Mount>> = RwLock::new(Vec::new());
+
+// 把文件系统挂载到path上。挂载点不需要在上一级文件系统中存在
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let path: Vec<String> = split_path(path)?.into_iter().map(|s| s.to_string()).collect();
+    let mut mounts = MOUNTS.write();
+    if mounts.iter().any(|m| m.path == path) {
+        return Err(FsError::AlreadyExists);
+    }
+    let root = Dentry::new("/", fs.root())?;
+    mounts.push(Mount { path, fs, root });
+    Ok(())
+}
+
+// 把所有文件系统的缓存写回设备
+pub fn sync_all() -> Result<()> {
+    for mount in MOUNTS.read().iter() {
+        mount.fs.sync()?;
+    }
+    Ok(())
+}
+
+// 按绝对路径查找目录项
+pub fn lookup(path: &str) -> Result<SlabArc<Dentry>> {
+    let parts = split_path(path)?;
+    walk(&parts)
+}
+
+// 查找路径所在的目录，返回目录和最后一部分的名字
+pub fn lookup_parent(path: &str) -> Result<(SlabArc<Dentry>, String)> {
+    let mut parts = split_path(path)?;
+    let name = parts.pop().ok_or(FsError::InvalidInput)?; // 根目录没有上一级
+    Ok((walk(&parts)?, name.to_string()))
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<File>> {
+    let dentry = if flags.contains(OpenFlags::CREATE) {
+        let (parent, name) = lookup_parent(path)?;
+        match parent.lookup(&name) {
+            Ok(_) if flags.contains(OpenFlags::EXCLUSIVE) => return Err(FsError::AlreadyExists),
+            Ok(dentry) => dentry,
+            Err(FsError::NotFound) => parent.create(&name, InodeType::File)?,
+            Err(e) => return Err(e),
+        }
+    } else {
+        lookup(path)?
+    };
+    File::open(dentry, flags)
+}
+
+pub fn mkdir(path: &str) -> Result<()> {
+    let (parent, name) = lookup_parent(path)?;
+    parent.create(&name, InodeType::Directory).map(|_| ())
+}
+
+// 删除文件或空目录。不能删除挂载点
+pub fn unlink(path: &str) -> Result<()> {
+    let parts = split_path(path)?;
+    if MOUNTS.read().iter().any(|m| m.path.iter().map(|s| s.as_str()).eq(parts.iter().copied())) {
+        return Err(FsError::InvalidInput);
+    }
+    let (parent, name) = lookup_parent(path)?;
+    parent.unlink(&name)
+}
+
+fn walk(parts: &[&str]) -> Result<SlabArc<Dentry>> {
+    let mounts = MOUNTS.read();
+    // 前缀最长的挂载点
+    let mount = mounts.iter()
+        .filter(|m| m.path.len() <= parts.len() && m.path.iter().zip(parts).all(|(a, b)| a == b))
+        .max_by_key(|m| m.path.len())
+        .ok_or(FsError::NotFound)?;
+    let mut cur = mount.root.clone();
+    let rest = &parts[mount.path.len()..];
+    drop(mounts);
+    for part in rest {
+        cur = cur.lookup(part)?;
+    }
+    Ok(cur)
+}
+
+// 把绝对路径拆分成各个部分，同时处理"."和".."
+fn split_path(path: &str) -> Result<Vec<&str>> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidInput);
+    }
+    let mut ans = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {},
+            ".." => { ans.pop(); },
+            _ if part.len() > MAX_NAME_LEN => return Err(FsError::InvalidInput),
+            _ => ans.push(part),
+        }
+    }
+    Ok(ans)
+}
+
+// 文件名的最大长度
+pub const MAX_NAME_LEN: usize = 255;
+
+// 挂载启动时就需要的文件系统：内存文件系统作为根目录，设备文件系统挂载在/dev
+pub fn init() {
+    let root = TmpFs::new();
+    // 挂载点不必在上一级文件系统中存在，这里创建出来，是为了列出根目录时能看到它们
+    for name in ["dev", "mnt", "proc"].iter() {
+        root.root().create(name, InodeType::Directory).expect("create mount point");
+    }
+    mount("/", Arc::new(root)).expect("mount tmpfs");
+    mount("/dev", Arc::new(DevFs::new())).expect("mount devfs");
+}
+
+pub(crate) fn test_vfs() {
+    assert_eq!(split_path("/a/./b/../c//d"), Ok(alloc::vec!["a", "c", "d"]));
+    assert_eq!(split_path("relative"), Err(FsError::InvalidInput));
+    let console = open("/dev/console", OpenFlags::READ | OpenFlags::WRITE).expect("open console");
+    assert_eq!(console.stat().kind, InodeType::CharDevice as u32);
+    assert_eq!(console.seek(SeekFrom::Start(0)), Err(FsError::NotSeekable));
+    let dev = open("/dev/", OpenFlags::READ | OpenFlags::DIRECTORY).expect("open /dev");
+    let names: Vec<String> = dev.inode().entries().unwrap().into_iter().map(|e| e.name).collect();
+    assert!(names.iter().any(|name| name == "console"), "console in /dev");
+    assert_eq!(open("/dev/not-exist", OpenFlags::READ).err(), Some(FsError::NotFound));
+    assert_eq!(open("/dev/console/x", OpenFlags::READ).err(), Some(FsError::NotADirectory));
+    println!("[kernel-vfs-test] Virtual file system test passed");
+}
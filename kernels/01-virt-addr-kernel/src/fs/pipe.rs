@@ -0,0 +1,142 @@
+//! 匿名管道
+//!
+//! 管道的读端和写端是两个打开的文件，共用内核中的一个环形缓冲区。它们不在任何文件系统中，
+//! 只能通过文件描述符访问；dup2和创建子进程时复制的描述符共享同一个端口。
+//!
+//! 缓冲区为空时读取、缓冲区满时写入都会返回WouldBlock，系统调用据此让进程阻塞，由调度器切换到其它进程。
+//! 写端关闭以后，读完缓冲区中剩余的数据就读到文件末尾；读端关闭以后，写入返回BrokenPipe
+
+use super::{Dentry, File, FsError, Inode, InodeType, Metadata, OpenFlags, Result};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+
+// 缓冲区的容量，和Linux的一页相同
+const PIPE_BUFFER_SIZE: usize = 4096;
+
+// 管道没有所属的文件系统，编号只用于区分不同的管道
+static NEXT_PIPE_INO: AtomicUsize = AtomicUsize::new(1);
+
+struct Pipe {
+    ino: usize,
+    buffer: Mutex<VecDeque<u8>>,
+    read_closed: AtomicBool,
+    write_closed: AtomicBool,
+}
+
+// 创建管道，返回读端和写端
+pub fn pipe() -> Result<(Arc<File>, Arc<File>)> {
+    let pipe = Arc::new(Pipe {
+        ino: NEXT_PIPE_INO.fetch_add(1, Ordering::Relaxed),
+        buffer: Mutex::new(VecDeque::with_capacity(PIPE_BUFFER_SIZE)),
+        read_closed: AtomicBool::new(false),
+        write_closed: AtomicBool::new(false),
+    });
+    let reader = File::open(Dentry::new("pipe", Arc::new(PipeReader { pipe: pipe.clone() }))?, OpenFlags::READ)?;
+    let writer = File::open(Dentry::new("pipe", Arc::new(PipeWriter { pipe }))?, OpenFlags::WRITE)?;
+    Ok((reader, writer))
+}
+
+// 管道的读端。打开它的文件全部关闭时，节点被释放，管道记录读端已经关闭
+struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+impl Inode for PipeReader {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: self.pipe.ino, kind: InodeType::Fifo, size: self.pipe.buffer.lock().len() }
+    }
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut buffer = self.pipe.buffer.lock();
+        if buffer.is_empty() {
+            // 先读缓冲区再检查写端，写端在关闭之前写入的数据不会丢失
+            return if self.pipe.write_closed.load(Ordering::Acquire) {
+                Ok(0)
+            } else {
+                Err(FsError::WouldBlock)
+            };
+        }
+        let n = buf.len().min(buffer.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.read_closed.store(true, Ordering::Release);
+        self.pipe.buffer.lock().clear(); // 没有人会再读取这些数据
+    }
+}
+
+// 管道的写端
+struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+impl Inode for PipeWriter {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: self.pipe.ino, kind: InodeType::Fifo, size: self.pipe.buffer.lock().len() }
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        if self.pipe.read_closed.load(Ordering::Acquire) {
+            return Err(FsError::BrokenPipe);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut buffer = self.pipe.buffer.lock();
+        let n = buf.len().min(PIPE_BUFFER_SIZE - buffer.len());
+        if n == 0 {
+            return Err(FsError::WouldBlock);
+        }
+        buffer.extend(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.write_closed.store(true, Ordering::Release);
+    }
+}
+
+pub(crate) fn test_pipe() {
+    let (reader, writer) = pipe().expect("create pipe");
+    assert_eq!(reader.stat().kind, InodeType::Fifo as u32);
+    assert_eq!(reader.seek(super::SeekFrom::Start(0)), Err(FsError::NotSeekable));
+    assert_eq!(reader.write(b"x"), Err(FsError::BadFileDescriptor));
+    let mut buf = [0u8; 16];
+    assert_eq!(reader.read(&mut buf), Err(FsError::WouldBlock));
+    assert_eq!(writer.write(b"hello"), Ok(5));
+    assert_eq!(writer.write(b", pipe"), Ok(6));
+    assert_eq!(reader.read(&mut buf[..4]), Ok(4));
+    assert_eq!(&buf[..4], b"hell");
+    // 写满缓冲区以后，只写入放得下的部分，之后的写入需要等待
+    let big = alloc::vec![0x5a; PIPE_BUFFER_SIZE];
+    assert_eq!(writer.write(&big), Ok(PIPE_BUFFER_SIZE - 7));
+    assert_eq!(writer.write(b"!"), Err(FsError::WouldBlock));
+    assert_eq!(reader.read(&mut buf[..7]), Ok(7));
+    assert_eq!(&buf[..7], b"o, pipe");
+    // 关闭写端以后，先读完剩下的数据，再读到文件末尾
+    drop(writer);
+    let mut total = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) => panic!("read pipe after writer closed: {:?}", e),
+        }
+    }
+    assert_eq!(total, PIPE_BUFFER_SIZE - 7);
+    // 关闭读端以后，写入返回BrokenPipe
+    let (reader, writer) = pipe().expect("create pipe");
+    drop(reader);
+    assert_eq!(writer.write(b"lost"), Err(FsError::BrokenPipe));
+    println!("[kernel-pipe-test] Pipe test passed");
+}
@@ -0,0 +1,240 @@
+//! 进程文件系统，挂载在/proc
+//!
+//! 文件的内容在读取时才生成，反映内核当时的状态：
+//! - meminfo：页帧分配器和内核堆的使用情况
+//! - sbi：SBI实现的编号和版本
+//! - slabinfo：内核对象缓存的使用情况
+//! - <pid>/status、<pid>/maps：进程的状态和地址空间中的映射；self指向当前运行的进程
+
+use super::{DirEntry, FileSystem, FsError, Inode, InodeType, Metadata, Result};
+use crate::{mm, process, sbi};
+use alloc::{boxed::Box, format, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::fmt::Write;
+
+const INO_ROOT: usize = 1;
+const INO_MEMINFO: usize = 2;
+const INO_SBI: usize = 3;
+const INO_SLABINFO: usize = 4;
+// 进程目录和其中文件的编号从这里开始，每个进程占用4个编号
+const INO_PID_BASE: usize = 0x1000;
+
+pub struct ProcFs {
+    root: Arc<ProcRoot>,
+}
+
+impl ProcFs {
+    pub fn new(frame_alloc: &'static mm::DefaultFrameAllocator) -> Self {
+        ProcFs { root: Arc::new(ProcRoot { frame_alloc }) }
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct ProcRoot {
+    frame_alloc: &'static mm::DefaultFrameAllocator,
+}
+
+impl ProcRoot {
+    fn pid_of(&self, name: &str) -> Option<usize> {
+        let pid = match name {
+            "self" => process::current_pid()?,
+            _ => name.parse().ok()?,
+        };
+        process::with_process(pid, |_| pid)
+    }
+}
+
+impl Inode for ProcRoot {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: INO_ROOT, kind: InodeType::Directory, size: 0 }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        match name {
+            "meminfo" => {
+                let frame_alloc = self.frame_alloc;
+                Ok(ProcFile::new(INO_MEMINFO, move || Ok(meminfo(frame_alloc))))
+            },
+            "sbi" => Ok(ProcFile::new(INO_SBI, || Ok(sbi_info()))),
+            "slabinfo" => Ok(ProcFile::new(INO_SLABINFO, || Ok(slabinfo()))),
+            _ => match self.pid_of(name) {
+                Some(pid) => Ok(Arc::new(ProcPidDir { pid })),
+                None => Err(FsError::NotFound),
+            },
+        }
+    }
+    fn entries(&self) -> Result<Vec<DirEntry>> {
+        let mut ans = alloc::vec![
+            DirEntry { name: "meminfo".to_string(), kind: InodeType::File },
+            DirEntry { name: "sbi".to_string(), kind: InodeType::File },
+            DirEntry { name: "slabinfo".to_string(), kind: InodeType::File },
+        ];
+        if process::current_pid().is_some() {
+            ans.push(DirEntry { name: "self".to_string(), kind: InodeType::Directory });
+        }
+        for pid in process::pids() {
+            ans.push(DirEntry { name: pid.to_string(), kind: InodeType::Directory });
+        }
+        Ok(ans)
+    }
+    fn cache_children(&self) -> bool {
+        false // 进程随时可能创建和退出，self指向的进程也会变化
+    }
+}
+
+struct ProcPidDir {
+    pid: usize,
+}
+
+impl Inode for ProcPidDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: INO_PID_BASE + self.pid * 4, kind: InodeType::Directory, size: 0 }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        let pid = self.pid;
+        match name {
+            "status" => Ok(ProcFile::new(INO_PID_BASE + pid * 4 + 1, move || status(pid))),
+            "maps" => Ok(ProcFile::new(INO_PID_BASE + pid * 4 + 2, move || maps(pid))),
+            _ => Err(FsError::NotFound),
+        }
+    }
+    fn entries(&self) -> Result<Vec<DirEntry>> {
+        Ok(["status", "maps"].iter()
+            .map(|name| DirEntry { name: name.to_string(), kind: InodeType::File })
+            .collect())
+    }
+}
+
+// 每次读取时调用generate生成完整的内容，再从中取出要读的部分
+struct ProcFile {
+    ino: usize,
+    generate: Box<dyn Fn() -> Result<String> + Send + Sync>,
+}
+
+impl ProcFile {
+    fn new(ino: usize, generate: impl Fn() -> Result<String> + Send + Sync + 'static) -> Arc<dyn Inode> {
+        Arc::new(ProcFile { ino, generate: Box::new(generate) })
+    }
+}
+
+impl Inode for ProcFile {
+    fn metadata(&self) -> Metadata {
+        // 和Linux一样，内容是动态生成的，长度记为0
+        Metadata { ino: self.ino, kind: InodeType::File, size: 0 }
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let content = (self.generate)()?;
+        let content = content.as_bytes();
+        if offset >= content.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(content.len() - offset);
+        buf[..n].copy_from_slice(&content[offset..offset + n]);
+        Ok(n)
+    }
+}
+
+fn meminfo(frame_alloc: &mm::DefaultFrameAllocator) -> String {
+    let frames = frame_alloc.lock().stats();
+    let heap = mm::heap_stats();
+    let mut ans = String::new();
+    // 两种分页模式下页帧都是4KiB
+    let _ = writeln!(ans, "FrameRange:     {:#x}-{:#x}", frames.start.as_usize() << 12, frames.end.as_usize() << 12);
+    let _ = writeln!(ans, "FrameTotal:     {:>10} frames", frames.total);
+    let _ = writeln!(ans, "FrameUsed:      {:>10} frames", frames.total - frames.free);
+    let _ = writeln!(ans, "FrameFree:      {:>10} frames", frames.free);
+    let _ = writeln!(ans, "HeapTotal:      {:>10} bytes", heap.total);
+    let _ = writeln!(ans, "HeapAllocated:  {:>10} bytes", heap.allocated);
+    let _ = writeln!(ans, "HeapRequested:  {:>10} bytes", heap.requested);
+    let _ = writeln!(ans, "HeapGrown:      {:>10} bytes", heap.grown);
+    let _ = writeln!(ans, "HeapFree:       {:>10} bytes", heap.total - heap.allocated);
+    ans
+}
+
+fn sbi_info() -> String {
+    let impl_name = match sbi::get_sbi_impl_id() {
+        0 => "Berkeley Boot Loader",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        _ => "unknown",
+    };
+    let spec_version = sbi::get_spec_version();
+    let mut ans = String::new();
+    let _ = writeln!(ans, "Implementation: {} ({})", impl_name, sbi::get_sbi_impl_id());
+    let _ = writeln!(ans, "ImplVersion:    {:#x}", sbi::get_sbi_impl_version());
+    // 规范版本的第24到30位是主版本号，低24位是次版本号
+    let _ = writeln!(ans, "SpecVersion:    {}.{}", (spec_version >> 24) & 0x7f, spec_version & 0xff_ffff);
+    let _ = writeln!(ans, "MVendorId:      {:#x}", sbi::get_mvendorid());
+    let _ = writeln!(ans, "MArchId:        {:#x}", sbi::get_marchid());
+    let _ = writeln!(ans, "MImpId:         {:#x}", sbi::get_mimpid());
+    ans
+}
+
+// 每个缓存一行：名字、对象大小、slab数，以及正在使用、在仓库中和在弹匣中的对象数
+fn slabinfo() -> String {
+    let mut ans = String::new();
+    let _ = writeln!(ans, "{:<16} {:>8} {:>6} {:>8} {:>8} {:>8}", "name", "objsize", "slabs", "in_use", "free", "cached");
+    for stats in mm::slab_stats() {
+        let _ = writeln!(ans, "{:<16} {:>8} {:>6} {:>8} {:>8} {:>8}",
+            stats.name, stats.object_size, stats.slabs, stats.in_use, stats.free, stats.cached);
+    }
+    ans
+}
+
+fn status(pid: usize) -> Result<String> {
+    process::with_process(pid, |p| {
+        let mut ans = String::new();
+        let _ = writeln!(ans, "Name:    {}", p.name);
+        let _ = writeln!(ans, "Pid:     {}", p.pid);
+        let _ = writeln!(ans, "PPid:    {}", p.ppid);
+        let _ = writeln!(ans, "State:   {}", p.state.as_str());
+        let _ = writeln!(ans, "Asid:    {}", p.asid);
+        let _ = writeln!(ans, "RootPpn: {:#x}", p.root_ppn);
+        ans
+    }).ok_or(FsError::NotFound)
+}
+
+// 和Linux的/proc/<pid>/maps格式相同：地址范围、权限、偏移、设备、节点号、名字
+fn maps(pid: usize) -> Result<String> {
+    process::with_process(pid, |p| {
+        let mut ans = String::new();
+        for area in p.areas.iter() {
+            let perms = format!(
+                "{}{}{}{}",
+                if area.read { 'r' } else { '-' },
+                if area.write { 'w' } else { '-' },
+                if area.execute { 'x' } else { '-' },
+                if area.shared { 's' } else { 'p' },
+            );
+            let _ = writeln!(ans, "{:08x}-{:08x} {} 00000000 00:00 0          {}", area.start, area.end, perms, area.name);
+        }
+        ans
+    }).ok_or(FsError::NotFound)
+}
+
+pub(crate) fn test_procfs(mount_point: &str) {
+    use super::OpenFlags;
+    let read_to_string = |path: &str| {
+        let file = super::open(path, OpenFlags::READ).expect("open proc file");
+        let mut buf = alloc::vec![0u8; 1024];
+        let n = file.read(&mut buf).expect("read proc file");
+        String::from_utf8(buf[..n].to_vec()).expect("proc file in UTF-8")
+    };
+    let meminfo = read_to_string(&format!("{}/meminfo", mount_point));
+    assert!(meminfo.starts_with("FrameRange:") && meminfo.contains("HeapTotal:"), "meminfo content");
+    assert!(read_to_string(&format!("{}/sbi", mount_point)).starts_with("Implementation:"), "sbi content");
+    // 查找路径时分配了目录项，目录项的缓存已经登记
+    let slabinfo = read_to_string(&format!("{}/slabinfo", mount_point));
+    assert!(slabinfo.lines().any(|line| line.starts_with("dentry ")), "slabinfo content");
+    assert_eq!(super::lookup(&format!("{}/0/status", mount_point)).err(), Some(FsError::NotFound));
+    println!("[kernel-procfs-test] Process file system test passed");
+}
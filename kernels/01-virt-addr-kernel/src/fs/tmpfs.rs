@@ -0,0 +1,110 @@
+//! 内存文件系统，作为根文件系统挂载在/
+//!
+//! 文件内容保存在内核堆上，关机后就丢失了。启动时从initramfs解包出的程序和数据文件都放在这里
+
+use super::{DirEntry, FileSystem, FsError, Inode, InodeType, Metadata, Result};
+use alloc::{collections::BTreeMap, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+pub struct TmpFs {
+    root: Arc<TmpDir>,
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        let next_ino = Arc::new(AtomicUsize::new(2)); // 根目录的编号是1
+        TmpFs { root: Arc::new(TmpDir { ino: 1, next_ino, children: Mutex::new(BTreeMap::new()) }) }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+struct TmpDir {
+    ino: usize,
+    // 同一个文件系统中的所有节点共用一个编号计数器
+    next_ino: Arc<AtomicUsize>,
+    children: Mutex<BTreeMap<String, Arc<dyn Inode>>>,
+}
+
+impl Inode for TmpDir {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: self.ino, kind: InodeType::Directory, size: 0 }
+    }
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>> {
+        self.children.lock().get(name).cloned().ok_or(FsError::NotFound)
+    }
+    fn create(&self, name: &str, kind: InodeType) -> Result<Arc<dyn Inode>> {
+        let mut children = self.children.lock();
+        if children.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let ino = self.next_ino.fetch_add(1, Ordering::Relaxed);
+        let inode: Arc<dyn Inode> = match kind {
+            InodeType::File => Arc::new(TmpFile { ino, data: Mutex::new(Vec::new()) }),
+            InodeType::Directory => Arc::new(TmpDir {
+                ino,
+                next_ino: self.next_ino.clone(),
+                children: Mutex::new(BTreeMap::new()),
+            }),
+            _ => return Err(FsError::Unsupported),
+        };
+        children.insert(name.to_string(), inode.clone());
+        Ok(inode)
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        let mut children = self.children.lock();
+        let inode = children.get(name).ok_or(FsError::NotFound)?;
+        if inode.metadata().kind == InodeType::Directory && !inode.entries()?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        children.remove(name);
+        Ok(())
+    }
+    fn entries(&self) -> Result<Vec<DirEntry>> {
+        Ok(self.children.lock().iter()
+            .map(|(name, inode)| DirEntry { name: name.clone(), kind: inode.metadata().kind })
+            .collect())
+    }
+}
+
+struct TmpFile {
+    ino: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl Inode for TmpFile {
+    fn metadata(&self) -> Metadata {
+        Metadata { ino: self.ino, kind: InodeType::File, size: self.data.lock().len() }
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let data = self.data.lock();
+        if offset >= data.len() {
+            return Ok(0);
+        }
+        let n = buf.len().min(data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        Ok(n)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let end = offset.checked_add(buf.len()).ok_or(FsError::InvalidInput)?;
+        let mut data = self.data.lock();
+        if end > data.len() {
+            // 在文件末尾之后写入，中间的部分填0
+            data.resize(end, 0);
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn truncate(&self, size: usize) -> Result<()> {
+        self.data.lock().resize(size, 0);
+        Ok(())
+    }
+}
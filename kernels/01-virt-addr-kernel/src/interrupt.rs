@@ -0,0 +1,53 @@
+//! 外部中断管理
+//!
+//! 驱动程序通过register_handler为自己的中断号注册处理函数。
+//! 无论中断发生在内核还是用户程序运行的时候，最终都由handle_external认领中断，再分发给处理函数。
+
+use crate::drivers::plic::{self, Plic};
+use alloc::{collections::BTreeMap, sync::Arc};
+use lazy_static::lazy_static;
+use spin::{Mutex, Once};
+
+type InterruptHandler = Arc<dyn Fn() + Send + Sync>;
+
+static PLIC: Once<Plic> = Once::new();
+
+lazy_static! {
+    static ref HANDLERS: Mutex<BTreeMap<u32, InterruptHandler>> = Mutex::new(BTreeMap::new());
+}
+
+// 初始化中断控制器，打开当前处理核的外部中断
+//
+// unsafe说明：调用者必须保证base是PLIC寄存器的地址，并且已经映射到当前的地址空间
+pub unsafe fn init(base: usize, hart_id: usize) {
+    let plic = PLIC.call_once(|| Plic::new(base));
+    plic.set_threshold(plic::supervisor_context(hart_id), 0);
+    riscv::register::sie::set_sext();
+}
+
+// 为中断号注册处理函数，并在当前处理核上使能这个中断
+pub fn register_handler<F>(irq: u32, handler: F)
+where F: Fn() + Send + Sync + 'static {
+    HANDLERS.lock().insert(irq, Arc::new(handler));
+    let plic = PLIC.get().expect("interrupt controller not initialized");
+    plic.set_priority(irq, 1);
+    plic.enable(plic::supervisor_context(crate::hart_id()), irq);
+}
+
+// 处理外部中断：认领、分发，然后通知中断控制器处理完成
+pub fn handle_external(hart_id: usize) {
+    let plic = match PLIC.get() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let context = plic::supervisor_context(hart_id);
+    while let Some(irq) = plic.claim(context) {
+        // 先复制处理函数再释放锁，处理函数里可以再注册其它中断
+        let handler = HANDLERS.lock().get(&irq).cloned();
+        match handler {
+            Some(handler) => handler(),
+            None => warn!("Unhandled external interrupt {}", irq),
+        }
+        plic.complete(context, irq);
+    }
+}
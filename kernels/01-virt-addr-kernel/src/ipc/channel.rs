@@ -0,0 +1,89 @@
+//! 消息通道
+//!
+//! 通道有两个端点，从一端发送的消息按顺序到达另一端。每个方向的队列最多容纳CHANNEL_CAPACITY条消息，
+//! 队列满时发送、队列空时接收返回EAGAIN，系统调用据此让进程阻塞，或者直接返回给非阻塞的调用者。
+//! 一端关闭以后，另一端发送返回EPIPE；接收完队列中剩余的消息以后，接收也返回EPIPE
+
+use super::Handle;
+use crate::syscall::{Errno, EAGAIN, EMSGSIZE, EPIPE};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+// 一条消息的最大字节数
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+// 每个方向最多排队的消息数
+const CHANNEL_CAPACITY: usize = 16;
+
+pub struct Message {
+    pub data: Vec<u8>,
+    // 随消息转移的句柄
+    pub handle: Option<Handle>,
+}
+
+struct Channel {
+    // queues[i]是发往第i个端点的消息
+    queues: [Mutex<VecDeque<Message>>; 2],
+    closed: [AtomicBool; 2],
+}
+
+// 通道的一个端点。指向它的句柄全部关闭时，端点被释放，通道记录这一端已经关闭
+pub struct Endpoint {
+    channel: Arc<Channel>,
+    side: usize,
+}
+
+// 创建通道，返回它的两个端点
+pub fn channel() -> (Arc<Endpoint>, Arc<Endpoint>) {
+    let channel = Arc::new(Channel {
+        queues: [Mutex::new(VecDeque::new()), Mutex::new(VecDeque::new())],
+        closed: [AtomicBool::new(false), AtomicBool::new(false)],
+    });
+    let a = Arc::new(Endpoint { channel: channel.clone(), side: 0 });
+    let b = Arc::new(Endpoint { channel, side: 1 });
+    (a, b)
+}
+
+impl Endpoint {
+    // 把消息放进对端的队列
+    pub fn send(&self, message: Message) -> Result<(), Errno> {
+        if message.data.len() > MAX_MESSAGE_SIZE {
+            return Err(EMSGSIZE);
+        }
+        let peer = 1 - self.side;
+        if self.channel.closed[peer].load(Ordering::Acquire) {
+            return Err(EPIPE);
+        }
+        let mut queue = self.channel.queues[peer].lock();
+        if queue.len() >= CHANNEL_CAPACITY {
+            return Err(EAGAIN);
+        }
+        queue.push_back(message);
+        Ok(())
+    }
+
+    // 用f处理队首的消息。f成功时消息出队，失败时消息留在队首，下次接收还是这一条
+    pub fn recv_with<R>(&self, f: impl FnOnce(&Message) -> Result<R, Errno>) -> Result<R, Errno> {
+        let mut queue = self.channel.queues[self.side].lock();
+        let message = match queue.front() {
+            Some(message) => message,
+            // 先检查队列再检查对端，对端在关闭之前发送的消息不会丢失
+            None if self.channel.closed[1 - self.side].load(Ordering::Acquire) => return Err(EPIPE),
+            None => return Err(EAGAIN),
+        };
+        let ans = f(message)?;
+        let message = queue.pop_front();
+        drop(queue);
+        drop(message); // 消息中的句柄可能是对象的最后一个引用，在释放队列的锁以后再释放它
+        Ok(ans)
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        self.channel.closed[self.side].store(true, Ordering::Release);
+        // 没有人会再接收这些消息，释放它们附带的句柄
+        let pending = core::mem::take(&mut *self.channel.queues[self.side].lock());
+        drop(pending);
+    }
+}
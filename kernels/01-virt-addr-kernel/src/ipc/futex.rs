@@ -0,0 +1,137 @@
+//! 快速用户态互斥（futex）
+//!
+//! 用户态的锁在没有竞争时只用原子操作，需要等待时才进入内核。等待的线程按用户地址对应的物理地址排队，
+//! 所以不同进程映射同一块共享内存时，它们在同一个地址上等待和唤醒。
+//!
+//! 阻塞的系统调用会被重新执行，所以线程第一次调用wait时登记到等待队列，之后每次重新执行时
+//! 检查自己是否已经被唤醒或者超时
+
+use crate::syscall::{Errno, EAGAIN, ETIMEDOUT};
+use alloc::collections::{BTreeMap, VecDeque};
+use core::task::Poll;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+struct Waiter {
+    key: usize,
+    // 超时的time寄存器值，None表示一直等待
+    deadline: Option<u64>,
+    woken: bool,
+}
+
+struct FutexTable {
+    // 物理地址到在它上面等待的线程，先等待的先被唤醒
+    queues: BTreeMap<usize, VecDeque<usize>>,
+    // 线程号到它的等待状态，被唤醒的线程下次执行系统调用时取走
+    waiters: BTreeMap<usize, Waiter>,
+}
+
+impl FutexTable {
+    fn remove(&mut self, tid: usize) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&tid)?;
+        if let Some(queue) = self.queues.get_mut(&waiter.key) {
+            queue.retain(|&t| t != tid);
+            if queue.is_empty() {
+                self.queues.remove(&waiter.key);
+            }
+        }
+        Some(waiter)
+    }
+}
+
+lazy_static! {
+    static ref FUTEX: Mutex<FutexTable> = Mutex::new(FutexTable { queues: BTreeMap::new(), waiters: BTreeMap::new() });
+}
+
+// tid号线程在物理地址key上等待。第一次调用时，value_matches检查用户的值是否还是期望的值，
+// 不是时返回EAGAIN，让用户态重新检查锁的状态。等待中返回Pending，被唤醒时返回Ok，超时时返回ETIMEDOUT
+pub fn wait(tid: usize, key: usize, deadline: Option<u64>, value_matches: impl FnOnce() -> bool) -> Poll<Result<(), Errno>> {
+    let mut table = FUTEX.lock();
+    let now = crate::timer::now();
+    if let Some(waiter) = table.waiters.get(&tid) {
+        if waiter.woken {
+            table.waiters.remove(&tid);
+            return Poll::Ready(Ok(()));
+        }
+        return match waiter.deadline {
+            Some(deadline) if now >= deadline => {
+                table.remove(tid);
+                Poll::Ready(Err(ETIMEDOUT))
+            },
+            Some(deadline) => {
+                crate::timer::set_alarm(deadline);
+                Poll::Pending
+            },
+            None => Poll::Pending,
+        };
+    }
+    // 检查值和登记等待都在持有锁的时候完成，唤醒不会在两者之间发生
+    if !value_matches() {
+        return Poll::Ready(Err(EAGAIN));
+    }
+    if let Some(deadline) = deadline {
+        if now >= deadline {
+            return Poll::Ready(Err(ETIMEDOUT));
+        }
+        crate::timer::set_alarm(deadline);
+    }
+    table.queues.entry(key).or_insert_with(VecDeque::new).push_back(tid);
+    table.waiters.insert(tid, Waiter { key, deadline, woken: false });
+    Poll::Pending
+}
+
+// 唤醒在物理地址key上等待的最多count个线程，返回唤醒的线程数
+pub fn wake(key: usize, count: usize) -> usize {
+    let mut table = FUTEX.lock();
+    let table = &mut *table;
+    let queue = match table.queues.get_mut(&key) {
+        Some(queue) => queue,
+        None => return 0,
+    };
+    let mut woken = 0;
+    while woken < count {
+        match queue.pop_front() {
+            Some(tid) => {
+                if let Some(waiter) = table.waiters.get_mut(&tid) {
+                    waiter.woken = true;
+                }
+                woken += 1;
+            },
+            None => break,
+        }
+    }
+    if queue.is_empty() {
+        table.queues.remove(&key);
+    }
+    woken
+}
+
+// 线程在等待的时候随进程一起结束，从等待队列中去掉它
+pub fn cancel(tid: usize) {
+    FUTEX.lock().remove(tid);
+}
+
+pub(crate) fn test_futex() {
+    // 使用不会分配给真实线程的线程号和地址
+    let (a, b, c) = (usize::MAX - 1, usize::MAX - 2, usize::MAX - 3);
+    let key = usize::MAX & !3;
+    assert_eq!(wait(a, key, None, || false), Poll::Ready(Err(EAGAIN)));
+    assert_eq!(wake(key, 1), 0);
+    assert_eq!(wait(a, key, None, || true), Poll::Pending);
+    assert_eq!(wait(b, key, None, || true), Poll::Pending);
+    // 重新执行时不再检查值
+    assert_eq!(wait(a, key, None, || false), Poll::Pending);
+    // 先等待的线程先被唤醒
+    assert_eq!(wake(key, 1), 1);
+    assert_eq!(wait(b, key, None, || true), Poll::Pending);
+    assert_eq!(wait(a, key, None, || true), Poll::Ready(Ok(())));
+    assert_eq!(wake(key, usize::MAX), 1);
+    assert_eq!(wait(b, key, None, || true), Poll::Ready(Ok(())));
+    // 已经过去的时刻立即超时
+    assert_eq!(wait(c, key, Some(0), || true), Poll::Ready(Err(ETIMEDOUT)));
+    // 结束的线程不会占用唤醒的名额
+    assert_eq!(wait(c, key, None, || true), Poll::Pending);
+    cancel(c);
+    assert_eq!(wake(key, 1), 0);
+    println!("[kernel-futex-test] Futex wait and wake test passed");
+}
@@ -0,0 +1,119 @@
+//! 进程间通信
+//!
+//! 共享内存和消息通道是内核对象，进程通过句柄使用它们。句柄是进程的句柄表中的编号，
+//! 和文件描述符类似：创建子进程时子进程复制父进程的句柄表，两个进程的句柄指向同一个对象。
+//! 所有指向一个对象的句柄都关闭以后，对象被释放。
+//!
+//! 通道的消息可以附带一个共享内存的句柄，句柄从发送者的句柄表转移到接收者的句柄表中。
+//! 接收者映射这块共享内存，就能直接读写发送者准备好的数据，不需要复制。
+//!
+//! futex让线程在用户内存中的一个地址上等待，用户态的锁和条件变量用它实现
+
+pub mod futex;
+mod channel;
+mod shm;
+
+pub use channel::{channel, Endpoint, Message, MAX_MESSAGE_SIZE};
+pub use shm::SharedMemory;
+
+use crate::syscall::{Errno, EBADF, EMFILE};
+use alloc::{sync::Arc, vec::Vec};
+
+#[derive(Clone)]
+pub enum Handle {
+    SharedMemory(Arc<SharedMemory>),
+    Channel(Arc<Endpoint>),
+}
+
+// 每个进程最多同时持有的句柄数
+const MAX_HANDLES: usize = 64;
+
+// 句柄表
+#[derive(Clone)]
+pub struct HandleTable {
+    handles: Vec<Option<Handle>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        HandleTable { handles: Vec::new() }
+    }
+
+    // 使用编号最小的空闲句柄
+    pub fn insert(&mut self, handle: Handle) -> Result<usize, Errno> {
+        if let Some(idx) = self.handles.iter().position(|h| h.is_none()) {
+            self.handles[idx] = Some(handle);
+            return Ok(idx);
+        }
+        if self.handles.len() >= MAX_HANDLES {
+            return Err(EMFILE);
+        }
+        self.handles.push(Some(handle));
+        Ok(self.handles.len() - 1)
+    }
+
+    pub fn get(&self, idx: usize) -> Result<Handle, Errno> {
+        self.handles.get(idx).cloned().flatten().ok_or(EBADF)
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Result<Handle, Errno> {
+        self.handles.get_mut(idx).and_then(Option::take).ok_or(EBADF)
+    }
+
+    // 句柄不存在或者不是共享内存时返回EBADF
+    pub fn shared_memory(&self, idx: usize) -> Result<Arc<SharedMemory>, Errno> {
+        match self.get(idx)? {
+            Handle::SharedMemory(shm) => Ok(shm),
+            _ => Err(EBADF),
+        }
+    }
+
+    // 句柄不存在或者不是通道时返回EBADF
+    pub fn channel(&self, idx: usize) -> Result<Arc<Endpoint>, Errno> {
+        match self.get(idx)? {
+            Handle::Channel(endpoint) => Ok(endpoint),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+pub(crate) fn test_ipc(frame_alloc: crate::scheduler::UserFrameAllocator) {
+    use crate::syscall::{EAGAIN, EEXIST, EMSGSIZE, ENOENT, EPIPE};
+    use alloc::vec;
+    let mut table = HandleTable::new();
+    // 有名字的共享内存，在所有句柄关闭以前可以用名字打开
+    let shm = SharedMemory::new_named("kernel-ipc-test", 0x1800, frame_alloc).unwrap();
+    assert_eq!(shm.len(), 0x2000);
+    assert_eq!(SharedMemory::new_named("kernel-ipc-test", 0x1000, frame_alloc).err(), Some(EEXIST));
+    let opened = SharedMemory::open("kernel-ipc-test").unwrap();
+    assert!(Arc::ptr_eq(&shm, &opened));
+    let shm_handle = table.insert(Handle::SharedMemory(shm)).unwrap();
+    drop(opened);
+    // 消息附带的句柄转移到接收者
+    let (a, b) = channel();
+    let a_handle = table.insert(Handle::Channel(a)).unwrap();
+    let a = table.channel(a_handle).unwrap();
+    assert_eq!(table.channel(shm_handle).err(), Some(EBADF));
+    a.send(Message { data: b"ping".to_vec(), handle: Some(table.remove(shm_handle).unwrap()) }).unwrap();
+    assert_eq!(table.get(shm_handle).err(), Some(EBADF));
+    assert_eq!(a.send(Message { data: vec![0; MAX_MESSAGE_SIZE + 1], handle: None }), Err(EMSGSIZE));
+    let received = b.recv_with(|msg| {
+        assert_eq!(msg.data, b"ping");
+        match msg.handle.clone() {
+            Some(Handle::SharedMemory(shm)) => Ok(shm),
+            _ => panic!("shared memory handle not transferred"),
+        }
+    }).unwrap();
+    assert_eq!(received.pages().len(), 2);
+    assert_eq!(b.recv_with(|_| Ok(())), Err(EAGAIN));
+    assert!(SharedMemory::open("kernel-ipc-test").is_ok());
+    drop(received);
+    assert_eq!(SharedMemory::open("kernel-ipc-test").err(), Some(ENOENT));
+    // 一端关闭以后，另一端发送失败，读完剩下的消息以后接收也失败
+    b.send(Message { data: b"last".to_vec(), handle: None }).unwrap();
+    drop(b);
+    assert_eq!(a.send(Message { data: b"pong".to_vec(), handle: None }), Err(EPIPE));
+    assert_eq!(a.recv_with(|msg| Ok(msg.data.len())), Ok(4));
+    assert_eq!(a.recv_with(|_| Ok(())), Err(EPIPE));
+    println!("[kernel-ipc-test] Shared memory and channel test passed");
+}
@@ -0,0 +1,87 @@
+//! 共享内存
+//!
+//! 共享内存由一组页帧组成，每个页帧都有引用计数。对象本身和映射它的每个区域各持有一份引用，
+//! 所以不同进程可以把它映射到不同的地址上，所有映射和句柄都不存在以后页帧才被释放
+
+use crate::{scheduler::UserFrameAllocator, syscall::{Errno, EEXIST, EINVAL, ENOENT}, vma::{PageFrame, PAGE_SIZE}};
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// 名字的最大长度
+const MAX_NAME_LEN: usize = 64;
+
+lazy_static! {
+    // 有名字的共享内存，对象释放以后名字随之消失
+    static ref NAMED: Mutex<BTreeMap<String, Weak<SharedMemory>>> = Mutex::new(BTreeMap::new());
+}
+
+pub struct SharedMemory {
+    name: Option<String>,
+    pages: Vec<Arc<PageFrame>>,
+}
+
+impl SharedMemory {
+    // 创建清零的共享内存，长度向上取整到页
+    pub fn new(len: usize, frame_alloc: UserFrameAllocator) -> Result<Arc<SharedMemory>, Errno> {
+        Ok(Arc::new(SharedMemory { name: None, pages: alloc_pages(len, frame_alloc)? }))
+    }
+
+    // 创建有名字的共享内存，其它进程可以用open打开它。名字已经被使用时返回EEXIST
+    pub fn new_named(name: &str, len: usize, frame_alloc: UserFrameAllocator) -> Result<Arc<SharedMemory>, Errno> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(EINVAL);
+        }
+        let mut named = NAMED.lock();
+        if named.get(name).map(|shm| shm.strong_count() > 0) == Some(true) {
+            return Err(EEXIST);
+        }
+        let shm = Arc::new(SharedMemory { name: Some(String::from(name)), pages: alloc_pages(len, frame_alloc)? });
+        named.insert(String::from(name), Arc::downgrade(&shm));
+        Ok(shm)
+    }
+
+    pub fn open(name: &str) -> Result<Arc<SharedMemory>, Errno> {
+        NAMED.lock().get(name).and_then(Weak::upgrade).ok_or(ENOENT)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    pub fn pages(&self) -> &[Arc<PageFrame>] {
+        &self.pages
+    }
+
+    // 在/proc/<pid>/maps中显示的名字
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => alloc::format!("[shm:{}]", name),
+            None => String::from("[shm]"),
+        }
+    }
+}
+
+fn alloc_pages(len: usize, frame_alloc: UserFrameAllocator) -> Result<Vec<Arc<PageFrame>>, Errno> {
+    if len == 0 {
+        return Err(EINVAL);
+    }
+    let count = (len - 1) / PAGE_SIZE + 1;
+    let mut pages = Vec::with_capacity(count);
+    for _ in 0..count {
+        pages.push(Arc::new(PageFrame::zeroed(frame_alloc)?));
+    }
+    Ok(pages)
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        if let Some(name) = &self.name {
+            let mut named = NAMED.lock();
+            // 同名的新对象可能已经创建了
+            if named.get(name).map(|shm| shm.strong_count() == 0) == Some(true) {
+                named.remove(name);
+            }
+        }
+    }
+}
@@ -1,4 +1,5 @@
 #![feature(naked_functions, asm, global_asm)]
+#![feature(riscv_target_feature)]
 #![feature(alloc_error_handler)]
 #![feature(panic_info_message)]
 #![feature(generator_trait)]
@@ -7,194 +8,646 @@
 #![no_main]
 
 extern crate alloc;
+#[macro_use]
+extern crate log;
 
 #[macro_use]
 mod console;
+mod backtrace;
+mod config;
 mod sbi;
+mod dtb;
+mod drivers;
+mod fs;
+mod ipc;
+#[macro_use]
 mod executor;
+mod fpu;
+mod trap;
+mod interrupt;
 mod mm;
+mod process;
+mod scheduler;
+mod signal;
 mod syscall;
+mod task;
+mod timer;
+mod user_heap;
+mod vma;
 
 use core::panic::PanicInfo;
-use alloc::vec::Vec;
+use alloc::{string::{String, ToString}, sync::Arc, vec, vec::Vec};
 use syscall::{syscall, SyscallOperation};
 
+// 按照目标平台的指针宽度，选择内核使用的分页模式。RV64使用Sv39，RV32使用Sv32
+#[cfg(target_pointer_width = "64")]
+use mm::{
+    Sv39 as KernelPageMode, Sv39Flags as KernelPageFlags, 
+    activate_paged_riscv_sv39 as activate_paged_riscv, get_satp_sv39 as get_satp,
+};
+#[cfg(target_pointer_width = "32")]
+use mm::{
+    Sv32 as KernelPageMode, Sv32Flags as KernelPageFlags, 
+    activate_paged_riscv_sv32 as activate_paged_riscv, get_satp_sv32 as get_satp,
+};
+
+// 物理内存布局，暂时对qemu写死。
+// RV64的RustSBI把内核放在0x80200000；RV32的固件按4M对齐，内核放在0x80400000，
+// 所以RV32下initramfs和可分配页帧都要往后放。
+// 内核链接在这些物理地址加上mm::PHYS_VIRT_OFFSET的虚拟地址上，访问物理内存都要经过线性映射
+#[cfg(target_pointer_width = "64")]
+mod layout {
+    pub const INITRAMFS_BASE: usize = 0x80400000;
+    pub const MEMORY_END: usize = 0x80800000;
+}
+#[cfg(target_pointer_width = "32")]
+mod layout {
+    pub const INITRAMFS_BASE: usize = 0x80800000;
+    pub const MEMORY_END: usize = 0x80c00000;
+}
+// 设备树没有给出initramfs的位置时，xtask用loader设备把它放在INITRAMFS_BASE，最大1MiB
+const INITRAMFS_PAGES: usize = 256;
+// xtask把内核的符号表放在initramfs之后，最大256KiB
+const SYMBOLS_BASE: usize = layout::INITRAMFS_BASE + INITRAMFS_PAGES * 0x1000;
+const SYMBOLS_PAGES: usize = 64;
+const FRAME_ALLOC_BASE: usize = SYMBOLS_BASE + SYMBOLS_PAGES * 0x1000;
+// 用户程序从虚拟地址0x1000开始，用户堆紧接在程序之后；两者的大小由内核命令行设置
+const USER_PROGRAM_BASE: usize = 0x1000;
+// 用户栈的位置
+const USER_STACK_BASE: usize = 0x60000000;
+// 每个进程的程序、栈和堆最多占用这么多内存
+const USER_MEMORY_LIMIT: usize = 16 * 1024 * 1024;
+// 启动后运行的第一个用户程序。内核命令行没有给出init=，initramfs中有/etc/init时，使用其中写的路径
+const INIT_PROGRAM: &str = "/bin/shell";
+const INIT_CONFIG: &str = "/etc/init";
+
 pub extern "C" fn rust_main(hartid: usize, dtb_pa: usize) -> ! {
-    println!("[kernel] Hart id = {}, DTB physical address = {:#x}", hartid, dtb_pa);
     mm::heap_init();
+    // 设备树在切换到内核地址空间之前解析，此时启动页表映射了它
+    let device_info = unsafe { dtb::DeviceInfo::parse(dtb_pa) }.expect("parse device tree");
+    let boot_config = config::BootConfig::parse(device_info.bootargs.as_deref().unwrap_or(""));
+    console::init_log(&boot_config);
+    info!("Hart id = {}, DTB physical address = {:#x}", hartid, dtb_pa);
+    if let Some(bootargs) = &device_info.bootargs {
+        info!("Kernel command line: {}", bootargs);
+    }
+    for arg in &boot_config.ignored {
+        warn!("Ignored kernel parameter `{}`", arg);
+    }
+    if let Some(uart) = device_info.uart {
+        unsafe { console::init_uart(mm::PhysAddr(uart.base).to_kernel_virt().0) };
+        info!("Console switched to ns16550a at {:#x}", uart.base);
+    }
+    fs::init();
+    load_initramfs(&device_info);
+    backtrace::init(SYMBOLS_BASE, SYMBOLS_PAGES * 0x1000);
     mm::test_frame_alloc();
     // 页帧分配器。对整个物理的地址空间来说，无论有多少个核，页帧分配器只有一个。
-    let from = mm::PhysAddr(0x80420000).page_number::<mm::Sv39>();
-    let to = mm::PhysAddr(0x80800000).page_number::<mm::Sv39>(); // 暂时对qemu写死
-    let frame_alloc = spin::Mutex::new(mm::StackFrameAllocator::new(from, to));
-    let mut kernel_addr_space = mm::PagedAddrSpace::try_new_in(mm::Sv39, &frame_alloc)
+    let from = mm::PhysAddr(FRAME_ALLOC_BASE).page_number::<KernelPageMode>();
+    let to = mm::PhysAddr(layout::MEMORY_END).page_number::<KernelPageMode>();
+    static FRAME_ALLOC: spin::Once<mm::DefaultFrameAllocator> = spin::Once::new();
+    let frame_alloc = FRAME_ALLOC.call_once(|| spin::Mutex::new(mm::StackFrameAllocator::new(from, to)));
+    mm::set_kernel_frame_allocator(frame_alloc);
+    let mut kernel_addr_space = mm::PagedAddrSpace::try_new_in(KernelPageMode, frame_alloc)
         .expect("allocate page to create kernel paged address space");
     mm::test_map_solve();
+    // 内存线性映射到高半部分。内核镜像按段映射，固件所在的内存和内核之后没有使用的内存不映射
+    for (name, start, end, flags) in kernel_sections().iter().cloned() {
+        assert!(start % 0x1000 == 0 && end % 0x1000 == 0, "kernel section {} is not page aligned", name);
+        trace!("kernel section {} = {:#x}..{:#x}, flags = {:?}", name, start, end, flags);
+        kernel_addr_space.allocate_map(
+            mm::VirtAddr(start).page_number::<KernelPageMode>(), 
+            mm::PhysAddr::from_kernel_virt(mm::VirtAddr(start)).page_number::<KernelPageMode>(), 
+            (end - start) / 0x1000,
+            flags
+        ).expect("allocate kernel section mapped space");
+    }
+    // initramfs已经解包，只在启动时读取
     kernel_addr_space.allocate_map(
-        mm::VirtAddr(0x80000000).page_number::<mm::Sv39>(), 
-        mm::PhysAddr(0x80000000).page_number::<mm::Sv39>(), 
-        1024,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X
-    ).expect("allocate one mapped space");
+        mm::PhysAddr(layout::INITRAMFS_BASE).to_kernel_virt().page_number::<KernelPageMode>(), 
+        mm::PhysAddr(layout::INITRAMFS_BASE).page_number::<KernelPageMode>(), 
+        INITRAMFS_PAGES,
+        KernelPageFlags::R
+    ).expect("allocate initramfs mapped space");
     kernel_addr_space.allocate_map(
-        mm::VirtAddr(0x80400000).page_number::<mm::Sv39>(), 
-        mm::PhysAddr(0x80400000).page_number::<mm::Sv39>(), 
-        32,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X
-    ).expect("allocate user program mapped space");
+        mm::PhysAddr(SYMBOLS_BASE).to_kernel_virt().page_number::<KernelPageMode>(), 
+        mm::PhysAddr(SYMBOLS_BASE).page_number::<KernelPageMode>(), 
+        SYMBOLS_PAGES,
+        KernelPageFlags::R
+    ).expect("allocate kernel symbols mapped space");
     kernel_addr_space.allocate_map(
-        mm::VirtAddr(0x80420000).page_number::<mm::Sv39>(), 
-        mm::PhysAddr(0x80420000).page_number::<mm::Sv39>(), 
-        1024 - 32, 
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X
+        mm::PhysAddr(FRAME_ALLOC_BASE).to_kernel_virt().page_number::<KernelPageMode>(), 
+        mm::PhysAddr(FRAME_ALLOC_BASE).page_number::<KernelPageMode>(), 
+        (layout::MEMORY_END - FRAME_ALLOC_BASE) / 0x1000, 
+        KernelPageFlags::R | KernelPageFlags::W
     ).expect("allocate remaining space");
-    let (vpn, ppn, n) = get_trampoline_text_paging_config::<mm::Sv39>();
-    let trampoline_va_start = vpn.addr_begin::<mm::Sv39>();
+    // 设备的寄存器，同样在线性映射中
+    let devices = device_info.uart.iter()
+        .chain(device_info.plic.iter())
+        .chain(device_info.virtio.iter());
+    for device in devices {
+        let page_count = (device.size + 0xfff) / 0x1000;
+        kernel_addr_space.allocate_map(
+            mm::PhysAddr(device.base).to_kernel_virt().page_number::<KernelPageMode>(), 
+            mm::PhysAddr(device.base).page_number::<KernelPageMode>(), 
+            page_count,
+            KernelPageFlags::R | KernelPageFlags::W
+        ).expect("allocate device mapped space");
+    }
+    let (vpn, ppn, n) = get_trampoline_text_paging_config::<KernelPageMode>();
+    let trampoline_va_start = vpn.addr_begin::<KernelPageMode>();
     kernel_addr_space.allocate_map(
         vpn, ppn, n,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X
+        KernelPageFlags::R | KernelPageFlags::X
     ).expect("allocate trampoline code mapped space");
     // 跳板数据页
     let data_len = core::mem::size_of::<executor::ResumeContext>();
-    let frame_size = 1_usize << <mm::Sv39 as mm::PageMode>::FRAME_SIZE_BITS;
+    let frame_size = 1_usize << <KernelPageMode as mm::PageMode>::FRAME_SIZE_BITS;
     assert!(data_len > 0, "resume context should take place in memory");
     let data_frame_count = (data_len - 1) / frame_size + 1; // roundup(data_len / frame_size)
     let mut frames = Vec::new();
     for i in 0..data_frame_count {
-        let frame_box = mm::FrameBox::try_new_in(&frame_alloc).expect("allocate user stack frame");
+        let frame_box = mm::FrameBox::try_new_in(frame_alloc).expect("allocate user stack frame");
         kernel_addr_space.allocate_map(
             // 去掉代码页的数量n
-            mm::VirtAddr(usize::MAX - n * 0x1000 - data_frame_count * 0x1000 + i * 0x1000 + 1).page_number::<mm::Sv39>(), 
+            mm::VirtAddr(usize::MAX - n * 0x1000 - data_frame_count * 0x1000 + i * 0x1000 + 1).page_number::<KernelPageMode>(), 
             frame_box.phys_page_num(), 
             1,
-            mm::Sv39Flags::R | mm::Sv39Flags::W
+            KernelPageFlags::R | KernelPageFlags::W
         ).expect("allocate trampoline data mapped space");
         frames.push((i, frame_box))
     }
     let trampoline_data_addr = mm::VirtAddr(usize::MAX - n * 0x1000 - data_frame_count * 0x1000 + 1);
     mm::test_asid_alloc();
+    user_heap::test_user_heap(frame_alloc);
+    mm::test_heap_growth(frame_alloc);
+    mm::test_slab();
     let max_asid = mm::max_asid();
     let mut asid_alloc = mm::StackAsidAllocator::new(max_asid);
     let kernel_asid = asid_alloc.allocate_asid().expect("alloc kernel asid");
-    let _kernel_satp = unsafe {
-        mm::activate_paged_riscv_sv39(kernel_addr_space.root_page_number(), kernel_asid)
+    let kernel_satp = unsafe {
+        activate_paged_riscv(kernel_addr_space.root_page_number(), kernel_asid)
     };
-    // println!("kernel satp = {:x?}", kernel_satp);
+    trace!("kernel satp = {:x?}", kernel_satp);
+    mm::test_linear_map(&kernel_addr_space);
+    mm::test_kernel_wx(&kernel_addr_space);
+    // 之后内核栈映射到内核地址空间中，由mm模块保存它
+    mm::set_kernel_space(kernel_addr_space);
+    mm::test_kernel_stack();
+    trap::init();
+    timer::init(device_info.timebase_frequency);
+    fpu::init();
+    if let Some(plic) = device_info.plic {
+        unsafe { interrupt::init(mm::PhysAddr(plic.base).to_kernel_virt().0, hartid) };
+        if let Some(irq) = device_info.uart.and_then(|uart| uart.irq) {
+            console::enable_uart_interrupt(irq);
+        }
+        drivers::probe_virtio(&device_info.virtio);
+        if let Some(blk) = drivers::block_device() {
+            drivers::virtio_blk::test_virtio_blk(blk);
+        }
+    }
+    fs::mount("/proc", Arc::new(fs::ProcFs::new(frame_alloc))).expect("mount procfs");
+    fs::test_vfs();
+    fs::test_initramfs();
+    fs::test_pipe();
+    fs::test_procfs("/proc");
+    vma::test_vma(frame_alloc);
+    ipc::test_ipc(frame_alloc);
+    ipc::futex::test_futex();
+    signal::test_signal();
+    backtrace::test_backtrace();
+    fpu::test_fpu();
+    console::test_log();
+    config::test_config();
+    if let Some(blk) = drivers::block_device() {
+        match fs::Fat32::mount(blk) {
+            Ok(fat) => {
+                fs::mount("/mnt", fat).expect("mount FAT32 file system");
+                info!("Mounted FAT32 file system at /mnt");
+                fs::test_fat32("/mnt");
+            },
+            Err(e) => info!("No FAT32 file system on block device: {:?}", e),
+        }
+    }
     executor::init(trampoline_va_start);
-    let (mut user_space, _user_stack, user_stack_addr) = 
-        create_sv39_app_address_space(&frame_alloc);
-    for (idx, frame_box) in frames.iter() {
-        user_space.allocate_map(
-            mm::VirtAddr(usize::MAX - n * 0x1000 - data_frame_count * 0x1000 + idx * 0x1000 + 1).page_number::<mm::Sv39>(), 
-            frame_box.phys_page_num(), 
-            1,
-            mm::Sv39Flags::R | mm::Sv39Flags::W
-        ).expect("allocate trampoline data mapped space");
+    let mut loader = ProcessLoader {
+        frame_alloc, asid_alloc, trampoline_data: frames, trampoline_data_addr,
+        program_pages: boot_config.program_pages, stack_pages: boot_config.stack_pages,
+    };
+    let init_program = init_program_path(&boot_config);
+    let init_fd_table = fs::FdTable::with_stdio().expect("open standard input and output");
+    // 参数以0分隔，第一个参数是程序的名字
+    let init_args = [init_program.as_bytes(), b"\0"].concat();
+    let (init, init_context) = loader.load(&init_program, &init_args, init_fd_table, 0).expect("load init program");
+    let mut rt = executor::Runtime::new(trampoline_va_start, trampoline_data_addr);
+    let mut scheduler = scheduler::Scheduler::new(boot_config.timeslice_ms);
+    let init_stack = mm::KernelStack::new().expect("allocate kernel stack for init program");
+    scheduler.add(&mut rt, init, init_context, init_stack);
+    loop {
+        // 当前线程的陷入在它自己的内核栈上处理
+        let stack_top = scheduler.kernel_stack_top();
+        unsafe { mm::run_on_kernel_stack(stack_top, || run_current_thread(&mut scheduler, &mut rt, &mut loader, hartid)) };
+        // 回到启动栈以后，才能释放结束的线程的内核栈
+        scheduler.free_exited_stacks();
     }
-    let user_asid = asid_alloc.allocate_asid().expect("alloc user asid");
-    // println!("User space = {:x?}", user_space);
-    // println!("Ppn = {:x?}", user_space.root_page_number());
-    let mut rt = executor::Runtime::new_user(
-        0x1000, 
-        user_stack_addr,
-        mm::get_satp_sv39(user_asid, user_space.root_page_number()),
-        trampoline_va_start,
-        trampoline_data_addr,
-    ); 
+}
+
+// 处理当前线程的信号，运行它直到下一次陷入，再处理这次陷入。在当前线程的内核栈上运行
+fn run_current_thread(scheduler: &mut scheduler::Scheduler, rt: &mut executor::Runtime, loader: &mut ProcessLoader, hartid: usize) {
     use core::pin::Pin;
     use core::ops::{Generator, GeneratorState};
-    loop {
-        match Pin::new(&mut rt).resume(()) {
-            GeneratorState::Yielded(executor::KernelTrap::Syscall()) => {
-                // println!("Kernel trap syscall!");
-                let ctx = unsafe { rt.context_mut() };
-                match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5], &user_space) {
-                    SyscallOperation::Return(ans) => {
-                        ctx.a0 = ans.code;
-                        ctx.a1 = ans.extra;
-                        ctx.sepc = ctx.sepc.wrapping_add(4);
-                    }
-                    SyscallOperation::Terminate(code) => {
-                        println!("[Kernel] Process returned with code {}", code);
-                        sbi::shutdown()
-                    }
-                    SyscallOperation::UserPanic(file, line, col, msg) => {
-                        let file = file.unwrap_or("<no file>");
-                        let msg = msg.unwrap_or("<no message>");
-                        println!("[Kernel] User process panicked at '{}', {}:{}:{}", msg, file, line, col);
-                        sbi::shutdown()
+    // 控制台上按下了Ctrl-C，向所有进程发送SIGINT。shell应当忽略它
+    if console::take_interrupt() {
+        scheduler.signal_all(signal::SIGINT);
+    }
+    // 回到用户态之前，处理当前进程的信号
+    let ctx = unsafe { rt.context_mut() };
+    let task = scheduler.current();
+    match task.signals.deliver(&task.space, ctx) {
+        signal::Delivery::Resume => {},
+        signal::Delivery::Stop => {
+            process::set_state(task.pid, process::ProcessState::Stopped);
+            scheduler.block_current(rt);
+            return
+        },
+        signal::Delivery::Terminate(code) => {
+            exit_process(scheduler, rt, loader, code);
+            return
+        },
+    }
+    if let Some(deadline) = scheduler.slice_deadline() {
+        timer::set_alarm(deadline);
+    }
+    match Pin::new(&mut *rt).resume(()) {
+        GeneratorState::Yielded(executor::KernelTrap::Syscall()) => {
+            trace!("Kernel trap syscall");
+            let ctx = unsafe { rt.context_mut() };
+            let tid = scheduler.current_tid();
+            let task = scheduler.current();
+            let exit_code = match syscall(ctx.a7, ctx.a6, [ctx.a0, ctx.a1, ctx.a2, ctx.a3, ctx.a4, ctx.a5], task, tid) {
+                SyscallOperation::Return(ans) => {
+                    ctx.a0 = ans.code;
+                    ctx.a1 = ans.extra;
+                    ctx.sepc = ctx.sepc.wrapping_add(4);
+                    scheduler.mark_progress();
+                    return
+                }
+                SyscallOperation::Block => {
+                    scheduler.block_current(rt);
+                    return
+                }
+                SyscallOperation::Spawn(path, args) => {
+                    // 子进程继承父进程打开的文件和句柄
+                    let handles = task.handles.clone();
+                    let (fd_table, ppid) = (task.fd_table.clone(), task.pid);
+                    let ans = mm::KernelStack::new().map_err(|_| syscall::ENOMEM)
+                        .and_then(|kernel_stack| Ok((loader.load(&path, &args, fd_table, ppid)?, kernel_stack)))
+                        .map(|((mut child, context), kernel_stack)| {
+                            child.handles = handles;
+                            let pid = child.pid;
+                            scheduler.add(rt, child, context, kernel_stack);
+                            pid
+                        });
+                    let ans = syscall::SyscallResult::from_result(ans);
+                    let ctx = unsafe { rt.context_mut() };
+                    ctx.a0 = ans.code;
+                    ctx.a1 = ans.extra;
+                    ctx.sepc = ctx.sepc.wrapping_add(4);
+                    scheduler.mark_progress();
+                    return
+                }
+                SyscallOperation::SpawnThread(entry, stack, tp, arg) => {
+                    let ans = syscall::SyscallResult::from_result(scheduler.spawn_thread(entry, stack, tp, arg));
+                    let ctx = unsafe { rt.context_mut() };
+                    ctx.a0 = ans.code;
+                    ctx.a1 = ans.extra;
+                    ctx.sepc = ctx.sepc.wrapping_add(4);
+                    scheduler.mark_progress();
+                    return
+                }
+                SyscallOperation::ExitThread(code) if task.running_threads() > 1 => {
+                    scheduler.exit_thread(rt, code);
+                    return
+                }
+                SyscallOperation::Yield => {
+                    ctx.a0 = 0;
+                    ctx.a1 = 0;
+                    ctx.sepc = ctx.sepc.wrapping_add(4);
+                    scheduler.yield_current(rt);
+                    return
+                }
+                SyscallOperation::Kill(pid, sig) => {
+                    let ans = syscall::SyscallResult::from_result(scheduler.send_signal(pid, sig).map(|_| 0));
+                    let ctx = unsafe { rt.context_mut() };
+                    ctx.a0 = ans.code;
+                    ctx.a1 = ans.extra;
+                    ctx.sepc = ctx.sepc.wrapping_add(4);
+                    scheduler.mark_progress();
+                    return
+                }
+                SyscallOperation::SigReturn => {
+                    // 恢复的上下文中已经有信号到来时的sepc，不需要推进
+                    if task.signals.sigreturn(&task.space, ctx).is_err() {
+                        task.signals.force(signal::SIGSEGV, ctx.sp);
                     }
+                    scheduler.mark_progress();
+                    return
                 }
-            },
-            GeneratorState::Yielded(executor::KernelTrap::IllegalInstruction(val)) => {
-                println!("[Kernel] Illegal instruction {:016x}, kernel dumpped.", val);
-                sbi::shutdown()
-            },
-            GeneratorState::Yielded(trap) => {
-                println!("[Kernel] Trap {:?}, kernel dumpped.", trap);
-                sbi::shutdown()
-            } 
-            GeneratorState::Complete(()) => sbi::shutdown()
-        }
+                // 最后一个线程结束时，进程以它的返回值结束
+                SyscallOperation::Terminate(code) | SyscallOperation::ExitThread(code) => code,
+                SyscallOperation::UserPanic(file, line, col, msg) => {
+                    let file = file.as_deref().unwrap_or("<no file>");
+                    let msg = msg.as_deref().unwrap_or("<no message>");
+                    warn!("User process {} panicked at '{}', {}:{}:{}", task.pid, msg, file, line, col);
+                    -1
+                }
+            };
+            exit_process(scheduler, rt, loader, exit_code);
+        },
+        GeneratorState::Yielded(executor::KernelTrap::External()) => {
+            interrupt::handle_external(hartid);
+        },
+        GeneratorState::Yielded(executor::KernelTrap::Timer()) => {
+            timer::handle_interrupt();
+            scheduler.preempt(rt);
+        },
+        // 用户程序的异常转换为信号，没有处理函数时进程被结束
+        GeneratorState::Yielded(executor::KernelTrap::LoadAccessFault(addr)) |
+        GeneratorState::Yielded(executor::KernelTrap::StoreAccessFault(addr)) |
+        GeneratorState::Yielded(executor::KernelTrap::PageFault(addr)) => {
+            user_fault(scheduler, rt, signal::SIGSEGV, addr);
+        },
+        GeneratorState::Yielded(executor::KernelTrap::Misaligned(addr)) => {
+            user_fault(scheduler, rt, signal::SIGBUS, addr);
+        },
+        GeneratorState::Yielded(executor::KernelTrap::IllegalInstruction(_)) => {
+            // 第一次使用浮点指令时打开浮点单元，重新执行这条指令
+            if !scheduler.enable_fpu(rt) {
+                let sepc = unsafe { rt.context_mut() }.sepc;
+                user_fault(scheduler, rt, signal::SIGILL, sepc);
+            }
+        },
+        GeneratorState::Complete(()) => shutdown()
     }
 }
 
+// 用户程序出错，输出它的调用栈，再向它发送对应的信号
+fn user_fault(scheduler: &mut scheduler::Scheduler, rt: &mut executor::Runtime, sig: usize, addr: usize) {
+    let ctx = unsafe { rt.context_mut() };
+    let task = scheduler.current();
+    warn!("Process {} received signal {} at {:#x}, pc = {:#x}", task.pid, sig, addr, ctx.sepc);
+    backtrace::print_user(task.pid, &task.space, ctx);
+    task.signals.force(sig, addr);
+}
+
+// 结束当前进程：结束它的所有线程，关闭它打开的文件（管道的另一端因此读到文件末尾），释放它的地址空间，
+// 再通知父进程。进程表中的记录保留到父进程取走返回值为止。没有其它线程可以运行时关机
+fn exit_process(scheduler: &mut scheduler::Scheduler, rt: &mut executor::Runtime, loader: &mut ProcessLoader, exit_code: i32) {
+    let (task, has_next) = scheduler.exit_current(rt);
+    process::set_state(task.pid, process::ProcessState::Exited(exit_code));
+    match process::with_process(task.pid, |p| p.ppid) {
+        // 子进程的返回值由父进程报告，这里只输出没有父进程的进程的返回值
+        Some(0) => info!("Process {} returned with code {}", task.pid, exit_code),
+        Some(ppid) => { let _ = scheduler.send_signal(ppid, signal::SIGCHLD); },
+        None => {},
+    }
+    loader.release(task);
+    if !has_next {
+        shutdown()
+    }
+}
+
+// 内核镜像中的各段，由链接脚本导出的符号划分，都按页对齐。每段只有它需要的权限：
+// 代码和跳板代码可读可执行，只读数据只读，数据、启动栈和bss段可读写
+fn kernel_sections() -> [(&'static str, usize, usize, KernelPageFlags); 6] {
+    extern "C" {
+        fn stext(); fn etext(); fn strampoline(); fn etrampoline(); fn srodata(); fn erodata();
+        fn sdata(); fn edata(); fn sbss(); fn ebss();
+    }
+    [
+        (".text", stext as usize, etext as usize, KernelPageFlags::R | KernelPageFlags::X),
+        (".trampoline", strampoline as usize, etrampoline as usize, KernelPageFlags::R | KernelPageFlags::X),
+        (".rodata", srodata as usize, erodata as usize, KernelPageFlags::R),
+        (".data", sdata as usize, edata as usize, KernelPageFlags::R | KernelPageFlags::W),
+        (".bss.stack", edata as usize, sbss as usize, KernelPageFlags::R | KernelPageFlags::W),
+        (".bss", sbss as usize, ebss as usize, KernelPageFlags::R | KernelPageFlags::W),
+    ]
+}
+
+// 跳板代码在内核镜像中，通过线性映射得到它的物理地址，再映射到地址空间的最高处
 fn get_trampoline_text_paging_config<M: mm::PageMode>() -> (mm::VirtPageNum, mm::PhysPageNum, usize) {
-    let (trampoline_pa_start, trampoline_pa_end) = {
+    let (trampoline_start, trampoline_end) = {
         extern "C" { fn strampoline(); fn etrampoline(); }
         (strampoline as usize, etrampoline as usize)
     };
-    assert_ne!(trampoline_pa_start, trampoline_pa_end, "trampoline code not declared");
-    let trampoline_len = trampoline_pa_end - trampoline_pa_start;
+    assert_ne!(trampoline_start, trampoline_end, "trampoline code not declared");
+    let trampoline_len = trampoline_end - trampoline_start;
     let trampoline_va_start = usize::MAX - trampoline_len + 1;
+    let trampoline_pa_start = mm::PhysAddr::from_kernel_virt(mm::VirtAddr(trampoline_start));
     let vpn = mm::VirtAddr(trampoline_va_start).page_number::<M>();
-    let ppn = mm::PhysAddr(trampoline_pa_start).page_number::<M>();
+    let ppn = trampoline_pa_start.page_number::<M>();
     let n = trampoline_len >> M::FRAME_SIZE_BITS;
-    // println!("va = {:x?}, pa = {:x?} {:x?}", trampoline_va_start, trampoline_pa_start, trampoline_pa_end);
-    // println!("l = {:x?}", trampoline_len);
-    // println!("vpn = {:x?}, ppn = {:x?}, n = {}", vpn, ppn, n);
+    trace!("trampoline va = {:#x}, pa = {:#x}, len = {:#x}", trampoline_va_start, trampoline_pa_start.0, trampoline_len);
+    trace!("trampoline vpn = {:x?}, ppn = {:x?}, n = {}", vpn, ppn, n);
     (vpn, ppn, n)
 }
 
-fn create_sv39_app_address_space<A: mm::FrameAllocator + Clone>(frame_alloc: A) -> (mm::PagedAddrSpace<mm::Sv39, A>, Vec<mm::FrameBox<A>>, mm::VirtAddr) {
-    let mut addr_space = mm::PagedAddrSpace::try_new_in(mm::Sv39, frame_alloc.clone())
-        .expect("allocate page to create kernel paged address space");
-    let (vpn, ppn, n) = get_trampoline_text_paging_config::<mm::Sv39>();
+// 找到initramfs，解包到根文件系统。位置优先从设备树的/chosen节点得到，没有时检查固定的位置。
+// 在切换到内核地址空间之前调用，此时启动页表线性映射了设备树给出的位置
+fn load_initramfs(device_info: &dtb::DeviceInfo) {
+    let (start, max_len) = match device_info.initrd {
+        Some((start, end)) => (start, end - start),
+        None => (layout::INITRAMFS_BASE, INITRAMFS_PAGES * 0x1000),
+    };
+    let va = mm::PhysAddr(start).to_kernel_virt().0;
+    let data = unsafe { core::slice::from_raw_parts(va as *const u8, max_len) };
+    if !fs::is_initramfs(data) {
+        warn!("No initramfs found at {:#x}", start);
+        return;
+    }
+    match fs::unpack_initramfs(data) {
+        Ok((len, file_count)) => info!("Unpacked {} files from initramfs at {:#x}, {} bytes", file_count, start, len),
+        Err(e) => error!("Failed to unpack initramfs at {:#x}: {:?}", start, e),
+    }
+}
+
+// 第一个用户程序的路径
+fn init_program_path(boot_config: &config::BootConfig) -> String {
+    if let Some(init) = &boot_config.init {
+        return init.clone();
+    }
+    let mut buf = [0u8; 256];
+    let len = match fs::open(INIT_CONFIG, fs::OpenFlags::READ) {
+        Ok(file) => file.read(&mut buf).unwrap_or(0),
+        Err(_) => 0,
+    };
+    match core::str::from_utf8(&buf[..len]).map(|s| s.trim()) {
+        Ok(path) if !path.is_empty() => path.to_string(),
+        _ => INIT_PROGRAM.to_string(),
+    }
+}
+
+// 创建用户进程需要的资源。每个进程的地址空间都要映射跳板代码页和这个处理核的跳板数据页
+struct ProcessLoader {
+    frame_alloc: &'static mm::DefaultFrameAllocator,
+    asid_alloc: mm::StackAsidAllocator,
+    trampoline_data: Vec<(usize, mm::FrameBox<&'static mm::DefaultFrameAllocator>)>,
+    trampoline_data_addr: mm::VirtAddr,
+    // 用户程序最多占用的页数和主线程栈的页数
+    program_pages: usize,
+    stack_pages: usize,
+}
+
+impl ProcessLoader {
+    // 加载path处的程序，创建新的进程并登记到进程表中。args是以0分隔的参数，放在用户栈的顶部
+    // 返回新的进程和它的主线程的上下文
+    fn load(&mut self, path: &str, args: &[u8], fd_table: fs::FdTable, ppid: usize) -> Result<(scheduler::Task, executor::ResumeContext), syscall::Errno> {
+        let (mut user_space, user_frames, user_stack_addr, mut user_areas) = 
+            create_app_address_space(self.frame_alloc, path, self.program_pages, self.stack_pages)?;
+        for (idx, frame_box) in self.trampoline_data.iter() {
+            user_space.allocate_map(
+                mm::VirtAddr(self.trampoline_data_addr.0 + idx * 0x1000).page_number::<KernelPageMode>(), 
+                frame_box.phys_page_num(), 
+                1,
+                KernelPageFlags::R | KernelPageFlags::W
+            ).map_err(|_| syscall::ENOMEM)?;
+        }
+        user_areas.push(process::MapArea {
+            start: self.trampoline_data_addr.0,
+            end: self.trampoline_data_addr.0 + self.trampoline_data.len() * 0x1000,
+            read: true, write: true, execute: false, shared: false,
+            name: "[trampoline data]".to_string(),
+        });
+        // 按地址从低到高排列，和Linux的maps一致
+        user_areas.sort_by_key(|area| area.start);
+        // 参数复制到栈顶的一页中，栈指针按16字节对齐放在参数的下面
+        if args.len() > 0x1000 - 16 {
+            return Err(syscall::E2BIG);
+        }
+        let args_addr = (user_stack_addr.0 - args.len()) & !0xf;
+        let top_frame = user_frames.last().unwrap(); // 最后分配的是栈顶的页
+        let top_page = top_frame.phys_page_num().addr_begin::<KernelPageMode>().to_kernel_virt().0;
+        let offset = args_addr - (user_stack_addr.0 - 0x1000);
+        let dst = unsafe { core::slice::from_raw_parts_mut((top_page + offset) as *mut u8, args.len()) };
+        dst.copy_from_slice(args);
+        let user_asid = self.asid_alloc.allocate_asid().map_err(|_| syscall::ENOMEM)?;
+        let user_satp = get_satp(user_asid, user_space.root_page_number());
+        trace!("user space root ppn = {:x?}, satp = {:x?}", user_space.root_page_number(), user_satp);
+        let pid = process::alloc_pid();
+        process::insert(process::Process {
+            pid,
+            ppid,
+            name: path.to_string(),
+            state: process::ProcessState::Running,
+            asid: user_asid.as_usize(),
+            root_ppn: user_space.root_page_number().as_usize(),
+            areas: user_areas,
+        });
+        // 程序从开头运行，a0和a1是参数的位置和长度
+        let mut context = executor::ResumeContext::new_user(USER_PROGRAM_BASE, mm::VirtAddr(args_addr));
+        context.a0 = args_addr;
+        context.a1 = args.len();
+        let heap = user_heap::UserHeap::new(USER_PROGRAM_BASE + self.program_pages * 0x1000, self.frame_alloc);
+        let mappings = vma::VmaSet::new(self.frame_alloc);
+        let task = scheduler::Task::new(pid, user_space, user_frames, heap, mappings, fd_table, user_asid, USER_MEMORY_LIMIT / 0x1000, user_satp);
+        Ok((task, context))
+    }
+
+    // 进程结束以后，回收它的地址空间编号；地址空间和页帧随Task一起释放
+    fn release(&mut self, task: mm::SlabBox<scheduler::Task>) {
+        self.asid_alloc.deallocate_asid(task.asid);
+    }
+}
+
+// 创建用户程序的地址空间，从文件系统中读取程序，复制到新分配的页帧中。
+// 同时返回地址空间中各段映射的信息，供/proc显示
+fn create_app_address_space<A: mm::FrameAllocator + Clone>(frame_alloc: A, path: &str, program_pages: usize, stack_pages: usize) -> Result<(mm::PagedAddrSpace<KernelPageMode, A>, Vec<mm::FrameBox<A>>, mm::VirtAddr, Vec<process::MapArea>), syscall::Errno> {
+    let mut addr_space = mm::PagedAddrSpace::try_new_in(KernelPageMode, frame_alloc.clone())
+        .map_err(|_| syscall::ENOMEM)?;
+    let (vpn, ppn, n) = get_trampoline_text_paging_config::<KernelPageMode>();
     // 跳板代码页
     addr_space.allocate_map(
         vpn, ppn, n,
-        mm::Sv39Flags::R | mm::Sv39Flags::X // 不开U特权，因为这里从sret弹出后，才真正到用户层
-    ).expect("allocate trampoline code mapped space");
-    // 用户程序空间
-    addr_space.allocate_map(
-        mm::VirtAddr(0x1000).page_number::<mm::Sv39>(), 
-        mm::PhysAddr(0x80400000).page_number::<mm::Sv39>(), 
-        32,
-        mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X | mm::Sv39Flags::U
-    ).expect("allocate user program mapped space");
-    // 用户栈
+        KernelPageFlags::R | KernelPageFlags::X // 不开U特权，因为这里从sret弹出后，才真正到用户层
+    ).map_err(|_| syscall::ENOMEM)?;
+    let trampoline_start = vpn.addr_begin::<KernelPageMode>().0;
+    let mut areas = vec![process::MapArea {
+        start: trampoline_start,
+        // 跳板代码页在地址空间的最高处，结束地址会溢出，记为最大的地址
+        end: trampoline_start.checked_add(n * 0x1000).unwrap_or(usize::MAX),
+        read: true, write: false, execute: true, shared: false,
+        name: "[trampoline]".to_string(),
+    }];
+    // 用户程序空间。程序之后剩余的部分填0，作为程序的bss段
+    let program = fs::open(path, fs::OpenFlags::READ)?;
+    if program.stat().kind != fs::InodeType::File as u32 || program.stat().size as usize > program_pages * 0x1000 {
+        return Err(syscall::ENOEXEC);
+    }
     let mut frames = Vec::new();
-    let stack_frame_n = 5;
-    for i in 0..stack_frame_n {
-        let frame_box = mm::FrameBox::try_new_in(frame_alloc.clone()).expect("allocate user stack frame");
+    for i in 0..program_pages {
+        let frame_box = mm::FrameBox::try_new_in(frame_alloc.clone()).map_err(|_| syscall::ENOMEM)?;
+        let page = unsafe { 
+            core::slice::from_raw_parts_mut(frame_box.phys_page_num().addr_begin::<KernelPageMode>().to_kernel_virt().0 as *mut u8, 0x1000)
+        };
+        let mut filled = 0;
+        while filled < page.len() {
+            match program.read(&mut page[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        page[filled..].fill(0);
+        addr_space.allocate_map(
+            mm::VirtAddr(USER_PROGRAM_BASE + i * 0x1000).page_number::<KernelPageMode>(), 
+            frame_box.phys_page_num(), 
+            1,
+            KernelPageFlags::R | KernelPageFlags::W | KernelPageFlags::X | KernelPageFlags::U
+        ).map_err(|_| syscall::ENOMEM)?;
+        frames.push(frame_box)
+    }
+    areas.push(process::MapArea {
+        start: USER_PROGRAM_BASE,
+        end: USER_PROGRAM_BASE + program_pages * 0x1000,
+        read: true, write: true, execute: true, shared: false,
+        name: path.to_string(),
+    });
+    // 用户栈
+    for i in 0..stack_pages {
+        let frame_box = mm::FrameBox::try_new_in(frame_alloc.clone()).map_err(|_| syscall::ENOMEM)?;
         addr_space.allocate_map(
-            mm::VirtAddr(0x60000000 + i * 0x1000).page_number::<mm::Sv39>(), 
+            mm::VirtAddr(USER_STACK_BASE + i * 0x1000).page_number::<KernelPageMode>(), 
             frame_box.phys_page_num(), 
             1,
-            mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::U
-        ).expect("allocate user stack mapped space");
+            KernelPageFlags::R | KernelPageFlags::W | KernelPageFlags::U
+        ).map_err(|_| syscall::ENOMEM)?;
         frames.push(frame_box)
     }
+    areas.push(process::MapArea {
+        start: USER_STACK_BASE,
+        end: USER_STACK_BASE + stack_pages * 0x1000,
+        read: true, write: true, execute: false, shared: false,
+        name: "[stack]".to_string(),
+    });
     // 跳板数据页在外面处理，这里不处理
-    /* 页表信息，调试用 */
-    // addr_space.allocate_map(
-    //     mm::VirtAddr(0x80420000).page_number::<mm::Sv39>(), 
-    //     mm::PhysAddr(0x80420000).page_number::<mm::Sv39>(), 
-    //     1024 - 32, 
-    //     mm::Sv39Flags::R | mm::Sv39Flags::W | mm::Sv39Flags::X | mm::Sv39Flags::U
-    // ).expect("allocate remaining space");
-    let stack_addr = mm::VirtAddr(0x60000000 + stack_frame_n * 0x1000); // 栈底是高地址
-    (addr_space, frames, stack_addr)
+    let stack_addr = mm::VirtAddr(USER_STACK_BASE + stack_pages * 0x1000); // 栈底是高地址
+    Ok((addr_space, frames, stack_addr, areas))
+}
+
+// 当前处理核的编号。入口函数把它保存在tp寄存器里，内核不会修改tp
+pub fn hart_id() -> usize {
+    let ans: usize;
+    unsafe { asm!("mv   {}, tp", out(reg) ans) };
+    ans
+}
+
+// 关机之前，先把文件系统缓存写回磁盘，再把串口缓冲区中还没发送的内容输出
+fn shutdown() -> ! {
+    if let Err(e) = fs::sync_all() {
+        error!("Failed to sync file systems: {:?}", e);
+    }
+    console::flush();
+    sbi::shutdown()
 }
 
 #[cfg_attr(not(test), panic_handler)]
@@ -210,6 +663,17 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         println!("Panicked: {}", info.message().unwrap());
     }
+    // 回溯当前所在的栈：线程的内核栈、异常栈或者启动栈
+    let sp: usize;
+    unsafe { asm!("mv {}, sp", out(reg) sp) };
+    let boot_stack = unsafe { BOOT_STACK.as_ptr() } as usize;
+    let stack = mm::kernel_stack_range(sp)
+        .or_else(|| trap::trap_stack_range(sp))
+        .unwrap_or(boot_stack..boot_stack + BOOT_STACK_SIZE);
+    backtrace::print_kernel(stack);
+    console::dump_log();
+    // 出错时文件系统的状态可能不一致，不写回缓存
+    console::flush();
     sbi::shutdown()
 }
 
@@ -218,12 +682,78 @@ const BOOT_STACK_SIZE: usize = 4096 * 4 * 8;
 #[link_section = ".bss.stack"]
 static mut BOOT_STACK: [u8; BOOT_STACK_SIZE] = [0; BOOT_STACK_SIZE];
 
+// 启动页表。_start用它开启分页，然后跳到高半部分的rust_main；切换到内核地址空间以后不再使用。
+// 它用最大的页把设备的寄存器和内存线性映射到mm::PHYS_VIRT_OFFSET之上，
+// 同时恒等映射内存，开启分页以后、跳到高半部分之前的几条指令仍然在低地址执行
+#[repr(C, align(4096))]
+struct BootPageTable([usize; BOOT_PAGE_TABLE_ENTRIES]);
+
+const BOOT_PAGE_TABLE_ENTRIES: usize = 4096 / core::mem::size_of::<usize>();
+// V、R、W、X、A、D位。预先设置A和D，处理核不需要写这个页表
+const BOOT_PAGE_FLAGS: usize = 0xcf;
+// Sv39用1GiB的页，映射物理地址的低4GiB；Sv32用4MiB的页，虚拟地址只有4GiB，
+// 只映射设备所在的低1GiB和内存开始的256MiB，设备树和initramfs需要在这个范围内
+#[cfg(target_pointer_width = "64")]
+const BOOT_PAGE_BITS: usize = 30;
+#[cfg(target_pointer_width = "64")]
+const BOOT_LINEAR_RANGES: [(usize, usize); 1] = [(0, 0x1_0000_0000)];
+#[cfg(target_pointer_width = "32")]
+const BOOT_PAGE_BITS: usize = 22;
+#[cfg(target_pointer_width = "32")]
+const BOOT_LINEAR_RANGES: [(usize, usize); 2] = [(0, 0x4000_0000), (0x8000_0000, 0x9000_0000)];
+const BOOT_IDENTITY_RANGE: (usize, usize) = (0x8000_0000, 0x9000_0000);
+
+const fn boot_page_table() -> BootPageTable {
+    let mut entries = [0; BOOT_PAGE_TABLE_ENTRIES];
+    let mut i = 0;
+    while i < BOOT_LINEAR_RANGES.len() {
+        let (mut pa, end) = BOOT_LINEAR_RANGES[i];
+        while pa < end {
+            let va = pa.wrapping_add(mm::PHYS_VIRT_OFFSET);
+            entries[(va >> BOOT_PAGE_BITS) % BOOT_PAGE_TABLE_ENTRIES] = ((pa >> 12) << 10) | BOOT_PAGE_FLAGS;
+            pa += 1 << BOOT_PAGE_BITS;
+        }
+        i += 1;
+    }
+    let (mut pa, end) = BOOT_IDENTITY_RANGE;
+    while pa < end {
+        entries[(pa >> BOOT_PAGE_BITS) % BOOT_PAGE_TABLE_ENTRIES] = ((pa >> 12) << 10) | BOOT_PAGE_FLAGS;
+        pa += 1 << BOOT_PAGE_BITS;
+    }
+    BootPageTable(entries)
+}
+
+static BOOT_PAGE_TABLE: BootPageTable = boot_page_table();
+
+// 启动页表的satp的MODE字段放到t1中：Sv39是8，在第60到63位；Sv32是1，在第31位
+#[cfg(target_pointer_width = "64")]
+macro_rules! boot_satp_mode {
+    () => { "li      t1, 8\n    slli    t1, t1, 60" };
+}
+#[cfg(target_pointer_width = "32")]
+macro_rules! boot_satp_mode {
+    () => { "li      t1, 1\n    slli    t1, t1, 31" };
+}
+// 线性映射的偏移放到t1中，和mm::PHYS_VIRT_OFFSET相同
+#[cfg(target_pointer_width = "64")]
+macro_rules! boot_phys_virt_offset {
+    () => { "li      t1, -1\n    slli    t1, t1, 38" };
+}
+#[cfg(target_pointer_width = "32")]
+macro_rules! boot_phys_virt_offset {
+    () => { "li      t1, 0x40000000" };
+}
+
 #[naked]
 #[link_section = ".text.entry"] 
 #[export_name = "_start"]
 unsafe extern "C" fn entry() -> ! {
-    asm!("
-    # 1. set sp
+    asm!(
+    "
+    # 0. save hart id
+    mv      tp, a0
+
+    # 1. set sp (physical address)
     # sp = bootstack + (hartid + 1) * 0x10000
     add     t0, a0, 1
     slli    t0, t0, 14
@@ -231,12 +761,29 @@ unsafe extern "C" fn entry() -> ! {
     addi    sp, sp, %pcrel_lo(1b)
     add     sp, sp, t0
 
-    # 2. jump to rust_main (absolute address)
+    # 2. enable paging with the boot page table
+1:  auipc   t0, %pcrel_hi({boot_page_table})
+    addi    t0, t0, %pcrel_lo(1b)
+    srli    t0, t0, 12
+    ",
+    boot_satp_mode!(),
+    "
+    or      t0, t0, t1
+    csrw    satp, t0
+    sfence.vma
+
+    # 3. move sp to the higher half and jump to rust_main (virtual address)
+    ",
+    boot_phys_virt_offset!(),
+    "
+    add     sp, sp, t1
 1:  auipc   t0, %pcrel_hi({rust_main})
     addi    t0, t0, %pcrel_lo(1b)
+    add     t0, t0, t1
     jr      t0
     ", 
     boot_stack = sym BOOT_STACK, 
+    boot_page_table = sym BOOT_PAGE_TABLE,
     rust_main = sym rust_main,
     options(noreturn))
 }
@@ -0,0 +1,205 @@
+//! 线程的内核栈
+//!
+//! 每个线程有自己的内核栈，内核在它上面处理这个线程的陷入。栈的页帧从页帧分配器取得，
+//! 映射到内核地址空间中专门的区域，不在线性映射中。区域分成大小相同的槽，每个槽最低的一页不映射，
+//! 作为保护页：内核栈溢出时访问保护页产生页异常，不会悄悄改写下面的内存。
+//!
+//! 内核栈映射在内核地址空间中，所以它在启动时创建以后交给这个模块保存，之后创建和释放内核栈时修改它
+
+use super::{DefaultFrameAllocator, FrameAllocError, FrameBox, PagedAddrSpace, PageMode, VirtAddr, KERNEL_FRAME_ALLOC, PAGE_SIZE};
+use crate::{KernelPageFlags, KernelPageMode};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+// 每个内核栈的页数，和启动时每个处理核的栈一样大
+const KERNEL_STACK_PAGES: usize = 4;
+// 每个槽的页数，包括一页保护页
+const SLOT_PAGES: usize = KERNEL_STACK_PAGES + 1;
+// 最多同时存在的内核栈
+const MAX_KERNEL_STACKS: usize = 1024;
+// 内核栈区域的起点。Sv39上在线性映射之后；Sv32上在线性映射的内存之后、跳板页之前
+#[cfg(target_pointer_width = "64")]
+const KERNEL_STACK_BASE: usize = 0xffff_ffd0_0000_0000;
+#[cfg(target_pointer_width = "32")]
+const KERNEL_STACK_BASE: usize = 0xe000_0000;
+
+type KernelSpace = PagedAddrSpace<KernelPageMode, &'static DefaultFrameAllocator>;
+
+static KERNEL_SPACE: spin::Once<spin::Mutex<KernelSpace>> = spin::Once::new();
+
+// 空闲的槽。释放的槽优先重新使用
+struct SlotAllocator {
+    next: usize,
+    recycled: Vec<usize>,
+}
+
+static SLOTS: spin::Mutex<SlotAllocator> = spin::Mutex::new(SlotAllocator { next: 0, recycled: Vec::new() });
+
+impl SlotAllocator {
+    fn allocate(&mut self) -> Option<usize> {
+        if let Some(slot) = self.recycled.pop() {
+            return Some(slot);
+        }
+        if self.next == MAX_KERNEL_STACKS {
+            return None;
+        }
+        self.next += 1;
+        Some(self.next - 1)
+    }
+}
+
+// 切换到内核地址空间以后，把它交给这个模块，之后才能创建内核栈
+pub(crate) fn set_kernel_space(space: KernelSpace) {
+    KERNEL_SPACE.call_once(|| spin::Mutex::new(space));
+}
+
+// 一个线程的内核栈。释放时取消映射并归还页帧，不能在它上面运行的时候释放
+pub struct KernelStack {
+    slot: usize,
+    frames: Vec<FrameBox<&'static DefaultFrameAllocator>>,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<Self, FrameAllocError> {
+        let space = KERNEL_SPACE.get().ok_or(FrameAllocError)?;
+        let frame_alloc = *KERNEL_FRAME_ALLOC.get().ok_or(FrameAllocError)?;
+        let slot = SLOTS.lock().allocate().ok_or(FrameAllocError)?;
+        // 先放入结构体，中途失败时由drop取消已经建立的映射
+        let mut stack = KernelStack { slot, frames: Vec::with_capacity(KERNEL_STACK_PAGES) };
+        let mut space = space.lock();
+        for i in 0..KERNEL_STACK_PAGES {
+            let frame = FrameBox::try_new_in(frame_alloc)?;
+            let vpn = VirtAddr(stack.bottom() + i * PAGE_SIZE).page_number::<KernelPageMode>();
+            space.allocate_map(vpn, frame.phys_page_num(), 1, KernelPageFlags::R | KernelPageFlags::W)?;
+            stack.frames.push(frame);
+        }
+        drop(space);
+        trace!("kernel stack {} = {:x?}", slot, stack.range());
+        // 这些页以前没有映射，处理核可能缓存了无效的页表项
+        unsafe { asm!("sfence.vma") };
+        Ok(stack)
+    }
+
+    // 栈顶，按16字节对齐
+    pub fn top(&self) -> usize {
+        self.bottom() + KERNEL_STACK_PAGES * PAGE_SIZE
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.bottom()..self.top()
+    }
+
+    // 栈的最低地址，保护页就在它下面
+    fn bottom(&self) -> usize {
+        KERNEL_STACK_BASE + (self.slot * SLOT_PAGES + 1) * PAGE_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut space = KERNEL_SPACE.get().expect("kernel stack without kernel space").lock();
+        for i in 0..self.frames.len() {
+            let va = self.bottom() + i * PAGE_SIZE;
+            space.unmap(VirtAddr(va).page_number::<KernelPageMode>()).expect("unmap kernel stack");
+            unsafe { asm!("sfence.vma {}, zero", in(reg) va) };
+        }
+        drop(space);
+        SLOTS.lock().recycled.push(self.slot);
+    }
+}
+
+// va是否在某个内核栈的保护页中
+pub fn is_kernel_stack_guard(va: usize) -> bool {
+    let offset = match va.checked_sub(KERNEL_STACK_BASE) {
+        Some(offset) if offset < MAX_KERNEL_STACKS * SLOT_PAGES * PAGE_SIZE => offset,
+        _ => return false,
+    };
+    offset / PAGE_SIZE % SLOT_PAGES == 0
+}
+
+// 包含va的内核栈的地址范围，只按地址计算，不检查这个栈是否存在。panic时用它确定回溯的范围
+pub fn kernel_stack_range(va: usize) -> Option<Range<usize>> {
+    let offset = va.checked_sub(KERNEL_STACK_BASE)?;
+    if offset >= MAX_KERNEL_STACKS * SLOT_PAGES * PAGE_SIZE || is_kernel_stack_guard(va) {
+        return None;
+    }
+    let bottom = KERNEL_STACK_BASE + (offset / PAGE_SIZE / SLOT_PAGES * SLOT_PAGES + 1) * PAGE_SIZE;
+    Some(bottom..bottom + KERNEL_STACK_PAGES * PAGE_SIZE)
+}
+
+// 切换到栈顶为top的内核栈上运行f，返回以后回到原来的栈。
+// 不安全：f运行期间这个栈不能被释放
+pub unsafe fn run_on_kernel_stack<F: FnOnce()>(top: usize, f: F) {
+    extern "C" fn call<F: FnOnce()>(f: *mut Option<F>) {
+        let f = unsafe { (*f).take() }.expect("closure called twice");
+        f()
+    }
+    let mut f = Some(f);
+    call_on_stack(&mut f as *mut Option<F> as usize, call::<F> as usize, top)
+}
+
+// a0 = 参数, a1 = 函数, a2 = 新的栈顶。原来的栈指针保存在s1中
+#[naked]
+unsafe extern "C" fn call_on_stack(_arg: usize, _f: usize, _top: usize) {
+    asm!(
+        xsp!("-", 4), // 保持栈按16字节对齐
+        xs!("ra", 0, "sp"),
+        xs!("s1", 1, "sp"),
+        "mv     s1, sp
+        mv      sp, a2
+        jalr    a1
+        mv      sp, s1",
+        xl!("ra", 0, "sp"),
+        xl!("s1", 1, "sp"),
+        xsp!("", 4),
+        "ret",
+        options(noreturn)
+    )
+}
+
+pub(crate) fn test_kernel_stack() {
+    let flags_of = |va: usize| {
+        let space = KERNEL_SPACE.get().expect("kernel space").lock();
+        let (entry, _) = space.find_ppn(VirtAddr(va).page_number::<KernelPageMode>()).ok()?;
+        Some(KernelPageMode::entry_flags(entry) & (KernelPageFlags::R | KernelPageFlags::W | KernelPageFlags::X))
+    };
+    let first = KernelStack::new().expect("allocate kernel stack");
+    let second = KernelStack::new().expect("allocate kernel stack");
+    for stack in [&first, &second].iter() {
+        let range = stack.range();
+        assert_eq!(range.end % 16, 0);
+        assert_eq!(range.end - range.start, KERNEL_STACK_PAGES * PAGE_SIZE);
+        for va in range.clone().step_by(PAGE_SIZE) {
+            assert_eq!(flags_of(va), Some(KernelPageFlags::R | KernelPageFlags::W));
+        }
+        // 栈的下面是不映射的保护页
+        assert_eq!(flags_of(range.start - 1), None);
+        assert!(is_kernel_stack_guard(range.start - 1));
+        assert!(!is_kernel_stack_guard(range.start));
+        assert_eq!(kernel_stack_range(range.end - 8), Some(range.clone()));
+        assert_eq!(kernel_stack_range(range.start - 1), None);
+    }
+    assert!(first.range().end <= second.range().start || second.range().end <= first.range().start);
+    assert_eq!(kernel_stack_range(0x8000_0000), None);
+    // 在新的栈上运行，回来以后仍然在原来的栈上
+    let mut inner_sp = 0;
+    let top = first.top();
+    unsafe {
+        run_on_kernel_stack(top, || {
+            asm!("mv {}, sp", out(reg) inner_sp);
+        })
+    };
+    assert!(first.range().contains(&inner_sp), "closure did not run on the kernel stack");
+    let outer_sp: usize;
+    unsafe { asm!("mv {}, sp", out(reg) outer_sp) };
+    assert!(!first.range().contains(&outer_sp));
+    // 释放以后取消映射，槽留给下一个内核栈
+    let second_range = second.range();
+    drop(second);
+    assert_eq!(flags_of(second_range.start), None);
+    let third = KernelStack::new().expect("allocate kernel stack");
+    assert_eq!(third.range(), second_range);
+    drop(third);
+    drop(first);
+    println!("[kernel-stack-test] Kernel stack and guard page test passed");
+}
@@ -1,16 +1,91 @@
 //! 虚拟内存模块
 
-use alloc::alloc::Layout;
-use buddy_system_allocator::LockedHeap;
-use core::ops::Range;
-
-const KERNEL_HEAP_SIZE: usize = 64 * 1024;
+mod kstack;
+mod slab;
+
+pub use kstack::{is_kernel_stack_guard, kernel_stack_range, run_on_kernel_stack, KernelStack};
+pub(crate) use kstack::{set_kernel_space, test_kernel_stack};
+pub use slab::{slab_stats, ArcInner, SlabArc, SlabBox, SlabCache, SlabStats};
+pub(crate) use slab::test_slab;
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use buddy_system_allocator::Heap;
+use core::{ops::Range, ptr::NonNull, sync::atomic::{AtomicUsize, Ordering}};
+
+// 文件系统的扇区缓存，以及内存文件系统中的文件内容，都在堆上分配。
+// 页帧分配器创建之前只能使用这些空间，解包initramfs也在这之前
+const KERNEL_HEAP_SIZE: usize = 1024 * 1024;
+// 堆不够时，每次至少从页帧分配器取这么多页帧
+const HEAP_GROW_PAGES: usize = 16;
+const PAGE_SIZE: usize = 0x1000;
+
+// 内核把物理地址空间线性映射到高半部分：物理地址加上这个偏移，就是内核访问它使用的虚拟地址。
+// 内核自己也链接在这里，所以内核镜像中的地址也在线性映射中。Sv39的偏移是高半部分的起点；
+// Sv32的虚拟地址只有32位，内存0x80000000映射到0xc0000000
+#[cfg(target_pointer_width = "64")]
+pub const PHYS_VIRT_OFFSET: usize = 0xffff_ffc0_0000_0000;
+#[cfg(target_pointer_width = "32")]
+pub const PHYS_VIRT_OFFSET: usize = 0x4000_0000;
 
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
 // 全局的堆分配器
 #[global_allocator]
-static HEAP: LockedHeap<32> = LockedHeap::empty();
+static HEAP: GrowableHeap = GrowableHeap {
+    heap: spin::Mutex::new(Heap::empty()),
+    grown: AtomicUsize::new(0),
+};
+
+// 内核的堆和对象缓存从这个页帧分配器取页帧，它创建以后才能设置
+static KERNEL_FRAME_ALLOC: spin::Once<&'static DefaultFrameAllocator> = spin::Once::new();
+
+// 伙伴分配算法的堆，空间不够时从页帧分配器取连续的页帧加入堆中。加入的页帧不再归还
+struct GrowableHeap {
+    heap: spin::Mutex<Heap<32>>,
+    // 从页帧分配器得到的字节数
+    grown: AtomicUsize,
+}
+
+impl GrowableHeap {
+    // 取足够放下layout的连续页帧加入堆中。伙伴分配算法的块按自身的大小对齐，所以页帧也要这样对齐。
+    // 页帧分配器还没有设置或者页帧不够时返回false
+    fn grow(&self, layout: &Layout) -> bool {
+        let frame_alloc = match KERNEL_FRAME_ALLOC.get() {
+            Some(frame_alloc) => frame_alloc,
+            None => return false,
+        };
+        let block_pages = (layout.size().max(layout.align()).next_power_of_two() + PAGE_SIZE - 1) / PAGE_SIZE;
+        // 页帧分配器持有锁的时候从不分配堆内存（回收列表在锁外扩充，见deallocate_frame），
+        // 所以这里可以等待它的锁，不会死锁
+        let frames = frame_alloc.lock().allocate_contiguous(block_pages.max(HEAP_GROW_PAGES), block_pages);
+        let frames = match frames {
+            Ok(frames) => frames,
+            Err(_) => return false,
+        };
+        let start = PhysAddr(frames.start.0 * PAGE_SIZE).to_kernel_virt().0;
+        let end = PhysAddr(frames.end.0 * PAGE_SIZE).to_kernel_virt().0;
+        unsafe { self.heap.lock().add_to_heap(start, end) };
+        self.grown.fetch_add(end - start, Ordering::Relaxed);
+        true
+    }
+}
+
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        loop {
+            let ans = self.heap.lock().alloc(layout);
+            match ans {
+                Ok(ptr) => return ptr.as_ptr(),
+                Err(_) => if !self.grow(&layout) {
+                    return core::ptr::null_mut();
+                },
+            }
+        }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.lock().dealloc(NonNull::new_unchecked(ptr), layout)
+    }
+}
 
 #[cfg_attr(not(test), alloc_error_handler)]
 #[allow(unused)]
@@ -20,7 +95,7 @@ fn alloc_error_handler(layout: Layout) -> ! {
 
 pub(crate) fn heap_init() {
     unsafe {
-        HEAP.lock().init(
+        HEAP.heap.lock().init(
             HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE
         )
     }
@@ -28,7 +103,34 @@ pub(crate) fn heap_init() {
     for i in 0..5 {
         vec.push(i);
     }
-    println!("[kernel] Alloc test: {:?}", vec);
+    debug!("Alloc test: {:?}", vec);
+}
+
+// 页帧分配器创建以后，堆和对象缓存不够时可以从它取页帧
+pub(crate) fn set_kernel_frame_allocator(frame_alloc: &'static DefaultFrameAllocator) {
+    KERNEL_FRAME_ALLOC.call_once(|| frame_alloc);
+}
+
+// 内核堆的使用情况，单位是字节
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub total: usize,
+    // 实际占用的大小，包括伙伴分配算法向上取整浪费的部分
+    pub allocated: usize,
+    // 调用者请求的大小
+    pub requested: usize,
+    // 从页帧分配器扩充的大小，包含在total中
+    pub grown: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let heap = HEAP.heap.lock();
+    HeapStats {
+        total: heap.stats_total_bytes(),
+        allocated: heap.stats_alloc_actual(),
+        requested: heap.stats_alloc_user(),
+        grown: HEAP.grown.load(Ordering::Relaxed),
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -38,6 +140,14 @@ impl PhysAddr {
     pub fn page_number<M: PageMode>(&self) -> PhysPageNum { 
         PhysPageNum(self.0 >> M::FRAME_SIZE_BITS)
     }
+    // 线性映射中这个物理地址对应的内核虚拟地址。内核访问页表、页帧和设备的寄存器都要经过它
+    pub fn to_kernel_virt(&self) -> VirtAddr {
+        VirtAddr(self.0.wrapping_add(PHYS_VIRT_OFFSET))
+    }
+    // 线性映射中的内核虚拟地址对应的物理地址，比如交给设备的缓冲区地址
+    pub fn from_kernel_virt(va: VirtAddr) -> PhysAddr {
+        PhysAddr(va.0.wrapping_sub(PHYS_VIRT_OFFSET))
+    }
     // pub fn page_offset(&self) -> usize { 
     //     self.0 & (PAGE_SIZE - 1)
     // }
@@ -51,7 +161,7 @@ impl VirtAddr {
         VirtPageNum(self.0 >> M::FRAME_SIZE_BITS)
     }
     pub fn page_offset<M: PageMode>(&self, lvl: PageLevel) -> usize { 
-        // println!("{:?}, {:?}, {}", lvl, M::get_layout_for_level(lvl), M::get_layout_for_level(lvl).page_size::<M>());
+        trace!("{:?}, {:?}, {}", lvl, M::get_layout_for_level(lvl), M::get_layout_for_level(lvl).page_size::<M>());
         self.0 & (M::get_layout_for_level(lvl).page_size::<M>() - 1)
     }
 }
@@ -60,6 +170,9 @@ impl VirtAddr {
 pub struct PhysPageNum(usize);
 
 impl PhysPageNum {
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
     pub fn addr_begin<M: PageMode>(&self) -> PhysAddr {
         PhysAddr(self.0 << M::FRAME_SIZE_BITS)
     }
@@ -94,14 +207,32 @@ use alloc::vec::Vec;
 // 页帧分配器。**对于物理空间的一个片段，只存在一个页帧分配器，无论有多少个处理核**
 #[derive(Debug)]
 pub struct StackFrameAllocator {
+    start: PhysPageNum,
     current: PhysPageNum,
     end: PhysPageNum,
     recycled: Vec<PhysPageNum>,
 }
 
+// 页帧分配器的使用情况，单位是页帧
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameStats {
+    pub start: PhysPageNum,
+    pub end: PhysPageNum,
+    pub total: usize,
+    pub free: usize,
+}
+
 impl StackFrameAllocator {
     pub fn new(start: PhysPageNum, end: PhysPageNum) -> Self {
-        StackFrameAllocator { current: start, end, recycled: Vec::new() }
+        StackFrameAllocator { start, current: start, end, recycled: Vec::new() }
+    }
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            start: self.start,
+            end: self.end,
+            total: self.end.0 - self.start.0,
+            free: self.end.0 - self.current.0 + self.recycled.len(),
+        }
     }
     pub fn allocate_frame(&mut self) -> Result<PhysPageNum, FrameAllocError> {
         if let Some(ppn) = self.recycled.pop() {
@@ -116,6 +247,16 @@ impl StackFrameAllocator {
             }
         }
     }
+    // 从还没有分配过的页帧中分配n个连续的页帧，开头按align个页帧对齐。对齐跳过的页帧也包含在返回的范围中，
+    // 由调用者一起使用，这样不需要把它们放进回收列表，也就不会在这里分配堆内存
+    pub fn allocate_contiguous(&mut self, n: usize, align: usize) -> Result<Range<PhysPageNum>, FrameAllocError> {
+        let align = align.max(1);
+        let aligned = (self.current.0 + align - 1) / align * align;
+        let end = aligned.checked_add(n).filter(|&end| end <= self.end.0).ok_or(FrameAllocError)?;
+        let ans = self.current..PhysPageNum(end);
+        self.current = PhysPageNum(end);
+        Ok(ans)
+    }
     pub fn deallocate_frame(&mut self, ppn: PhysPageNum) {
         // validity check
         if ppn.is_within_range(self.current, self.end) || self.recycled.iter().find(|&v| {*v == ppn}).is_some() {
@@ -164,6 +305,7 @@ pub(crate) fn test_frame_alloc() {
     alloc.deallocate_frame(f1.unwrap());
     let f3 = alloc.allocate_frame();
     assert_eq!(f3, Ok(PhysPageNum(0x80000)), "after free first, third allocation");
+    assert_eq!(alloc.stats().free, 0x80000 - 2, "free frames after allocation");
     println!("[kernel-frame-test] Frame allocator test passed");
 }
 
@@ -171,6 +313,9 @@ pub(crate) fn test_frame_alloc() {
 pub struct AddressSpaceId(u16);
 
 impl AddressSpaceId {
+    pub fn as_usize(&self) -> usize {
+        self.0 as usize
+    }
     fn next_asid(&self, max_asid: AddressSpaceId) -> Option<AddressSpaceId> {
         if self.0 >= max_asid.0 {
             None
@@ -243,7 +388,7 @@ impl StackAsidAllocator {
         }
     }
     
-    fn deallocate_asid(&mut self, asid: AddressSpaceId) {
+    pub fn deallocate_asid(&mut self, asid: AddressSpaceId) {
         if asid.next_asid(self.max).is_none() || self.recycled.iter().find(|&v| {*v == asid}).is_some() {
             panic!("Asid {:x?} has not been allocated!", asid);
         }
@@ -295,7 +440,21 @@ impl FrameAllocator for DefaultFrameAllocator {
         self.lock().allocate_frame()
     }
     fn deallocate_frame(&self, ppn: PhysPageNum) {
-        self.lock().deallocate_frame(ppn)
+        // 回收列表变长时要在堆上分配，而堆不够时又要从这里取页帧，所以不能在持有锁的时候让它变长
+        loop {
+            let mut alloc = self.lock();
+            if alloc.recycled.len() < alloc.recycled.capacity() {
+                return alloc.deallocate_frame(ppn);
+            }
+            let capacity = (alloc.recycled.capacity() * 2).max(16);
+            drop(alloc);
+            let mut spare = Vec::with_capacity(capacity);
+            let mut alloc = self.lock();
+            if alloc.recycled.capacity() < capacity {
+                spare.extend_from_slice(&alloc.recycled);
+                core::mem::swap(&mut alloc.recycled, &mut spare);
+            }
+        }
     }
 }
 
@@ -388,6 +547,12 @@ pub trait PageMode: Copy {
     fn entry_write_ppn_flags(entry: &mut Self::Entry, ppn: PhysPageNum, flags: Self::Flags);
     // 得到一个页表项目包含的物理页号
     fn entry_get_ppn(entry: &Self::Entry) -> PhysPageNum;
+    // 判断用户态能否读取页表项映射的内存；write为真时，还要求能够写入
+    fn entry_user_accessible(entry: &Self::Entry, write: bool) -> bool;
+    // 得到页表项的设置
+    fn entry_flags(entry: &Self::Entry) -> Self::Flags;
+    // 清除页表项，之后它成为无效的页表项
+    fn entry_clear(entry: &mut Self::Entry);
 }
 
 // 我们认为今天的分页系统都是分为不同的等级，就是多级页表，这里表示页表的等级是多少
@@ -402,9 +567,11 @@ impl PageLevel {
 }
 
 // Sv39分页系统模式；RISC-V RV64下有效
+#[cfg(target_pointer_width = "64")]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Sv39;
 
+#[cfg(target_pointer_width = "64")]
 impl PageMode for Sv39 {
     const FRAME_SIZE_BITS: usize = 12;
     const PPN_BITS: usize = 44;
@@ -497,13 +664,25 @@ impl PageMode for Sv39 {
     fn entry_get_ppn(entry: &Sv39PageEntry) -> PhysPageNum {
         entry.ppn()
     }
+    fn entry_user_accessible(entry: &Sv39PageEntry, write: bool) -> bool {
+        let need = if write { Sv39Flags::U | Sv39Flags::R | Sv39Flags::W } else { Sv39Flags::U | Sv39Flags::R };
+        entry.flags().contains(need)
+    }
+    fn entry_flags(entry: &Sv39PageEntry) -> Sv39Flags {
+        entry.flags()
+    }
+    fn entry_clear(entry: &mut Sv39PageEntry) {
+        entry.write_ppn_flags(PhysPageNum(0), Sv39Flags::empty());
+    }
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageTable {
     entries: [Sv39PageSlot; 512], // todo: other modes
 }
 
+#[cfg(target_pointer_width = "64")]
 impl core::ops::Index<usize> for Sv39PageTable {
     type Output = Sv39PageSlot;
     fn index(&self, idx: usize) -> &Sv39PageSlot {
@@ -511,17 +690,20 @@ impl core::ops::Index<usize> for Sv39PageTable {
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 impl core::ops::IndexMut<usize> for Sv39PageTable {
     fn index_mut(&mut self, idx: usize) -> &mut Sv39PageSlot {
         &mut self.entries[idx]
     }
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageSlot {
     bits: usize,
 }
 
+#[cfg(target_pointer_width = "64")]
 #[repr(C)]
 pub struct Sv39PageEntry {
     bits: usize,
@@ -529,6 +711,7 @@ pub struct Sv39PageEntry {
 
 use bit_field::BitField;
 
+#[cfg(target_pointer_width = "64")]
 impl Sv39PageEntry {
     #[inline]
     pub fn ppn(&self) -> PhysPageNum {
@@ -557,6 +740,166 @@ bitflags::bitflags! {
     }
 }
 
+// Sv32分页系统模式；RISC-V RV32下有效
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Sv32;
+
+impl PageMode for Sv32 {
+    const FRAME_SIZE_BITS: usize = 12;
+    const PPN_BITS: usize = 22;
+    type PageTable = Sv32PageTable;
+    fn get_layout_for_level(level: PageLevel) -> FrameLayout {
+        unsafe { match level.0 {
+            0 => FrameLayout::new_unchecked(1), // 4K页，最低层页
+            1 => FrameLayout::new_unchecked(1024), // 4M页，最高层大页
+            _ => unimplemented!("this level does not exist on Sv32")
+        } }
+    }
+    fn visit_levels_until(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(1), PageLevel(0)],
+            1 => &[PageLevel(1)],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn visit_levels_before(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(1)],
+            1 => &[],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn visit_levels_from(level: PageLevel) -> &'static [PageLevel] {
+        match level.0 {
+            0 => &[PageLevel(0)],
+            1 => &[PageLevel(1), PageLevel(0)],
+            _ => unimplemented!("this level does not exist on Sv32"),
+        }
+    }
+    fn vpn_index(vpn: VirtPageNum, level: PageLevel) -> usize {
+        (vpn.0 >> (level.0 * 10)) & 1023
+    }
+    fn vpn_index_range(vpn_range: Range<VirtPageNum>, level: PageLevel) -> Range<usize> {
+        let start = (vpn_range.start.0 >> (level.0 * 10)) & 1023;
+        let mut end = (vpn_range.end.0 >> (level.0 * 10)) & 1023;
+        if level.0 == 0 {
+            let start_idx1 = vpn_range.start.0 >> 10;
+            let end_idx1 = vpn_range.end.0 >> 10;
+            if end_idx1 > start_idx1 {
+                end = 1024;
+            }
+        }
+        start..end
+    }
+    fn vpn_level_index(vpn: VirtPageNum, level: PageLevel, idx: usize) -> VirtPageNum {
+        VirtPageNum(match level.0 {
+            0 => (vpn.0 & !((1 << 10) - 1)) + idx,
+            1 => (vpn.0 & !((1 << 20) - 1)) + (idx << 10),
+            _ => unimplemented!("this level does not exist on Sv32"),
+        })
+    }
+    type Entry = Sv32PageEntry;
+    type Slot = Sv32PageSlot;
+    fn slot_try_get_entry(slot: &mut Sv32PageSlot) -> Result<&mut Sv32PageEntry, &mut Sv32PageSlot> {
+        // note(unsafe): slot是合法的
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        if ans.flags().contains(Sv32Flags::V) {
+            Ok(ans)
+        } else {
+            Err(slot)
+        }
+    }
+    fn init_page_table(table: &mut Self::PageTable) {
+        table.entries = unsafe { core::mem::MaybeUninit::zeroed().assume_init() }; // 全零
+    }
+    type Flags = Sv32Flags;
+    fn slot_set_child(slot: &mut Sv32PageSlot, ppn: PhysPageNum) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv32Flags::V); // V=1, R=W=X=0
+    }
+    fn slot_set_mapping(slot: &mut Sv32PageSlot, ppn: PhysPageNum, flags: Sv32Flags) {
+        let ans = unsafe { &mut *(slot as *mut _ as *mut Sv32PageEntry) };
+        ans.write_ppn_flags(ppn, Sv32Flags::V | flags);
+    }
+    fn entry_is_leaf_page(entry: &mut Sv32PageEntry) -> bool {
+        // 如果包含R、W或X项，就是叶子节点。
+        entry.flags().intersects(Sv32Flags::R | Sv32Flags::W | Sv32Flags::X)
+    }
+    fn entry_write_ppn_flags(entry: &mut Sv32PageEntry, ppn: PhysPageNum, flags: Sv32Flags) {
+        entry.write_ppn_flags(ppn, flags);
+    }
+    fn entry_get_ppn(entry: &Sv32PageEntry) -> PhysPageNum {
+        entry.ppn()
+    }
+    fn entry_user_accessible(entry: &Sv32PageEntry, write: bool) -> bool {
+        let need = if write { Sv32Flags::U | Sv32Flags::R | Sv32Flags::W } else { Sv32Flags::U | Sv32Flags::R };
+        entry.flags().contains(need)
+    }
+    fn entry_flags(entry: &Sv32PageEntry) -> Sv32Flags {
+        entry.flags()
+    }
+    fn entry_clear(entry: &mut Sv32PageEntry) {
+        entry.write_ppn_flags(PhysPageNum(0), Sv32Flags::empty());
+    }
+}
+
+// Sv32的页表项是32位的，一个页表有1024项，正好占满一个4K页帧
+#[repr(C)]
+pub struct Sv32PageTable {
+    entries: [Sv32PageSlot; 1024],
+}
+
+impl core::ops::Index<usize> for Sv32PageTable {
+    type Output = Sv32PageSlot;
+    fn index(&self, idx: usize) -> &Sv32PageSlot {
+        &self.entries[idx]
+    }
+}
+
+impl core::ops::IndexMut<usize> for Sv32PageTable {
+    fn index_mut(&mut self, idx: usize) -> &mut Sv32PageSlot {
+        &mut self.entries[idx]
+    }
+}
+
+#[repr(C)]
+pub struct Sv32PageSlot {
+    bits: u32,
+}
+
+#[repr(C)]
+pub struct Sv32PageEntry {
+    bits: u32,
+}
+
+impl Sv32PageEntry {
+    #[inline]
+    pub fn ppn(&self) -> PhysPageNum {
+        PhysPageNum(self.bits.get_bits(10..32) as usize)
+    }
+    #[inline]
+    pub fn flags(&self) -> Sv32Flags {
+        Sv32Flags::from_bits_truncate(self.bits.get_bits(0..8) as u8)
+    }
+    #[inline]
+    pub fn write_ppn_flags(&mut self, ppn: PhysPageNum, flags: Sv32Flags) {
+        self.bits = ((ppn.0 as u32) << 10) | flags.bits() as u32
+    }
+}
+
+bitflags::bitflags! {
+    pub struct Sv32Flags: u8 {
+        const V = 1 << 0;
+        const R = 1 << 1;
+        const W = 1 << 2;
+        const X = 1 << 3;
+        const U = 1 << 4;
+        const G = 1 << 5;
+        const A = 1 << 6;
+        const D = 1 << 7;
+    }
+}
+
 // 表示一个分页系统实现的地址空间
 //
 // 如果属于直接映射或者线性偏移映射，不应当使用这个结构体，应当使用其它的结构体。
@@ -573,7 +916,7 @@ impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
     pub fn try_new_in(page_mode: M, frame_alloc: A) -> Result<Self, FrameAllocError> {
         // 新建一个满足根页表对齐要求的帧；虽然代码没有体现，通常对齐要求是1
         let mut root_frame = FrameBox::try_new_in(frame_alloc.clone())?;
-        // println!("[kernel-alloc-map-test] Root frame: {:x?}", root_frame.phys_page_num());
+        trace!("Root frame: {:x?}", root_frame.phys_page_num());
         // 向帧里填入一个空的根页表 
         unsafe { fill_frame_with_initialized_page_table::<A, M>(&mut root_frame) };
         Ok(Self { root_frame, frames: Vec::new(), frame_alloc, page_mode })
@@ -584,13 +927,14 @@ impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
     }
 }
 
+// 页表通过线性映射访问
 #[inline] unsafe fn unref_ppn_mut<'a, M: PageMode>(ppn: PhysPageNum) -> &'a mut M::PageTable {
-    let pa = ppn.addr_begin::<M>();
-    &mut *(pa.0 as *mut M::PageTable)
+    let va = ppn.addr_begin::<M>().to_kernel_virt();
+    &mut *(va.0 as *mut M::PageTable)
 }
 
 #[inline] unsafe fn fill_frame_with_initialized_page_table<A: FrameAllocator, M: PageMode>(b: &mut FrameBox<A>) {
-    let a = &mut *(b.ppn.addr_begin::<M>().0 as *mut M::PageTable);
+    let a = unref_ppn_mut::<M>(b.ppn);
     M::init_page_table(a);
 }
 
@@ -599,7 +943,7 @@ impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
     unsafe fn alloc_get_table(&mut self, entry_level: PageLevel, vpn_start: VirtPageNum) -> Result<&mut M::PageTable, FrameAllocError> {
         let mut ppn = self.root_frame.phys_page_num();
         for &level in M::visit_levels_before(entry_level) {
-            // println!("[] BEFORE PPN = {:x?}", ppn);
+            trace!("BEFORE PPN = {:x?}", ppn);
             let page_table = unref_ppn_mut::<M>(ppn);
             let vidx = M::vpn_index(vpn_start, level);
             match M::slot_try_get_entry(&mut page_table[vidx]) {
@@ -607,26 +951,26 @@ impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
                 Err(mut slot) => {  // 需要一个内部页表，这里的页表项却没有数据，我们需要填写数据
                     let frame_box = FrameBox::try_new_in(self.frame_alloc.clone())?;
                     M::slot_set_child(&mut slot, frame_box.phys_page_num());
-                    // println!("[] Created a new frame box");
+                    trace!("Created a new frame box");
                     ppn = frame_box.phys_page_num();
                     self.frames.push(frame_box);
                 }
             }
         }
-        // println!("[kernel-alloc-map-test] in alloc_get_table PPN: {:x?}", ppn);
+        trace!("in alloc_get_table PPN: {:x?}", ppn);
         let page_table = unref_ppn_mut::<M>(ppn); // 此时ppn是当前所需要修改的页表
         // 创建了一个没有约束的生命周期。不过我们可以判断它是合法的，因为它的所有者是Self，在Self的周期内都合法
         Ok(&mut *(page_table as *mut _))
     }
     pub fn allocate_map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, n: usize, flags: M::Flags) -> Result<(), FrameAllocError> {
         for (page_level, vpn_range) in MapPairs::solve(vpn, ppn, n, self.page_mode) {
-            // println!("[kernel-alloc-map-test] PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
+            trace!("PAGE LEVEL: {:?}, VPN RANGE: {:x?}", page_level, vpn_range);
             let table = unsafe { self.alloc_get_table(page_level, vpn_range.start) }?;
             let idx_range = M::vpn_index_range(vpn_range.clone(), page_level);
-            // println!("[kernel-alloc-map-test] IDX RANGE: {:?}", idx_range);
+            trace!("IDX RANGE: {:?}", idx_range);
             for vidx in idx_range {
                 let this_ppn = PhysPageNum(ppn.0 + M::vpn_level_index(vpn_range.start, page_level, vidx).0 - vpn.0);
-                // println!("[kernel-alloc-map-test] Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
+                trace!("Table: {:p} Vidx {} -> Ppn {:x?}", table, vidx, this_ppn);
                 match M::slot_try_get_entry(&mut table[vidx]) {
                     Ok(_entry) => panic!("already allocated"),
                     Err(slot) => M::slot_set_mapping(slot, this_ppn, flags.clone())
@@ -635,15 +979,35 @@ impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
         }
         Ok(())
     }
-    // pub fn unmap(&mut self, vpn: VirtPageNum) {
-    //     todo!()
-    // }
+    // 取消一个4K页的映射，返回它映射到的物理页号。中间的页表保留，随地址空间一起释放。
+    // 用户的地址空间在下次切换进去的时候刷新页表缓存
+    pub fn unmap(&mut self, vpn: VirtPageNum) -> Result<PhysPageNum, PageError> {
+        let mut ppn = self.root_frame.phys_page_num();
+        for &lvl in M::visit_levels_until(PageLevel::leaf_level()) {
+            let page_table = unsafe { unref_ppn_mut::<M>(ppn) };
+            let vidx = M::vpn_index(vpn, lvl);
+            match M::slot_try_get_entry(&mut page_table[vidx]) {
+                Ok(entry) => if M::entry_is_leaf_page(entry) {
+                    if lvl != PageLevel::leaf_level() {
+                        return Err(PageError::HugePage);
+                    }
+                    let ans = M::entry_get_ppn(entry);
+                    M::entry_clear(entry);
+                    return Ok(ans)
+                } else {
+                    ppn = M::entry_get_ppn(entry)
+                },
+                Err(_slot) => return Err(PageError::InvalidEntry)
+            }
+        }
+        Err(PageError::NotLeafInLowerestPage)
+    }
 
     /// 根据虚拟页号查询物理页号，可能出错。
     pub fn find_ppn(&self, vpn: VirtPageNum) -> Result<(&M::Entry, PageLevel), PageError> {
         let mut ppn = self.root_frame.phys_page_num();
         for &lvl in M::visit_levels_until(PageLevel::leaf_level()) {
-            // 注意: 要求内核对页表空间有恒等映射，可以直接解释物理地址
+            // 页表所在的页帧通过线性映射访问
             let page_table = unsafe { unref_ppn_mut::<M>(ppn) };
             let vidx = M::vpn_index(vpn, lvl);
             match M::slot_try_get_entry(&mut page_table[vidx]) {
@@ -657,6 +1021,33 @@ impl<M: PageMode, A: FrameAllocator + Clone> PagedAddrSpace<M, A> {
         }
         Err(PageError::NotLeafInLowerestPage)
     }
+
+    // 按虚拟页号从小到大访问所有有效的叶子页表项，调用f(虚拟页号, 页表等级, 页表项)。
+    // 高半部分的虚拟页号没有符号扩展
+    pub fn for_each_leaf<F: FnMut(VirtPageNum, PageLevel, &M::Entry)>(&self, mut f: F) {
+        let levels = M::visit_levels_until(PageLevel::leaf_level());
+        unsafe { walk_page_table::<M, F>(self.root_frame.phys_page_num(), levels, VirtPageNum(0), &mut f) }
+    }
+}
+
+// 访问ppn处的页表，它是levels中第一个等级的页表，其中的虚拟页号从vpn开始
+unsafe fn walk_page_table<M: PageMode, F: FnMut(VirtPageNum, PageLevel, &M::Entry)>(ppn: PhysPageNum, levels: &[PageLevel], vpn: VirtPageNum, f: &mut F) {
+    let (&level, lower_levels) = match levels.split_first() {
+        Some(levels) => levels,
+        None => return,
+    };
+    let page_table = unref_ppn_mut::<M>(ppn);
+    let entry_count = (1 << M::FRAME_SIZE_BITS) / core::mem::size_of::<M::Slot>();
+    for idx in 0..entry_count {
+        let this_vpn = M::vpn_level_index(vpn, level, idx);
+        if let Ok(entry) = M::slot_try_get_entry(&mut page_table[idx]) {
+            if M::entry_is_leaf_page(entry) {
+                f(this_vpn, level, entry)
+            } else {
+                walk_page_table::<M, F>(M::entry_get_ppn(entry), lower_levels, this_vpn, f)
+            }
+        }
+    }
 }
 
 /// 查询物理页号可能出现的错误
@@ -665,7 +1056,11 @@ pub enum PageError {
     /// 节点不具有有效位
     InvalidEntry,
     /// 第0层页表不能是内部节点
-    NotLeafInLowerestPage
+    NotLeafInLowerestPage,
+    /// 用户态没有访问这个页的权限
+    PermissionDenied,
+    /// 这里映射的是大页，不能按4K页取消映射
+    HugePage,
 }
 
 #[derive(Debug)]
@@ -703,7 +1098,7 @@ impl<M: PageMode> MapPairs<M> {
             }
             break;
         } 
-        // println!("[SOLVE] Ans = {:x?}", ans);
+        trace!("Map pairs = {:x?}", ans);
         Self { ans_iter: ans.into_iter(), mode }
     }
 }
@@ -716,6 +1111,14 @@ impl<M> Iterator for MapPairs<M> {
 }
 
 pub(crate) fn test_map_solve() {
+    #[cfg(target_pointer_width = "64")]
+    test_map_solve_sv39();
+    test_map_solve_sv32();
+    println!("[kernel-map-solve] Map solver test passed");
+}
+
+#[cfg(target_pointer_width = "64")]
+fn test_map_solve_sv39() {
     let pairs = MapPairs::solve(VirtPageNum(0x90_000), PhysPageNum(0x50_000), 666666, Sv39).collect::<Vec<_>>();
     assert_eq!(pairs, [
         (PageLevel(2), VirtPageNum(786432)..VirtPageNum(1048576)), 
@@ -729,12 +1132,134 @@ pub(crate) fn test_map_solve() {
         (PageLevel(0), VirtPageNum(589825)..VirtPageNum(590336)), 
         (PageLevel(0), VirtPageNum(667136)..VirtPageNum(667602))
     ]);
-    println!("[kernel-map-solve] Map solver test passed");
+}
+
+fn test_map_solve_sv32() {
+    let pairs = MapPairs::solve(VirtPageNum(0x80_000), PhysPageNum(0x90_000), 5000, Sv32).collect::<Vec<_>>();
+    assert_eq!(pairs, [
+        (PageLevel(1), VirtPageNum(524288)..VirtPageNum(528384)), 
+        (PageLevel(0), VirtPageNum(528384)..VirtPageNum(529288))
+    ]);
+    let pairs = MapPairs::solve(VirtPageNum(0x80_001), PhysPageNum(0x90_001), 3000, Sv32).collect::<Vec<_>>();
+    assert_eq!(pairs, [
+        (PageLevel(1), VirtPageNum(525312)..VirtPageNum(526336)), 
+        (PageLevel(0), VirtPageNum(524289)..VirtPageNum(525312)), 
+        (PageLevel(0), VirtPageNum(526336)..VirtPageNum(527289))
+    ]);
+}
+
+// 稀疏映射的范围和间隔。每隔SPARSE_STRIDE映射连续的SPARSE_RUN个4K页，每一段在不同的末级页表中
+#[cfg(target_pointer_width = "64")]
+const SPARSE_SPAN: usize = 4 << 30;
+#[cfg(target_pointer_width = "32")]
+const SPARSE_SPAN: usize = 2 << 30;
+const SPARSE_STRIDE: usize = 32 << 20;
+const SPARSE_RUN: usize = 16;
+
+pub(crate) fn test_heap_growth(frame_alloc: &'static DefaultFrameAllocator) {
+    use crate::{KernelPageFlags, KernelPageMode};
+    let free_before = frame_alloc.lock().stats().free;
+    let grown_before = heap_stats().grown;
+    let data = FrameBox::try_new_in(frame_alloc).expect("allocate data frame");
+    let mut space = PagedAddrSpace::try_new_in(KernelPageMode, frame_alloc).expect("create address space");
+    let runs = SPARSE_SPAN / SPARSE_STRIDE;
+    // 所有的页都映射到同一个页帧，每一页都是单独的4K映射
+    for i in 0..runs {
+        for j in 0..SPARSE_RUN {
+            let vpn = VirtAddr(i * SPARSE_STRIDE + j * PAGE_SIZE).page_number::<KernelPageMode>();
+            space.allocate_map(vpn, data.phys_page_num(), 1, KernelPageFlags::R | KernelPageFlags::W)
+                .expect("map sparse page");
+        }
+    }
+    for i in 0..runs {
+        for j in 0..SPARSE_RUN {
+            let vpn = VirtAddr(i * SPARSE_STRIDE + j * PAGE_SIZE).page_number::<KernelPageMode>();
+            let (entry, level) = space.find_ppn(vpn).expect("find sparse page");
+            assert_eq!(level, PageLevel::leaf_level());
+            assert_eq!(KernelPageMode::entry_get_ppn(entry), data.phys_page_num());
+        }
+        let next = VirtAddr(i * SPARSE_STRIDE + SPARSE_RUN * PAGE_SIZE).page_number::<KernelPageMode>();
+        assert!(space.find_ppn(next).is_err(), "page after a sparse run should not be mapped");
+    }
+    // 每一段都要单独的末级页表
+    assert!(free_before - frame_alloc.lock().stats().free >= runs + 2);
+    drop(space);
+    drop(data);
+    // 回收列表变长时堆可能扩充，扩充用掉的页帧不会归还
+    let grown_frames = (heap_stats().grown - grown_before) / PAGE_SIZE;
+    assert_eq!(frame_alloc.lock().stats().free + grown_frames, free_before, "page tables should be freed");
+    // 用4K的块占满堆，堆应当从页帧分配器扩充。先留好列表的空间，扩充只能由块的分配引起
+    let mut blocks = Vec::with_capacity(2 * heap_stats().total / PAGE_SIZE);
+    let before = heap_stats();
+    while heap_stats().total == before.total {
+        assert!(blocks.len() <= before.total / PAGE_SIZE, "kernel heap did not grow");
+        blocks.push(alloc::vec![0u8; PAGE_SIZE]);
+    }
+    let after = heap_stats();
+    assert!(after.grown >= before.grown + HEAP_GROW_PAGES * PAGE_SIZE);
+    assert_eq!(after.total - before.total, after.grown - before.grown);
+    // 让堆扩充的那次分配得到的是新取的页帧，不在静态的HEAP_SPACE中
+    let heap_start = unsafe { HEAP_SPACE.as_ptr() } as usize;
+    let last = blocks.last().unwrap().as_ptr() as usize;
+    assert!(!(heap_start..heap_start + KERNEL_HEAP_SIZE).contains(&last), "allocation at {:#x} did not go past the static heap", last);
+    let frames_start = frame_alloc.lock().stats().start.addr_begin::<KernelPageMode>().to_kernel_virt().0;
+    assert!(last >= frames_start, "allocation at {:#x} is not in a frame from the frame allocator", last);
+    blocks.last_mut().unwrap()[PAGE_SIZE - 1] = 1;
+    drop(blocks);
+    assert!(heap_stats().allocated <= before.allocated);
+    println!("[kernel-heap-test] Sparse mapping and heap growth test passed");
+}
+
+// 检查内核地址空间的线性映射：内核的代码、静态变量和页帧都映射在物理地址加上偏移的地方，
+// 以前恒等映射的低地址不再映射
+pub(crate) fn test_linear_map<A: FrameAllocator + Clone>(space: &PagedAddrSpace<crate::KernelPageMode, A>) {
+    use crate::KernelPageMode;
+    let translate = |va: usize| {
+        let (entry, level) = space.find_ppn(VirtAddr(va).page_number::<KernelPageMode>()).ok()?;
+        let page_size = KernelPageMode::get_layout_for_level(level).page_size::<KernelPageMode>();
+        Some(KernelPageMode::entry_get_ppn(entry).addr_begin::<KernelPageMode>().0 + (va & (page_size - 1)))
+    };
+    let pa = PhysAddr(0x8040_1234);
+    assert_eq!(PhysAddr::from_kernel_virt(pa.to_kernel_virt()), pa);
+    let frame = FrameBox::try_new_in(*KERNEL_FRAME_ALLOC.get().expect("kernel frame allocator")).expect("allocate frame");
+    let frame_va = frame.phys_page_num().addr_begin::<KernelPageMode>().to_kernel_virt().0;
+    let addrs = [test_linear_map::<A> as usize, unsafe { HEAP_SPACE.as_ptr() } as usize, frame_va + 8];
+    for &va in addrs.iter() {
+        assert!(va >= PHYS_VIRT_OFFSET, "kernel address {:#x} is not in the linear map", va);
+        assert_eq!(translate(va), Some(PhysAddr::from_kernel_virt(VirtAddr(va)).0));
+    }
+    assert_eq!(translate(PhysAddr::from_kernel_virt(VirtAddr(frame_va)).0), None);
+    println!("[kernel-linear-map-test] Higher half linear mapping test passed");
+}
+
+// 检查内核地址空间的权限：没有既可写又可执行的页，代码、只读数据和数据各自只有需要的权限，固件所在的内存不映射
+pub(crate) fn test_kernel_wx<A: FrameAllocator + Clone>(space: &PagedAddrSpace<crate::KernelPageMode, A>) {
+    use crate::{KernelPageFlags as Flags, KernelPageMode};
+    let mut pages = 0;
+    space.for_each_leaf(|vpn, level, entry| {
+        let flags = KernelPageMode::entry_flags(entry);
+        assert!(!flags.contains(Flags::W | Flags::X), "page {:x?} at level {:?} is writable and executable", vpn, level);
+        pages += 1;
+    });
+    assert!(pages > 0);
+    let flags_of = |va: usize| {
+        let (entry, _) = space.find_ppn(VirtAddr(va).page_number::<KernelPageMode>()).ok()?;
+        Some(KernelPageMode::entry_flags(entry) & (Flags::R | Flags::W | Flags::X))
+    };
+    static RODATA: [u8; 4] = *b"wx!\0";
+    static DATA: AtomicUsize = AtomicUsize::new(1);
+    assert_eq!(flags_of(test_kernel_wx::<A> as usize), Some(Flags::R | Flags::X));
+    assert_eq!(flags_of(RODATA.as_ptr() as usize), Some(Flags::R));
+    assert_eq!(flags_of(&DATA as *const _ as usize), Some(Flags::R | Flags::W));
+    assert_eq!(flags_of(unsafe { HEAP_SPACE.as_ptr() } as usize), Some(Flags::R | Flags::W));
+    assert_eq!(flags_of(PhysAddr(0x80000000).to_kernel_virt().0), None, "firmware should not be mapped");
+    println!("[kernel-wx-test] Kernel mapping permission test passed");
 }
 
 // 切换地址空间，同时需要提供1.地址空间的详细设置 2.地址空间编号
 // 同时返回：satp寄存器的值
 use riscv::register::satp::Satp;
+#[cfg(target_pointer_width = "64")]
 pub unsafe fn activate_paged_riscv_sv39(root_ppn: PhysPageNum, asid: AddressSpaceId) -> Satp {
     use riscv::register::satp::{self, Mode};
     satp::set(Mode::Sv39, asid.0 as usize, root_ppn.0);
@@ -743,49 +1268,60 @@ pub unsafe fn activate_paged_riscv_sv39(root_ppn: PhysPageNum, asid: AddressSpac
 }
 
 // 得到satp的值
+#[cfg(target_pointer_width = "64")]
 pub fn get_satp_sv39(asid: AddressSpaceId, ppn: PhysPageNum) -> Satp {
     let bits = (8 << 60) | ((asid.0 as usize) << 44) | ppn.0;
     unsafe { core::mem::transmute(bits) }
 }
 
-// 帧翻译：在空间1中访问空间2的帧。要求空间1具有恒等映射特性
-pub fn translate_frame_read</*M1, A1, */M2, A2, F>(
+#[cfg(target_pointer_width = "32")]
+pub unsafe fn activate_paged_riscv_sv32(root_ppn: PhysPageNum, asid: AddressSpaceId) -> Satp {
+    use riscv::register::satp::{self, Mode};
+    satp::set(Mode::Sv32, asid.0 as usize, root_ppn.0);
+    asm!("sfence.vma x0, {}", in(reg) asid.0 as usize);
+    satp::read()
+}
+
+// 得到satp的值。RV32下，MODE只有一位，ASID有9位，PPN有22位
+#[cfg(target_pointer_width = "32")]
+pub fn get_satp_sv32(asid: AddressSpaceId, ppn: PhysPageNum) -> Satp {
+    let bits = (1 << 31) | ((asid.0 as usize) << 22) | ppn.0;
+    unsafe { core::mem::transmute(bits) }
+}
+
+// 帧翻译：在空间1中访问空间2中用户态可以访问的一段内存。空间1通过线性映射访问得到的物理页
+//
+// 内存按帧切分，按顺序调用f(物理页号, 帧内偏移, 长度)。
+// 遇到没有映射或者用户态不能访问的页，返回错误；此时之前的部分已经处理过了
+pub fn translate_user_frames</*M1, A1, */M2, A2, F>(
     // as1: &PagedAddrSpace<M1, A1>, 
     as2: &PagedAddrSpace<M2, A2>, 
     vaddr2: VirtAddr, 
     len_bytes2: usize, 
-    f: F
+    write: bool,
+    mut f: F
 ) -> Result<(), PageError>
 where 
     // M1: PageMode, 
     // A1: FrameAllocator + Clone,
     M2: PageMode, 
     A2: FrameAllocator + Clone,
-    F: Fn(PhysPageNum, usize, usize) // 按顺序返回空间1中的帧
+    F: FnMut(PhysPageNum, usize, usize) // 按顺序返回空间1中的帧
 {
-    // println!("vaddr2 = {:x?}, len_bytes2 = {}", vaddr2, len_bytes2);
-    let mut vpn2 = vaddr2.page_number::<M2>();
+    let mut vaddr = vaddr2.0;
     let mut remaining_len = len_bytes2;
-    let (mut entry, mut lvl) = as2.find_ppn(vpn2)?;
-    let mut cur_offset = vaddr2.page_offset::<M2>(lvl);
     while remaining_len > 0 {
-        let ppn = M2::entry_get_ppn(entry);
-        let cur_frame_layout = M2::get_layout_for_level(lvl);
-        let cur_len = if remaining_len <= cur_frame_layout.page_size::<M2>() {
-            remaining_len
-        } else {
-            cur_frame_layout.page_size::<M2>()
-        };
-        f(ppn, cur_offset, cur_len);
-        // println!("[] {} {} {}", cur_frame_layout.page_size::<M2>(), cur_offset, cur_len);
-        remaining_len -= cur_len;
-        if remaining_len == 0 {
-            return Ok(())
+        let (entry, lvl) = as2.find_ppn(VirtAddr(vaddr).page_number::<M2>())?;
+        if !M2::entry_user_accessible(entry, write) {
+            return Err(PageError::PermissionDenied);
         }
-        cur_offset = 0; // 下一个帧从头开始
-        vpn2 = vpn2.next_page_by_level::<M2>(lvl);
-        (entry, lvl) = as2.find_ppn(vpn2)?;
-        // println!("[] {}", remaining_len);
+        let page_size = M2::get_layout_for_level(lvl).page_size::<M2>();
+        let cur_offset = VirtAddr(vaddr).page_offset::<M2>(lvl);
+        // 不能越过当前页的末尾
+        let cur_len = core::cmp::min(remaining_len, page_size - cur_offset);
+        f(M2::entry_get_ppn(entry), cur_offset, cur_len);
+        remaining_len -= cur_len;
+        vaddr = vaddr.checked_add(cur_len).ok_or(PageError::InvalidEntry)?;
     }
     Ok(())
 }
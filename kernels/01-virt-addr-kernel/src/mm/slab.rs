@@ -0,0 +1,398 @@
+//! 固定大小的内核对象的缓存
+//!
+//! 每种对象有一个SlabCache。缓存从页帧分配器取整页作为slab，切成同样大小的对象；
+//! 空闲的对象用开头的一个字串成链表，放在缓存的仓库（depot）中。slab不会还给页帧分配器，
+//! 频繁创建和释放的对象不经过伙伴分配算法，也就不会让内核堆产生碎片。
+//!
+//! 每个处理核有一个弹匣（magazine），存放这个处理核最近释放的对象，分配和释放都先在弹匣中进行。
+//! 内核运行时不打开中断（见task模块），弹匣只被自己的处理核访问，所以这条路径不需要加锁，时间是O(1)。
+//! 弹匣空了从仓库取半个弹匣的对象，满了把一半还给仓库，只有这时才需要获得仓库的锁。
+//!
+//! 缓存中的对象用SlabBox持有；需要共享的对象，比如目录项，用带引用计数的SlabArc持有
+
+use super::{FrameAllocError, FrameAllocator, PhysAddr, KERNEL_FRAME_ALLOC, PAGE_SIZE};
+use alloc::vec::Vec;
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem::{align_of, size_of, ManuallyDrop},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+// 支持的处理核数量，处理核编号必须小于它
+const MAX_HARTS: usize = 8;
+// 每个弹匣最多存放的对象数
+const MAGAZINE_SIZE: usize = 32;
+
+struct Magazine {
+    objects: [usize; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: UnsafeCell<Magazine> = UnsafeCell::new(Magazine { objects: [0; MAGAZINE_SIZE], len: 0 });
+
+    fn push(&mut self, object: usize) {
+        self.objects[self.len] = object;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.objects[self.len])
+    }
+}
+
+// 仓库中的空闲对象，每个空闲对象的第一个字是下一个空闲对象的地址，0表示链表结束
+struct Depot {
+    free: usize,
+    free_count: usize,
+    slabs: usize,
+}
+
+impl Depot {
+    fn push(&mut self, object: usize) {
+        unsafe { *(object as *mut usize) = self.free };
+        self.free = object;
+        self.free_count += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.free == 0 {
+            return None;
+        }
+        let object = self.free;
+        self.free = unsafe { *(object as *const usize) };
+        self.free_count -= 1;
+        Some(object)
+    }
+}
+
+// 一种对象的缓存，通常放在静态变量中
+pub struct SlabCache<T> {
+    name: &'static str,
+    depot: spin::Mutex<Depot>,
+    magazines: [UnsafeCell<Magazine>; MAX_HARTS],
+    // 正在使用的对象数
+    in_use: AtomicUsize,
+    _marker: PhantomData<T>,
+}
+
+// 弹匣只被所属的处理核在关中断时访问；对象可能在一个处理核上分配，在另一个处理核上释放
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+// 缓存的使用情况，对象数的单位是个
+#[derive(Copy, Clone, Debug)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    // 仓库中的空闲对象
+    pub free: usize,
+    // 弹匣中的空闲对象
+    pub cached: usize,
+}
+
+impl<T> SlabCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            name,
+            depot: spin::Mutex::new(Depot { free: 0, free_count: 0, slabs: 0 }),
+            magazines: [Magazine::EMPTY; MAX_HARTS],
+            in_use: AtomicUsize::new(0),
+            _marker: PhantomData,
+        }
+    }
+
+    // 每个对象占用的大小，至少能放下空闲链表的一个字，并且满足对齐要求
+    fn object_size() -> usize {
+        let align = align_of::<T>().max(align_of::<usize>());
+        let size = size_of::<T>().max(size_of::<usize>());
+        (size + align - 1) / align * align
+    }
+
+    fn objects_per_slab() -> usize {
+        PAGE_SIZE / Self::object_size()
+    }
+
+    // 当前处理核的弹匣
+    #[allow(clippy::mut_from_ref)]
+    fn magazine(&self) -> &mut Magazine {
+        let hart_id = crate::hart_id();
+        assert!(hart_id < MAX_HARTS, "hart {} has no slab magazine", hart_id);
+        unsafe { &mut *self.magazines[hart_id].get() }
+    }
+
+    // 对象放回当前处理核的弹匣，弹匣满了时先把一半还给仓库
+    fn free_raw(&self, object: usize) {
+        let magazine = self.magazine();
+        if magazine.len == MAGAZINE_SIZE {
+            let mut depot = self.depot.lock();
+            for _ in 0..MAGAZINE_SIZE / 2 {
+                depot.push(magazine.pop().unwrap());
+            }
+        }
+        magazine.push(object);
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let depot = self.depot.lock();
+        let in_use = self.in_use.load(Ordering::Relaxed);
+        let total = depot.slabs * Self::objects_per_slab();
+        SlabStats {
+            name: self.name,
+            object_size: Self::object_size(),
+            slabs: depot.slabs,
+            in_use,
+            free: depot.free_count,
+            cached: total.saturating_sub(in_use + depot.free_count),
+        }
+    }
+}
+
+impl<T: Send + 'static> SlabCache<T> {
+    // 分配一个对象，放入value
+    pub fn alloc(&'static self, value: T) -> Result<SlabBox<T>, FrameAllocError> {
+        let ptr = self.alloc_raw()? as *mut T;
+        unsafe { ptr.write(value) };
+        Ok(SlabBox { ptr: unsafe { NonNull::new_unchecked(ptr) }, cache: self })
+    }
+
+    fn alloc_raw(&'static self) -> Result<usize, FrameAllocError> {
+        let magazine = self.magazine();
+        let object = match magazine.pop() {
+            Some(object) => object,
+            None => {
+                self.refill(magazine)?;
+                magazine.pop().expect("refilled magazine is empty")
+            },
+        };
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        Ok(object)
+    }
+
+    // 从仓库取半个弹匣的对象，仓库空了时先切分一个新的slab
+    fn refill(&'static self, magazine: &mut Magazine) -> Result<(), FrameAllocError> {
+        let mut depot = self.depot.lock();
+        let first_slab = depot.slabs == 0;
+        if depot.free_count == 0 {
+            self.grow(&mut depot)?;
+        }
+        while magazine.len < MAGAZINE_SIZE / 2 {
+            match depot.pop() {
+                Some(object) => magazine.push(object),
+                None => break,
+            }
+        }
+        drop(depot);
+        // 第一次分配slab以后登记这个缓存。slab_stats先获得列表的锁再获得仓库的锁，这里不能反过来
+        if first_slab {
+            CACHES.lock().push(self);
+        }
+        Ok(())
+    }
+
+    fn grow(&'static self, depot: &mut Depot) -> Result<(), FrameAllocError> {
+        assert!(align_of::<T>() <= PAGE_SIZE && Self::object_size() <= PAGE_SIZE, "object of cache {} is larger than a slab", self.name);
+        let page = match KERNEL_FRAME_ALLOC.get() {
+            // 页帧通过线性映射访问
+            Some(frame_alloc) => PhysAddr(frame_alloc.allocate_frame()?.as_usize() * PAGE_SIZE).to_kernel_virt().0,
+            // 启动早期还没有页帧分配器，比如创建根文件系统的目录项时，这时从内核堆中取一页作为slab
+            None => {
+                let page = unsafe { alloc::alloc::alloc(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()) };
+                if page.is_null() {
+                    return Err(FrameAllocError);
+                }
+                page as usize
+            },
+        };
+        // 倒序放入，分配时按地址从低到高取出
+        for i in (0..Self::objects_per_slab()).rev() {
+            depot.push(page + i * Self::object_size());
+        }
+        depot.slabs += 1;
+        Ok(())
+    }
+}
+
+// 用来在/proc/slabinfo中列出所有的缓存
+trait Cache: Sync {
+    fn stats(&self) -> SlabStats;
+}
+
+impl<T: Send> Cache for SlabCache<T> {
+    fn stats(&self) -> SlabStats {
+        SlabCache::stats(self)
+    }
+}
+
+// 已经分配过slab的缓存
+static CACHES: spin::Mutex<Vec<&'static dyn Cache>> = spin::Mutex::new(Vec::new());
+
+pub fn slab_stats() -> Vec<SlabStats> {
+    CACHES.lock().iter().map(|cache| cache.stats()).collect()
+}
+
+// 对象缓存中的一个对象，相当于Box
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    // 取出其中的值，对象还给缓存
+    pub fn into_inner(this: Self) -> T {
+        let this = ManuallyDrop::new(this);
+        let value = unsafe { this.ptr.as_ptr().read() };
+        this.cache.free_raw(this.ptr.as_ptr() as usize);
+        value
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { core::ptr::drop_in_place(self.ptr.as_ptr()) };
+        self.cache.free_raw(self.ptr.as_ptr() as usize);
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// SlabArc的对象，引用计数放在值的前面
+pub struct ArcInner<T> {
+    count: AtomicUsize,
+    value: T,
+}
+
+impl<T: Send + Sync + 'static> SlabCache<ArcInner<T>> {
+    // 分配一个共享的对象，放入value
+    pub fn alloc_arc(&'static self, value: T) -> Result<SlabArc<T>, FrameAllocError> {
+        let object = ManuallyDrop::new(self.alloc(ArcInner { count: AtomicUsize::new(1), value })?);
+        Ok(SlabArc { ptr: object.ptr, cache: object.cache })
+    }
+}
+
+// 对象缓存中的一个共享对象，相当于Arc。最后一个引用释放时，对象还给缓存
+pub struct SlabArc<T: 'static> {
+    ptr: NonNull<ArcInner<T>>,
+    cache: &'static SlabCache<ArcInner<T>>,
+}
+
+unsafe impl<T: Send + Sync> Send for SlabArc<T> {}
+unsafe impl<T: Send + Sync> Sync for SlabArc<T> {}
+
+impl<T> SlabArc<T> {
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    pub fn strong_count(this: &Self) -> usize {
+        unsafe { this.ptr.as_ref() }.count.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for SlabArc<T> {
+    fn clone(&self) -> Self {
+        unsafe { self.ptr.as_ref() }.count.fetch_add(1, Ordering::Relaxed);
+        SlabArc { ptr: self.ptr, cache: self.cache }
+    }
+}
+
+impl<T> Deref for SlabArc<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &unsafe { self.ptr.as_ref() }.value
+    }
+}
+
+impl<T> Drop for SlabArc<T> {
+    fn drop(&mut self) {
+        if unsafe { self.ptr.as_ref() }.count.fetch_sub(1, Ordering::Release) != 1 {
+            return;
+        }
+        // 其它处理核对这个对象的修改都在它们减少计数之前，释放之前要看到这些修改
+        fence(Ordering::Acquire);
+        drop(SlabBox { ptr: self.ptr, cache: self.cache });
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+pub(crate) fn test_slab() {
+    #[derive(Debug, PartialEq)]
+    struct Object {
+        id: usize,
+        payload: [u8; 40],
+    }
+    static OBJECTS: SlabCache<Object> = SlabCache::new("slab_test");
+    assert_eq!(SlabCache::<Object>::object_size(), size_of::<Object>());
+    assert_eq!(SlabCache::<u8>::object_size(), size_of::<usize>());
+    // 超过一个弹匣的对象，分配时需要多次从仓库取，释放时需要还给仓库
+    let count = MAGAZINE_SIZE * 3;
+    let mut objects = Vec::new();
+    for id in 0..count {
+        objects.push(OBJECTS.alloc(Object { id, payload: [id as u8; 40] }).expect("allocate slab object"));
+    }
+    for (id, object) in objects.iter().enumerate() {
+        assert_eq!(**object, Object { id, payload: [id as u8; 40] });
+    }
+    let stats = OBJECTS.stats();
+    assert_eq!(stats.in_use, count);
+    assert_eq!(stats.slabs, (count + SlabCache::<Object>::objects_per_slab() - 1) / SlabCache::<Object>::objects_per_slab());
+    assert!(slab_stats().iter().any(|stats| stats.name == "slab_test"));
+    // 刚释放的对象留在弹匣里，下一次分配立即取回它
+    let last = objects.pop().unwrap();
+    let addr = &*last as *const Object as usize;
+    drop(last);
+    let again = OBJECTS.alloc(Object { id: 0, payload: [0; 40] }).expect("allocate slab object");
+    assert_eq!(&*again as *const Object as usize, addr);
+    drop(again);
+    drop(objects);
+    let stats = OBJECTS.stats();
+    assert_eq!(stats.in_use, 0);
+    assert_eq!(stats.free + stats.cached, stats.slabs * SlabCache::<Object>::objects_per_slab());
+    assert!(stats.cached <= MAGAZINE_SIZE);
+    // 共享的对象在最后一个引用释放时才还给缓存
+    static SHARED: SlabCache<ArcInner<Object>> = SlabCache::new("slab_arc_test");
+    let first = SHARED.alloc_arc(Object { id: 1, payload: [1; 40] }).expect("allocate shared slab object");
+    let second = first.clone();
+    assert!(SlabArc::ptr_eq(&first, &second));
+    assert_eq!(SlabArc::strong_count(&first), 2);
+    drop(first);
+    assert_eq!(SHARED.stats().in_use, 1);
+    assert_eq!(second.id, 1);
+    drop(second);
+    assert_eq!(SHARED.stats().in_use, 0);
+    println!("[kernel-slab-test] Slab allocator test passed");
+}
@@ -0,0 +1,135 @@
+//! 进程表
+//!
+//! 记录每个进程的编号、状态和地址空间的信息，/proc文件系统从这里读取进程的状态。
+//! 进程的地址空间和打开的文件由调度器持有，见scheduler模块
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::RwLock;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProcessState {
+    Running,
+    // 收到停止信号，等待SIGCONT
+    Stopped,
+    // 进程已经结束，记录它的返回值
+    Exited(i32),
+}
+
+impl ProcessState {
+    // 和Linux的/proc/<pid>/status中State一行的写法相同
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessState::Running => "R (running)",
+            ProcessState::Stopped => "T (stopped)",
+            ProcessState::Exited(_) => "Z (zombie)",
+        }
+    }
+}
+
+// 地址空间中的一段映射
+#[derive(Clone, Debug)]
+pub struct MapArea {
+    pub start: usize,
+    pub end: usize,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    // 是否和其它进程共享，只有mmap的共享映射为true
+    pub shared: bool,
+    // 映射的来源，比如程序的路径或者"[stack]"
+    pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct Process {
+    pub pid: usize,
+    // 父进程的编号，没有父进程时为0
+    pub ppid: usize,
+    pub name: String,
+    pub state: ProcessState,
+    pub asid: usize,
+    // 根页表的物理页号
+    pub root_ppn: usize,
+    pub areas: Vec<MapArea>,
+}
+
+lazy_static! {
+    static ref PROCESSES: RwLock<BTreeMap<usize, Process>> = RwLock::new(BTreeMap::new());
+}
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+// 当前运行的进程，0表示没有
+static CURRENT_PID: AtomicUsize = AtomicUsize::new(0);
+
+pub fn alloc_pid() -> usize {
+    NEXT_PID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn insert(process: Process) {
+    PROCESSES.write().insert(process.pid, process);
+}
+
+pub fn set_state(pid: usize, state: ProcessState) {
+    if let Some(process) = PROCESSES.write().get_mut(&pid) {
+        process.state = state;
+    }
+}
+
+// 更新进程地址空间中起始地址为area.start的映射，没有时插入；area为空时删除这段映射
+pub fn set_area(pid: usize, area: MapArea) {
+    if let Some(process) = PROCESSES.write().get_mut(&pid) {
+        let areas = &mut process.areas;
+        match areas.binary_search_by_key(&area.start, |a| a.start) {
+            Ok(idx) if area.start == area.end => { areas.remove(idx); },
+            Ok(idx) => areas[idx] = area,
+            Err(_) if area.start == area.end => {},
+            Err(idx) => areas.insert(idx, area),
+        }
+    }
+}
+
+// 把进程地址空间中[start, end)范围内的映射替换为areas
+pub fn set_areas_in(pid: usize, start: usize, end: usize, areas: Vec<MapArea>) {
+    if let Some(process) = PROCESSES.write().get_mut(&pid) {
+        process.areas.retain(|a| a.end <= start || a.start >= end);
+        process.areas.extend(areas);
+        process.areas.sort_by_key(|a| a.start);
+    }
+}
+
+pub fn set_current(pid: usize) {
+    CURRENT_PID.store(pid, Ordering::Relaxed);
+}
+
+pub fn current_pid() -> Option<usize> {
+    match CURRENT_PID.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(pid),
+    }
+}
+
+// 所有进程的编号，从小到大排列
+pub fn pids() -> Vec<usize> {
+    PROCESSES.read().keys().copied().collect()
+}
+
+// 等待pid号子进程结束。结束时从进程表中删除它，返回它的返回值；还在运行时返回Some(None)；
+// 不是parent的子进程时返回None
+pub fn try_wait(parent: usize, pid: usize) -> Option<Option<i32>> {
+    let mut processes = PROCESSES.write();
+    match processes.get(&pid) {
+        Some(process) if process.ppid == parent => match process.state {
+            ProcessState::Running | ProcessState::Stopped => Some(None),
+            ProcessState::Exited(code) => {
+                processes.remove(&pid);
+                Some(Some(code))
+            },
+        },
+        _ => None,
+    }
+}
+
+pub fn with_process<R>(pid: usize, f: impl FnOnce(&Process) -> R) -> Option<R> {
+    PROCESSES.read().get(&pid).map(f)
+}
@@ -121,6 +121,10 @@ pub fn shutdown() -> ! {
     unreachable!()
 }
 
-pub fn set_timer(time: usize) {
-    sbi_call_legacy(SBI_SET_TIMER, time, 0, 0);
+// 在time寄存器达到time时产生时钟中断，同时清除正在等待的时钟中断。RV32上高32位放在a1中
+pub fn set_timer(time: u64) {
+    #[cfg(target_pointer_width = "64")]
+    sbi_call_legacy(SBI_SET_TIMER, time as usize, 0, 0);
+    #[cfg(target_pointer_width = "32")]
+    sbi_call_legacy(SBI_SET_TIMER, time as usize, (time >> 32) as usize, 0);
 }
@@ -0,0 +1,326 @@
+//! 用户进程和线程的调度
+//!
+//! 进程持有地址空间、打开的文件和句柄等资源，同一个进程的所有线程共享它们；
+//! 每个线程有自己的上下文，包括用户栈的位置和保存线程局部存储位置的tp寄存器。
+//!
+//! 每个处理核只有一个跳板数据页，正在运行的线程的上下文放在那里。切换线程时，
+//! 先把当前线程的上下文复制出来，再把下一个线程的上下文和它所属进程的地址空间换上去。
+//! 所以一个地址空间中可以有任意多个线程，它们轮流使用这个处理核的跳板数据页。
+//!
+//! 调度是轮转式的：线程在退出、让出或者系统调用阻塞的时候，把处理核让给队列中的下一个线程；
+//! 内核命令行设置了时间片时，线程用完时间片以后也会在时钟中断时被换下。
+//! 阻塞的系统调用不会推进sepc，线程下次运行时重新执行ecall，再尝试一次这个系统调用。
+//!
+//! 浮点寄存器不在跳板数据页上，切换线程时按sstatus.FS保存和恢复，见fpu模块。
+//!
+//! 每个线程还有自己的内核栈，内核在当前线程的内核栈上处理它的陷入。线程结束时内核可能还在它的栈上，
+//! 这个栈等到回到启动栈以后才释放
+
+use crate::{executor::{ResumeContext, Runtime}, fpu::{self, FpContext}, fs, ipc, mm, process, signal::{self, SignalState}, syscall::{Errno, EAGAIN, EDEADLK, EINVAL, ENOMEM, ESRCH}, timer, user_heap::UserHeap, vma::VmaSet, KernelPageMode};
+use alloc::{collections::{BTreeMap, VecDeque}, vec::Vec};
+use riscv::register::{satp::Satp, sstatus::FS};
+
+// 用户进程的地址空间和页帧都从全局的页帧分配器中分配
+pub type UserFrameAllocator = &'static mm::DefaultFrameAllocator;
+
+// 进程和线程的记录从各自的缓存中分配
+static TASKS: mm::SlabCache<Task> = mm::SlabCache::new("task");
+static THREADS: mm::SlabCache<Thread> = mm::SlabCache::new("thread");
+
+pub struct Task {
+    pub pid: usize,
+    pub space: mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>,
+    pub heap: UserHeap<UserFrameAllocator>,
+    // mmap创建的映射
+    pub mappings: VmaSet,
+    pub fd_table: fs::FdTable,
+    // 共享内存和通道的句柄
+    pub handles: ipc::HandleTable,
+    // 等待处理和被屏蔽的信号，以及信号的处理方式
+    pub signals: SignalState,
+    pub asid: mm::AddressSpaceId,
+    // 程序和栈占用的页帧，进程结束时随Task一起释放
+    _frames: Vec<mm::FrameBox<UserFrameAllocator>>,
+    // 进程最多可以占用的页数，包括程序、栈、堆和mmap映射
+    memory_limit: usize,
+    satp: Satp,
+    // 进程的线程，还在运行的线程为None，结束的线程记录返回值，等待其它线程取走
+    threads: BTreeMap<usize, Option<i32>>,
+}
+
+impl Task {
+    pub fn new(
+        pid: usize,
+        space: mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>,
+        frames: Vec<mm::FrameBox<UserFrameAllocator>>,
+        heap: UserHeap<UserFrameAllocator>,
+        mappings: VmaSet,
+        fd_table: fs::FdTable,
+        asid: mm::AddressSpaceId,
+        memory_limit: usize,
+        satp: Satp,
+    ) -> Self {
+        let threads = BTreeMap::new();
+        let handles = ipc::HandleTable::new();
+        let signals = SignalState::new();
+        Task { pid, space, heap, mappings, fd_table, handles, signals, asid, _frames: frames, memory_limit, satp, threads }
+    }
+
+    // 在内存限制中还可以分配的页数
+    pub fn available_pages(&self) -> usize {
+        self.memory_limit.saturating_sub(self._frames.len() + self.heap.pages() + self.mappings.pages())
+    }
+
+    // 还在运行的线程数
+    pub fn running_threads(&self) -> usize {
+        self.threads.values().filter(|state| state.is_none()).count()
+    }
+
+    // 等待同一个进程中的tid号线程结束。结束时取走它的返回值；还在运行时返回EAGAIN
+    pub fn try_join(&mut self, current: usize, tid: usize) -> Result<i32, Errno> {
+        if tid == current {
+            return Err(EDEADLK);
+        }
+        match self.threads.get(&tid) {
+            Some(Some(code)) => {
+                let code = *code;
+                self.threads.remove(&tid);
+                Ok(code)
+            },
+            Some(None) => Err(EAGAIN),
+            None => Err(ESRCH),
+        }
+    }
+}
+
+// 线程不在运行时，保存它的上下文
+struct Thread {
+    tid: usize,
+    pid: usize,
+    context: ResumeContext,
+    // 线程用过浮点单元以后，切换出去时浮点寄存器保存在这里
+    fp: Option<mm::SlabBox<FpContext>>,
+    // 内核在这个栈上处理线程的陷入
+    kernel_stack: mm::KernelStack,
+}
+
+pub struct Scheduler {
+    tasks: BTreeMap<usize, mm::SlabBox<Task>>,
+    // 队首是正在运行的线程
+    threads: VecDeque<mm::SlabBox<Thread>>,
+    // 连续阻塞的次数。所有线程都阻塞时，只有外部的输入或者时钟中断能让它们继续，这时等待中断
+    blocked_in_row: usize,
+    // 处理核上的浮点寄存器属于哪个线程。不在运行的线程的浮点寄存器总是已经保存了，
+    // 所以换上这个线程时不需要恢复
+    fp_owner: Option<usize>,
+    // 时间片的长度，为0时不抢占
+    timeslice_ms: u64,
+    // 当前线程的时间片结束时的time寄存器值
+    slice_end: u64,
+    // 结束的线程的内核栈。结束时内核可能还在这个栈上运行，回到启动栈以后才能释放
+    exited_stacks: Vec<mm::KernelStack>,
+}
+
+impl Scheduler {
+    pub fn new(timeslice_ms: u64) -> Self {
+        Scheduler {
+            tasks: BTreeMap::new(), threads: VecDeque::new(), blocked_in_row: 0, fp_owner: None,
+            timeslice_ms, slice_end: u64::MAX, exited_stacks: Vec::new(),
+        }
+    }
+
+    // 加入新的进程，它的主线程从context开始运行，在kernel_stack上处理陷入，线程号和进程号相同。
+    // 第一个加入的进程直接换上去运行，其它进程排在队尾
+    pub fn add(&mut self, rt: &mut Runtime, mut task: Task, context: ResumeContext, kernel_stack: mm::KernelStack) {
+        if self.threads.is_empty() {
+            unsafe { rt.load_context(&context, task.satp) };
+            process::set_current(task.pid);
+        }
+        task.threads.insert(task.pid, None);
+        let thread = THREADS.alloc(Thread { tid: task.pid, pid: task.pid, context, fp: None, kernel_stack }).expect("allocate thread record");
+        self.threads.push_back(thread);
+        self.tasks.insert(task.pid, TASKS.alloc(task).expect("allocate process record"));
+    }
+
+    pub fn current(&mut self) -> &mut Task {
+        let pid = self.threads.front().expect("no running thread").pid;
+        self.tasks.get_mut(&pid).expect("thread without process")
+    }
+
+    pub fn current_tid(&self) -> usize {
+        self.threads.front().expect("no running thread").tid
+    }
+
+    // 当前线程的内核栈的栈顶
+    pub fn kernel_stack_top(&self) -> usize {
+        self.threads.front().expect("no running thread").kernel_stack.top()
+    }
+
+    // 释放结束的线程的内核栈，不能在这些栈上调用
+    pub fn free_exited_stacks(&mut self) {
+        self.exited_stacks.clear();
+    }
+
+    // 在当前进程中创建线程，从entry开始运行，a0是arg。stack是用户栈的栈顶，按16字节对齐；
+    // tp是线程局部存储的位置。返回新线程的线程号
+    pub fn spawn_thread(&mut self, entry: usize, stack: usize, tp: usize, arg: usize) -> Result<usize, Errno> {
+        if stack % 16 != 0 {
+            return Err(EINVAL);
+        }
+        let kernel_stack = mm::KernelStack::new().map_err(|_| ENOMEM)?;
+        let tid = process::alloc_pid();
+        let task = self.current();
+        let mut context = ResumeContext::new_user(entry, mm::VirtAddr(stack));
+        context.tp = tp;
+        context.a0 = arg;
+        let thread = THREADS.alloc(Thread { tid, pid: task.pid, context, fp: None, kernel_stack }).map_err(|_| ENOMEM)?;
+        task.threads.insert(tid, None);
+        self.threads.push_back(thread);
+        Ok(tid)
+    }
+
+    // 当前线程执行了非法指令。如果它还没有用过浮点单元，为它打开浮点单元，从清零的浮点寄存器开始，
+    // 返回true，让线程重新执行这条指令；否则返回false，这是真正的非法指令
+    pub fn enable_fpu(&mut self, rt: &mut Runtime) -> bool {
+        let ctx = unsafe { rt.context_mut() };
+        if !fpu::is_available() || fpu::state(ctx) != FS::Off {
+            return false;
+        }
+        let thread = self.threads.front_mut().expect("no running thread");
+        let fp = thread.fp.get_or_insert_with(fpu::new_context);
+        unsafe { fpu::restore(fp) };
+        self.fp_owner = Some(thread.tid);
+        fpu::set_state(ctx, FS::Initial);
+        true
+    }
+
+    // 向pid号进程发送信号。sig为0时只检查进程是否存在
+    pub fn send_signal(&mut self, pid: usize, sig: usize) -> Result<(), Errno> {
+        if sig != 0 && !signal::is_valid(sig) {
+            return Err(EINVAL);
+        }
+        let task = self.tasks.get_mut(&pid).ok_or(ESRCH)?;
+        if sig == 0 {
+            return Ok(());
+        }
+        let stopped = task.signals.is_stopped();
+        task.signals.send(sig);
+        if stopped && !task.signals.is_stopped() {
+            process::set_state(pid, process::ProcessState::Running);
+        }
+        Ok(())
+    }
+
+    // 向所有进程发送信号
+    pub fn signal_all(&mut self, sig: usize) {
+        let pids: Vec<usize> = self.tasks.keys().copied().collect();
+        for pid in pids {
+            let _ = self.send_signal(pid, sig);
+        }
+    }
+
+    // 当前线程的系统调用完成了，说明线程之间还有进展
+    pub fn mark_progress(&mut self) {
+        self.blocked_in_row = 0;
+    }
+
+    // 当前线程的系统调用需要等待，切换到下一个线程
+    pub fn block_current(&mut self, rt: &mut Runtime) {
+        self.blocked_in_row += 1;
+        if self.blocked_in_row >= self.threads.len() {
+            crate::console::wait_for_input();
+            self.blocked_in_row = 0;
+        }
+        self.switch_next(rt);
+    }
+
+    // 当前线程主动让出处理核，排到队尾
+    pub fn yield_current(&mut self, rt: &mut Runtime) {
+        self.blocked_in_row = 0;
+        self.switch_next(rt);
+    }
+
+    // 当前线程的时间片结束的时刻，返回用户态之前用它设置时钟中断。不抢占或者只有一个线程时返回None
+    pub fn slice_deadline(&self) -> Option<u64> {
+        Some(self.slice_end).filter(|_| self.timeslice_ms > 0 && self.threads.len() > 1)
+    }
+
+    // 时钟中断到来时，当前线程的时间片用完了就排到队尾
+    pub fn preempt(&mut self, rt: &mut Runtime) {
+        if self.slice_deadline().map_or(false, |deadline| timer::now() >= deadline) {
+            self.yield_current(rt);
+        }
+    }
+
+    // 当前线程结束，记录它的返回值，换上下一个线程。进程的最后一个线程应当用exit_current结束
+    pub fn exit_thread(&mut self, rt: &mut Runtime, code: i32) {
+        let thread = self.threads.pop_front().expect("no running thread");
+        self.release_fpu(thread.tid);
+        let task = self.tasks.get_mut(&thread.pid).expect("thread without process");
+        task.threads.insert(thread.tid, Some(code));
+        debug_assert!(task.running_threads() > 0, "last thread exited without ending its process");
+        self.exited_stacks.push(mm::SlabBox::into_inner(thread).kernel_stack);
+        self.blocked_in_row = 0;
+        self.load_front(rt);
+    }
+
+    // 当前进程结束，结束它的所有线程，把进程从调度器中取出并返回，换上下一个线程。
+    // 没有线程可以运行时返回的第二项为false
+    pub fn exit_current(&mut self, rt: &mut Runtime) -> (mm::SlabBox<Task>, bool) {
+        let pid = self.threads.front().expect("no running thread").pid;
+        let (exited, running): (VecDeque<mm::SlabBox<Thread>>, VecDeque<mm::SlabBox<Thread>>) = self.threads.drain(..).partition(|thread| thread.pid == pid);
+        self.threads = running;
+        for thread in exited {
+            // 其它线程可能正在futex上等待
+            ipc::futex::cancel(thread.tid);
+            self.release_fpu(thread.tid);
+            self.exited_stacks.push(mm::SlabBox::into_inner(thread).kernel_stack);
+        }
+        let task = self.tasks.remove(&pid).expect("thread without process");
+        self.blocked_in_row = 0;
+        (task, self.load_front(rt))
+    }
+
+    // 换上队首的线程，队列为空时返回false
+    fn load_front(&mut self, rt: &mut Runtime) -> bool {
+        match self.threads.front() {
+            Some(next) => {
+                if fpu::state(&next.context) != FS::Off && self.fp_owner != Some(next.tid) {
+                    unsafe { fpu::restore(next.fp.as_ref().expect("floating-point state not saved")) };
+                    self.fp_owner = Some(next.tid);
+                }
+                let satp = self.tasks[&next.pid].satp;
+                unsafe { rt.load_context(&next.context, satp) };
+                process::set_current(next.pid);
+                self.slice_end = timer::deadline_after_us(self.timeslice_ms.saturating_mul(1000));
+                true
+            },
+            None => {
+                process::set_current(0);
+                false
+            },
+        }
+    }
+
+    // 结束的线程不再拥有处理核上的浮点寄存器
+    fn release_fpu(&mut self, tid: usize) {
+        if self.fp_owner == Some(tid) {
+            self.fp_owner = None;
+        }
+    }
+
+    fn switch_next(&mut self, rt: &mut Runtime) {
+        if self.threads.len() <= 1 {
+            return; // 只有一个线程，继续运行它
+        }
+        let mut thread = self.threads.pop_front().unwrap();
+        unsafe { rt.save_context(&mut thread.context) };
+        // 只有修改过的浮点寄存器需要保存，之后处理核上的寄存器和保存的副本相同
+        if fpu::state(&thread.context) == FS::Dirty {
+            unsafe { fpu::save(thread.fp.as_mut().expect("floating-point state not allocated")) };
+            fpu::set_state(&mut thread.context, FS::Clean);
+        }
+        self.threads.push_back(thread);
+        self.load_front(rt);
+    }
+}
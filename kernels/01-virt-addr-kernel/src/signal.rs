@@ -0,0 +1,271 @@
+//! 信号
+//!
+//! 每个进程有一组等待处理的信号和一组被屏蔽的信号。内核在回到用户态之前检查当前进程的信号，
+//! 按信号的处理方式结束进程、忽略信号、停止进程，或者运行用户注册的处理函数。
+//!
+//! 运行处理函数时，内核把当前的上下文保存到用户栈上的信号帧中，再把sepc改为处理函数的地址，
+//! ra改为用户提供的返回函数。处理函数返回到返回函数，它调用sigreturn，内核从栈上的信号帧恢复上下文。
+//!
+//! 信号的编号和默认的处理方式与Linux相同。用户程序的访问异常和非法指令转换为SIGSEGV和SIGILL，
+//! 控制台上的Ctrl-C转换为SIGINT
+
+use crate::{executor::ResumeContext, mm, scheduler::UserFrameAllocator, syscall::{copy_from_user, copy_to_user, Errno, EINVAL}, KernelPageMode};
+use core::mem::{size_of, MaybeUninit};
+
+// 信号的数量，编号从1到NSIG - 1
+pub const NSIG: usize = 32;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+pub const SIGTTIN: usize = 21;
+pub const SIGTTOU: usize = 22;
+pub const SIGURG: usize = 23;
+pub const SIGWINCH: usize = 28;
+
+// 处理函数的两个特殊值
+pub const SIG_DFL: usize = 0;
+pub const SIG_IGN: usize = 1;
+
+// sigprocmask的how参数
+pub const SIG_BLOCK: usize = 0;
+pub const SIG_UNBLOCK: usize = 1;
+pub const SIG_SETMASK: usize = 2;
+
+// 进程被信号结束时，返回值是128加上信号的编号，和shell的习惯相同
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+const fn bit(sig: usize) -> u32 {
+    1 << sig
+}
+
+// SIGKILL和SIGSTOP不能被屏蔽、忽略或者处理
+const UNBLOCKABLE: u32 = bit(SIGKILL) | bit(SIGSTOP);
+const STOP_SIGNALS: u32 = bit(SIGSTOP) | bit(SIGTSTP) | bit(SIGTTIN) | bit(SIGTTOU);
+
+pub fn is_valid(sig: usize) -> bool {
+    sig > 0 && sig < NSIG
+}
+
+enum DefaultAction {
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+}
+
+fn default_action(sig: usize) -> DefaultAction {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => DefaultAction::Ignore,
+        SIGCONT => DefaultAction::Continue,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => DefaultAction::Stop,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// 用户注册的处理方式
+#[derive(Copy, Clone, Debug)]
+pub struct Action {
+    // 处理函数的地址，或者SIG_DFL、SIG_IGN
+    pub handler: usize,
+    // 运行处理函数期间额外屏蔽的信号，正在处理的信号总是被屏蔽
+    pub mask: u32,
+    // 处理函数返回到这里，它应当调用sigreturn
+    pub restorer: usize,
+}
+
+const DEFAULT_ACTION: Action = Action { handler: SIG_DFL, mask: 0, restorer: 0 };
+
+// 运行处理函数之前压到用户栈上的信号帧，sigreturn从中恢复上下文和屏蔽的信号
+#[repr(C)]
+struct SignalFrame {
+    context: ResumeContext,
+    blocked: usize,
+    signo: usize,
+}
+
+// 回到用户态之前处理信号的结果
+pub enum Delivery {
+    // 继续运行当前线程，上下文可能已经被改为处理函数
+    Resume,
+    // 进程被停止，收到SIGCONT以前不能运行
+    Stop,
+    // 进程被信号结束，参数是返回值
+    Terminate(i32),
+}
+
+pub struct SignalState {
+    pending: u32,
+    blocked: u32,
+    actions: [Action; NSIG],
+    stopped: bool,
+    // 最近一次访问异常或者非法指令的地址，作为第二个参数传给处理函数
+    fault_addr: usize,
+}
+
+impl SignalState {
+    // 新进程的所有信号都使用默认的处理方式
+    pub fn new() -> Self {
+        SignalState { pending: 0, blocked: 0, actions: [DEFAULT_ACTION; NSIG], stopped: false, fault_addr: 0 }
+    }
+
+    // 发送信号。SIGCONT让停止的进程继续运行，并丢弃还没有处理的停止信号；停止信号丢弃还没有处理的SIGCONT
+    pub fn send(&mut self, sig: usize) {
+        if sig == SIGCONT {
+            self.stopped = false;
+            self.pending &= !STOP_SIGNALS;
+        } else if STOP_SIGNALS & bit(sig) != 0 {
+            self.pending &= !bit(SIGCONT);
+        }
+        self.pending |= bit(sig);
+    }
+
+    // 当前指令产生的异常，信号被屏蔽或者忽略时也不能跳过，这时恢复为默认的处理方式
+    pub fn force(&mut self, sig: usize, fault_addr: usize) {
+        if self.blocked & bit(sig) != 0 || self.actions[sig].handler == SIG_IGN {
+            self.actions[sig] = DEFAULT_ACTION;
+            self.blocked &= !bit(sig);
+        }
+        self.fault_addr = fault_addr;
+        self.send(sig);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // 设置信号的处理方式，返回原来的处理函数
+    pub fn set_action(&mut self, sig: usize, action: Action) -> Result<usize, Errno> {
+        if !is_valid(sig) || UNBLOCKABLE & bit(sig) != 0 {
+            return Err(EINVAL);
+        }
+        let old = core::mem::replace(&mut self.actions[sig], action);
+        // 改为忽略时，已经在等待的信号也被丢弃
+        if action.handler == SIG_IGN {
+            self.pending &= !bit(sig);
+        }
+        Ok(old.handler)
+    }
+
+    // 修改屏蔽的信号，返回原来的屏蔽字
+    pub fn set_mask(&mut self, how: usize, set: u32) -> Result<u32, Errno> {
+        let old = self.blocked;
+        self.blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(EINVAL),
+        } & !UNBLOCKABLE & !1;
+        Ok(old)
+    }
+
+    // 回到用户态之前调用，处理一个没有被屏蔽的信号。需要运行处理函数时，把信号帧压到space中的用户栈上，
+    // 修改ctx使线程从处理函数开始运行
+    pub fn deliver(&mut self, space: &mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, ctx: &mut ResumeContext) -> Delivery {
+        loop {
+            let ready = self.pending & !self.blocked;
+            if self.stopped && ready & bit(SIGKILL) == 0 {
+                return Delivery::Stop; // 停止的进程只能被SIGKILL结束
+            }
+            if ready == 0 {
+                return Delivery::Resume;
+            }
+            let sig = if ready & bit(SIGKILL) != 0 { SIGKILL } else { ready.trailing_zeros() as usize };
+            self.pending &= !bit(sig);
+            let action = self.actions[sig];
+            match action.handler {
+                SIG_IGN => continue,
+                SIG_DFL => match default_action(sig) {
+                    DefaultAction::Terminate => return Delivery::Terminate(SIGNAL_EXIT_BASE + sig as i32),
+                    DefaultAction::Ignore | DefaultAction::Continue => continue,
+                    DefaultAction::Stop => {
+                        self.stopped = true;
+                        return Delivery::Stop;
+                    },
+                },
+                _ => return self.run_handler(sig, action, space, ctx),
+            }
+        }
+    }
+
+    fn run_handler(&mut self, sig: usize, action: Action, space: &mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, ctx: &mut ResumeContext) -> Delivery {
+        let frame = SignalFrame { context: ctx.clone(), blocked: self.blocked as usize, signo: sig };
+        let sp = ctx.sp.wrapping_sub(size_of::<SignalFrame>()) & !15; // 栈按16字节对齐
+        let bytes = unsafe {
+            core::slice::from_raw_parts(&frame as *const SignalFrame as *const u8, size_of::<SignalFrame>())
+        };
+        if copy_to_user(space, sp, bytes).is_err() {
+            // 用户栈已经不能使用，无法运行处理函数
+            return Delivery::Terminate(SIGNAL_EXIT_BASE + SIGSEGV as i32);
+        }
+        ctx.sp = sp;
+        ctx.sepc = action.handler;
+        ctx.ra = action.restorer;
+        ctx.a0 = sig;
+        ctx.a1 = match sig {
+            SIGSEGV | SIGILL | SIGBUS => self.fault_addr,
+            _ => 0,
+        };
+        self.blocked |= (action.mask | bit(sig)) & !UNBLOCKABLE;
+        Delivery::Resume
+    }
+
+    // 处理函数返回以后，从用户栈顶的信号帧恢复上下文和屏蔽字。
+    // 信号帧在用户内存中，可能被修改过，所以特权级等由内核决定的字段保持不变
+    pub fn sigreturn(&mut self, space: &mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, ctx: &mut ResumeContext) -> Result<(), Errno> {
+        let mut frame = MaybeUninit::<SignalFrame>::zeroed();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(frame.as_mut_ptr() as *mut u8, size_of::<SignalFrame>())
+        };
+        copy_from_user(space, ctx.sp, bytes)?;
+        let frame = unsafe { frame.assume_init() };
+        let mut context = frame.context;
+        context.sstatus = ctx.sstatus;
+        context.kernel_stack = ctx.kernel_stack;
+        context.kernel_satp = ctx.kernel_satp;
+        *ctx = context;
+        self.blocked = frame.blocked as u32 & !UNBLOCKABLE & !1;
+        Ok(())
+    }
+}
+
+pub(crate) fn test_signal() {
+    let mut state = SignalState::new();
+    // 屏蔽的信号保持等待，解除屏蔽以后才处理
+    assert_eq!(state.set_mask(SIG_BLOCK, bit(SIGUSR1) | bit(SIGKILL)), Ok(0));
+    assert_eq!(state.blocked, bit(SIGUSR1));
+    state.send(SIGUSR1);
+    assert_eq!(state.pending & !state.blocked, 0);
+    assert_eq!(state.set_action(SIGKILL, Action { handler: SIG_IGN, mask: 0, restorer: 0 }), Err(EINVAL));
+    assert_eq!(state.set_action(SIGUSR1, Action { handler: SIG_IGN, mask: 0, restorer: 0 }), Ok(SIG_DFL));
+    assert_eq!(state.pending, 0);
+    // 停止以后，SIGCONT让进程继续，并且丢弃等待中的停止信号
+    state.send(SIGTSTP);
+    state.stopped = true;
+    state.send(SIGCONT);
+    assert!(!state.is_stopped());
+    assert_eq!(state.pending, bit(SIGCONT));
+    // 异常产生的信号不能被忽略
+    assert_eq!(state.set_action(SIGSEGV, Action { handler: SIG_IGN, mask: 0, restorer: 0 }), Ok(SIG_DFL));
+    state.force(SIGSEGV, 0x1000);
+    assert_eq!(state.actions[SIGSEGV].handler, SIG_DFL);
+    assert_ne!(state.pending & bit(SIGSEGV), 0);
+    assert!(matches!(default_action(SIGCHLD), DefaultAction::Ignore));
+    println!("[kernel-signal-test] Signal mask and action test passed");
+}
@@ -0,0 +1,131 @@
+//! 文件模块的系统调用
+
+use super::{copy_from_user, copy_to_user, read_user_str, Errno, EINVAL, ENOSYS};
+use crate::{fs, mm};
+use alloc::{vec, vec::Vec};
+
+const FUNCTION_FILE_OPEN: usize = 1;
+const FUNCTION_FILE_CLOSE: usize = 2;
+const FUNCTION_FILE_READ: usize = 3;
+const FUNCTION_FILE_WRITE: usize = 4;
+const FUNCTION_FILE_LSEEK: usize = 5;
+const FUNCTION_FILE_FSTAT: usize = 6;
+const FUNCTION_FILE_GETDENTS: usize = 7;
+const FUNCTION_FILE_MKDIR: usize = 8;
+const FUNCTION_FILE_UNLINK: usize = 9;
+const FUNCTION_FILE_DUP2: usize = 10;
+const FUNCTION_FILE_PIPE: usize = 11;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+// 每次在内核和用户之间复制的最大字节数。一次读操作最多返回这么多字节
+const IO_CHUNK: usize = 4096;
+
+pub fn do_file<M, A>(function: usize, args: [usize; 6], user_as: &mm::PagedAddrSpace<M, A>, fd_table: &mut fs::FdTable) -> Result<usize, Errno>
+where M: mm::PageMode, A: mm::FrameAllocator + Clone {
+    match function {
+        FUNCTION_FILE_OPEN => { // [path_buf, path_len, flags]
+            let [path_buf, path_len, flags, ..] = args;
+            let path = read_user_str(user_as, path_buf, path_len)?;
+            let flags = fs::OpenFlags::from_bits(flags as u32).ok_or(EINVAL)?;
+            let file = fs::open(&path, flags)?;
+            Ok(fd_table.insert(file)?)
+        },
+        FUNCTION_FILE_CLOSE => { // [fd]
+            fd_table.close(args[0])?;
+            Ok(0)
+        },
+        FUNCTION_FILE_READ => { // [fd, buf, len]
+            let [fd, buf, len, ..] = args;
+            let file = fd_table.get(fd)?;
+            let mut kernel_buf: Vec<u8> = vec![0; len.min(IO_CHUNK)];
+            let n = file.read(&mut kernel_buf)?;
+            copy_to_user(user_as, buf, &kernel_buf[..n])?;
+            Ok(n)
+        },
+        FUNCTION_FILE_WRITE => { // [fd, buf, len]
+            let [fd, buf, len, ..] = args;
+            let file = fd_table.get(fd)?;
+            let mut kernel_buf: Vec<u8> = vec![0; len.min(IO_CHUNK)];
+            let mut written = 0;
+            while written < len {
+                let chunk = &mut kernel_buf[..(len - written).min(IO_CHUNK)];
+                copy_from_user(user_as, buf + written, chunk)?;
+                let n = match file.write(chunk) {
+                    Ok(n) => n,
+                    Err(_) if written > 0 => break, // 已经写入了一部分，先返回写入的长度
+                    Err(e) => return Err(e.into()),
+                };
+                written += n;
+                if n < chunk.len() {
+                    break;
+                }
+            }
+            Ok(written)
+        },
+        FUNCTION_FILE_LSEEK => { // [fd, offset as isize, whence]
+            let [fd, offset, whence, ..] = args;
+            let pos = match whence {
+                SEEK_SET => fs::SeekFrom::Start(offset),
+                SEEK_CUR => fs::SeekFrom::Current(offset as isize),
+                SEEK_END => fs::SeekFrom::End(offset as isize),
+                _ => return Err(EINVAL),
+            };
+            Ok(fd_table.get(fd)?.seek(pos)?)
+        },
+        FUNCTION_FILE_FSTAT => { // [fd, stat_buf]
+            let [fd, stat_buf, ..] = args;
+            let stat = fd_table.get(fd)?.stat();
+            let bytes = unsafe {
+                core::slice::from_raw_parts(&stat as *const fs::FileStat as *const u8, core::mem::size_of::<fs::FileStat>())
+            };
+            copy_to_user(user_as, stat_buf, bytes)?;
+            Ok(0)
+        },
+        FUNCTION_FILE_GETDENTS => { // [fd, buf, len]
+            let [fd, buf, len, ..] = args;
+            let file = fd_table.get(fd)?;
+            let mut kernel_buf: Vec<u8> = vec![0; len.min(IO_CHUNK)];
+            let n = file.getdents(&mut kernel_buf)?;
+            copy_to_user(user_as, buf, &kernel_buf[..n])?;
+            Ok(n)
+        },
+        FUNCTION_FILE_MKDIR => { // [path_buf, path_len]
+            let path = read_user_str(user_as, args[0], args[1])?;
+            fs::mkdir(&path)?;
+            Ok(0)
+        },
+        FUNCTION_FILE_UNLINK => { // [path_buf, path_len]
+            let path = read_user_str(user_as, args[0], args[1])?;
+            fs::unlink(&path)?;
+            Ok(0)
+        },
+        FUNCTION_FILE_DUP2 => { // [old_fd, new_fd]
+            Ok(fd_table.dup2(args[0], args[1])?)
+        },
+        FUNCTION_FILE_PIPE => { // [fds_buf]，写入读端和写端的描述符
+            let (reader, writer) = fs::pipe()?;
+            let read_fd = fd_table.insert(reader)?;
+            let write_fd = match fd_table.insert(writer) {
+                Ok(fd) => fd,
+                Err(e) => {
+                    fd_table.close(read_fd)?;
+                    return Err(e.into());
+                },
+            };
+            let fds = [read_fd, write_fd];
+            let bytes = unsafe {
+                core::slice::from_raw_parts(fds.as_ptr() as *const u8, core::mem::size_of_val(&fds))
+            };
+            if let Err(e) = copy_to_user(user_as, args[0], bytes) {
+                fd_table.close(read_fd)?;
+                fd_table.close(write_fd)?;
+                return Err(e);
+            }
+            Ok(0)
+        },
+        _ => Err(ENOSYS),
+    }
+}
@@ -0,0 +1,186 @@
+//! 进程间通信模块的系统调用
+
+use super::{copy_from_user, copy_to_user, memory, read_user_str, Errno, SyscallOperation, SyscallResult, EAGAIN, EFAULT, EINVAL, EMSGSIZE, ENOMEM, ENOSYS};
+use crate::{ipc::{self, futex, Handle, Message, SharedMemory}, mm, scheduler::Task, timer, vma::{self, Backing, Prot, Vma}, KernelPageMode};
+use alloc::{vec, vec::Vec};
+use core::{sync::atomic::{AtomicU32, Ordering}, task::Poll};
+
+const FUNCTION_IPC_CLOSE: usize = 1;
+const FUNCTION_IPC_SHM_CREATE: usize = 2;
+const FUNCTION_IPC_SHM_OPEN: usize = 3;
+const FUNCTION_IPC_SHM_SIZE: usize = 4;
+const FUNCTION_IPC_SHM_MAP: usize = 5;
+const FUNCTION_IPC_CHANNEL_CREATE: usize = 6;
+const FUNCTION_IPC_CHANNEL_SEND: usize = 7;
+const FUNCTION_IPC_CHANNEL_RECV: usize = 8;
+const FUNCTION_IPC_FUTEX_WAIT: usize = 9;
+const FUNCTION_IPC_FUTEX_WAKE: usize = 10;
+
+// 发送和接收的flags：队列满或者空时不阻塞，返回EAGAIN
+const IPC_NONBLOCK: usize = 1;
+// 消息不附带句柄，接收到的消息没有句柄时也写入这个值
+const NO_HANDLE: usize = usize::MAX;
+// futex_wait不设超时
+const NO_TIMEOUT: usize = usize::MAX;
+
+// tid是发起系统调用的线程，futex按线程排队
+pub fn do_ipc(function: usize, args: [usize; 6], task: &mut Task, tid: usize) -> SyscallOperation {
+    if function == FUNCTION_IPC_FUTEX_WAIT {
+        // 值不相等时也返回EAGAIN，这时不能阻塞，所以单独处理
+        return match futex_wait(args, task, tid) {
+            Poll::Pending => SyscallOperation::Block,
+            Poll::Ready(ans) => SyscallOperation::Return(SyscallResult::from_result(ans.map(|_| 0))),
+        };
+    }
+    let nonblock = match function {
+        FUNCTION_IPC_CHANNEL_SEND | FUNCTION_IPC_CHANNEL_RECV => args[4] & IPC_NONBLOCK != 0,
+        _ => true,
+    };
+    match ipc(function, args, task) {
+        Err(EAGAIN) if !nonblock => SyscallOperation::Block,
+        ans => SyscallOperation::Return(SyscallResult::from_result(ans)),
+    }
+}
+
+fn ipc(function: usize, args: [usize; 6], task: &mut Task) -> Result<usize, Errno> {
+    match function {
+        FUNCTION_IPC_CLOSE => { // [handle]
+            task.handles.remove(args[0])?;
+            Ok(0)
+        },
+        FUNCTION_IPC_SHM_CREATE => { // [len, name_buf, name_len]，name_len为0时创建没有名字的共享内存
+            let [len, name_buf, name_len, ..] = args;
+            // 共享内存计入创建者的内存限制
+            if len.saturating_add(vma::PAGE_SIZE - 1) / vma::PAGE_SIZE > task.available_pages() {
+                return Err(ENOMEM);
+            }
+            let frame_alloc = task.mappings.frame_alloc();
+            let shm = if name_len == 0 {
+                SharedMemory::new(len, frame_alloc)?
+            } else {
+                SharedMemory::new_named(&read_user_str(&task.space, name_buf, name_len)?, len, frame_alloc)?
+            };
+            task.handles.insert(Handle::SharedMemory(shm))
+        },
+        FUNCTION_IPC_SHM_OPEN => { // [name_buf, name_len]
+            let [name_buf, name_len, ..] = args;
+            let shm = SharedMemory::open(&read_user_str(&task.space, name_buf, name_len)?)?;
+            task.handles.insert(Handle::SharedMemory(shm))
+        },
+        FUNCTION_IPC_SHM_SIZE => { // [handle]
+            Ok(task.handles.shared_memory(args[0])?.len())
+        },
+        FUNCTION_IPC_SHM_MAP => { // [handle, addr, prot]，映射整个共享内存，返回映射的起始地址
+            let [handle, addr, prot, ..] = args;
+            let shm = task.handles.shared_memory(handle)?;
+            let prot = Prot::from_bits(prot).ok_or(EINVAL)?;
+            let len = shm.len();
+            if len / vma::PAGE_SIZE > task.available_pages() {
+                return Err(ENOMEM);
+            }
+            let start = task.mappings.find_free(addr, len).ok_or(ENOMEM)?;
+            let vma = Vma::new(start, len, prot, Prot::all(), true, Backing::SharedMemory(shm), task.mappings.frame_alloc())?;
+            let ans = task.mappings.insert(&mut task.space, vma);
+            memory::update_areas(task);
+            ans.map(|_| start)
+        },
+        FUNCTION_IPC_CHANNEL_CREATE => { // [handles_buf]，写入两个端点的句柄
+            let (a, b) = ipc::channel();
+            let a = task.handles.insert(Handle::Channel(a))?;
+            let b = match task.handles.insert(Handle::Channel(b)) {
+                Ok(b) => b,
+                Err(e) => {
+                    task.handles.remove(a)?;
+                    return Err(e);
+                },
+            };
+            let handles = [a, b];
+            let bytes = unsafe {
+                core::slice::from_raw_parts(handles.as_ptr() as *const u8, core::mem::size_of_val(&handles))
+            };
+            if let Err(e) = copy_to_user(&task.space, args[0], bytes) {
+                task.handles.remove(a)?;
+                task.handles.remove(b)?;
+                return Err(e);
+            }
+            Ok(0)
+        },
+        FUNCTION_IPC_CHANNEL_SEND => { // [handle, buf, len, transfer, flags]，transfer是随消息转移的共享内存句柄
+            let [handle, buf, len, transfer, ..] = args;
+            let endpoint = task.handles.channel(handle)?;
+            if len > ipc::MAX_MESSAGE_SIZE {
+                return Err(EMSGSIZE);
+            }
+            let mut data: Vec<u8> = vec![0; len];
+            copy_from_user(&task.space, buf, &mut data)?;
+            // 只能转移共享内存。通道的端点随消息转移可能形成循环引用，让通道永远不被释放
+            let attached = match transfer {
+                NO_HANDLE => None,
+                transfer => Some(Handle::SharedMemory(task.handles.shared_memory(transfer)?)),
+            };
+            endpoint.send(Message { data, handle: attached })?;
+            if transfer != NO_HANDLE {
+                task.handles.remove(transfer)?;
+            }
+            Ok(0)
+        },
+        FUNCTION_IPC_CHANNEL_RECV => { // [handle, buf, len, handle_buf, flags]，返回消息的长度，handle_buf处写入附带的句柄
+            let [handle, buf, len, handle_buf, ..] = args;
+            let endpoint = task.handles.channel(handle)?;
+            let (space, handles) = (&task.space, &mut task.handles);
+            endpoint.recv_with(|message| {
+                if message.data.len() > len {
+                    return Err(EMSGSIZE); // 消息留在队列中，可以用更大的缓冲区重新接收
+                }
+                copy_to_user(space, buf, &message.data)?;
+                let received = match message.handle.clone() {
+                    Some(handle) => handles.insert(handle)?,
+                    None => NO_HANDLE,
+                };
+                if let Err(e) = copy_to_user(space, handle_buf, &received.to_ne_bytes()) {
+                    if received != NO_HANDLE {
+                        handles.remove(received)?;
+                    }
+                    return Err(e);
+                }
+                Ok(message.data.len())
+            })
+        },
+        FUNCTION_IPC_FUTEX_WAKE => { // [addr, count]，返回唤醒的线程数
+            let [addr, count, ..] = args;
+            let key = futex_key(task, addr)?;
+            Ok(futex::wake(key, count))
+        },
+        _ => Err(ENOSYS),
+    }
+}
+
+// [addr, expected, timeout_us]，addr处的32位值等于expected时等待，直到被唤醒或者超时
+fn futex_wait(args: [usize; 6], task: &Task, tid: usize) -> Poll<Result<(), Errno>> {
+    let [addr, expected, timeout_us, ..] = args;
+    let key = match futex_key(task, addr) {
+        Ok(key) => key,
+        Err(e) => return Poll::Ready(Err(e)),
+    };
+    // 重新执行时使用第一次登记的超时时刻
+    let deadline = match timeout_us {
+        NO_TIMEOUT => None,
+        us => Some(timer::deadline_after_us(us as u64)),
+    };
+    futex::wait(tid, key, deadline, || {
+        let value = unsafe { &*(mm::PhysAddr(key).to_kernel_virt().0 as *const AtomicU32) };
+        value.load(Ordering::SeqCst) == expected as u32
+    })
+}
+
+// 用户地址对应的物理地址。共享内存在不同进程中的地址不同，物理地址相同
+fn futex_key(task: &Task, addr: usize) -> Result<usize, Errno> {
+    if addr % 4 != 0 {
+        return Err(EINVAL);
+    }
+    let mut key = 0;
+    mm::translate_user_frames(&task.space, mm::VirtAddr(addr), 4, false, |ppn, offset, _| {
+        key = ppn.addr_begin::<KernelPageMode>().0 + offset;
+    }).map_err(|_| EFAULT)?;
+    Ok(key)
+}
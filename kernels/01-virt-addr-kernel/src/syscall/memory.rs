@@ -0,0 +1,109 @@
+//! 内存模块的系统调用
+
+use super::{Errno, EACCES, EINVAL, ENODEV, ENOMEM, ENOSYS};
+use crate::{fs, process, scheduler::Task, vma::{self, Backing, Prot, Vma}};
+use alloc::string::ToString;
+
+const FUNCTION_MEMORY_BRK: usize = 1;
+const FUNCTION_MEMORY_MMAP: usize = 2;
+const FUNCTION_MEMORY_MUNMAP: usize = 3;
+const FUNCTION_MEMORY_MPROTECT: usize = 4;
+
+// mmap的flags，和Linux相同
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+pub fn do_memory(function: usize, args: [usize; 6], task: &mut Task) -> Result<usize, Errno> {
+    match function {
+        FUNCTION_MEMORY_BRK => { // [new_brk]，返回新的堆末尾；new_brk为0时只返回当前的堆末尾
+            if args[0] == 0 {
+                return Ok(task.heap.brk());
+            }
+            let max_pages = task.heap.pages() + task.available_pages();
+            let brk = task.heap.set_brk(&mut task.space, args[0], max_pages)?;
+            let start = task.heap.start();
+            process::set_area(task.pid, process::MapArea {
+                start,
+                end: start + task.heap.pages() * 0x1000,
+                read: true, write: true, execute: false, shared: false,
+                name: "[heap]".to_string(),
+            });
+            Ok(brk)
+        },
+        FUNCTION_MEMORY_MMAP => { // [addr, len, prot, flags, fd, offset]，返回映射的起始地址
+            let [addr, len, prot, flags, fd, offset] = args;
+            let prot = Prot::from_bits(prot).ok_or(EINVAL)?;
+            let len = len.checked_add(vma::PAGE_SIZE - 1).ok_or(ENOMEM)? & !(vma::PAGE_SIZE - 1);
+            if len == 0 || offset % vma::PAGE_SIZE != 0 || flags & !(MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS) != 0 {
+                return Err(EINVAL);
+            }
+            let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+                MAP_SHARED => true,
+                MAP_PRIVATE => false,
+                _ => return Err(EINVAL),
+            };
+            let (backing, max_prot) = if flags & MAP_ANONYMOUS != 0 {
+                // 匿名的共享映射只在进程内部共享，和私有映射相同
+                (Backing::Anonymous, Prot::all())
+            } else {
+                let file = task.fd_table.get(fd)?;
+                if file.stat().kind != fs::InodeType::File as u32 {
+                    return Err(ENODEV);
+                }
+                if !file.flags().contains(fs::OpenFlags::READ) {
+                    return Err(EACCES);
+                }
+                let max_prot = if shared && !file.flags().contains(fs::OpenFlags::WRITE) {
+                    Prot::READ | Prot::EXEC
+                } else {
+                    Prot::all()
+                };
+                (Backing::File(file.inode().clone(), file.dentry().name().to_string(), offset), max_prot)
+            };
+            if !max_prot.contains(prot) {
+                return Err(EACCES);
+            }
+            let start = if flags & MAP_FIXED != 0 {
+                vma::check_range(addr, len)?;
+                addr
+            } else {
+                task.mappings.find_free(addr, len).ok_or(ENOMEM)?
+            };
+            // MAP_FIXED覆盖的映射会被取消，它们占用的页可以重新使用
+            if len / vma::PAGE_SIZE > task.available_pages() + task.mappings.pages_in(start, start + len) {
+                return Err(ENOMEM);
+            }
+            // 先准备好新的页，失败时原来的映射保持不变
+            let vma = Vma::new(start, len, prot, max_prot, shared, backing, task.mappings.frame_alloc())?;
+            task.mappings.unmap(&mut task.space, start, start + len);
+            let ans = task.mappings.insert(&mut task.space, vma);
+            update_areas(task);
+            ans.map(|_| start)
+        },
+        FUNCTION_MEMORY_MUNMAP => { // [addr, len]
+            let [addr, len, ..] = args;
+            let len = len.checked_add(vma::PAGE_SIZE - 1).ok_or(EINVAL)? & !(vma::PAGE_SIZE - 1);
+            vma::check_range(addr, len)?;
+            task.mappings.unmap(&mut task.space, addr, addr + len);
+            update_areas(task);
+            Ok(0)
+        },
+        FUNCTION_MEMORY_MPROTECT => { // [addr, len, prot]
+            let [addr, len, prot, ..] = args;
+            let prot = Prot::from_bits(prot).ok_or(EINVAL)?;
+            let len = len.checked_add(vma::PAGE_SIZE - 1).ok_or(EINVAL)? & !(vma::PAGE_SIZE - 1);
+            vma::check_range(addr, len)?;
+            let ans = task.mappings.protect(&mut task.space, addr, addr + len, prot);
+            update_areas(task); // 失败时可能已经修改了一部分
+            ans.map(|_| 0)
+        },
+        _ => Err(ENOSYS),
+    }
+}
+
+// 把mmap的映射同步到进程表，/proc/<pid>/maps从那里读取
+pub(super) fn update_areas(task: &Task) {
+    process::set_areas_in(task.pid, vma::MMAP_BASE, vma::MMAP_END, task.mappings.areas());
+}
@@ -0,0 +1,213 @@
+//! 系统调用
+//!
+//! 用户程序把模块编号放在a7，功能编号放在a6，参数放在a0到a5。
+//! 返回时a0是错误号，成功为0；a1是返回值。
+
+mod file;
+mod ipc;
+mod memory;
+
+use crate::{fs, mm, process, scheduler::Task, signal};
+use alloc::{string::String, vec, vec::Vec};
+
+const MODULE_PROCESS: usize = 0x114514;
+const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
+const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+const FUNCTION_PROCESS_SPAWN: usize = 1;
+const FUNCTION_PROCESS_WAIT: usize = 2;
+const FUNCTION_PROCESS_THREAD_CREATE: usize = 3;
+const FUNCTION_PROCESS_THREAD_EXIT: usize = 4;
+const FUNCTION_PROCESS_THREAD_JOIN: usize = 5;
+const FUNCTION_PROCESS_THREAD_SELF: usize = 6;
+const FUNCTION_PROCESS_YIELD: usize = 7;
+const FUNCTION_PROCESS_KILL: usize = 8;
+const FUNCTION_PROCESS_SIGACTION: usize = 9;
+const FUNCTION_PROCESS_SIGPROCMASK: usize = 10;
+const FUNCTION_PROCESS_SIGRETURN: usize = 11;
+
+const MODULE_FILE: usize = 0xf11e;
+
+const MODULE_MEMORY: usize = 0x3e3;
+
+const MODULE_IPC: usize = 0x1bc;
+
+pub enum SyscallOperation {
+    Return(SyscallResult),
+    // 系统调用需要等待，比如读取空的管道。进程让出处理核，下次运行时重新执行这个系统调用
+    Block,
+    Terminate(i32),
+    UserPanic(Option<String>, u32, u32, Option<String>),
+    // 创建运行path处程序的子进程，参数是以0分隔的字符串，第一个是程序的名字
+    Spawn(String, Vec<u8>),
+    // 在当前进程中创建线程，参数是入口、栈顶、tp和传给线程的a0
+    SpawnThread(usize, usize, usize, usize),
+    // 结束当前线程；进程的最后一个线程结束时，进程也结束
+    ExitThread(i32),
+    // 系统调用已经完成，线程让出处理核
+    Yield,
+    // 向pid号进程发送信号
+    Kill(usize, usize),
+    // 信号处理函数返回，从用户栈上的信号帧恢复上下文
+    SigReturn,
+}
+
+pub struct SyscallResult {
+    pub code: usize,
+    pub extra: usize,
+}
+
+impl SyscallResult {
+    pub fn from_result(ans: Result<usize, Errno>) -> Self {
+        match ans {
+            Ok(extra) => SyscallResult { code: 0, extra },
+            Err(Errno(code)) => SyscallResult { code, extra: 0 },
+        }
+    }
+}
+
+// 返回给用户的错误号，和Linux的错误号保持一致
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Errno(pub usize);
+
+pub const ENOENT: Errno = Errno(2);
+pub const ESRCH: Errno = Errno(3);
+pub const E2BIG: Errno = Errno(7);
+pub const ENOEXEC: Errno = Errno(8);
+pub const EBADF: Errno = Errno(9);
+pub const ECHILD: Errno = Errno(10);
+pub const EAGAIN: Errno = Errno(11);
+pub const ENOMEM: Errno = Errno(12);
+pub const EACCES: Errno = Errno(13);
+pub const EFAULT: Errno = Errno(14);
+pub const EEXIST: Errno = Errno(17);
+pub const ENODEV: Errno = Errno(19);
+pub const EINVAL: Errno = Errno(22);
+pub const EMFILE: Errno = Errno(24);
+pub const EPIPE: Errno = Errno(32);
+pub const EDEADLK: Errno = Errno(35);
+pub const ENAMETOOLONG: Errno = Errno(36);
+pub const ENOSYS: Errno = Errno(38);
+pub const EMSGSIZE: Errno = Errno(90);
+pub const ETIMEDOUT: Errno = Errno(110);
+
+impl From<fs::FsError> for Errno {
+    fn from(e: fs::FsError) -> Errno {
+        Errno(e.errno())
+    }
+}
+
+// tid是发起系统调用的线程，task是它所属的进程
+pub fn syscall(module: usize, function: usize, args: [usize; 6], task: &mut Task, tid: usize) -> SyscallOperation {
+    match module {
+        MODULE_PROCESS => do_process(function, args, task, tid),
+        MODULE_FILE => match file::do_file(function, args, &task.space, &mut task.fd_table) {
+            Err(EAGAIN) => SyscallOperation::Block,
+            ans => SyscallOperation::Return(SyscallResult::from_result(ans)),
+        },
+        MODULE_MEMORY => SyscallOperation::Return(SyscallResult::from_result(memory::do_memory(function, args, task))),
+        MODULE_IPC => ipc::do_ipc(function, args, task, tid),
+        // 未知的系统调用只返回错误，不能让用户程序使内核停止
+        _ => SyscallOperation::Return(SyscallResult::from_result(Err(ENOSYS))),
+    }
+}
+
+fn do_process(function: usize, args: [usize; 6], task: &mut Task, tid: usize) -> SyscallOperation {
+    let user_as = &task.space;
+    match function {
+        FUNCTION_PROCESS_EXIT => SyscallOperation::Terminate(args[0] as i32),
+        FUNCTION_PROCESS_SPAWN => { // [path_buf, path_len, args_buf, args_len]
+            let [path_buf, path_len, args_buf, args_len, ..] = args;
+            let spawn_args = || {
+                let path = read_user_str(user_as, path_buf, path_len)?;
+                if args_len > MAX_USER_STR {
+                    return Err(E2BIG);
+                }
+                let mut spawn_args: Vec<u8> = vec![0; args_len];
+                copy_from_user(user_as, args_buf, &mut spawn_args)?;
+                Ok((path, spawn_args))
+            };
+            match spawn_args() {
+                Ok((path, spawn_args)) => SyscallOperation::Spawn(path, spawn_args),
+                Err(e) => SyscallOperation::Return(SyscallResult::from_result(Err(e))),
+            }
+        },
+        FUNCTION_PROCESS_WAIT => { // [pid]，返回子进程的返回值
+            let parent = process::current_pid().unwrap_or(0);
+            match process::try_wait(parent, args[0]) {
+                Some(Some(code)) => SyscallOperation::Return(SyscallResult::from_result(Ok(code as isize as usize))),
+                Some(None) => SyscallOperation::Block, // 子进程还在运行
+                None => SyscallOperation::Return(SyscallResult::from_result(Err(ECHILD))),
+            }
+        },
+        FUNCTION_PROCESS_THREAD_CREATE => { // [entry, stack, tp, arg]，返回线程号
+            let [entry, stack, tp, arg, ..] = args;
+            SyscallOperation::SpawnThread(entry, stack, tp, arg)
+        },
+        FUNCTION_PROCESS_THREAD_EXIT => SyscallOperation::ExitThread(args[0] as i32),
+        FUNCTION_PROCESS_THREAD_JOIN => { // [tid]，返回线程的返回值
+            match task.try_join(tid, args[0]) {
+                Err(EAGAIN) => SyscallOperation::Block, // 线程还在运行
+                ans => SyscallOperation::Return(SyscallResult::from_result(ans.map(|code| code as isize as usize))),
+            }
+        },
+        FUNCTION_PROCESS_THREAD_SELF => SyscallOperation::Return(SyscallResult::from_result(Ok(tid))),
+        FUNCTION_PROCESS_YIELD => SyscallOperation::Yield,
+        FUNCTION_PROCESS_KILL => SyscallOperation::Kill(args[0], args[1]), // [pid, sig]
+        FUNCTION_PROCESS_SIGACTION => { // [sig, handler, mask, restorer]，返回原来的处理函数
+            let [sig, handler, mask, restorer, ..] = args;
+            let action = signal::Action { handler, mask: mask as u32, restorer };
+            SyscallOperation::Return(SyscallResult::from_result(task.signals.set_action(sig, action)))
+        },
+        FUNCTION_PROCESS_SIGPROCMASK => { // [how, set]，返回原来屏蔽的信号
+            let [how, set, ..] = args;
+            let ans = task.signals.set_mask(how, set as u32).map(|old| old as usize);
+            SyscallOperation::Return(SyscallResult::from_result(ans))
+        },
+        FUNCTION_PROCESS_SIGRETURN => SyscallOperation::SigReturn,
+        FUNCTION_PROCESS_PANIC => { // [line as usize, col as usize, f_buf, f_len, m_buf, m_len]
+            let [line, col, f_buf, f_len, m_buf, m_len] = args;
+            // 字符串在用户空间中，读取失败时当作没有提供
+            let read = |ptr: usize, len: usize| if ptr == 0 { None } else { read_user_str(user_as, ptr, len).ok() };
+            SyscallOperation::UserPanic(read(f_buf, f_len), line as u32, col as u32, read(m_buf, m_len))
+        },
+        _ => SyscallOperation::Return(SyscallResult::from_result(Err(ENOSYS))),
+    }
+}
+
+// 从用户空间的ptr处读取数据，填满buf
+pub(crate) fn copy_from_user<M, A>(user_as: &mm::PagedAddrSpace<M, A>, ptr: usize, buf: &mut [u8]) -> Result<(), Errno>
+where M: mm::PageMode, A: mm::FrameAllocator + Clone {
+    let mut copied = 0;
+    mm::translate_user_frames(user_as, mm::VirtAddr(ptr), buf.len(), false, |ppn, offset, len| {
+        let src = ppn.addr_begin::<M>().to_kernel_virt().0 + offset;
+        let src = unsafe { core::slice::from_raw_parts(src as *const u8, len) };
+        buf[copied..copied + len].copy_from_slice(src);
+        copied += len;
+    }).map_err(|_| EFAULT)
+}
+
+// 把data写到用户空间的ptr处
+pub(crate) fn copy_to_user<M, A>(user_as: &mm::PagedAddrSpace<M, A>, ptr: usize, data: &[u8]) -> Result<(), Errno>
+where M: mm::PageMode, A: mm::FrameAllocator + Clone {
+    let mut copied = 0;
+    mm::translate_user_frames(user_as, mm::VirtAddr(ptr), data.len(), true, |ppn, offset, len| {
+        let dst = ppn.addr_begin::<M>().to_kernel_virt().0 + offset;
+        let dst = unsafe { core::slice::from_raw_parts_mut(dst as *mut u8, len) };
+        dst.copy_from_slice(&data[copied..copied + len]);
+        copied += len;
+    }).map_err(|_| EFAULT)
+}
+
+// 用户传入的路径等字符串的最大长度
+const MAX_USER_STR: usize = 4096;
+
+// 读取用户空间中(ptr, len)表示的UTF-8字符串
+fn read_user_str<M, A>(user_as: &mm::PagedAddrSpace<M, A>, ptr: usize, len: usize) -> Result<String, Errno>
+where M: mm::PageMode, A: mm::FrameAllocator + Clone {
+    if len > MAX_USER_STR {
+        return Err(ENAMETOOLONG);
+    }
+    let mut buf: Vec<u8> = vec![0; len];
+    copy_from_user(user_as, ptr, &mut buf)?;
+    String::from_utf8(buf).map_err(|_| EINVAL)
+}
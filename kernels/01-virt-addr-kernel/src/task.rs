@@ -0,0 +1,45 @@
+//! 内核中的异步任务
+//!
+//! 驱动程序的读写请求是Future，完成时由中断处理函数唤醒。
+//! 在还没有调度器的地方，用block_on在当前处理核上等待一个Future完成。
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use riscv::{asm::wfi, register::sstatus};
+
+// 在当前处理核上运行Future直到完成。等待期间短暂打开中断，让驱动程序的中断处理函数能够运行
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    // note(unsafe): future在这个函数返回之前不会被移动
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(ans) = future.as_mut().poll(&mut cx) {
+            return ans;
+        }
+        wait_for_interrupt();
+    }
+}
+
+// 等待并处理一次中断。
+// 即使sstatus.SIE为0，有中断等待处理时wfi也会返回；然后短暂打开中断，在内核的异常入口处理它
+pub fn wait_for_interrupt() {
+    unsafe {
+        wfi();
+        sstatus::set_sie();
+        sstatus::clear_sie();
+    }
+}
+
+// 每次中断之后都会重新轮询，所以唤醒器不需要做任何事
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+}
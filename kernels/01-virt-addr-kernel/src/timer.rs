@@ -0,0 +1,48 @@
+//! 时钟
+//!
+//! time寄存器以固定的频率增加，频率由设备树给出。需要在某个时刻醒来时，用set_alarm设置时钟中断；
+//! 中断不做其它事情，只是让等待中断的处理核醒来，由调度器重新检查阻塞的线程是否已经超时
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::{sie, time};
+
+// QEMU virt平台的时钟频率，设备树中没有给出时使用
+const DEFAULT_TIMEBASE_FREQUENCY: usize = 10_000_000;
+
+static TIMEBASE_FREQUENCY: AtomicUsize = AtomicUsize::new(DEFAULT_TIMEBASE_FREQUENCY);
+// 已经设置的时钟中断时刻，没有设置时为u64::MAX。RV32没有64位的原子操作，所以用锁保护
+static NEXT_ALARM: spin::Mutex<u64> = spin::Mutex::new(u64::MAX);
+
+pub fn init(timebase_frequency: Option<usize>) {
+    if let Some(freq) = timebase_frequency.filter(|&freq| freq > 0) {
+        TIMEBASE_FREQUENCY.store(freq, Ordering::Relaxed);
+    }
+    unsafe { sie::set_stimer() };
+}
+
+// 当前的time寄存器
+pub fn now() -> u64 {
+    time::read64()
+}
+
+// 从现在开始经过us微秒以后的time寄存器值
+pub fn deadline_after_us(us: u64) -> u64 {
+    let freq = TIMEBASE_FREQUENCY.load(Ordering::Relaxed) as u64;
+    let ticks = (us as u128 * freq as u128 / 1_000_000).min(u64::MAX as u128) as u64;
+    now().saturating_add(ticks)
+}
+
+// 在deadline时产生时钟中断。已经设置了更早的中断时不需要改变
+pub fn set_alarm(deadline: u64) {
+    let mut next = NEXT_ALARM.lock();
+    if deadline < *next {
+        *next = deadline;
+        crate::sbi::set_timer(deadline);
+    }
+}
+
+// 时钟中断的处理函数。取消已经设置的中断，还需要等待的线程下次检查时重新设置
+pub fn handle_interrupt() {
+    *NEXT_ALARM.lock() = u64::MAX;
+    crate::sbi::set_timer(u64::MAX);
+}
@@ -0,0 +1,169 @@
+//! 内核态的异常和中断处理
+//!
+//! 用户程序运行时，stvec指向跳板页上的入口，由执行器处理用户的异常；
+//! 回到内核以后，stvec指向这里的kernel_trap_entry，处理发生在内核中的异常和中断。
+//! 内核中的异常都无法恢复，入口把它们换到每个处理核自己的异常栈上处理：
+//! 内核栈溢出时原来的栈已经不能使用，在异常栈上仍然可以报告错误。
+
+use core::ops::Range;
+use riscv::register::{
+    scause::{self, Trap, Exception, Interrupt},
+    stvec::{self, TrapMode}, stval,
+};
+
+// 每个处理核的异常栈的大小，和入口中的左移位数一致
+const TRAP_STACK_SIZE: usize = 4096 * 2;
+// 和启动栈一样，最多支持8个处理核
+const MAX_HARTS: usize = 8;
+
+static mut TRAP_STACK: [u8; TRAP_STACK_SIZE * MAX_HARTS] = [0; TRAP_STACK_SIZE * MAX_HARTS];
+
+// 包含va的异常栈的地址范围
+pub fn trap_stack_range(va: usize) -> Option<Range<usize>> {
+    let start = unsafe { TRAP_STACK.as_ptr() } as usize;
+    let offset = va.checked_sub(start).filter(|&offset| offset < TRAP_STACK_SIZE * MAX_HARTS)?;
+    let bottom = start + offset / TRAP_STACK_SIZE * TRAP_STACK_SIZE;
+    Some(bottom..bottom + TRAP_STACK_SIZE)
+}
+
+// 设置内核的异常入口
+pub fn init() {
+    unsafe { set_kernel_trap_entry() };
+}
+
+// 执行器从用户程序返回内核以后，调用这个函数恢复内核的异常入口
+#[inline]
+pub unsafe fn set_kernel_trap_entry() {
+    stvec::write(kernel_trap_entry as usize, TrapMode::Direct);
+}
+
+// 内核异常时保存的上下文。只需要保存调用者保存的寄存器，其它寄存器由处理函数自己保存。
+// 另外记下被打断时的sp和s0：异常换了栈，要从它们回溯原来的栈
+#[derive(Debug)]
+#[repr(C)]
+pub struct KernelTrapFrame {
+    pub ra: usize, // 0
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    pub a0: usize, // 8
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub sstatus: usize, // 16
+    pub sepc: usize, // 17
+    pub sp: usize, // 18
+    pub s0: usize, // 19
+}
+
+extern "C" fn kernel_trap_handler(frame: &mut KernelTrapFrame) {
+    match scause::read().cause() {
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            crate::interrupt::handle_external(crate::hart_id());
+        },
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            crate::timer::handle_interrupt();
+        },
+        // 访问了内核栈下面的保护页。panic只能回溯异常栈，这里先从出错时的帧指针回溯溢出的内核栈，
+        // 它就在保护页之上
+        Trap::Exception(Exception::LoadPageFault) | Trap::Exception(Exception::StorePageFault)
+            if crate::mm::is_kernel_stack_guard(stval::read()) => {
+            let stval = stval::read();
+            if let Some(stack) = crate::mm::kernel_stack_range((stval & !0xfff) + 0x1000) {
+                crate::backtrace::print_kernel_trap(frame.sepc, frame.s0, stack);
+            }
+            panic!("kernel stack overflow! stval: {:#x}, sepc: {:#x}, sp: {:#x}", stval, frame.sepc, frame.sp)
+        },
+        e => panic!(
+            "unhandled kernel trap: {:?}! stval: {:#x}, frame: {:#x?}",
+            e, stval::read(), frame
+        ),
+    }
+}
+
+#[naked]
+unsafe extern "C" fn kernel_trap_entry() -> ! {
+    asm!(
+        ".p2align 2", // 对齐到4字节
+        // 内核态不使用sscratch，借用它保存t0。中断的scause最高位是1，留在原来的栈上；
+        // 异常换到这个处理核的异常栈上，栈顶是trap_stack + (hartid + 1) * 8KiB。
+        // 两种情况到标号2时都是t0 = 原来的sp，sp = 要使用的栈
+        "csrw   sscratch, t0
+        csrr    t0, scause
+        bgez    t0, 1f
+        mv      t0, sp
+        j       2f
+1:      auipc   t0, %pcrel_hi({trap_stack})
+        addi    t0, t0, %pcrel_lo(1b)",
+        // 没有别的寄存器可以用，借用tp算出偏移以后恢复它，panic时还要用它得到处理核编号
+        "addi   tp, tp, 1
+        slli    tp, tp, 13
+        add     t0, t0, tp
+        srli    tp, tp, 13
+        addi    tp, tp, -1",
+        // 交换sp和t0，不需要其它寄存器
+        "xor    sp, sp, t0
+        xor     t0, sp, t0
+        xor     sp, sp, t0
+2:      ",
+        xsp!("-", 20), // 20个寄存器的空间，保证栈按16字节对齐
+        xs!("t0", 18, "sp"),
+        "csrr   t0, sscratch",
+        xs!("ra", 0, "sp"),
+        xs!("t0", 1, "sp"),
+        xs!("t1", 2, "sp"),
+        xs!("t2", 3, "sp"),
+        xs!("t3", 4, "sp"),
+        xs!("t4", 5, "sp"),
+        xs!("t5", 6, "sp"),
+        xs!("t6", 7, "sp"),
+        xs!("a0", 8, "sp"),
+        xs!("a1", 9, "sp"),
+        xs!("a2", 10, "sp"),
+        xs!("a3", 11, "sp"),
+        xs!("a4", 12, "sp"),
+        xs!("a5", 13, "sp"),
+        xs!("a6", 14, "sp"),
+        xs!("a7", 15, "sp"),
+        "csrr   t0, sstatus",
+        xs!("t0", 16, "sp"),
+        "csrr   t1, sepc",
+        xs!("t1", 17, "sp"),
+        xs!("s0", 19, "sp"),
+        "mv     a0, sp",
+        "call   {handler}",
+        xl!("t0", 16, "sp"),
+        xl!("t1", 17, "sp"),
+        "csrw   sstatus, t0
+        csrw    sepc, t1",
+        xl!("ra", 0, "sp"),
+        xl!("t0", 1, "sp"),
+        xl!("t1", 2, "sp"),
+        xl!("t2", 3, "sp"),
+        xl!("t3", 4, "sp"),
+        xl!("t4", 5, "sp"),
+        xl!("t5", 6, "sp"),
+        xl!("t6", 7, "sp"),
+        xl!("a0", 8, "sp"),
+        xl!("a1", 9, "sp"),
+        xl!("a2", 10, "sp"),
+        xl!("a3", 11, "sp"),
+        xl!("a4", 12, "sp"),
+        xl!("a5", 13, "sp"),
+        xl!("a6", 14, "sp"),
+        xl!("a7", 15, "sp"),
+        xsp!("", 20),
+        "sret",
+        handler = sym kernel_trap_handler,
+        trap_stack = sym TRAP_STACK,
+        options(noreturn)
+    )
+}
@@ -0,0 +1,114 @@
+//! 用户进程的堆
+//!
+//! 堆从程序占用的空间之后开始，向高地址增长。进程用brk系统调用移动堆的末尾，
+//! 内核按页分配或者释放页帧，并修改页表。堆和程序、栈、mmap映射一起计入进程的内存限制
+
+use crate::{mm, syscall::{Errno, ENOMEM}, KernelPageFlags, KernelPageMode};
+use alloc::vec::Vec;
+
+const PAGE_SIZE: usize = 0x1000;
+
+pub struct UserHeap<A: mm::FrameAllocator + Clone> {
+    // 堆的起始地址，按页对齐
+    start: usize,
+    // 堆的末尾，不一定按页对齐
+    brk: usize,
+    // 按地址顺序排列，第i项映射在start + i * PAGE_SIZE处
+    frames: Vec<mm::FrameBox<A>>,
+    frame_alloc: A,
+}
+
+impl<A: mm::FrameAllocator + Clone> UserHeap<A> {
+    pub fn new(start: usize, frame_alloc: A) -> Self {
+        UserHeap { start, brk: start, frames: Vec::new(), frame_alloc }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+
+    // 堆已经占用的页数
+    pub fn pages(&self) -> usize {
+        self.frames.len()
+    }
+
+    // 把堆的末尾移动到new_brk，返回新的末尾。max_pages是进程的内存限制允许堆占用的页数。
+    // 不能移动到起始地址之前；超过内存限制或者页帧不够时返回ENOMEM，堆保持原来的大小
+    pub fn set_brk(&mut self, space: &mut mm::PagedAddrSpace<KernelPageMode, A>, new_brk: usize, max_pages: usize) -> Result<usize, Errno> {
+        if new_brk < self.start {
+            return Err(ENOMEM);
+        }
+        let pages = match new_brk.checked_add(PAGE_SIZE - 1) {
+            Some(end) => (end - self.start) / PAGE_SIZE,
+            None => return Err(ENOMEM),
+        };
+        if pages > max_pages {
+            return Err(ENOMEM);
+        }
+        while self.frames.len() < pages {
+            if let Err(e) = self.push_page(space) {
+                self.shrink(space, self.brk);
+                return Err(e);
+            }
+        }
+        self.shrink(space, new_brk);
+        self.brk = new_brk;
+        Ok(new_brk)
+    }
+
+    // 在堆的末尾映射一个清零的页
+    fn push_page(&mut self, space: &mut mm::PagedAddrSpace<KernelPageMode, A>) -> Result<(), Errno> {
+        let frame_box = mm::FrameBox::try_new_in(self.frame_alloc.clone()).map_err(|_| ENOMEM)?;
+        let page = frame_box.phys_page_num().addr_begin::<KernelPageMode>().to_kernel_virt().0;
+        unsafe { core::ptr::write_bytes(page as *mut u8, 0, PAGE_SIZE) };
+        let vpn = mm::VirtAddr(self.start + self.frames.len() * PAGE_SIZE).page_number::<KernelPageMode>();
+        space.allocate_map(vpn, frame_box.phys_page_num(), 1, KernelPageFlags::R | KernelPageFlags::W | KernelPageFlags::U)
+            .map_err(|_| ENOMEM)?;
+        self.frames.push(frame_box);
+        Ok(())
+    }
+
+    // 释放brk所在页之后的所有页
+    fn shrink(&mut self, space: &mut mm::PagedAddrSpace<KernelPageMode, A>, brk: usize) {
+        let keep = (brk - self.start + PAGE_SIZE - 1) / PAGE_SIZE;
+        while self.frames.len() > keep {
+            let vpn = mm::VirtAddr(self.start + (self.frames.len() - 1) * PAGE_SIZE).page_number::<KernelPageMode>();
+            space.unmap(vpn).expect("unmap user heap page");
+            self.frames.pop();
+        }
+    }
+}
+
+pub(crate) fn test_user_heap(frame_alloc: &'static mm::DefaultFrameAllocator) {
+    use mm::PageMode;
+    let mut space = mm::PagedAddrSpace::try_new_in(KernelPageMode, frame_alloc).expect("create address space");
+    let start = 0x10000000;
+    let mut heap = UserHeap::new(start, frame_alloc);
+    let page_of = |space: &mm::PagedAddrSpace<KernelPageMode, _>, addr: usize| {
+        space.find_ppn(mm::VirtAddr(addr).page_number::<KernelPageMode>()).is_ok()
+    };
+    assert_eq!(heap.set_brk(&mut space, start + 1, 4), Ok(start + 1));
+    assert_eq!((heap.brk(), heap.pages()), (start + 1, 1));
+    assert!(page_of(&space, start) && !page_of(&space, start + PAGE_SIZE));
+    // 新映射的页已经清零
+    let ppn = space.find_ppn(mm::VirtAddr(start).page_number::<KernelPageMode>()).map(|(entry, _)| KernelPageMode::entry_get_ppn(entry)).unwrap();
+    let page = unsafe { core::slice::from_raw_parts(ppn.addr_begin::<KernelPageMode>().to_kernel_virt().0 as *const u8, PAGE_SIZE) };
+    assert!(page.iter().all(|&b| b == 0));
+    assert_eq!(heap.set_brk(&mut space, start + 4 * PAGE_SIZE, 4), Ok(start + 4 * PAGE_SIZE));
+    assert_eq!(heap.pages(), 4);
+    // 超过内存限制，堆保持原来的大小
+    assert_eq!(heap.set_brk(&mut space, start + 4 * PAGE_SIZE + 1, 4), Err(ENOMEM));
+    assert_eq!((heap.brk(), heap.pages()), (start + 4 * PAGE_SIZE, 4));
+    assert_eq!(heap.set_brk(&mut space, start - 1, 4), Err(ENOMEM));
+    // 缩小以后，释放的页不再能访问
+    assert_eq!(heap.set_brk(&mut space, start + PAGE_SIZE, 4), Ok(start + PAGE_SIZE));
+    assert_eq!(heap.pages(), 1);
+    assert!(page_of(&space, start) && !page_of(&space, start + PAGE_SIZE));
+    assert_eq!(heap.set_brk(&mut space, start, 4), Ok(start));
+    assert!(!page_of(&space, start));
+    println!("[kernel-user-heap-test] User heap test passed");
+}
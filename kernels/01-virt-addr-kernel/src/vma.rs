@@ -0,0 +1,409 @@
+//! 用户地址空间中由mmap创建的映射区域
+//!
+//! 每个区域记录地址范围、访问权限和其中每一页的页帧。映射时立即分配页帧并填好内容，
+//! 之后访问这些页不会产生缺页异常。
+//!
+//! 页帧由引用计数管理：私有映射的页只属于一个区域；共享内存的页属于共享内存对象和映射它的所有区域；
+//! 文件的共享映射在全局的文件页缓存中查找，映射同一个文件同一位置的进程共享同一个页帧，
+//! 最后一个映射取消时把内容写回文件。
+//! 通过read和write访问文件不经过这个缓存，两者之间不保证一致
+
+use crate::{fs, ipc, mm, process, scheduler::UserFrameAllocator, syscall::{Errno, EACCES, EINVAL, ENOMEM}, KernelPageFlags, KernelPageMode};
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+pub const PAGE_SIZE: usize = 0x1000;
+// mmap只能使用这段地址，它在程序、堆和栈之间，不包括内核和跳板页所在的地址
+pub const MMAP_BASE: usize = 0x10000000;
+pub const MMAP_END: usize = 0x60000000;
+
+bitflags::bitflags! {
+    // 访问权限，和Linux的PROT_*相同
+    pub struct Prot: usize {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+impl Prot {
+    // 转换为页表项的权限。RISC-V不允许只写不读的页，可写的页同时可读；没有任何权限的页不映射
+    fn page_flags(self) -> Option<KernelPageFlags> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut flags = KernelPageFlags::U;
+        if self.intersects(Prot::READ | Prot::WRITE) {
+            flags |= KernelPageFlags::R;
+        }
+        if self.contains(Prot::WRITE) {
+            flags |= KernelPageFlags::W;
+        }
+        if self.contains(Prot::EXEC) {
+            flags |= KernelPageFlags::X;
+        }
+        Some(flags)
+    }
+}
+
+// 映射区域中的一页
+pub struct PageFrame {
+    frame: mm::FrameBox<UserFrameAllocator>,
+    // 文件共享映射的页，记录所在的文件和在文件中的位置
+    file: Option<(Arc<dyn fs::Inode>, usize)>,
+}
+
+impl PageFrame {
+    // 分配一个清零的页帧
+    pub fn zeroed(frame_alloc: UserFrameAllocator) -> Result<PageFrame, Errno> {
+        let frame = mm::FrameBox::try_new_in(frame_alloc).map_err(|_| ENOMEM)?;
+        let page = PageFrame { frame, file: None };
+        unsafe { core::ptr::write_bytes(page.as_mut_ptr(), 0, PAGE_SIZE) };
+        Ok(page)
+    }
+
+    // 分配页帧，填入文件中offset开始的一页内容，文件末尾之后的部分为0
+    fn load(frame_alloc: UserFrameAllocator, inode: &Arc<dyn fs::Inode>, offset: usize) -> Result<PageFrame, Errno> {
+        let page = PageFrame::zeroed(frame_alloc)?;
+        let buf = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr(), PAGE_SIZE) };
+        let mut filled = 0;
+        while filled < PAGE_SIZE {
+            match inode.read_at(offset + filled, &mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        Ok(page)
+    }
+
+    pub fn phys_page_num(&self) -> mm::PhysPageNum {
+        self.frame.phys_page_num()
+    }
+
+    // 页帧通过线性映射访问
+    fn as_mut_ptr(&self) -> *mut u8 {
+        self.frame.phys_page_num().addr_begin::<KernelPageMode>().to_kernel_virt().0 as *mut u8
+    }
+}
+
+impl Drop for PageFrame {
+    fn drop(&mut self) {
+        let (inode, offset) = match self.file.take() {
+            Some(file) => file,
+            None => return,
+        };
+        // 写回文件，但不会让文件变长
+        let size = inode.metadata().size;
+        if offset < size {
+            let len = (size - offset).min(PAGE_SIZE);
+            let data = unsafe { core::slice::from_raw_parts(self.as_mut_ptr(), len) };
+            if let Err(e) = inode.write_at(offset, data) {
+                warn!("Failed to write back shared mapping at offset {:#x}: {:?}", offset, e);
+            }
+        }
+        let key = (Arc::as_ptr(&inode) as *const () as usize, offset);
+        let mut cache = FILE_PAGES.lock();
+        // 这一页释放的同时，可能已经有新的映射在缓存中放了新的页
+        if cache.get(&key).map(|page| page.strong_count() == 0) == Some(true) {
+            cache.remove(&key);
+        }
+    }
+}
+
+lazy_static! {
+    // 文件共享映射的页缓存，键是节点的地址和页在文件中的位置
+    static ref FILE_PAGES: Mutex<BTreeMap<(usize, usize), Weak<PageFrame>>> = Mutex::new(BTreeMap::new());
+}
+
+// 找到文件中offset处的共享页，不在缓存中时从文件读取
+fn shared_file_page(frame_alloc: UserFrameAllocator, inode: &Arc<dyn fs::Inode>, offset: usize) -> Result<Arc<PageFrame>, Errno> {
+    let key = (Arc::as_ptr(inode) as *const () as usize, offset);
+    if let Some(page) = FILE_PAGES.lock().get(&key).and_then(Weak::upgrade) {
+        return Ok(page);
+    }
+    let mut page = PageFrame::load(frame_alloc, inode, offset)?;
+    // 读取成功以后才记录所在的文件，失败时不会写回
+    page.file = Some((inode.clone(), offset));
+    let page = Arc::new(page);
+    FILE_PAGES.lock().insert(key, Arc::downgrade(&page));
+    Ok(page)
+}
+
+// 映射的来源
+#[derive(Clone)]
+pub enum Backing {
+    Anonymous,
+    // 映射的文件、文件的路径和区域开始处在文件中的位置
+    File(Arc<dyn fs::Inode>, String, usize),
+    // 共享内存对象，区域从它的第一页开始
+    SharedMemory(Arc<ipc::SharedMemory>),
+}
+
+pub struct Vma {
+    start: usize,
+    end: usize,
+    prot: Prot,
+    // mprotect最多可以设置的权限。只读打开的文件不能共享映射为可写
+    max_prot: Prot,
+    shared: bool,
+    backing: Backing,
+    // 第i项映射在start + i * PAGE_SIZE处
+    pages: Vec<Arc<PageFrame>>,
+}
+
+impl Vma {
+    // 创建映射区域，分配所有的页帧并填好内容。start和len按页对齐
+    pub fn new(
+        start: usize, len: usize, prot: Prot, max_prot: Prot, shared: bool, backing: Backing,
+        frame_alloc: UserFrameAllocator,
+    ) -> Result<Vma, Errno> {
+        let mut pages = Vec::with_capacity(len / PAGE_SIZE);
+        for i in 0..len / PAGE_SIZE {
+            let page = match &backing {
+                Backing::Anonymous => Arc::new(PageFrame::zeroed(frame_alloc)?),
+                Backing::File(inode, _, offset) if shared => shared_file_page(frame_alloc, inode, offset + i * PAGE_SIZE)?,
+                Backing::File(inode, _, offset) => Arc::new(PageFrame::load(frame_alloc, inode, offset + i * PAGE_SIZE)?),
+                Backing::SharedMemory(shm) => shm.pages().get(i).cloned().ok_or(EINVAL)?,
+            };
+            pages.push(page);
+        }
+        Ok(Vma { start, end: start + len, prot, max_prot, shared, backing, pages })
+    }
+
+    // 在addr处分成两段，返回后一段
+    fn split_off(&mut self, addr: usize) -> Vma {
+        let pages = self.pages.split_off((addr - self.start) / PAGE_SIZE);
+        let backing = match &self.backing {
+            Backing::File(inode, path, offset) => Backing::File(inode.clone(), path.clone(), offset + (addr - self.start)),
+            backing => backing.clone(), // 后一段的页已经分开，共享内存不需要记录偏移
+        };
+        let tail = Vma { start: addr, end: self.end, prot: self.prot, max_prot: self.max_prot, shared: self.shared, backing, pages };
+        self.end = addr;
+        tail
+    }
+
+    // 在页表中映射这个区域的所有页；失败时取消已经建立的映射
+    fn map(&self, space: &mut mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>) -> Result<(), Errno> {
+        let flags = match self.prot.page_flags() {
+            Some(flags) => flags,
+            None => return Ok(()),
+        };
+        for (i, page) in self.pages.iter().enumerate() {
+            let vpn = mm::VirtAddr(self.start + i * PAGE_SIZE).page_number::<KernelPageMode>();
+            if space.allocate_map(vpn, page.phys_page_num(), 1, flags).is_err() {
+                self.unmap_pages(space, i);
+                return Err(ENOMEM);
+            }
+        }
+        Ok(())
+    }
+
+    fn unmap(&self, space: &mut mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>) {
+        if self.prot.page_flags().is_some() {
+            self.unmap_pages(space, self.pages.len());
+        }
+    }
+
+    // 取消前n页的映射
+    fn unmap_pages(&self, space: &mut mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, n: usize) {
+        for i in 0..n {
+            let vpn = mm::VirtAddr(self.start + i * PAGE_SIZE).page_number::<KernelPageMode>();
+            space.unmap(vpn).expect("unmap user mapping");
+        }
+    }
+
+    fn area(&self) -> process::MapArea {
+        let name = match &self.backing {
+            Backing::Anonymous => String::new(),
+            Backing::File(_, path, _) => path.clone(),
+            Backing::SharedMemory(shm) => shm.display_name(),
+        };
+        process::MapArea {
+            start: self.start,
+            end: self.end,
+            read: self.prot.contains(Prot::READ),
+            write: self.prot.contains(Prot::WRITE),
+            execute: self.prot.contains(Prot::EXEC),
+            shared: self.shared,
+            name,
+        }
+    }
+}
+
+// 一个进程的所有映射区域，按地址从低到高排列，互不重叠
+pub struct VmaSet {
+    vmas: Vec<Vma>,
+    frame_alloc: UserFrameAllocator,
+}
+
+impl VmaSet {
+    pub fn new(frame_alloc: UserFrameAllocator) -> Self {
+        VmaSet { vmas: Vec::new(), frame_alloc }
+    }
+
+    // 新的区域从这里分配页帧
+    pub fn frame_alloc(&self) -> UserFrameAllocator {
+        self.frame_alloc
+    }
+
+    // 所有区域占用的页数，计入进程的内存限制
+    pub fn pages(&self) -> usize {
+        self.vmas.iter().map(|vma| vma.pages.len()).sum()
+    }
+
+    // [start, end)中已经映射的页数
+    pub fn pages_in(&self, start: usize, end: usize) -> usize {
+        self.vmas.iter()
+            .map(|vma| vma.end.min(end).saturating_sub(vma.start.max(start)) / PAGE_SIZE)
+            .sum()
+    }
+
+    // 找到一段长度为len的空闲地址，优先使用hint
+    pub fn find_free(&self, hint: usize, len: usize) -> Option<usize> {
+        if check_range(hint, len).is_ok() && self.is_free(hint, hint + len) {
+            return Some(hint);
+        }
+        let mut start = MMAP_BASE;
+        for vma in self.vmas.iter() {
+            if vma.start >= start + len {
+                break;
+            }
+            start = start.max(vma.end);
+        }
+        if start + len <= MMAP_END { Some(start) } else { None }
+    }
+
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        self.vmas.iter().all(|vma| vma.end <= start || vma.start >= end)
+    }
+
+    // 加入新的区域并映射到页表中，区域的地址必须是空闲的
+    pub fn insert(&mut self, space: &mut mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, vma: Vma) -> Result<(), Errno> {
+        debug_assert!(self.is_free(vma.start, vma.end));
+        vma.map(space)?;
+        let idx = self.vmas.iter().position(|v| v.start > vma.start).unwrap_or(self.vmas.len());
+        self.vmas.insert(idx, vma);
+        Ok(())
+    }
+
+    // 取消[start, end)中的所有映射，区域跨过边界时只取消其中的部分。这段地址中可以没有映射
+    pub fn unmap(&mut self, space: &mut mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, start: usize, end: usize) {
+        self.split_at(start);
+        self.split_at(end);
+        let mut i = 0;
+        while i < self.vmas.len() {
+            if self.vmas[i].start >= start && self.vmas[i].end <= end {
+                let vma = self.vmas.remove(i);
+                vma.unmap(space); // 页帧随vma一起释放；共享的页在最后一个映射取消时释放
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    // 修改[start, end)的访问权限，这段地址必须全部已经映射
+    pub fn protect(&mut self, space: &mut mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, start: usize, end: usize, prot: Prot) -> Result<(), Errno> {
+        let mut covered = start;
+        for vma in self.vmas.iter().filter(|vma| vma.end > start && vma.start < end) {
+            if vma.start > covered {
+                return Err(ENOMEM); // 中间有没有映射的地址，和Linux相同
+            }
+            if !vma.max_prot.contains(prot) {
+                return Err(EACCES);
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return Err(ENOMEM);
+        }
+        self.split_at(start);
+        self.split_at(end);
+        for vma in self.vmas.iter_mut().filter(|vma| vma.start >= start && vma.end <= end) {
+            vma.unmap(space);
+            vma.prot = prot;
+            vma.map(space)?;
+        }
+        Ok(())
+    }
+
+    // 在/proc/<pid>/maps中显示的映射
+    pub fn areas(&self) -> Vec<process::MapArea> {
+        self.vmas.iter().map(Vma::area).collect()
+    }
+
+    // 把跨过addr的区域在addr处分开
+    fn split_at(&mut self, addr: usize) {
+        if let Some(idx) = self.vmas.iter().position(|vma| vma.start < addr && addr < vma.end) {
+            let tail = self.vmas[idx].split_off(addr);
+            self.vmas.insert(idx + 1, tail);
+        }
+    }
+}
+
+// 检查[start, start + len)按页对齐，并且在mmap可以使用的地址范围中
+pub fn check_range(start: usize, len: usize) -> Result<(), Errno> {
+    if start % PAGE_SIZE != 0 || len == 0 || len % PAGE_SIZE != 0 {
+        return Err(EINVAL);
+    }
+    match start.checked_add(len) {
+        Some(end) if start >= MMAP_BASE && end <= MMAP_END => Ok(()),
+        _ => Err(EINVAL),
+    }
+}
+
+pub(crate) fn test_vma(frame_alloc: UserFrameAllocator) {
+    let mut space = mm::PagedAddrSpace::try_new_in(KernelPageMode, frame_alloc).expect("create address space");
+    let mut set = VmaSet::new(frame_alloc);
+    let mapped = |space: &mm::PagedAddrSpace<KernelPageMode, UserFrameAllocator>, addr: usize| {
+        space.find_ppn(mm::VirtAddr(addr).page_number::<KernelPageMode>()).is_ok()
+    };
+    // 内核、跳板页和程序所在的地址不能映射
+    assert_eq!(check_range(0x80200000, PAGE_SIZE), Err(EINVAL));
+    assert_eq!(check_range(usize::MAX - PAGE_SIZE + 1, PAGE_SIZE), Err(EINVAL));
+    assert_eq!(check_range(0x1000, PAGE_SIZE), Err(EINVAL));
+    assert_eq!(check_range(MMAP_BASE + 1, PAGE_SIZE), Err(EINVAL));
+    let start = set.find_free(0, 4 * PAGE_SIZE).unwrap();
+    assert_eq!(start, MMAP_BASE);
+    let vma = Vma::new(start, 4 * PAGE_SIZE, Prot::READ | Prot::WRITE, Prot::all(), false, Backing::Anonymous, frame_alloc).unwrap();
+    set.insert(&mut space, vma).unwrap();
+    assert_eq!(set.pages(), 4);
+    assert_eq!(set.find_free(start, PAGE_SIZE), Some(start + 4 * PAGE_SIZE));
+    // 取消中间一页，区域分成两段
+    set.unmap(&mut space, start + PAGE_SIZE, start + 2 * PAGE_SIZE);
+    assert_eq!(set.pages(), 3);
+    assert!(mapped(&space, start) && !mapped(&space, start + PAGE_SIZE) && mapped(&space, start + 2 * PAGE_SIZE));
+    assert_eq!(set.find_free(0, PAGE_SIZE), Some(start + PAGE_SIZE));
+    assert_eq!(set.pages_in(start, start + 3 * PAGE_SIZE), 2);
+    // 没有权限的页不在页表中，恢复权限以后重新映射
+    assert_eq!(set.protect(&mut space, start, start + 2 * PAGE_SIZE, Prot::READ), Err(ENOMEM));
+    set.protect(&mut space, start + 2 * PAGE_SIZE, start + 4 * PAGE_SIZE, Prot::empty()).unwrap();
+    assert!(!mapped(&space, start + 3 * PAGE_SIZE));
+    set.protect(&mut space, start + 3 * PAGE_SIZE, start + 4 * PAGE_SIZE, Prot::READ).unwrap();
+    assert!(!mapped(&space, start + 2 * PAGE_SIZE) && mapped(&space, start + 3 * PAGE_SIZE));
+    let areas = set.areas();
+    assert_eq!(areas.len(), 3);
+    assert!(!areas[1].read && areas[2].read && !areas[2].write);
+    // 文件的共享映射：两个区域共享页帧，最后一个取消时写回文件
+    let file = fs::open("/kernel-vma-test", fs::OpenFlags::READ | fs::OpenFlags::WRITE | fs::OpenFlags::CREATE).expect("create file");
+    file.write(b"hello, mmap").unwrap();
+    let backing = Backing::File(file.inode().clone(), String::from("/kernel-vma-test"), 0);
+    let a = Vma::new(MMAP_END - PAGE_SIZE, PAGE_SIZE, Prot::READ | Prot::WRITE, Prot::all(), true, backing.clone(), frame_alloc).unwrap();
+    let b = Vma::new(MMAP_END - 2 * PAGE_SIZE, PAGE_SIZE, Prot::READ, Prot::all(), true, backing.clone(), frame_alloc).unwrap();
+    assert_eq!(a.pages[0].phys_page_num(), b.pages[0].phys_page_num());
+    let private = Vma::new(MMAP_END - 3 * PAGE_SIZE, PAGE_SIZE, Prot::READ, Prot::all(), false, backing, frame_alloc).unwrap();
+    assert_ne!(private.pages[0].phys_page_num(), a.pages[0].phys_page_num());
+    unsafe { *a.pages[0].as_mut_ptr() = b'j' };
+    drop(a);
+    let mut buf = [0u8; 16];
+    assert_eq!(file.inode().read_at(0, &mut buf), Ok(11));
+    assert_eq!(&buf[..11], b"hello, mmap", "written back before the last mapping is removed");
+    drop(b);
+    assert_eq!(file.inode().read_at(0, &mut buf), Ok(11));
+    assert_eq!(&buf[..11], b"jello, mmap");
+    drop(private);
+    fs::unlink("/kernel-vma-test").expect("remove test file");
+    set.unmap(&mut space, MMAP_BASE, MMAP_END);
+    assert_eq!(set.pages(), 0);
+    println!("[kernel-vma-test] Memory mapping test passed");
+}
@@ -6,6 +6,7 @@ use std::path::PathBuf;
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=src/linker64.ld");
+    println!("cargo:rerun-if-changed=src/linker32.ld");
 
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
 
@@ -13,5 +14,9 @@ fn main() {
         .unwrap()
         .write_all(include_bytes!("src/linker64.ld"))
         .unwrap();
+    fs::File::create(out_dir.join("linker32.ld"))
+        .unwrap()
+        .write_all(include_bytes!("src/linker32.ld"))
+        .unwrap();
     println!("cargo:rustc-link-search={}", out_dir.display());
 }
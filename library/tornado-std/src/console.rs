@@ -1,14 +1,11 @@
 use core::fmt::{self, Write};
-use super::write;
+use crate::io::{self, Write as _};
 
 struct Stdout;
 
-const STDOUT: usize = 1;
-
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        write(STDOUT, s.as_bytes());
-        Ok(())
+        io::stdout().write_all(s.as_bytes()).map_err(|_| fmt::Error)
     }
 }
 
@@ -16,6 +13,19 @@ pub fn print(args: fmt::Arguments) {
     Stdout.write_fmt(args).unwrap();
 }
 
+struct Stderr;
+
+impl Write for Stderr {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        io::stderr().write_all(s.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+// 标准错误可能已经关闭或者重定向到断开的管道，输出失败时忽略错误
+pub fn eprint(args: fmt::Arguments) {
+    let _ = Stderr.write_fmt(args);
+}
+
 #[macro_export]
 macro_rules! print {
     ($fmt: literal $(, $($arg: tt)+)?) => {
@@ -29,3 +39,17 @@ macro_rules! println {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
 }
+
+#[macro_export]
+macro_rules! eprint {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::eprint(format_args!($fmt $(, $($arg)+)?));
+    }
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    ($fmt: literal $(, $($arg: tt)+)?) => {
+        $crate::console::eprint(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
+    }
+}
@@ -0,0 +1,43 @@
+//! 进程的运行环境
+//!
+//! 内核启动进程时，把以0分隔的参数放在用户栈的顶部，第一个参数是程序的名字
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+static ARGS_PTR: AtomicUsize = AtomicUsize::new(0);
+static ARGS_LEN: AtomicUsize = AtomicUsize::new(0);
+
+// unsafe说明：(args_ptr, args_len)必须是内核传入的参数位置，在程序运行期间一直有效
+pub(crate) unsafe fn init_args(args_ptr: usize, args_len: usize) {
+    ARGS_PTR.store(args_ptr, Ordering::Relaxed);
+    ARGS_LEN.store(args_len, Ordering::Relaxed);
+}
+
+// 程序的参数，第一个是程序的名字
+pub fn args() -> Args {
+    let (ptr, len) = (ARGS_PTR.load(Ordering::Relaxed), ARGS_LEN.load(Ordering::Relaxed));
+    let data = if ptr == 0 {
+        &[][..]
+    } else {
+        unsafe { core::slice::from_raw_parts(ptr as *const u8, len) }
+    };
+    Args { rest: data }
+}
+
+pub struct Args {
+    rest: &'static [u8],
+}
+
+impl Iterator for Args {
+    type Item = &'static str;
+    fn next(&mut self) -> Option<&'static str> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let end = self.rest.iter().position(|&b| b == 0).unwrap_or(self.rest.len());
+        let arg = &self.rest[..end];
+        self.rest = &self.rest[(end + 1).min(self.rest.len())..];
+        // 参数由启动它的程序从&str复制而来，不是合法的UTF-8时返回空字符串
+        Some(core::str::from_utf8(arg).unwrap_or(""))
+    }
+}
@@ -0,0 +1,261 @@
+//! 文件系统
+//!
+//! 路径都是绝对路径。tornado-std没有内存分配器，目录项的名字保存在固定大小的缓冲区中
+
+use crate::io::{self, cvt, Read, Seek, SeekFrom, Write};
+use crate::syscall::{self, FileStat};
+
+// 打开文件的方式，和内核中的定义保持一致
+const OPEN_READ: u32 = 1 << 0;
+const OPEN_WRITE: u32 = 1 << 1;
+const OPEN_APPEND: u32 = 1 << 2;
+const OPEN_CREATE: u32 = 1 << 3;
+const OPEN_TRUNCATE: u32 = 1 << 4;
+const OPEN_EXCLUSIVE: u32 = 1 << 5;
+const OPEN_DIRECTORY: u32 = 1 << 6;
+
+const SEEK_SET: usize = 0;
+const SEEK_CUR: usize = 1;
+const SEEK_END: usize = 2;
+
+// 文件名的最大长度
+const MAX_NAME_LEN: usize = 255;
+
+// 打开的文件，离开作用域时自动关闭
+#[derive(Debug)]
+pub struct File {
+    fd: usize,
+}
+
+impl File {
+    // 以只读方式打开文件
+    pub fn open(path: &str) -> io::Result<File> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    // 以只写方式打开文件；文件不存在时创建，存在时清空
+    pub fn create(path: &str) -> io::Result<File> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        let mut stat = FileStat::default();
+        cvt(syscall::sys_fstat(self.fd, &mut stat))?;
+        Ok(Metadata { stat })
+    }
+
+    pub fn as_raw_fd(&self) -> usize {
+        self.fd
+    }
+
+    // 取出文件描述符，之后不再自动关闭它
+    pub fn into_raw_fd(self) -> usize {
+        let fd = self.fd;
+        core::mem::forget(self);
+        fd
+    }
+
+    // unsafe说明：fd必须是打开的文件描述符，并且没有被其它File持有，否则会被关闭两次
+    pub unsafe fn from_raw_fd(fd: usize) -> File {
+        File { fd }
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        cvt(syscall::sys_read(self.fd, buf))
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        cvt(syscall::sys_write(self.fd, buf))
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(n) => (n as isize, SEEK_SET),
+            SeekFrom::Current(n) => (n as isize, SEEK_CUR),
+            SeekFrom::End(n) => (n as isize, SEEK_END),
+        };
+        cvt(syscall::sys_lseek(self.fd, offset, whence)).map(|pos| pos as u64)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        syscall::sys_close(self.fd);
+    }
+}
+
+// 打开文件的选项，用法和std::fs::OpenOptions相同
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    flags: u32,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        OpenOptions { flags: 0 }
+    }
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.set(OPEN_READ, read)
+    }
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.set(OPEN_WRITE, write)
+    }
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.set(OPEN_APPEND, append)
+    }
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.set(OPEN_TRUNCATE, truncate)
+    }
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.set(OPEN_CREATE, create)
+    }
+    // 总是创建新文件，文件已经存在时返回错误
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.set(OPEN_CREATE | OPEN_EXCLUSIVE, create_new)
+    }
+    pub fn open(&self, path: &str) -> io::Result<File> {
+        let fd = cvt(syscall::sys_open(path, self.flags))?;
+        Ok(File { fd })
+    }
+    fn set(&mut self, flag: u32, value: bool) -> &mut Self {
+        if value {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+        self
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FileType {
+    File,
+    Directory,
+    CharDevice,
+    Fifo,
+    Unknown,
+}
+
+impl FileType {
+    fn from_raw(kind: u32) -> FileType {
+        match kind {
+            1 => FileType::File,
+            2 => FileType::Directory,
+            3 => FileType::CharDevice,
+            4 => FileType::Fifo,
+            _ => FileType::Unknown,
+        }
+    }
+    pub fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+    pub fn is_dir(&self) -> bool {
+        *self == FileType::Directory
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Metadata {
+    stat: FileStat,
+}
+
+impl Metadata {
+    pub fn file_type(&self) -> FileType {
+        FileType::from_raw(self.stat.kind)
+    }
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+    pub fn len(&self) -> u64 {
+        self.stat.size
+    }
+    pub fn ino(&self) -> u64 {
+        self.stat.ino
+    }
+}
+
+pub fn metadata(path: &str) -> io::Result<Metadata> {
+    File::open(path)?.metadata()
+}
+
+pub fn create_dir(path: &str) -> io::Result<()> {
+    cvt(syscall::sys_mkdir(path)).map(|_| ())
+}
+
+pub fn remove_file(path: &str) -> io::Result<()> {
+    cvt(syscall::sys_unlink(path)).map(|_| ())
+}
+
+// 删除空目录
+pub fn remove_dir(path: &str) -> io::Result<()> {
+    cvt(syscall::sys_unlink(path)).map(|_| ())
+}
+
+// 列出目录中的项目
+pub fn read_dir(path: &str) -> io::Result<ReadDir> {
+    let file = OpenOptions { flags: OPEN_READ | OPEN_DIRECTORY }.open(path)?;
+    Ok(ReadDir { file, buf: [0; 512], len: 0, pos: 0 })
+}
+
+pub struct ReadDir {
+    file: File,
+    buf: [u8; 512],
+    len: usize,
+    pos: usize,
+}
+
+impl Iterator for ReadDir {
+    type Item = io::Result<DirEntry>;
+    fn next(&mut self) -> Option<io::Result<DirEntry>> {
+        if self.pos >= self.len {
+            // 缓冲区中的记录用完了，向内核读取下一批
+            match cvt(syscall::sys_getdents(self.file.fd, &mut self.buf)) {
+                Ok(0) => return None,
+                Ok(n) => {
+                    self.len = n;
+                    self.pos = 0;
+                },
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        // 记录格式：记录长度（u16，小端序），类型（u8），名字长度（u8），名字
+        let rec = &self.buf[self.pos..self.len];
+        let rec_len = u16::from_le_bytes([rec[0], rec[1]]) as usize;
+        let name_len = rec[3] as usize;
+        let mut entry = DirEntry { name: [0; MAX_NAME_LEN], name_len, kind: rec[2] };
+        entry.name[..name_len].copy_from_slice(&rec[4..4 + name_len]);
+        self.pos += rec_len;
+        Some(Ok(entry))
+    }
+}
+
+pub struct DirEntry {
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+    kind: u8,
+}
+
+impl DirEntry {
+    pub fn file_name(&self) -> &str {
+        // 内核保证名字是合法的UTF-8
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+    pub fn file_type(&self) -> FileType {
+        FileType::from_raw(self.kind as u32)
+    }
+}
+
+impl core::fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("DirEntry").field("name", &self.file_name()).field("type", &self.file_type()).finish()
+    }
+}
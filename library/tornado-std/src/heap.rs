@@ -0,0 +1,60 @@
+//! 进程的堆
+//!
+//! 内核在程序之后为进程保留堆空间，brk系统调用移动堆的末尾，堆的大小受到进程内存限制的约束。
+//! 打开alloc特性时，tornado-std用伙伴分配器管理堆，空间不够时用sbrk向内核申请，
+//! 程序可以用`extern crate alloc;`使用Vec、String和Box
+
+use crate::io::{self, cvt};
+use crate::syscall;
+
+const PAGE_SIZE: usize = 0x1000;
+
+// 把堆的末尾移动到addr，返回新的末尾
+pub fn brk(addr: usize) -> io::Result<usize> {
+    if addr == 0 {
+        return Err(io::Error::from_raw_os_error(12)); // ENOMEM，地址0在堆的起始地址之前
+    }
+    cvt(syscall::sys_brk(addr))
+}
+
+// 把堆扩大increment字节，increment为负数时缩小，返回原来的末尾，也就是新空间的起始地址
+pub fn sbrk(increment: isize) -> io::Result<usize> {
+    let old = cvt(syscall::sys_brk(0))?;
+    if increment != 0 {
+        let new = (old as isize).checked_add(increment).ok_or(io::Error::from_raw_os_error(12))?;
+        brk(new as usize)?;
+    }
+    Ok(old)
+}
+
+#[cfg(feature = "alloc")]
+mod allocator {
+    use super::PAGE_SIZE;
+    use buddy_system_allocator::{Heap, LockedHeapWithRescue};
+    use core::alloc::Layout;
+
+    const ORDER: usize = 32;
+    // 每次至少向内核申请这么多空间，减少系统调用的次数
+    const MIN_GROW: usize = 16 * 1024;
+
+    #[global_allocator]
+    static HEAP: LockedHeapWithRescue<ORDER> = LockedHeapWithRescue::new(grow);
+
+    // 分配失败时扩大堆，之后分配器会再试一次。伙伴分配器的块按大小对齐，
+    // 新的空间是请求大小向上取到2的幂的两倍，保证其中有一个完整的、对齐的块
+    fn grow(heap: &mut Heap<ORDER>, layout: &Layout) {
+        let size = layout.size().max(layout.align()).checked_next_power_of_two().and_then(|size| size.checked_mul(2));
+        let size = match size.and_then(|size| size.max(MIN_GROW).checked_add(PAGE_SIZE - 1)) {
+            Some(size) => size & !(PAGE_SIZE - 1),
+            None => return, // 请求太大，不可能满足
+        };
+        if let Ok(start) = super::sbrk(size as isize) {
+            unsafe { heap.add_to_heap(start, start + size) };
+        }
+    }
+
+    #[alloc_error_handler]
+    fn alloc_error_handler(layout: Layout) -> ! {
+        panic!("out of memory when allocating {:?}", layout)
+    }
+}
@@ -0,0 +1,198 @@
+//! 输入输出
+//!
+//! 标准输入、标准输出和标准错误是文件描述符0、1、2，内核启动程序时把它们指向控制台设备
+
+use crate::fs::File;
+use crate::syscall::{self, SyscallResult};
+use core::fmt;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+// 系统调用返回的错误，包含内核给出的错误号
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Error {
+    errno: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidInput,
+    BadFileDescriptor,
+    NotSeekable,
+    NoSpace,
+    BrokenPipe,
+    WouldBlock,
+    TimedOut,
+    ArgumentListTooLong,
+    NotExecutable,
+    OutOfMemory,
+    UnexpectedEof,
+    WriteZero,
+    Unsupported,
+    Other,
+}
+
+impl Error {
+    pub const fn from_raw_os_error(errno: usize) -> Error {
+        Error { errno }
+    }
+
+    pub fn raw_os_error(&self) -> usize {
+        self.errno
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self.errno {
+            2 => ErrorKind::NotFound,
+            7 => ErrorKind::ArgumentListTooLong,
+            8 => ErrorKind::NotExecutable,
+            9 => ErrorKind::BadFileDescriptor,
+            17 => ErrorKind::AlreadyExists,
+            20 => ErrorKind::NotADirectory,
+            21 => ErrorKind::IsADirectory,
+            22 | 36 | 90 => ErrorKind::InvalidInput,
+            11 => ErrorKind::WouldBlock,
+            110 => ErrorKind::TimedOut,
+            12 => ErrorKind::OutOfMemory,
+            13 => ErrorKind::PermissionDenied,
+            28 => ErrorKind::NoSpace,
+            29 => ErrorKind::NotSeekable,
+            32 => ErrorKind::BrokenPipe,
+            19 | 38 => ErrorKind::Unsupported,
+            39 => ErrorKind::DirectoryNotEmpty,
+            UNEXPECTED_EOF => ErrorKind::UnexpectedEof,
+            WRITE_ZERO => ErrorKind::WriteZero,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+// 由tornado-std自己产生的错误，没有对应的内核错误号
+const UNEXPECTED_EOF: usize = usize::MAX;
+const WRITE_ZERO: usize = usize::MAX - 1;
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::WriteZero => write!(f, "{:?}", self.kind()),
+            kind => write!(f, "{:?} (errno {})", kind, self.errno),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+// 把系统调用的返回值转换为Result
+pub(crate) fn cvt(ans: SyscallResult) -> Result<usize> {
+    if ans.code == 0 {
+        Ok(ans.extra)
+    } else {
+        Err(Error::from_raw_os_error(ans.code))
+    }
+}
+
+pub trait Read {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err(Error::from_raw_os_error(UNEXPECTED_EOF)),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait Write {
+    fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(Error::from_raw_os_error(WRITE_ZERO)),
+                n => buf = &buf[n..],
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+pub trait Seek {
+    // 移动读写位置，返回新的位置
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+}
+
+pub struct Stdin;
+pub struct Stdout;
+pub struct Stderr;
+
+pub fn stdin() -> Stdin {
+    Stdin
+}
+
+pub fn stdout() -> Stdout {
+    Stdout
+}
+
+pub fn stderr() -> Stderr {
+    Stderr
+}
+
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        cvt(syscall::sys_read(STDIN, buf))
+    }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        cvt(syscall::sys_write(STDOUT, buf))
+    }
+}
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        cvt(syscall::sys_write(STDERR, buf))
+    }
+}
+
+// 创建匿名管道，返回读端和写端。
+// 管道为空时读取会等待，直到有数据写入或者所有写端都关闭（此时读到文件末尾）；
+// 所有读端都关闭以后，写入返回BrokenPipe错误
+pub fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    cvt(syscall::sys_pipe(&mut fds))?;
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// 让new_fd指向old_fd打开的文件，用于重定向标准输入输出
+pub fn dup2(old_fd: usize, new_fd: usize) -> Result<usize> {
+    cvt(syscall::sys_dup2(old_fd, new_fd))
+}
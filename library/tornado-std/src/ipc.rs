@@ -0,0 +1,224 @@
+//! 进程间通信
+//!
+//! 共享内存和消息通道由内核管理，进程通过句柄使用它们。创建子进程时，子进程继承父进程的所有句柄，
+//! 可以把句柄的编号作为参数传给子进程。
+//!
+//! 通道的消息可以附带一块共享内存，发送以后它的句柄转移到接收者。双方映射同一块共享内存，
+//! 大块的数据不需要经过内核复制
+
+use crate::io::{self, cvt};
+use crate::syscall;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::slice;
+use core::task::{Context, Poll};
+
+// 一条消息的最大字节数，和内核保持一致
+pub const MAX_MESSAGE_SIZE: usize = 1024;
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+
+const IPC_NONBLOCK: usize = 1;
+const NO_HANDLE: usize = usize::MAX;
+
+// 共享内存。所有句柄和映射都不存在以后，内核释放它的内存
+#[derive(Debug)]
+pub struct SharedMemory {
+    handle: usize,
+    len: usize,
+}
+
+impl SharedMemory {
+    // 创建清零的共享内存，长度向上取整到页
+    pub fn new(len: usize) -> io::Result<SharedMemory> {
+        SharedMemory::create(len, "")
+    }
+
+    // 创建有名字的共享内存，其它进程可以用open打开。名字已经被使用时返回AlreadyExists
+    pub fn create_named(name: &str, len: usize) -> io::Result<SharedMemory> {
+        if name.is_empty() {
+            return Err(io::Error::from_raw_os_error(22)); // EINVAL
+        }
+        SharedMemory::create(len, name)
+    }
+
+    pub fn open(name: &str) -> io::Result<SharedMemory> {
+        let handle = cvt(syscall::sys_shm_open(name))?;
+        Ok(unsafe { SharedMemory::from_raw_handle(handle) })
+    }
+
+    fn create(len: usize, name: &str) -> io::Result<SharedMemory> {
+        let handle = cvt(syscall::sys_shm_create(len, name))?;
+        Ok(unsafe { SharedMemory::from_raw_handle(handle) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // 映射到当前进程的地址空间，可读可写
+    pub fn map(&self) -> io::Result<SharedMapping> {
+        let addr = cvt(syscall::sys_shm_map(self.handle, 0, PROT_READ | PROT_WRITE))?;
+        Ok(SharedMapping { ptr: addr as *mut u8, len: self.len })
+    }
+
+    pub fn as_raw_handle(&self) -> usize {
+        self.handle
+    }
+
+    pub fn into_raw_handle(self) -> usize {
+        let handle = self.handle;
+        core::mem::forget(self);
+        handle
+    }
+
+    // unsafe说明：handle必须是共享内存的句柄，并且没有被其它对象持有，否则会被关闭两次
+    pub unsafe fn from_raw_handle(handle: usize) -> SharedMemory {
+        let len = cvt(syscall::sys_shm_size(handle)).unwrap_or(0);
+        SharedMemory { handle, len }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        syscall::sys_handle_close(self.handle);
+    }
+}
+
+// 共享内存的一个映射，离开作用域时取消。其它进程可以同时读写这段内存
+pub struct SharedMapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Deref for SharedMapping {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for SharedMapping {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        syscall::sys_munmap(self.ptr as usize, self.len);
+    }
+}
+
+// 创建通道，返回它的两个端点
+pub fn channel() -> io::Result<(Channel, Channel)> {
+    let mut handles = [0; 2];
+    cvt(syscall::sys_channel_create(&mut handles))?;
+    Ok((Channel { handle: handles[0] }, Channel { handle: handles[1] }))
+}
+
+// 通道的一个端点
+#[derive(Debug)]
+pub struct Channel {
+    handle: usize,
+}
+
+// 接收到的消息
+#[derive(Debug)]
+pub struct Message {
+    // 消息的字节数，内容在接收时传入的缓冲区中
+    pub len: usize,
+    pub shared_memory: Option<SharedMemory>,
+}
+
+impl Channel {
+    // 发送消息，对端的队列满时等待
+    pub fn send(&self, data: &[u8]) -> io::Result<()> {
+        cvt(syscall::sys_channel_send(self.handle, data, NO_HANDLE, 0)).map(|_| ())
+    }
+
+    // 发送消息，队列满时返回WouldBlock
+    pub fn try_send(&self, data: &[u8]) -> io::Result<()> {
+        cvt(syscall::sys_channel_send(self.handle, data, NO_HANDLE, IPC_NONBLOCK)).map(|_| ())
+    }
+
+    // 发送附带共享内存的消息，共享内存的句柄转移给接收者。发送失败时句柄被关闭
+    pub fn send_with(&self, data: &[u8], shared_memory: SharedMemory) -> io::Result<()> {
+        cvt(syscall::sys_channel_send(self.handle, data, shared_memory.as_raw_handle(), 0))?;
+        core::mem::forget(shared_memory); // 句柄已经不在当前进程中
+        Ok(())
+    }
+
+    // 接收消息，没有消息时等待。buf小于消息时返回InvalidInput，消息留在队列中
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<Message> {
+        self.recv_flags(buf, 0)
+    }
+
+    // 接收消息，没有消息时返回WouldBlock
+    pub fn try_recv(&self, buf: &mut [u8]) -> io::Result<Message> {
+        self.recv_flags(buf, IPC_NONBLOCK)
+    }
+
+    // 异步地接收消息。没有消息时返回Pending，并让执行器稍后再轮询
+    pub fn recv_async<'a>(&'a self, buf: &'a mut [u8]) -> Recv<'a> {
+        Recv { channel: self, buf }
+    }
+
+    fn recv_flags(&self, buf: &mut [u8], flags: usize) -> io::Result<Message> {
+        let mut received = NO_HANDLE;
+        let len = cvt(syscall::sys_channel_recv(self.handle, buf, &mut received, flags))?;
+        let shared_memory = match received {
+            NO_HANDLE => None,
+            handle => Some(unsafe { SharedMemory::from_raw_handle(handle) }),
+        };
+        Ok(Message { len, shared_memory })
+    }
+
+    pub fn as_raw_handle(&self) -> usize {
+        self.handle
+    }
+
+    pub fn into_raw_handle(self) -> usize {
+        let handle = self.handle;
+        core::mem::forget(self);
+        handle
+    }
+
+    // unsafe说明：handle必须是通道端点的句柄，并且没有被其它对象持有，否则会被关闭两次
+    pub unsafe fn from_raw_handle(handle: usize) -> Channel {
+        Channel { handle }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        syscall::sys_handle_close(self.handle);
+    }
+}
+
+// Channel::recv_async返回的Future
+pub struct Recv<'a> {
+    channel: &'a Channel,
+    buf: &'a mut [u8],
+}
+
+impl Future for Recv<'_> {
+    type Output = io::Result<Message>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        match this.channel.try_recv(this.buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // 内核不会在消息到达时通知，只能请执行器再轮询一次
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            },
+            ans => Poll::Ready(ans),
+        }
+    }
+}
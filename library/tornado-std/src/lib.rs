@@ -1,12 +1,24 @@
 #![no_std]
 #![feature(asm)]
 #![feature(linkage)]
+#![feature(naked_functions)]
 #![feature(panic_info_message)]
+#![cfg_attr(feature = "alloc", feature(alloc_error_handler))]
 
 #[macro_use]
 #[doc(hidden)]
 pub mod console;
 mod syscall;
+pub mod io;
+pub mod fs;
+pub mod env;
+pub mod process;
+pub mod heap;
+pub mod mman;
+pub mod ipc;
+pub mod thread;
+pub mod sync;
+pub mod signal;
 
 #[cfg_attr(not(test), panic_handler)]
 #[allow(unused)]
@@ -22,11 +34,15 @@ fn panic_handler(panic_info: &core::panic::PanicInfo) -> ! {
 
 #[no_mangle]
 #[link_section = ".text.entry"]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(args_ptr: usize, args_len: usize) -> ! {
     extern "C" {
         fn sbss(); fn ebss();
     } 
     unsafe { r0::zero_bss(&mut sbss as *mut _ as *mut u64, &mut ebss as *mut _ as *mut u64) };
+    // 内核把参数放在栈顶，a0和a1是它的位置和长度。清零bss段以后才能保存
+    unsafe { env::init_args(args_ptr, args_len) };
+    // 主线程的线程局部变量需要在main之前准备好
+    unsafe { thread::init_main_thread() };
     exit(main());
     panic!("unreachable after sys_exit!");
 }
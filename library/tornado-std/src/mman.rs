@@ -0,0 +1,149 @@
+//! 内存映射
+//!
+//! 把文件或者匿名内存映射到进程的地址空间中，映射在离开作用域时取消。
+//! 文件的共享映射在所有映射它的进程之间共享，最后一个映射取消时写回文件；
+//! 私有映射是映射时文件内容的副本，修改不会写回。
+//!
+//! 通过read和write访问文件不会立即看到共享映射中的修改，反过来也一样
+
+use crate::fs::File;
+use crate::io::{self, cvt};
+use crate::syscall;
+use core::ops::{Deref, DerefMut};
+use core::slice;
+
+const PROT_READ: usize = 1;
+const PROT_WRITE: usize = 2;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_ANONYMOUS: usize = 0x20;
+
+// 创建映射的选项
+#[derive(Clone, Debug, Default)]
+pub struct MmapOptions {
+    offset: usize,
+    len: Option<usize>,
+}
+
+impl MmapOptions {
+    pub fn new() -> Self {
+        MmapOptions::default()
+    }
+
+    // 映射从文件中offset处开始，offset必须按页对齐。默认为0
+    pub fn offset(&mut self, offset: usize) -> &mut Self {
+        self.offset = offset;
+        self
+    }
+
+    // 映射的字节数。映射文件时默认到文件的末尾；匿名映射必须设置
+    pub fn len(&mut self, len: usize) -> &mut Self {
+        self.len = Some(len);
+        self
+    }
+
+    // 只读的共享映射，文件需要以读的方式打开。
+    // unsafe说明：其它进程可以通过可写的共享映射修改这段内存，调用者要保证读取时没有人修改它
+    pub unsafe fn map(&self, file: &File) -> io::Result<Mmap> {
+        let len = self.file_len(file)?;
+        let ptr = mmap(len, PROT_READ, MAP_SHARED, file.as_raw_fd(), self.offset)?;
+        Ok(Mmap { inner: MmapInner { ptr, len } })
+    }
+
+    // 可写的共享映射，文件需要以读写的方式打开。
+    // unsafe说明：同map，其它进程可以同时读写这段内存
+    pub unsafe fn map_mut(&self, file: &File) -> io::Result<MmapMut> {
+        let len = self.file_len(file)?;
+        let ptr = mmap(len, PROT_READ | PROT_WRITE, MAP_SHARED, file.as_raw_fd(), self.offset)?;
+        Ok(MmapMut { inner: MmapInner { ptr, len } })
+    }
+
+    // 文件内容的可写副本，修改不会写回文件
+    pub fn map_copy(&self, file: &File) -> io::Result<MmapMut> {
+        let len = self.file_len(file)?;
+        let ptr = mmap(len, PROT_READ | PROT_WRITE, MAP_PRIVATE, file.as_raw_fd(), self.offset)?;
+        Ok(MmapMut { inner: MmapInner { ptr, len } })
+    }
+
+    // 清零的匿名内存
+    pub fn map_anon(&self) -> io::Result<MmapMut> {
+        let len = self.len.ok_or(io::Error::from_raw_os_error(22))?; // EINVAL
+        let ptr = mmap(len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, 0, 0)?;
+        Ok(MmapMut { inner: MmapInner { ptr, len } })
+    }
+
+    fn file_len(&self, file: &File) -> io::Result<usize> {
+        match self.len {
+            Some(len) => Ok(len),
+            None => Ok((file.metadata()?.len() as usize).saturating_sub(self.offset)),
+        }
+    }
+}
+
+fn mmap(len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> io::Result<*mut u8> {
+    cvt(syscall::sys_mmap(0, len, prot, flags, fd, offset)).map(|addr| addr as *mut u8)
+}
+
+struct MmapInner {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl MmapInner {
+    fn protect(&self, prot: usize) -> io::Result<()> {
+        cvt(syscall::sys_mprotect(self.ptr as usize, self.len, prot)).map(|_| ())
+    }
+}
+
+impl Drop for MmapInner {
+    fn drop(&mut self) {
+        syscall::sys_munmap(self.ptr as usize, self.len);
+    }
+}
+
+// 只读的映射
+pub struct Mmap {
+    inner: MmapInner,
+}
+
+impl Mmap {
+    // 把映射改为可写。只读打开的文件的共享映射不能改为可写
+    pub fn make_mut(self) -> io::Result<MmapMut> {
+        self.inner.protect(PROT_READ | PROT_WRITE)?;
+        Ok(MmapMut { inner: self.inner })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.inner.ptr, self.inner.len) }
+    }
+}
+
+// 可写的映射
+pub struct MmapMut {
+    inner: MmapInner,
+}
+
+impl MmapMut {
+    // 把映射改为只读，之后写入这段内存会产生访问异常
+    pub fn make_read_only(self) -> io::Result<Mmap> {
+        self.inner.protect(PROT_READ)?;
+        Ok(Mmap { inner: self.inner })
+    }
+}
+
+impl Deref for MmapMut {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.inner.ptr, self.inner.len) }
+    }
+}
+
+impl DerefMut for MmapMut {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.inner.ptr, self.inner.len) }
+    }
+}
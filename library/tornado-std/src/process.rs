@@ -0,0 +1,54 @@
+//! 进程
+//!
+//! 子进程继承父进程打开的所有文件描述符。先用io::dup2把标准输入输出指向管道或者文件，
+//! 再创建子进程，就可以重定向子进程的输入输出
+
+use crate::io::{self, cvt};
+use crate::syscall;
+
+// 传给子进程的参数的最大总长度，包括分隔参数的0
+pub const MAX_ARGS_LEN: usize = 1024;
+
+// 正在运行的子进程
+#[derive(Debug)]
+pub struct Child {
+    pid: usize,
+}
+
+impl Child {
+    pub fn id(&self) -> usize {
+        self.pid
+    }
+
+    // 等待子进程结束，返回它的返回值
+    pub fn wait(&mut self) -> io::Result<i32> {
+        cvt(syscall::sys_wait(self.pid)).map(|code| code as i32)
+    }
+
+    // 向子进程发送SIGKILL
+    pub fn kill(&mut self) -> io::Result<()> {
+        crate::signal::kill(self.pid, crate::signal::SIGKILL)
+    }
+}
+
+// 创建子进程运行path处的程序。args是程序的名字之后的参数，子进程的第一个参数是path
+pub fn spawn(path: &str, args: &[&str]) -> io::Result<Child> {
+    let mut buf = [0u8; MAX_ARGS_LEN];
+    let mut len = 0;
+    for arg in core::iter::once(&path).chain(args.iter()) {
+        let bytes = arg.as_bytes();
+        if len + bytes.len() + 1 > buf.len() {
+            return Err(io::Error::from_raw_os_error(7)); // E2BIG
+        }
+        buf[len..len + bytes.len()].copy_from_slice(bytes);
+        len += bytes.len() + 1; // 结尾的0已经在缓冲区中
+    }
+    let pid = cvt(syscall::sys_spawn(path, &buf[..len]))?;
+    Ok(Child { pid })
+}
+
+// 结束当前进程
+pub fn exit(code: i32) -> ! {
+    syscall::sys_exit(code);
+    unreachable!("process exited")
+}
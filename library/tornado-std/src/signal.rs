@@ -0,0 +1,150 @@
+//! 信号
+//!
+//! 信号的编号和默认的处理方式与Linux相同。处理函数在收到信号的线程上运行，第一个参数是信号的编号，
+//! 第二个参数是访问异常或者非法指令的地址，其它信号为0。
+//!
+//! 处理函数可能在程序的任何位置打断当前线程，所以它只应该修改原子变量，或者调用不需要加锁的系统调用。
+//! 处理函数运行期间，正在处理的信号被屏蔽
+
+use crate::io::{self, cvt};
+use crate::syscall;
+
+pub const SIGHUP: usize = 1;
+pub const SIGINT: usize = 2;
+pub const SIGQUIT: usize = 3;
+pub const SIGILL: usize = 4;
+pub const SIGTRAP: usize = 5;
+pub const SIGABRT: usize = 6;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGUSR1: usize = 10;
+pub const SIGSEGV: usize = 11;
+pub const SIGUSR2: usize = 12;
+pub const SIGPIPE: usize = 13;
+pub const SIGALRM: usize = 14;
+pub const SIGTERM: usize = 15;
+pub const SIGCHLD: usize = 17;
+pub const SIGCONT: usize = 18;
+pub const SIGSTOP: usize = 19;
+pub const SIGTSTP: usize = 20;
+
+// 进程被信号结束时，返回值是128加上信号的编号
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+// 进程被信号结束时返回信号的编号
+pub fn exit_signal(code: i32) -> Option<usize> {
+    if code > SIGNAL_EXIT_BASE && code < SIGNAL_EXIT_BASE + 32 {
+        Some((code - SIGNAL_EXIT_BASE) as usize)
+    } else {
+        None
+    }
+}
+
+// 一组信号
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SigSet(u32);
+
+impl SigSet {
+    pub const fn empty() -> Self {
+        SigSet(0)
+    }
+
+    pub const fn with(self, sig: usize) -> Self {
+        SigSet(self.0 | 1 << sig)
+    }
+
+    pub fn contains(&self, sig: usize) -> bool {
+        self.0 & 1 << sig != 0
+    }
+
+    pub fn insert(&mut self, sig: usize) {
+        self.0 |= 1 << sig;
+    }
+
+    pub fn remove(&mut self, sig: usize) {
+        self.0 &= !(1 << sig);
+    }
+}
+
+// 信号的处理方式
+#[derive(Copy, Clone, Debug)]
+pub enum SigHandler {
+    Default,
+    Ignore,
+    // 参数是信号的编号和异常的地址
+    Handler(extern "C" fn(usize, usize)),
+}
+
+impl SigHandler {
+    fn from_raw(raw: usize) -> Self {
+        match raw {
+            SIG_DFL => SigHandler::Default,
+            SIG_IGN => SigHandler::Ignore,
+            addr => SigHandler::Handler(unsafe { core::mem::transmute::<usize, extern "C" fn(usize, usize)>(addr) }),
+        }
+    }
+
+    fn to_raw(self) -> usize {
+        match self {
+            SigHandler::Default => SIG_DFL,
+            SigHandler::Ignore => SIG_IGN,
+            SigHandler::Handler(f) => f as usize,
+        }
+    }
+}
+
+// 设置信号的处理方式，处理函数运行期间还屏蔽mask中的信号。返回原来的处理方式。
+// SIGKILL和SIGSTOP不能被处理或者忽略
+pub fn sigaction(sig: usize, handler: SigHandler, mask: SigSet) -> io::Result<SigHandler> {
+    let old = cvt(syscall::sys_sigaction(sig, handler.to_raw(), mask.0 as usize, restorer as usize))?;
+    Ok(SigHandler::from_raw(old))
+}
+
+// 设置信号的处理方式，不额外屏蔽其它信号
+pub fn signal(sig: usize, handler: SigHandler) -> io::Result<SigHandler> {
+    sigaction(sig, handler, SigSet::empty())
+}
+
+fn sigprocmask(how: usize, set: SigSet) -> io::Result<SigSet> {
+    cvt(syscall::sys_sigprocmask(how, set.0 as usize)).map(|old| SigSet(old as u32))
+}
+
+// 屏蔽set中的信号，它们保持等待，直到解除屏蔽。返回原来屏蔽的信号
+pub fn block(set: SigSet) -> io::Result<SigSet> {
+    sigprocmask(SIG_BLOCK, set)
+}
+
+// 解除屏蔽set中的信号，已经在等待的信号会在系统调用返回前处理
+pub fn unblock(set: SigSet) -> io::Result<SigSet> {
+    sigprocmask(SIG_UNBLOCK, set)
+}
+
+// 把屏蔽的信号设为set
+pub fn set_mask(set: SigSet) -> io::Result<SigSet> {
+    sigprocmask(SIG_SETMASK, set)
+}
+
+// 向pid号进程发送信号。sig为0时只检查进程是否存在
+pub fn kill(pid: usize, sig: usize) -> io::Result<()> {
+    cvt(syscall::sys_kill(pid, sig)).map(|_| ())
+}
+
+// 处理函数返回到这里。此时栈顶是内核保存的信号帧，调用sigreturn恢复被打断的上下文。
+// 不能使用栈，所以用汇编直接发起系统调用，编号和syscall模块中的PROCESS和SIGRETURN相同
+#[naked]
+unsafe extern "C" fn restorer() -> ! {
+    asm!(
+        "li     a7, 0x114514",
+        "li     a6, 11",
+        "ecall",
+        options(noreturn)
+    )
+}
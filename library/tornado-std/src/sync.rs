@@ -0,0 +1,466 @@
+//! 同步原语
+//!
+//! 锁和条件变量在没有竞争时只用原子操作，需要等待时用futex系统调用让出处理核。
+//! 内核按物理地址区分futex，所以放在共享内存中的锁也可以在进程之间使用。
+//!
+//! 线程panic时整个进程都会结束，锁不会在持有者panic以后被其它线程看到，所以这里的锁没有“中毒”状态，
+//! lock直接返回守卫
+
+use crate::io::{self, cvt};
+use crate::syscall;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+const NO_TIMEOUT: usize = usize::MAX;
+
+// futex的值等于expected时等待，直到被唤醒或者超时。超时时返回false；
+// 值已经改变或者被唤醒时返回true，调用者需要重新检查自己等待的条件
+fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    let timeout_us = match timeout {
+        Some(timeout) => timeout.as_micros().min(NO_TIMEOUT as u128 - 1) as usize,
+        None => NO_TIMEOUT,
+    };
+    let ans = cvt(syscall::sys_futex_wait(futex, expected, timeout_us));
+    !matches!(ans, Err(e) if e.kind() == io::ErrorKind::TimedOut)
+}
+
+fn futex_wake(futex: &AtomicU32, count: usize) {
+    syscall::sys_futex_wake(futex, count);
+}
+
+// 互斥锁的状态
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+// 已经上锁，并且可能有线程在等待，解锁时需要唤醒
+const CONTENDED: u32 = 2;
+
+// 互斥锁
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex { state: AtomicU32::new(UNLOCKED), data: UnsafeCell::new(data) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    // 上锁，已经被其它线程持有时等待
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        if self.state.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            self.lock_contended();
+        }
+        MutexGuard { mutex: self }
+    }
+
+    // 尝试上锁，已经被持有时返回None
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state.compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    // 不知道是否还有其它线程在等待，所以拿到锁时也标记为CONTENDED，解锁时唤醒下一个
+    fn lock_contended(&self) {
+        while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            futex_wait(&self.state, CONTENDED, None);
+        }
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.debug_struct("Mutex").field("data", &format_args!("<locked>")).finish(),
+        }
+    }
+}
+
+// 互斥锁的守卫，离开作用域时解锁
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+// 条件变量。seq在每次通知时增加，等待的线程在它上面等待，错过的通知能从值的变化中发现
+pub struct Condvar {
+    seq: AtomicU32,
+}
+
+// Condvar::wait_timeout的结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { seq: AtomicU32::new(0) }
+    }
+
+    // 解锁并等待通知，返回前重新上锁。可能在没有通知时返回，调用者应当在循环中检查条件
+    pub fn wait<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_inner(guard, None).0
+    }
+
+    // 等待到condition返回false为止
+    pub fn wait_while<'a, T, F>(&self, mut guard: MutexGuard<'a, T>, mut condition: F) -> MutexGuard<'a, T>
+    where T: ?Sized, F: FnMut(&mut T) -> bool {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    // 和wait相同，最多等待timeout
+    pub fn wait_timeout<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, timeout: Duration) -> (MutexGuard<'a, T>, WaitTimeoutResult) {
+        let (guard, woken) = self.wait_inner(guard, Some(timeout));
+        (guard, WaitTimeoutResult(!woken))
+    }
+
+    fn wait_inner<'a, T: ?Sized>(&self, guard: MutexGuard<'a, T>, timeout: Option<Duration>) -> (MutexGuard<'a, T>, bool) {
+        // 先读出seq再解锁，解锁之后的通知会改变seq，futex_wait因此立即返回
+        let seq = self.seq.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        let woken = futex_wait(&self.seq, seq, timeout);
+        (mutex.lock(), woken)
+    }
+
+    pub fn notify_one(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.seq, 1);
+    }
+
+    pub fn notify_all(&self) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.seq, usize::MAX);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Condvar {
+        Condvar::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Condvar").finish()
+    }
+}
+
+// 读写锁的状态：没有被持有时为0，被读者持有时是读者的数量，被写者持有时为WRITE_LOCKED
+const WRITE_LOCKED: u32 = u32::MAX;
+
+// 读写锁。多个读者可以同时持有，写者独占。不保证公平，持续不断的读者可能让写者一直等待
+pub struct RwLock<T: ?Sized> {
+    state: AtomicU32,
+    // 正在等待的线程数，为0时解锁不需要唤醒
+    waiting: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock { state: AtomicU32::new(0), waiting: AtomicU32::new(0), data: UnsafeCell::new(data) }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    // 以读者的身份上锁，有写者时等待
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state < WRITE_LOCKED - 1 {
+                if self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    return RwLockReadGuard { lock: self };
+                }
+            } else {
+                self.wait(state);
+            }
+        }
+    }
+
+    // 以写者的身份上锁，有其它读者或者写者时等待
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        loop {
+            match self.state.compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return RwLockWriteGuard { lock: self },
+                Err(state) => self.wait(state),
+            }
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let state = self.state.load(Ordering::Relaxed);
+        if state >= WRITE_LOCKED - 1 {
+            return None;
+        }
+        self.state.compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed).ok()?;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    // 在state改变之前等待
+    fn wait(&self, state: u32) {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        futex_wait(&self.state, state, None);
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn wake_all(&self) {
+        if self.waiting.load(Ordering::SeqCst) != 0 {
+            futex_wake(&self.state, usize::MAX);
+        }
+    }
+
+    fn read_unlock(&self) {
+        // 最后一个读者离开时，等待的写者可以上锁
+        if self.state.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.wake_all();
+        }
+    }
+
+    fn write_unlock(&self) {
+        self.state.store(0, Ordering::SeqCst);
+        self.wake_all();
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("RwLock").field("data", &&*guard).finish(),
+            None => f.debug_struct("RwLock").field("data", &format_args!("<locked>")).finish(),
+        }
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+// Once的状态
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+// 正在运行，并且有线程在等待它完成
+const QUEUED: u32 = 2;
+const COMPLETE: u32 = 3;
+
+// 只运行一次的初始化
+pub struct Once {
+    state: AtomicU32,
+}
+
+impl Once {
+    pub const fn new() -> Once {
+        Once { state: AtomicU32::new(INCOMPLETE) }
+    }
+
+    // 第一次调用时运行f；其它线程同时调用时，等待f运行完成再返回
+    pub fn call_once<F: FnOnce()>(&self, f: F) {
+        let mut f = Some(f);
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => return,
+                INCOMPLETE => {
+                    if self.state.compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire).is_ok() {
+                        (f.take().unwrap())();
+                        if self.state.swap(COMPLETE, Ordering::Release) == QUEUED {
+                            futex_wake(&self.state, usize::MAX);
+                        }
+                        return;
+                    }
+                },
+                RUNNING => {
+                    // 标记有线程在等待，失败时重新读取状态
+                    if self.state.compare_exchange(RUNNING, QUEUED, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                        futex_wait(&self.state, QUEUED, None);
+                    }
+                },
+                _ => {
+                    futex_wait(&self.state, QUEUED, None);
+                },
+            }
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl Default for Once {
+    fn default() -> Once {
+        Once::new()
+    }
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Once").field("completed", &self.is_completed()).finish()
+    }
+}
+
+// 屏障。n个线程都调用wait以后，它们才一起继续运行
+pub struct Barrier {
+    lock: Mutex<BarrierState>,
+    cvar: Condvar,
+    num_threads: usize,
+}
+
+struct BarrierState {
+    count: usize,
+    // 每凑齐一次增加1，等待的线程通过它判断自己这一批是否已经凑齐
+    generation: usize,
+}
+
+// Barrier::wait的结果，每一批中恰好有一个线程是领导者
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl Barrier {
+    pub const fn new(n: usize) -> Barrier {
+        Barrier {
+            lock: Mutex::new(BarrierState { count: 0, generation: 0 }),
+            cvar: Condvar::new(),
+            num_threads: n,
+        }
+    }
+
+    // 等待这一批的所有线程到达。最后到达的线程是领导者
+    pub fn wait(&self) -> BarrierWaitResult {
+        let mut state = self.lock.lock();
+        let generation = state.generation;
+        state.count += 1;
+        if state.count < self.num_threads {
+            let _state = self.cvar.wait_while(state, |state| state.generation == generation);
+            BarrierWaitResult(false)
+        } else {
+            state.count = 0;
+            state.generation = state.generation.wrapping_add(1);
+            self.cvar.notify_all();
+            BarrierWaitResult(true)
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier").field("num_threads", &self.num_threads).finish()
+    }
+}
@@ -1,15 +1,65 @@
 const MODULE_PROCESS: usize = 0x114514;
 const FUNCTION_PROCESS_EXIT: usize = 0x1919810;
 const FUNCTION_PROCESS_PANIC: usize = 0x11451419;
+const FUNCTION_PROCESS_SPAWN: usize = 1;
+const FUNCTION_PROCESS_WAIT: usize = 2;
+const FUNCTION_PROCESS_THREAD_CREATE: usize = 3;
+const FUNCTION_PROCESS_THREAD_EXIT: usize = 4;
+const FUNCTION_PROCESS_THREAD_JOIN: usize = 5;
+const FUNCTION_PROCESS_THREAD_SELF: usize = 6;
+const FUNCTION_PROCESS_YIELD: usize = 7;
+const FUNCTION_PROCESS_KILL: usize = 8;
+const FUNCTION_PROCESS_SIGACTION: usize = 9;
+const FUNCTION_PROCESS_SIGPROCMASK: usize = 10;
 
-const MODULE_TEST_INTERFACE: usize = 0x233666;
-const FUNCTION_TEST_WRITE: usize = 0x666233;
+const MODULE_FILE: usize = 0xf11e;
+const FUNCTION_FILE_OPEN: usize = 1;
+const FUNCTION_FILE_CLOSE: usize = 2;
+const FUNCTION_FILE_READ: usize = 3;
+const FUNCTION_FILE_WRITE: usize = 4;
+const FUNCTION_FILE_LSEEK: usize = 5;
+const FUNCTION_FILE_FSTAT: usize = 6;
+const FUNCTION_FILE_GETDENTS: usize = 7;
+const FUNCTION_FILE_MKDIR: usize = 8;
+const FUNCTION_FILE_UNLINK: usize = 9;
+const FUNCTION_FILE_DUP2: usize = 10;
+const FUNCTION_FILE_PIPE: usize = 11;
+
+const MODULE_MEMORY: usize = 0x3e3;
+const FUNCTION_MEMORY_BRK: usize = 1;
+const FUNCTION_MEMORY_MMAP: usize = 2;
+const FUNCTION_MEMORY_MUNMAP: usize = 3;
+const FUNCTION_MEMORY_MPROTECT: usize = 4;
+
+const MODULE_IPC: usize = 0x1bc;
+const FUNCTION_IPC_CLOSE: usize = 1;
+const FUNCTION_IPC_SHM_CREATE: usize = 2;
+const FUNCTION_IPC_SHM_OPEN: usize = 3;
+const FUNCTION_IPC_SHM_SIZE: usize = 4;
+const FUNCTION_IPC_SHM_MAP: usize = 5;
+const FUNCTION_IPC_CHANNEL_CREATE: usize = 6;
+const FUNCTION_IPC_CHANNEL_SEND: usize = 7;
+const FUNCTION_IPC_CHANNEL_RECV: usize = 8;
+const FUNCTION_IPC_FUTEX_WAIT: usize = 9;
+const FUNCTION_IPC_FUTEX_WAKE: usize = 10;
+
+use core::sync::atomic::AtomicU32;
 
 pub struct SyscallResult {
     pub code: usize,
     pub extra: usize,
 }
 
+// fstat返回的文件信息，和内核中的定义保持一致
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct FileStat {
+    pub ino: u64,
+    pub size: u64,
+    pub kind: u32,
+    pub _reserved: u32,
+}
+
 fn syscall_1(module: usize, function: usize, arg: usize) -> SyscallResult {
     match () {
         #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
@@ -74,8 +124,151 @@ fn syscall_6(module: usize, function: usize, args: [usize; 6]) -> SyscallResult
     }
 }
 
+pub fn sys_open(path: &str, flags: u32) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_OPEN, [path.as_ptr() as usize, path.len(), flags as usize])
+}
+
+pub fn sys_close(fd: usize) -> SyscallResult {
+    syscall_1(MODULE_FILE, FUNCTION_FILE_CLOSE, fd)
+}
+
+pub fn sys_read(fd: usize, buffer: &mut [u8]) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_READ, [fd, buffer.as_mut_ptr() as usize, buffer.len()])
+}
+
 pub fn sys_write(fd: usize, buffer: &[u8]) -> SyscallResult {
-    syscall_3(MODULE_TEST_INTERFACE, FUNCTION_TEST_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
+    syscall_3(MODULE_FILE, FUNCTION_FILE_WRITE, [fd, buffer.as_ptr() as usize, buffer.len()])
+}
+
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_LSEEK, [fd, offset as usize, whence])
+}
+
+pub fn sys_fstat(fd: usize, stat: &mut FileStat) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_FSTAT, [fd, stat as *mut FileStat as usize, 0])
+}
+
+pub fn sys_getdents(fd: usize, buffer: &mut [u8]) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_GETDENTS, [fd, buffer.as_mut_ptr() as usize, buffer.len()])
+}
+
+pub fn sys_mkdir(path: &str) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_MKDIR, [path.as_ptr() as usize, path.len(), 0])
+}
+
+pub fn sys_unlink(path: &str) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_UNLINK, [path.as_ptr() as usize, path.len(), 0])
+}
+
+pub fn sys_dup2(old_fd: usize, new_fd: usize) -> SyscallResult {
+    syscall_3(MODULE_FILE, FUNCTION_FILE_DUP2, [old_fd, new_fd, 0])
+}
+
+pub fn sys_pipe(fds: &mut [usize; 2]) -> SyscallResult {
+    syscall_1(MODULE_FILE, FUNCTION_FILE_PIPE, fds.as_mut_ptr() as usize)
+}
+
+pub fn sys_spawn(path: &str, args: &[u8]) -> SyscallResult {
+    syscall_6(
+        MODULE_PROCESS, FUNCTION_PROCESS_SPAWN,
+        [path.as_ptr() as usize, path.len(), args.as_ptr() as usize, args.len(), 0, 0]
+    )
+}
+
+pub fn sys_wait(pid: usize) -> SyscallResult {
+    syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_WAIT, pid)
+}
+
+// 新线程从entry开始运行，a0是arg；stack是栈顶，tp是线程局部存储的位置
+pub fn sys_thread_create(entry: usize, stack: usize, tp: usize, arg: usize) -> SyscallResult {
+    syscall_6(MODULE_PROCESS, FUNCTION_PROCESS_THREAD_CREATE, [entry, stack, tp, arg, 0, 0])
+}
+
+pub fn sys_thread_exit(exit_code: i32) -> SyscallResult {
+    syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_THREAD_EXIT, exit_code as usize)
+}
+
+pub fn sys_thread_join(tid: usize) -> SyscallResult {
+    syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_THREAD_JOIN, tid)
+}
+
+pub fn sys_thread_self() -> SyscallResult {
+    syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_THREAD_SELF, 0)
+}
+
+pub fn sys_yield() -> SyscallResult {
+    syscall_1(MODULE_PROCESS, FUNCTION_PROCESS_YIELD, 0)
+}
+
+pub fn sys_kill(pid: usize, sig: usize) -> SyscallResult {
+    syscall_3(MODULE_PROCESS, FUNCTION_PROCESS_KILL, [pid, sig, 0])
+}
+
+// 处理函数返回到restorer，它应当调用sigreturn。返回原来的处理函数
+pub fn sys_sigaction(sig: usize, handler: usize, mask: usize, restorer: usize) -> SyscallResult {
+    syscall_6(MODULE_PROCESS, FUNCTION_PROCESS_SIGACTION, [sig, handler, mask, restorer, 0, 0])
+}
+
+pub fn sys_sigprocmask(how: usize, set: usize) -> SyscallResult {
+    syscall_3(MODULE_PROCESS, FUNCTION_PROCESS_SIGPROCMASK, [how, set, 0])
+}
+
+pub fn sys_brk(addr: usize) -> SyscallResult {
+    syscall_1(MODULE_MEMORY, FUNCTION_MEMORY_BRK, addr)
+}
+
+pub fn sys_mmap(addr: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> SyscallResult {
+    syscall_6(MODULE_MEMORY, FUNCTION_MEMORY_MMAP, [addr, len, prot, flags, fd, offset])
+}
+
+pub fn sys_munmap(addr: usize, len: usize) -> SyscallResult {
+    syscall_3(MODULE_MEMORY, FUNCTION_MEMORY_MUNMAP, [addr, len, 0])
+}
+
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> SyscallResult {
+    syscall_3(MODULE_MEMORY, FUNCTION_MEMORY_MPROTECT, [addr, len, prot])
+}
+
+pub fn sys_handle_close(handle: usize) -> SyscallResult {
+    syscall_1(MODULE_IPC, FUNCTION_IPC_CLOSE, handle)
+}
+
+// name为空时创建没有名字的共享内存
+pub fn sys_shm_create(len: usize, name: &str) -> SyscallResult {
+    syscall_3(MODULE_IPC, FUNCTION_IPC_SHM_CREATE, [len, name.as_ptr() as usize, name.len()])
+}
+
+pub fn sys_shm_open(name: &str) -> SyscallResult {
+    syscall_3(MODULE_IPC, FUNCTION_IPC_SHM_OPEN, [name.as_ptr() as usize, name.len(), 0])
+}
+
+pub fn sys_shm_size(handle: usize) -> SyscallResult {
+    syscall_1(MODULE_IPC, FUNCTION_IPC_SHM_SIZE, handle)
+}
+
+pub fn sys_shm_map(handle: usize, addr: usize, prot: usize) -> SyscallResult {
+    syscall_3(MODULE_IPC, FUNCTION_IPC_SHM_MAP, [handle, addr, prot])
+}
+
+pub fn sys_channel_create(handles: &mut [usize; 2]) -> SyscallResult {
+    syscall_1(MODULE_IPC, FUNCTION_IPC_CHANNEL_CREATE, handles.as_mut_ptr() as usize)
+}
+
+pub fn sys_channel_send(handle: usize, buffer: &[u8], transfer: usize, flags: usize) -> SyscallResult {
+    syscall_6(MODULE_IPC, FUNCTION_IPC_CHANNEL_SEND, [handle, buffer.as_ptr() as usize, buffer.len(), transfer, flags, 0])
+}
+
+pub fn sys_channel_recv(handle: usize, buffer: &mut [u8], received: &mut usize, flags: usize) -> SyscallResult {
+    syscall_6(MODULE_IPC, FUNCTION_IPC_CHANNEL_RECV, [handle, buffer.as_mut_ptr() as usize, buffer.len(), received as *mut usize as usize, flags, 0])
+}
+
+// futex的值不等于expected时立即返回EAGAIN；timeout_us为usize::MAX时一直等待
+pub fn sys_futex_wait(futex: &AtomicU32, expected: u32, timeout_us: usize) -> SyscallResult {
+    syscall_3(MODULE_IPC, FUNCTION_IPC_FUTEX_WAIT, [futex as *const AtomicU32 as usize, expected as usize, timeout_us])
+}
+
+pub fn sys_futex_wake(futex: &AtomicU32, count: usize) -> SyscallResult {
+    syscall_3(MODULE_IPC, FUNCTION_IPC_FUTEX_WAKE, [futex as *const AtomicU32 as usize, count, 0])
 }
 
 pub fn sys_exit(exit_code: i32) -> SyscallResult {
@@ -0,0 +1,180 @@
+//! 线程
+//!
+//! 同一个进程的线程共享地址空间和打开的文件，每个线程有自己的栈和线程局部变量。
+//! 用`#[thread_local]`声明的静态变量在每个线程中有一份副本，tp寄存器指向当前线程的副本。
+//!
+//! 每个线程使用一块匿名映射：底部是栈，栈顶之上是线程局部变量，最上面保存线程要运行的闭包和它的返回值
+
+use crate::io::{self, cvt};
+use crate::mman::{MmapMut, MmapOptions};
+use crate::syscall;
+use core::mem::{align_of, size_of};
+use core::ptr;
+
+// 新线程默认的栈大小
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+// 线程局部变量块的对齐，不小于.tdata和.tbss中变量的对齐
+const TLS_ALIGN: usize = 64;
+
+// 线程的闭包和返回值，放在线程映射的顶部
+struct Packet<F, T> {
+    f: Option<F>,
+    result: Option<T>,
+}
+
+// 创建线程的选项
+#[derive(Clone, Debug)]
+pub struct Builder {
+    stack_size: usize,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder { stack_size: DEFAULT_STACK_SIZE }
+    }
+
+    // 新线程的栈大小，默认为64KiB
+    pub fn stack_size(mut self, size: usize) -> Builder {
+        self.stack_size = size;
+        self
+    }
+
+    // 创建线程运行f。线程的栈和线程局部变量也计入进程的内存限制
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+        let stack_size = align_up(self.stack_size, 16);
+        let tls_offset = align_up(stack_size, TLS_ALIGN);
+        let packet_offset = align_up(tls_offset + tls_size(), align_of::<Packet<F, T>>().max(16));
+        let mut region = MmapOptions::new().len(packet_offset + size_of::<Packet<F, T>>()).map_anon()?;
+        let base = region.as_mut_ptr();
+        // 匿名映射按页对齐，块内的偏移满足对齐以后，地址也满足对齐
+        let (tid, packet) = unsafe {
+            let tls = base.add(tls_offset);
+            init_tls(tls);
+            let packet = base.add(packet_offset) as *mut Packet<F, T>;
+            ptr::write(packet, Packet { f: Some(f), result: None });
+            let entry = thread_start::<F, T> as usize;
+            match cvt(syscall::sys_thread_create(entry, base.add(stack_size) as usize, tls as usize, packet as usize)) {
+                Ok(tid) => (tid, packet),
+                Err(e) => {
+                    // 线程没有创建，闭包要在这里释放
+                    ptr::drop_in_place(packet);
+                    return Err(e);
+                },
+            }
+        };
+        Ok(JoinHandle { tid, region: Some(region), result: unsafe { &mut (*packet).result } })
+    }
+}
+
+// 创建线程运行f，失败时panic
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    Builder::new().spawn(f).expect("failed to spawn thread")
+}
+
+extern "C" fn thread_start<F, T>(packet: *mut Packet<F, T>) -> !
+where F: FnOnce() -> T {
+    // 线程结束以前，创建它的线程不会释放packet所在的映射
+    let packet = unsafe { &mut *packet };
+    let f = packet.f.take().unwrap();
+    packet.result = Some(f());
+    syscall::sys_thread_exit(0);
+    unreachable!("thread exited")
+}
+
+// 等待线程结束的句柄
+pub struct JoinHandle<T> {
+    tid: usize,
+    region: Option<MmapMut>,
+    result: *mut Option<T>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> Thread {
+        Thread { tid: self.tid }
+    }
+
+    // 等待线程结束，返回它的闭包的返回值。之后线程的栈被释放
+    pub fn join(mut self) -> io::Result<T> {
+        cvt(syscall::sys_thread_join(self.tid))?;
+        let result = unsafe { (*self.result).take() };
+        drop(self.region.take());
+        // 线程的闭包panic时整个进程已经结束，不会运行到这里
+        Ok(result.expect("thread result"))
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        // 没有等待的线程可能还在运行，它的栈不能释放
+        if let Some(region) = self.region.take() {
+            core::mem::forget(region);
+        }
+    }
+}
+
+// 线程的标识
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Thread {
+    tid: usize,
+}
+
+impl Thread {
+    // 线程号。进程的主线程的线程号等于进程号
+    pub fn id(&self) -> usize {
+        self.tid
+    }
+}
+
+// 当前线程
+pub fn current() -> Thread {
+    Thread { tid: syscall::sys_thread_self().extra }
+}
+
+// 让出处理核，让其它线程先运行
+pub fn yield_now() {
+    syscall::sys_yield();
+}
+
+fn align_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+extern "C" {
+    fn stdata(); fn etdata(); fn etbss();
+}
+
+// 线程局部变量块的字节数。.tbss紧接在.tdata之后
+fn tls_size() -> usize {
+    etbss as usize - stdata as usize
+}
+
+// 复制.tdata中的初始值，清零.tbss部分。
+// unsafe说明：tls必须按TLS_ALIGN对齐，并且有tls_size()个字节可写
+unsafe fn init_tls(tls: *mut u8) {
+    let data_len = etdata as usize - stdata as usize;
+    ptr::copy_nonoverlapping(stdata as usize as *const u8, tls, data_len);
+    ptr::write_bytes(tls.add(data_len), 0, tls_size() - data_len);
+}
+
+// 为主线程准备线程局部变量，在main之前运行。
+// unsafe说明：只能在_start中调用一次，这时还没有访问过线程局部变量
+pub(crate) unsafe fn init_main_thread() {
+    let size = tls_size();
+    if size == 0 {
+        return;
+    }
+    let mut region = MmapOptions::new().len(size).map_anon().expect("allocate thread local storage");
+    let tls = region.as_mut_ptr();
+    init_tls(tls);
+    core::mem::forget(region); // 主线程的线程局部变量一直使用到进程结束
+    asm!("mv tp, {}", in(reg) tls);
+}
@@ -9,7 +9,17 @@ use serde::Deserialize;
 #[macro_use]
 extern crate clap;
 
-const DEFAULT_TARGET: &'static str = "riscv64imac-unknown-none-elf";
+// initramfs的最大长度，和内核中的物理内存布局保持一致
+const INITRAMFS_MAX_SIZE: usize = 1024 * 1024;
+
+// 内核符号表的最大长度，紧接在initramfs之后，和内核中的物理内存布局保持一致
+const SYMBOLS_MAX_SIZE: usize = 256 * 1024;
+
+// 没有指定程序时，打包到initramfs中的程序。第一个程序是shell，启动后可以在其中运行其它程序
+const DEFAULT_APPS: &[&str] = &["shell", "hello-world", "proc-info"];
+
+// 默认磁盘镜像的大小。每簇一个扇区时，FAT32至少需要65525个簇
+const DEFAULT_DISK_SIZE: u64 = 64 * 1024 * 1024;
 
 #[derive(Debug)]
 struct XtaskEnv {
@@ -17,6 +27,7 @@ struct XtaskEnv {
     kernel_package_name: String,
     kernel_binary_name: String,
     compile_mode: CompileMode,
+    target: Target,
 }
 
 #[derive(Debug)]
@@ -25,6 +36,88 @@ enum CompileMode {
     Release
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Riscv64Imac,
+    Riscv32Imac,
+    // 只用于用户程序。内核不使用浮点指令，浮点寄存器由内核按需保存
+    Riscv64Gc,
+}
+
+impl Target {
+    fn from_arg(arg: &str) -> Target {
+        match arg {
+            "riscv64imac" => Target::Riscv64Imac,
+            "riscv32imac" => Target::Riscv32Imac,
+            _ => {
+                println!("xtask: unsupported target {}, use riscv64imac or riscv32imac", arg);
+                process::exit(1);
+            }
+        }
+    }
+    // 用户程序在Cargo.toml的[package.metadata.xtask]中指定的指令集，必须和内核的寄存器宽度相同
+    fn from_app_arg(arg: &str, kernel: Target, app_name: &str) -> Target {
+        let target = match arg {
+            "riscv64imac" => Target::Riscv64Imac,
+            "riscv32imac" => Target::Riscv32Imac,
+            "riscv64gc" => Target::Riscv64Gc,
+            _ => {
+                println!("xtask: app {} uses unsupported target {}, use riscv64imac, riscv32imac or riscv64gc", app_name, arg);
+                process::exit(1);
+            }
+        };
+        if target.binary_architecture() != kernel.binary_architecture() {
+            println!("xtask: app {} is built for {}, but the kernel is built for {}", app_name, target.triple(), kernel.triple());
+            process::exit(1);
+        }
+        target
+    }
+    fn triple(&self) -> &'static str {
+        match self {
+            Target::Riscv64Imac => "riscv64imac-unknown-none-elf",
+            Target::Riscv32Imac => "riscv32imac-unknown-none-elf",
+            Target::Riscv64Gc => "riscv64gc-unknown-none-elf",
+        }
+    }
+    fn binary_architecture(&self) -> &'static str {
+        match self {
+            Target::Riscv64Imac | Target::Riscv64Gc => "riscv64",
+            Target::Riscv32Imac => "riscv32",
+        }
+    }
+    fn qemu(&self) -> &'static str {
+        match self {
+            Target::Riscv64Imac | Target::Riscv64Gc => "qemu-system-riscv64",
+            Target::Riscv32Imac => "qemu-system-riscv32",
+        }
+    }
+    // RustSBI的二进制只支持RV64；RV32使用QEMU自带的OpenSBI固件
+    fn bios(&self) -> &'static str {
+        match self {
+            Target::Riscv64Imac | Target::Riscv64Gc => "../../../bootloader/rustsbi-qemu.bin",
+            Target::Riscv32Imac => "default",
+        }
+    }
+    // 和内核中的物理内存布局保持一致。RV32的固件把内核放在0x80400000，initramfs要放到后面
+    fn initramfs_load_address(&self) -> usize {
+        match self {
+            Target::Riscv64Imac | Target::Riscv64Gc => 0x80400000,
+            Target::Riscv32Imac => 0x80800000,
+        }
+    }
+    fn symbols_load_address(&self) -> usize {
+        self.initramfs_load_address() + INITRAMFS_MAX_SIZE
+    }
+    // 内核和用户程序都保留帧指针，出错时可以回溯调用栈。
+    // 设置RUSTFLAGS以后.cargo/config.toml中的rustflags不再生效，所以链接脚本也要在这里给出
+    fn rustflags(&self) -> &'static str {
+        match self {
+            Target::Riscv64Imac | Target::Riscv64Gc => "-C link-arg=-Tlinker64.ld -C force-frame-pointers=yes",
+            Target::Riscv32Imac => "-C link-arg=-Tlinker32.ld -C force-frame-pointers=yes",
+        }
+    }
+}
+
 fn main() {    
     let matches = clap_app!(xtask =>
         (version: crate_version!())
@@ -33,24 +126,41 @@ fn main() {
         (@subcommand make =>
             (about: "Build project")
             (@arg release: --release "Build artifacts in release mode, with optimizations")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
         )
         (@subcommand asm =>
             (about: "View asm code for project")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
         )
         (@subcommand size =>
             (about: "View size for project")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
         )
         (@subcommand qemu =>
             (about: "Run QEMU")
             (@arg release: --release "Build artifacts in release mode, with optimizations")
-            (@arg app: "Choose the apps to be bundled")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
+            (@arg disk: --disk +takes_value "Disk image attached as the virtio block device")
+            (@arg append: --append +takes_value "Kernel command line, passed to QEMU's -append; for example \"init=/bin/hello-world loglevel=debug\"")
+            (@arg app: ... "Choose the apps to be bundled into initramfs, the first one runs at boot; shell, hello-world and proc-info by default")
         )
         (@subcommand debug =>
             (about: "Debug with QEMU and GDB stub")
-            (@arg app: "Choose the apps to be bundled")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
+            (@arg disk: --disk +takes_value "Disk image attached as the virtio block device")
+            (@arg append: --append +takes_value "Kernel command line, passed to QEMU's -append; for example \"init=/bin/hello-world loglevel=debug\"")
+            (@arg app: ... "Choose the apps to be bundled into initramfs, the first one runs at boot; shell, hello-world and proc-info by default")
+        )
+        (@subcommand mkdisk =>
+            (about: "Create a FAT32 disk image with host tools (mkfs.vfat and mtools)")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
+            (@arg size: --size +takes_value "Image size in MiB, 64 by default")
+            (@arg output: --output +takes_value "Output image, disk.img in the target directory by default")
+            (@arg dir: "Files in this directory are copied into the image, `disk` of the project by default")
         )
         (@subcommand gdb =>
             (about: "Run GDB debugger")
+            (@arg target: --target +takes_value "Target instruction set, riscv64imac or riscv32imac")
         )
     ).get_matches();
     let kernel_package_path = project_root().join("kernels").join(&default_kernel_path());
@@ -61,42 +171,68 @@ fn main() {
         kernel_package_name,
         kernel_binary_name,
         compile_mode: CompileMode::Debug,
+        target: Target::Riscv64Imac,
     };
-    println!("xtask: package {}, mode: {:?}", xtask_env.kernel_package_name, xtask_env.compile_mode);
+    if let (_, Some(sub_matches)) = matches.subcommand() {
+        if let Some(target) = sub_matches.value_of("target") {
+            xtask_env.target = Target::from_arg(target);
+        }
+    }
+    println!("xtask: package {}, mode: {:?}, target: {}", xtask_env.kernel_package_name, xtask_env.compile_mode, xtask_env.target.triple());
     if let Some(matches) = matches.subcommand_matches("make") {
         if matches.is_present("release") {
             xtask_env.compile_mode = CompileMode::Release;
         }
         xtask_build_kernel(&xtask_env);
         xtask_binary_kernel(&xtask_env);
+        xtask_symbols_kernel(&xtask_env);
         // xtask_build_apps(&xtask_env); // todo: multiple apps
     } else if let Some(matches) = matches.subcommand_matches("qemu") {
         if matches.is_present("release") {
             xtask_env.compile_mode = CompileMode::Release;
         }
-        let chosen_app = "hello-world"; // todo: 目前是写死的
-        if let Some(app_matches) = matches.values_of("app") {
-            for app_name in app_matches {
-                println!("xtask: building app {}", app_name);
-                xtask_build_app(&xtask_env, app_name);
-                xtask_binary_app(&xtask_env, app_name);
-            }
+        let apps: Vec<&str> = matches.values_of("app").map(|apps| apps.collect()).unwrap_or_else(|| DEFAULT_APPS.to_vec());
+        let mut targets = Vec::new();
+        for app_name in apps.iter() {
+            let target = app_target(&xtask_env, app_name);
+            println!("xtask: building app {} for {}", app_name, target.triple());
+            xtask_build_app(&xtask_env, app_name, target);
+            xtask_binary_app(&xtask_env, app_name, target);
+            targets.push(target);
         }
+        xtask_pack_initramfs(&xtask_env, &apps, &targets);
         xtask_build_kernel(&xtask_env);
         xtask_binary_kernel(&xtask_env);
-        xtask_qemu_run(&xtask_env, chosen_app);
+        xtask_symbols_kernel(&xtask_env);
+        let disk = xtask_disk_image(&xtask_env, matches.value_of("disk"));
+        xtask_qemu_run(&xtask_env, &disk, matches.value_of("append"));
     } else if let Some(matches) = matches.subcommand_matches("debug") {
-        let chosen_app = "hello-world"; // todo: 目前是写死的
-        if let Some(app_matches) = matches.values_of("app") {
-            for app_name in app_matches {
-                println!("xtask: building app {}", app_name);
-                xtask_build_app(&xtask_env, app_name);
-                xtask_binary_app(&xtask_env, app_name);
-            }
+        let apps: Vec<&str> = matches.values_of("app").map(|apps| apps.collect()).unwrap_or_else(|| DEFAULT_APPS.to_vec());
+        let mut targets = Vec::new();
+        for app_name in apps.iter() {
+            let target = app_target(&xtask_env, app_name);
+            println!("xtask: building app {} for {}", app_name, target.triple());
+            xtask_build_app(&xtask_env, app_name, target);
+            xtask_binary_app(&xtask_env, app_name, target);
+            targets.push(target);
         }
+        xtask_pack_initramfs(&xtask_env, &apps, &targets);
         xtask_build_kernel(&xtask_env);
         xtask_binary_kernel(&xtask_env);
-        xtask_qemu_debug(&xtask_env, chosen_app);
+        xtask_symbols_kernel(&xtask_env);
+        let disk = xtask_disk_image(&xtask_env, matches.value_of("disk"));
+        xtask_qemu_debug(&xtask_env, &disk, matches.value_of("append"));
+    } else if let Some(matches) = matches.subcommand_matches("mkdisk") {
+        let size = match matches.value_of("size") {
+            Some(size) => size.parse::<u64>().expect("disk size in MiB") * 1024 * 1024,
+            None => DEFAULT_DISK_SIZE,
+        };
+        let path = match matches.value_of("output") {
+            Some(output) => env::current_dir().unwrap().join(output),
+            None => dist_dir(&xtask_env).join("disk.img"),
+        };
+        let content = matches.value_of("dir").map(|dir| env::current_dir().unwrap().join(dir));
+        xtask_make_disk(&path, size, content.as_deref().or(default_disk_content().as_deref()));
     } else if let Some(_matches) = matches.subcommand_matches("gdb") {
         xtask_gdb(&xtask_env);
     } else if let Some(_matches) = matches.subcommand_matches("asm") {
@@ -120,7 +256,8 @@ fn xtask_build_kernel(xtask_env: &XtaskEnv) {
         CompileMode::Release => { command.arg("--release"); },
     }
     command.args(&["--package", &xtask_env.kernel_package_name]);
-    command.args(&["--target", DEFAULT_TARGET]);
+    command.args(&["--target", xtask_env.target.triple()]);
+    command.env("RUSTFLAGS", xtask_env.target.rustflags());
     let status = command
         .status().unwrap();
     if !status.success() {
@@ -129,7 +266,7 @@ fn xtask_build_kernel(xtask_env: &XtaskEnv) {
     }
 }
 
-fn xtask_build_app(xtask_env: &XtaskEnv, app_name: &str) {
+fn xtask_build_app(xtask_env: &XtaskEnv, app_name: &str, target: Target) {
     let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
     let mut command = Command::new(cargo);
     command.current_dir(project_root().join("apps").join(app_name));
@@ -139,7 +276,8 @@ fn xtask_build_app(xtask_env: &XtaskEnv, app_name: &str) {
         CompileMode::Release => { command.arg("--release"); },
     }
     command.args(&["--package", app_name]);
-    command.args(&["--target", DEFAULT_TARGET]);
+    command.args(&["--target", target.triple()]);
+    command.env("RUSTFLAGS", target.rustflags());
     let status = command
         .status().unwrap();
     if !status.success() {
@@ -148,12 +286,12 @@ fn xtask_build_app(xtask_env: &XtaskEnv, app_name: &str) {
     }
 }
 
-fn xtask_binary_app(xtask_env: &XtaskEnv, app_name: &str) {
+fn xtask_binary_app(xtask_env: &XtaskEnv, app_name: &str, target: Target) {
     let objcopy = "rust-objcopy";
     let status = Command::new(objcopy)
-        .current_dir(dist_dir(xtask_env))
+        .current_dir(target_dist_dir(xtask_env, target))
         .arg(app_name)
-        .arg("--binary-architecture=riscv64")
+        .arg(format!("--binary-architecture={}", target.binary_architecture()))
         .arg("--strip-all")
         .args(&["-O", "binary", &format!("{}.bin", app_name)])
         .status().unwrap();
@@ -197,7 +335,7 @@ build: firmware
     let status = Command::new(objcopy)
         .current_dir(dist_dir(xtask_env))
         .arg(&xtask_env.kernel_package_name)
-        .arg("--binary-architecture=riscv64")
+        .arg(format!("--binary-architecture={}", xtask_env.target.binary_architecture()))
         .arg("--strip-all")
         .args(&["-O", "binary", &xtask_env.kernel_binary_name])
         .status().unwrap();
@@ -208,7 +346,237 @@ build: firmware
     }
 }
 
-fn xtask_qemu_run(xtask_env: &XtaskEnv, one_app: &str) {
+// 从内核的ELF文件中提取函数的符号，内核回溯调用栈时用它把地址转换为函数名。
+// 格式：魔数"KSYM"，32位的符号数，每个符号的64位地址、32位名字偏移和32位名字长度，之后是所有的名字。
+// 符号按地址排序，数值都是小端序。符号表太大时不生成，内核只输出地址
+fn xtask_symbols_kernel(xtask_env: &XtaskEnv) {
+    let path = dist_dir(xtask_env).join(symbols_file_name(xtask_env));
+    let output = Command::new("rust-nm")
+        .current_dir(dist_dir(xtask_env))
+        .args(&["--defined-only", "--numeric-sort", "--demangle"])
+        .arg(&xtask_env.kernel_package_name)
+        .output().unwrap();
+    if !output.status.success() {
+        println!("nm kernel symbols failed");
+        process::exit(1);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut symbols = Vec::new();
+    for line in stdout.lines() {
+        // 地址、类型和名字，名字中可能有空格
+        let mut fields = line.splitn(3, ' ');
+        let (addr, kind, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(addr), Some(kind), Some(name)) => (addr, kind, name),
+            _ => continue,
+        };
+        if kind != "t" && kind != "T" {
+            continue;
+        }
+        if let Ok(addr) = u64::from_str_radix(addr, 16) {
+            symbols.push((addr, strip_symbol_hash(name)));
+        }
+    }
+    let mut table = Vec::new();
+    let mut names = Vec::new();
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    for (addr, name) in symbols.iter() {
+        table.extend_from_slice(&addr.to_le_bytes());
+        table.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    table.extend_from_slice(&names);
+    if table.len() > SYMBOLS_MAX_SIZE {
+        println!("xtask: kernel symbol table is {} bytes, larger than {} bytes, backtraces will not be symbolized", table.len(), SYMBOLS_MAX_SIZE);
+        let _ = fs::remove_file(&path);
+        return;
+    }
+    fs::write(&path, &table).expect("write kernel symbol table");
+    println!("xtask: extracted {} kernel symbols, {} bytes", symbols.len(), table.len());
+}
+
+// 去掉Rust符号名末尾的哈希，比如`::h0123456789abcdef`
+fn strip_symbol_hash(name: &str) -> &str {
+    match name.rfind("::h") {
+        Some(pos) if name.len() - pos == 19 && name[pos + 3..].chars().all(|c| c.is_ascii_hexdigit()) => &name[..pos],
+        _ => name,
+    }
+}
+
+fn symbols_file_name(xtask_env: &XtaskEnv) -> String {
+    format!("{}.sym", xtask_env.kernel_package_name)
+}
+
+// 用loader设备把符号表放在内核约定的位置，没有符号表时不加载
+fn symbols_loader_args(xtask_env: &XtaskEnv) -> Vec<String> {
+    let file_name = symbols_file_name(xtask_env);
+    if !dist_dir(xtask_env).join(&file_name).exists() {
+        return Vec::new();
+    }
+    vec!["-device".to_string(), format!("loader,file={},addr={:#x}", file_name, xtask_env.target.symbols_load_address())]
+}
+
+// QEMU把-append的内容放在设备树/chosen节点的bootargs中，内核从那里读取命令行
+fn append_args(append: Option<&str>) -> Vec<&str> {
+    match append {
+        Some(append) => vec!["-append", append],
+        None => Vec::new(),
+    }
+}
+
+// 把程序打包到initramfs的/bin目录下，项目initramfs目录中的数据文件按原来的路径打包。
+// 第一个程序的路径写在/etc/init中，内核启动后运行它。targets是每个程序编译时使用的指令集
+fn xtask_pack_initramfs(xtask_env: &XtaskEnv, apps: &[&str], targets: &[Target]) {
+    let mut archive = CpioWriter::new();
+    archive.push_dir("bin");
+    for (app_name, target) in apps.iter().zip(targets) {
+        let binary = fs::read(target_dist_dir(xtask_env, *target).join(format!("{}.bin", app_name))).expect("read app binary");
+        archive.push_file(&format!("bin/{}", app_name), &binary);
+    }
+    archive.push_dir("etc");
+    archive.push_file("etc/init", format!("/bin/{}\n", apps[0]).as_bytes());
+    let data_dir = project_root().join("initramfs");
+    if data_dir.is_dir() {
+        archive.push_host_dir(&data_dir, "");
+    }
+    let archive = archive.finish();
+    if archive.len() > INITRAMFS_MAX_SIZE {
+        println!("xtask: initramfs is {} bytes, larger than {} bytes", archive.len(), INITRAMFS_MAX_SIZE);
+        process::exit(1);
+    }
+    fs::write(dist_dir(xtask_env).join("initramfs.cpio"), &archive).expect("write initramfs");
+    println!("xtask: packed {} app(s) into initramfs, {} bytes", apps.len(), archive.len());
+}
+
+// cpio newc格式的归档
+struct CpioWriter {
+    buf: Vec<u8>,
+    next_ino: u32,
+}
+
+impl CpioWriter {
+    const MODE_DIRECTORY: u32 = 0o040755;
+    const MODE_REGULAR: u32 = 0o100755;
+
+    fn new() -> Self {
+        CpioWriter { buf: Vec::new(), next_ino: 1 }
+    }
+    fn push_dir(&mut self, name: &str) {
+        self.push(name, Self::MODE_DIRECTORY, &[]);
+    }
+    fn push_file(&mut self, name: &str, content: &[u8]) {
+        self.push(name, Self::MODE_REGULAR, content);
+    }
+    // 递归打包主机上的目录，prefix是它在归档中的路径
+    fn push_host_dir(&mut self, dir: &Path, prefix: &str) {
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir).expect("read initramfs directory")
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        for path in entries {
+            let file_name = path.file_name().unwrap().to_str().expect("file name in UTF-8");
+            let name = if prefix.is_empty() { file_name.to_string() } else { format!("{}/{}", prefix, file_name) };
+            if path.is_dir() {
+                self.push_dir(&name);
+                self.push_host_dir(&path, &name);
+            } else {
+                self.push_file(&name, &fs::read(&path).expect("read initramfs file"));
+            }
+        }
+    }
+    // 头部是魔数和13个8位十六进制数，之后是文件名和内容，各自按4字节对齐
+    fn push(&mut self, name: &str, mode: u32, content: &[u8]) {
+        let nlink = if mode == Self::MODE_DIRECTORY { 2 } else { 1 };
+        let fields = [self.next_ino, mode, 0, 0, nlink, 0, content.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        self.next_ino += 1;
+        self.buf.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            self.buf.extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.push(0);
+        self.align();
+        self.buf.extend_from_slice(content);
+        self.align();
+    }
+    fn align(&mut self) {
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+    }
+    fn finish(mut self) -> Vec<u8> {
+        self.push("TRAILER!!!", 0, &[]);
+        self.buf
+    }
+}
+
+// 块设备使用的磁盘镜像。没有指定时，使用输出目录下的disk.img，不存在就创建一个FAT32镜像。
+// 镜像在多次运行之间保留，QEMU退出后可以用mtools查看内核写入的文件
+fn xtask_disk_image(xtask_env: &XtaskEnv, disk: Option<&str>) -> PathBuf {
+    if let Some(disk) = disk {
+        return env::current_dir().unwrap().join(disk);
+    }
+    let path = dist_dir(xtask_env).join("disk.img");
+    if !path.exists() {
+        xtask_make_disk(&path, DEFAULT_DISK_SIZE, default_disk_content().as_deref());
+    }
+    path
+}
+
+// 创建FAT32格式的磁盘镜像，把目录content中的文件复制到镜像的根目录
+fn xtask_make_disk(path: &Path, size: u64, content: Option<&Path>) {
+    println!("xtask: creating FAT32 disk image {}", path.display());
+    // mkfs.vfat -C要求镜像文件不存在，大小以KiB为单位
+    if path.exists() {
+        fs::remove_file(path).unwrap();
+    }
+    let mut mkfs = Command::new("mkfs.vfat");
+    mkfs.args(&["-F", "32", "-s", "1", "-n", "TORNADO", "-C"])
+        .arg(path)
+        .arg((size / 1024).to_string());
+    run_host_tool(mkfs, "mkfs.vfat", "dosfstools");
+    let content = match content {
+        Some(content) => content,
+        None => return,
+    };
+    let files: Vec<PathBuf> = fs::read_dir(content)
+        .expect("read disk content directory")
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    if files.is_empty() {
+        return;
+    }
+    println!("xtask: copying files in {} into disk image", content.display());
+    let mut mcopy = Command::new("mcopy");
+    mcopy.env("MTOOLS_SKIP_CHECK", "1")
+        .arg("-i").arg(path)
+        .arg("-s")
+        .args(&files)
+        .arg("::/");
+    run_host_tool(mcopy, "mcopy", "mtools");
+}
+
+// 项目中的disk目录，存放默认复制到磁盘镜像中的文件
+fn default_disk_content() -> Option<PathBuf> {
+    Some(project_root().join("disk")).filter(|path| path.is_dir())
+}
+
+fn run_host_tool(mut command: Command, program: &str, package: &str) {
+    match command.status() {
+        Ok(status) if status.success() => {},
+        Ok(_) => {
+            println!("xtask: {} failed", program);
+            process::exit(1);
+        },
+        Err(_) => {
+            println!("xtask: {} not found, please install {}", program, package);
+            process::exit(1);
+        },
+    }
+}
+
+fn xtask_qemu_run(xtask_env: &XtaskEnv, disk: &Path, append: Option<&str>) {
     /*
     qemu: build
     @qemu-system-riscv64 \
@@ -219,13 +587,17 @@ fn xtask_qemu_run(xtask_env: &XtaskEnv, one_app: &str) {
             -device loader,file={{test-kernel-bin}},addr=0x80200000 \
             -smp threads={{threads}}
     */
-    let status = Command::new("qemu-system-riscv64")
+    let status = Command::new(xtask_env.target.qemu())
         .current_dir(dist_dir(xtask_env))
         .args(&["-machine", "virt"])
-        .args(&["-bios", "../../../bootloader/rustsbi-qemu.bin"])
+        .args(&["-bios", xtask_env.target.bios()])
         .arg("-nographic")
         .args(&["-kernel", &xtask_env.kernel_binary_name])
-        .args(&["-device", &format!("loader,file={}.bin,addr=0x80400000", one_app)])
+        .args(&["-device", &format!("loader,file=initramfs.cpio,addr={:#x}", xtask_env.target.initramfs_load_address())])
+        .args(symbols_loader_args(xtask_env))
+        .args(append_args(append))
+        .args(&["-drive", &format!("file={},if=none,format=raw,id=x0", disk.display())])
+        .args(&["-device", "virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0"])
         .status().unwrap();
     
     if !status.success() {
@@ -234,14 +606,18 @@ fn xtask_qemu_run(xtask_env: &XtaskEnv, one_app: &str) {
     }
 }
 
-fn xtask_qemu_debug(xtask_env: &XtaskEnv, one_app: &str) {
-    let status = Command::new("qemu-system-riscv64")
+fn xtask_qemu_debug(xtask_env: &XtaskEnv, disk: &Path, append: Option<&str>) {
+    let status = Command::new(xtask_env.target.qemu())
         .current_dir(dist_dir(xtask_env))
         .args(&["-machine", "virt"])
-        .args(&["-bios", "../../../bootloader/rustsbi-qemu.bin"])
+        .args(&["-bios", xtask_env.target.bios()])
         .args(&["-kernel", &xtask_env.kernel_binary_name])
         .arg("-nographic")
-        .args(&["-device", &format!("loader,file={}.bin,addr=0x80400000", one_app)])
+        .args(&["-device", &format!("loader,file=initramfs.cpio,addr={:#x}", xtask_env.target.initramfs_load_address())])
+        .args(symbols_loader_args(xtask_env))
+        .args(append_args(append))
+        .args(&["-drive", &format!("file={},if=none,format=raw,id=x0", disk.display())])
+        .args(&["-device", "virtio-blk-device,drive=x0,bus=virtio-mmio-bus.0"])
         .args(&["-gdb", "tcp::1234", "-S"])
         .status().unwrap();
     
@@ -275,7 +651,12 @@ fn project_root() -> PathBuf {
 }
 
 fn dist_dir(xtask_env: &XtaskEnv) -> PathBuf {
-    let mut path_buf = project_root().join("target").join(DEFAULT_TARGET);
+    target_dist_dir(xtask_env, xtask_env.target)
+}
+
+// 用户程序可以使用和内核不同的指令集，它们的输出在另一个目录中
+fn target_dist_dir(xtask_env: &XtaskEnv, target: Target) -> PathBuf {
+    let mut path_buf = project_root().join("target").join(target.triple());
     path_buf = match xtask_env.compile_mode {
         CompileMode::Debug => path_buf.join("debug"),
         CompileMode::Release => path_buf.join("release"),
@@ -300,6 +681,39 @@ struct Package {
     name: String,
 }
 
+// 用户程序编译时使用的指令集，在程序的Cargo.toml中这样指定，没有指定时和内核相同：
+// [package.metadata.xtask]
+// target = "riscv64gc"
+fn app_target(xtask_env: &XtaskEnv, app_name: &str) -> Target {
+    let path = project_root().join("apps").join(app_name).join("Cargo.toml");
+    let buf = fs::read_to_string(path).expect("read app cargo toml file");
+    let cfg: AppToml = toml::from_str(&buf).expect("deserialize app cargo toml");
+    match cfg.package.metadata.and_then(|metadata| metadata.xtask).and_then(|xtask| xtask.target) {
+        Some(target) => Target::from_app_arg(&target, xtask_env.target, app_name),
+        None => xtask_env.target,
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct AppToml {
+    package: AppPackage,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct AppPackage {
+    metadata: Option<AppMetadata>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct AppMetadata {
+    xtask: Option<AppXtaskMetadata>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct AppXtaskMetadata {
+    target: Option<String>,
+}
+
 fn default_kernel_path() -> String {
     let workspace_toml = project_root().join("Cargo.toml");
     let buf = fs::read_to_string(workspace_toml).expect("read workspace cargo toml file");